@@ -0,0 +1,286 @@
+use crate::directory::user_secret;
+use crate::message::{split_header_list, SipRequest};
+use crate::sip_defs::*;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::Instant;
+
+// Digest authentication (RFC 3261 §22, RFC 7616, RFC 8760): REGISTER is
+// challenged with 401 and WWW-Authenticate, initial INVITEs with 407 and
+// Proxy-Authenticate. Each challenge offers SHA-256 and MD5 with qop=auth,
+// and only answers with qop=auth are accepted (no RFC 2069 fallback): nonces
+// expire after NONCE_LIFETIME and their nonce count must grow. At most
+// MAX_AUTH_NONCES are remembered; the oldest go first.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    // Absent means MD5 (RFC 7616 §3.3). The -sess variants are not offered.
+    pub fn parse(value: Option<&str>) -> Option<Self> {
+        match value {
+            None => Some(DigestAlgorithm::Md5),
+            Some(name) if name.eq_ignore_ascii_case("MD5") => Some(DigestAlgorithm::Md5),
+            Some(name) if name.eq_ignore_ascii_case("SHA-256") => Some(DigestAlgorithm::Sha256),
+            Some(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    // Lowercase hex digest of `data`.
+    fn hash(&self, data: &str) -> String {
+        let digest = match self {
+            DigestAlgorithm::Md5 => Md5::digest(data.as_bytes()).to_vec(),
+            DigestAlgorithm::Sha256 => Sha256::digest(data.as_bytes()).to_vec(),
+        };
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // H(username:realm:password), what a user directory may keep instead of
+    // the password.
+    pub fn ha1(&self, username: &str, realm: &str, password: &str) -> String {
+        self.hash(&format!("{}:{}:{}", username, realm, password))
+    }
+}
+
+// The credentials of an Authorization or Proxy-Authorization header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: Option<String>,
+    pub qop: Option<String>,
+    pub nc: Option<String>, // Eight hex digits, hashed as sent
+    pub cnonce: Option<String>,
+}
+
+impl Credentials {
+    // Parses `Digest username="...", realm="...", ...`; None for other
+    // schemes or when a mandatory parameter is missing.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (scheme, params) = value.split_once(char::is_whitespace)?;
+        if !scheme.eq_ignore_ascii_case("Digest") {
+            return None;
+        }
+        let params = split_header_list(params)
+            .into_iter()
+            .filter_map(|param| param.split_once('='))
+            .map(|(name, value)| {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                (name.trim().to_ascii_lowercase(), value.to_string())
+            })
+            .collect::<Vec<_>>();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+        };
+        Some(Credentials {
+            username: param("username")?,
+            realm: param("realm")?,
+            nonce: param("nonce")?,
+            uri: param("uri")?,
+            response: param("response")?,
+            algorithm: param("algorithm"),
+            qop: param("qop"),
+            nc: param("nc"),
+            cnonce: param("cnonce"),
+        })
+    }
+
+    // The response a client knowing `password` computes for `method`
+    // (RFC 7616 §3.4.1); None for an algorithm we do not support.
+    pub fn expected_response(&self, method: &str, password: &str) -> Option<String> {
+        let algorithm = DigestAlgorithm::parse(self.algorithm.as_deref())?;
+        let ha1 = algorithm.ha1(&self.username, &self.realm, password);
+        Some(self.response_with_ha1(algorithm, &ha1, method))
+    }
+
+    fn response_with_ha1(&self, algorithm: DigestAlgorithm, ha1: &str, method: &str) -> String {
+        let ha2 = algorithm.hash(&format!("{}:{}", method, self.uri));
+        let data = match (&self.qop, &self.nc, &self.cnonce) {
+            (Some(qop), Some(nc), Some(cnonce)) => {
+                format!("{}:{}:{}:{}:{}:{}", ha1, self.nonce, nc, cnonce, qop, ha2)
+            }
+            _ => format!("{}:{}:{}", ha1, self.nonce, ha2),
+        };
+        algorithm.hash(&data)
+    }
+}
+
+impl fmt::Display for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+            self.username, self.realm, self.nonce, self.uri, self.response
+        )?;
+        if let Some(algorithm) = &self.algorithm {
+            write!(f, ", algorithm={}", algorithm)?;
+        }
+        if let Some(qop) = &self.qop {
+            write!(f, ", qop={}", qop)?;
+        }
+        if let Some(nc) = &self.nc {
+            write!(f, ", nc={}", nc)?;
+        }
+        if let Some(cnonce) = &self.cnonce {
+            write!(f, ", cnonce=\"{}\"", cnonce)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,   // No credentials for our realm: challenge
+    Invalid,   // Wrong response or unusable credentials: challenge again
+    Stale,     // Right password, expired or replayed nonce: challenge with stale=true
+    Forbidden, // Credentials of another user: 403
+}
+
+// A fresh nonce, remembered until it expires.
+pub fn issue_nonce(now: Instant) -> String {
+    let value = format!("{:032x}", rand::random::<u128>());
+    let mut nonces = lock_nonces();
+    nonces.retain(|nonce| now.duration_since(nonce.issued) < NONCE_LIFETIME);
+    if nonces.len() >= MAX_AUTH_NONCES {
+        // Issued in order, so the oldest are at the front
+        let excess = nonces.len() + 1 - MAX_AUTH_NONCES;
+        nonces.drain(..excess);
+    }
+    nonces.push(IssuedNonce {
+        value: value.clone(),
+        issued: now,
+        last_nc: 0,
+    });
+    value
+}
+
+// The algorithms `username` can answer a challenge with, most preferred
+// first: both with a password, only its own with a stored HA1.
+pub fn user_algorithms(username: &str) -> Vec<DigestAlgorithm> {
+    match user_secret(username) {
+        Some(UserSecret::Ha1(algorithm, _)) => vec![algorithm],
+        _ => vec![DigestAlgorithm::Sha256, DigestAlgorithm::Md5],
+    }
+}
+
+// WWW-Authenticate / Proxy-Authenticate values for a new challenge, one per
+// algorithm in the order given (RFC 8760 §2.4).
+pub fn challenge(stale: bool, algorithms: &[DigestAlgorithm], now: Instant) -> Vec<String> {
+    let nonce = issue_nonce(now);
+    algorithms
+        .iter()
+        .map(|algorithm| {
+            let mut value = format!(
+                "Digest realm=\"{}\", nonce=\"{}\", algorithm={}, qop=\"auth\"",
+                AUTH_REALM,
+                nonce,
+                algorithm.as_str()
+            );
+            if stale {
+                value.push_str(", stale=true");
+            }
+            value
+        })
+        .collect()
+}
+
+// Checks the credentials `request` carries for `username`: Authorization
+// for REGISTER, Proxy-Authorization for anything else.
+pub fn authenticate(request: &SipRequest, username: &str, now: Instant) -> Result<(), AuthError> {
+    let header = if request.method == "REGISTER" {
+        "Authorization"
+    } else {
+        "Proxy-Authorization"
+    };
+    let credentials = request
+        .headers
+        .get_all(header)
+        .filter_map(|h| Credentials::parse(&h.value))
+        .find(|credentials| credentials.realm == AUTH_REALM)
+        .ok_or(AuthError::Missing)?;
+    if credentials.username != username {
+        return Err(AuthError::Forbidden);
+    }
+    // Credentials of an unknown user fail like a wrong password
+    let secret = user_secret(username).ok_or(AuthError::Invalid)?;
+    // Without a nonce count a nonce could be replayed for its whole lifetime
+    let nc = match (&credentials.qop, &credentials.nc, &credentials.cnonce) {
+        (Some(qop), Some(nc), Some(_)) if qop == "auth" => {
+            u32::from_str_radix(nc, 16).map_err(|_| AuthError::Invalid)?
+        }
+        _ => return Err(AuthError::Invalid),
+    };
+    if credentials.uri != request.uri {
+        return Err(AuthError::Invalid);
+    }
+    let algorithm =
+        DigestAlgorithm::parse(credentials.algorithm.as_deref()).ok_or(AuthError::Invalid)?;
+    let ha1 = match secret {
+        UserSecret::Password(password) => algorithm.ha1(username, &credentials.realm, &password),
+        UserSecret::Ha1(kept, ha1) if kept == algorithm => ha1,
+        UserSecret::Ha1(..) => return Err(AuthError::Invalid),
+    };
+    let expected = credentials.response_with_ha1(algorithm, &ha1, &request.method);
+    if !constant_time_eq(&expected, &credentials.response) {
+        return Err(AuthError::Invalid);
+    }
+    use_nonce(&credentials.nonce, nc, now)
+}
+
+// Compares two responses without stopping at the first difference, so the
+// time taken does not tell how much of a guess was right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+// Accepts a nonce we issued that has not expired, with a nonce count above
+// any used with it before.
+fn use_nonce(value: &str, nc: u32, now: Instant) -> Result<(), AuthError> {
+    let mut nonces = lock_nonces();
+    let nonce = nonces
+        .iter_mut()
+        .find(|nonce| nonce.value == value)
+        .filter(|nonce| now.duration_since(nonce.issued) < NONCE_LIFETIME)
+        .ok_or(AuthError::Stale)?;
+    if nc <= nonce.last_nc {
+        return Err(AuthError::Stale);
+    }
+    nonce.last_nc = nc;
+    Ok(())
+}
+
+fn lock_nonces() -> std::sync::MutexGuard<'static, Vec<IssuedNonce>> {
+    match AUTH_NONCES.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            eprintln!("AUTH_NONCES mutex poisoned; continuing with existing data.");
+            poisoned.into_inner()
+        }
+    }
+}
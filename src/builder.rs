@@ -0,0 +1,305 @@
+use crate::message::*;
+use crate::sip_defs::*;
+use rand::Rng;
+
+// Programmatic construction of SIP messages.
+// Both builders compute Content-Length themselves and add the configured
+// User-Agent (requests) or Server (responses) header.
+
+// Default reason phrases (RFC 3261 §21 and the extensions we use).
+pub fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Trying",
+        180 => "Ringing",
+        181 => "Call Is Being Forwarded",
+        182 => "Queued",
+        183 => "Session Progress",
+        200 => "OK",
+        202 => "Accepted",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Moved Temporarily",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        420 => "Bad Extension",
+        422 => "Session Interval Too Small",
+        423 => "Interval Too Brief",
+        480 => "Temporarily Unavailable",
+        481 => "Call/Transaction Does Not Exist",
+        482 => "Loop Detected",
+        483 => "Too Many Hops",
+        486 => "Busy Here",
+        487 => "Request Terminated",
+        488 => "Not Acceptable Here",
+        491 => "Request Pending",
+        500 => "Server Internal Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        504 => "Server Time-out",
+        600 => "Busy Everywhere",
+        603 => "Decline",
+        604 => "Does Not Exist Anywhere",
+        606 => "Not Acceptable",
+        _ => match code / 100 {
+            1 => "Session Progress",
+            2 => "OK",
+            3 => "Redirection",
+            4 => "Client Error",
+            5 => "Server Error",
+            _ => "Global Failure",
+        },
+    }
+}
+
+fn random_hex(len: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..len)
+        .map(|_| std::char::from_digit(rng.gen_range(0..16), 16).unwrap_or('0'))
+        .collect()
+}
+
+// New RFC 3261 branch id (with the z9hG4bK magic cookie).
+pub fn generate_branch() -> String {
+    format!("z9hG4bK{}", random_hex(16))
+}
+
+// New From/To tag.
+pub fn generate_tag() -> String {
+    random_hex(10)
+}
+
+// New Call-ID local to this server.
+pub fn generate_call_id() -> String {
+    format!("{}@{}", random_hex(20), SIP_SERVER_IP_ADDRESS)
+}
+
+// Via value for requests originated by this server.
+pub fn server_via(branch: &str) -> String {
+    format!(
+        "SIP/2.0/UDP {}:{};branch={};rport",
+        SIP_SERVER_IP_ADDRESS, SIP_PORT, branch
+    )
+}
+
+// Contact value pointing back at this server.
+pub fn server_contact() -> String {
+    format!(
+        "<sip:{}@{}:{}>",
+        SERVER_CONTACT_USER, SIP_SERVER_IP_ADDRESS, SIP_PORT
+    )
+}
+
+pub struct ResponseBuilder {
+    response: SipResponse,
+}
+
+impl ResponseBuilder {
+    // Starts a response to `request`, copying Via (all of them, in order), From,
+    // To, Call-ID and CSeq as required by RFC 3261 §8.2.6.2. Header names are
+    // kept in the form the peer used.
+    pub fn from_request(request: &SipRequest, code: u16) -> Self {
+        let mut headers = Headers::new();
+        for name in ["Via", "From", "To", "Call-ID", "CSeq"] {
+            for header in request.headers.get_all(name) {
+                headers.push(&header.name, &header.value);
+            }
+        }
+        // 100 Trying also echoes Timestamp (§8.2.6.1).
+        if code == 100 {
+            if let Some(timestamp) = request.headers.get_header("Timestamp") {
+                headers.push(&timestamp.name, &timestamp.value);
+            }
+        }
+        ResponseBuilder {
+            response: SipResponse {
+                version: "SIP/2.0".to_string(),
+                status_code: code,
+                reason: reason_phrase(code).to_string(),
+                headers,
+                body: String::new(),
+            },
+        }
+    }
+
+    pub fn reason(mut self, reason: &str) -> Self {
+        self.response.reason = reason.to_string();
+        self
+    }
+
+    // Adds a To-tag unless the request already carried one (§8.2.6.2).
+    pub fn to_tag(mut self, tag: &str) -> Self {
+        if let Some(to) = self.response.headers.get_header("To") {
+            let has_tag = to
+                .value
+                .split(';')
+                .skip(1)
+                .any(|p| p.trim().to_ascii_lowercase().starts_with("tag="));
+            if !has_tag {
+                let value = format!("{};tag={}", to.value, tag);
+                self.response.headers.set("To", &value);
+            }
+        }
+        self
+    }
+
+    // Appends a header line.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.response.headers.push(name, value);
+        self
+    }
+
+    // Replaces (or adds) a header.
+    pub fn set_header(mut self, name: &str, value: &str) -> Self {
+        self.response.headers.set(name, value);
+        self
+    }
+
+    pub fn contact(self, value: &str) -> Self {
+        self.set_header("Contact", value)
+    }
+
+    pub fn body(mut self, content_type: &str, body: &str) -> Self {
+        self.response.headers.set("Content-Type", content_type);
+        self.response.body = body.to_string();
+        self
+    }
+
+    pub fn build(mut self) -> SipResponse {
+        if !self.response.headers.contains("Server") {
+            self.response.headers.push("Server", SERVER_USER_AGENT);
+        }
+        let length = self.response.body.len().to_string();
+        self.response.headers.set("Content-Length", &length);
+        self.response
+    }
+}
+
+pub struct RequestBuilder {
+    request: SipRequest,
+}
+
+impl RequestBuilder {
+    pub fn new(method: &str, uri: &str) -> Self {
+        RequestBuilder {
+            request: SipRequest {
+                method: method.to_string(),
+                uri: uri.to_string(),
+                version: "SIP/2.0".to_string(),
+                headers: Headers::new(),
+                body: String::new(),
+            },
+        }
+    }
+
+    // CANCEL for a client INVITE: same Request-URI, top Via, From, To, Call-ID,
+    // CSeq number and Route set as the request being cancelled (RFC 3261 §9.1).
+    pub fn cancel_for(invite: &SipRequest) -> SipRequest {
+        Self::mirror_invite(
+            "CANCEL",
+            invite,
+            invite.headers.get("To").unwrap_or_default(),
+        )
+    }
+
+    // ACK for a non-2xx final response to a client INVITE. It belongs to the
+    // INVITE transaction, so it reuses the INVITE's top Via, and takes the To
+    // header (with tag) from the response (RFC 3261 §17.1.1.3).
+    pub fn ack_for(invite: &SipRequest, response: &SipResponse) -> SipRequest {
+        let to = response
+            .headers
+            .get("To")
+            .or_else(|| invite.headers.get("To"))
+            .unwrap_or_default();
+        Self::mirror_invite("ACK", invite, to)
+    }
+
+    fn mirror_invite(method: &str, invite: &SipRequest, to: &str) -> SipRequest {
+        let mut builder = RequestBuilder::new(method, &invite.uri);
+        if let Some(via) = invite.top_via() {
+            builder = builder.via_value(via);
+        }
+        builder = builder
+            .from(invite.headers.get("From").unwrap_or_default())
+            .to(to)
+            .call_id(invite.call_id().unwrap_or_default());
+        if let Some((number, _)) = invite.cseq() {
+            builder = builder.cseq(number);
+        }
+        for route in invite.headers.get_list("Route") {
+            builder = builder.header("Route", route);
+        }
+        builder.build()
+    }
+
+    // Adds this server's Via with the given branch on top of any existing ones.
+    pub fn via(mut self, branch: &str) -> Self {
+        self.request.headers.push_front("Via", &server_via(branch));
+        self
+    }
+
+    // Adds a Via value verbatim on top (e.g. to reuse an INVITE's Via for CANCEL).
+    pub fn via_value(mut self, value: &str) -> Self {
+        self.request.headers.push_front("Via", value);
+        self
+    }
+
+    pub fn from(self, value: &str) -> Self {
+        self.set_header("From", value)
+    }
+
+    pub fn to(self, value: &str) -> Self {
+        self.set_header("To", value)
+    }
+
+    pub fn call_id(self, value: &str) -> Self {
+        self.set_header("Call-ID", value)
+    }
+
+    // CSeq with the request's own method.
+    pub fn cseq(self, number: u32) -> Self {
+        let value = format!("{} {}", number, self.request.method);
+        self.set_header("CSeq", &value)
+    }
+
+    pub fn max_forwards(self, value: u32) -> Self {
+        self.set_header("Max-Forwards", &value.to_string())
+    }
+
+    pub fn contact(self, value: &str) -> Self {
+        self.set_header("Contact", value)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.request.headers.push(name, value);
+        self
+    }
+
+    pub fn set_header(mut self, name: &str, value: &str) -> Self {
+        self.request.headers.set(name, value);
+        self
+    }
+
+    pub fn body(mut self, content_type: &str, body: &str) -> Self {
+        self.request.headers.set("Content-Type", content_type);
+        self.request.body = body.to_string();
+        self
+    }
+
+    pub fn build(mut self) -> SipRequest {
+        if !self.request.headers.contains("Max-Forwards") {
+            let value = DEFAULT_MAX_FORWARDS.to_string();
+            self.request.headers.push("Max-Forwards", &value);
+        }
+        if !self.request.headers.contains("User-Agent") {
+            self.request.headers.push("User-Agent", SERVER_USER_AGENT);
+        }
+        let length = self.request.body.len().to_string();
+        self.request.headers.set("Content-Length", &length);
+        self.request
+    }
+}
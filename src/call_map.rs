@@ -1,6 +1,14 @@
 use crate::sip_defs::*;
+use crate::transaction::TransactionTable;
+use crate::transfer::Replaces;
 use std::sync::MutexGuard; // To type hint the lock guard
 
+impl Default for CallMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CallMap {
     // Creates a new, empty CallMap initialized with inactive calls
     pub fn new() -> Self {
@@ -12,7 +20,12 @@ impl CallMap {
                 ..Default::default() // Initialize other fields to default
             });
         }
-        CallMap { calls, size: 0 }
+        CallMap {
+            calls,
+            size: 0,
+            transactions: TransactionTable::new(),
+            messages: Vec::new(),
+        }
     }
 
     // Finds an *active* call by Call-ID.
@@ -34,11 +47,37 @@ impl CallMap {
                 if call.b_leg_uuid == call_id {
                     return (Some(index), B_LEG);
                 }
+                if call
+                    .transfer
+                    .as_ref()
+                    .is_some_and(|transfer| transfer.uuid == call_id)
+                {
+                    return (Some(index), TRANSFER_LEG);
+                }
             }
         }
         (None, 0) // Not found
     }
 
+    // Finds the call and leg whose dialog a Replaces header names. Every
+    // early dialog of a forked INVITE to B counts as the B leg.
+    pub fn find_call_by_dialog(call_map: &CallMap, replaces: &Replaces) -> (Option<usize>, i32) {
+        for (index, call) in call_map.calls.iter().enumerate() {
+            if !call.is_active {
+                continue;
+            }
+            if replaces.matches(&call.a_leg_dialog) {
+                return (Some(index), A_LEG);
+            }
+            if replaces.matches(&call.b_leg_dialog)
+                || call.forks.iter().any(|fork| replaces.matches(&fork.dialog))
+            {
+                return (Some(index), B_LEG);
+            }
+        }
+        (None, 0)
+    }
+
     // Allocates a new call from the map if available.
     // Returns a mutable reference to the newly activated call.
     // Takes a mutable guard.
@@ -86,4 +125,4 @@ impl CallMap {
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,225 @@
+use crate::builder::{generate_branch, generate_tag, RequestBuilder};
+use crate::message::*;
+use crate::routing::{is_loose_route, route_uri};
+use crate::uri::NameAddr;
+use std::net::SocketAddr;
+
+// RFC 3261 §12 dialog state, one per call leg. The B2BUA acts as UAS
+// towards the caller (A leg) and as UAC towards the callee (B leg).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DialogState {
+    #[default]
+    Early, // created, no 2xx yet
+    Confirmed,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dialog {
+    pub state: DialogState,
+    pub call_id: String,
+    pub local_tag: String,
+    pub remote_tag: Option<String>,
+    // name-addr of each side, without tag
+    pub local_uri: String,
+    pub remote_uri: String,
+    pub local_cseq: u32,
+    pub remote_cseq: Option<u32>,
+    // Request-URI for requests within the dialog
+    pub remote_target: String,
+    // Route headers for requests within the dialog, in order
+    pub route_set: Vec<String>,
+}
+
+// name-addr with the tag parameter removed.
+fn without_tag(value: &str) -> Option<(String, Option<String>)> {
+    let mut addr = NameAddr::parse(value).ok()?;
+    let tag = addr.tag().map(str::to_string);
+    addr.remove_param("tag");
+    Some((addr.to_string(), tag))
+}
+
+// URI of the first Contact header value.
+fn contact_target<M: MessageHeaders>(message: &M) -> Option<String> {
+    let contact = message.headers().get_list("Contact").into_iter().next()?;
+    NameAddr::parse(contact)
+        .ok()
+        .map(|addr| addr.uri.to_string())
+}
+
+// Record-Route values of a message, top first.
+fn record_route<M: MessageHeaders>(message: &M) -> Vec<String> {
+    message
+        .headers()
+        .get_list("Record-Route")
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+impl Dialog {
+    // Dialog state of a UAS that received `request` and answers with
+    // `local_tag` in the To header (RFC 3261 §12.1.1).
+    pub fn uas(request: &SipRequest, local_tag: &str) -> Option<Self> {
+        let (remote_uri, remote_tag) = without_tag(request.headers.get("From")?)?;
+        let (local_uri, _) = without_tag(request.headers.get("To")?)?;
+        Some(Dialog {
+            state: DialogState::Early,
+            call_id: request.call_id()?.to_string(),
+            local_tag: local_tag.to_string(),
+            remote_tag,
+            local_uri,
+            remote_uri,
+            local_cseq: 0,
+            remote_cseq: request.cseq().map(|(number, _)| number),
+            remote_target: contact_target(request).unwrap_or_default(),
+            // UAS route set: Record-Route in the order received
+            route_set: record_route(request),
+        })
+    }
+
+    // Dialog state of a UAC about to send its initial request. The local
+    // tag is generated here; the remote tag comes with the first response.
+    pub fn uac(call_id: &str, local_uri: &str, remote_uri: &str, remote_target: &str) -> Self {
+        Dialog {
+            state: DialogState::Early,
+            call_id: call_id.to_string(),
+            local_tag: generate_tag(),
+            remote_tag: None,
+            local_uri: local_uri.to_string(),
+            remote_uri: remote_uri.to_string(),
+            local_cseq: 0,
+            remote_cseq: None,
+            remote_target: remote_target.to_string(),
+            route_set: Vec::new(),
+        }
+    }
+
+    // Our side as it appears in From (UAC) or To (UAS) headers.
+    pub fn local_party(&self) -> String {
+        format!("{};tag={}", self.local_uri, self.local_tag)
+    }
+
+    pub fn remote_party(&self) -> String {
+        match &self.remote_tag {
+            Some(tag) => format!("{};tag={}", self.remote_uri, tag),
+            None => self.remote_uri.clone(),
+        }
+    }
+
+    // Learns the remote tag, target and route set from a response to an
+    // INVITE we sent; a 2xx confirms the dialog (RFC 3261 §12.1.2). Until
+    // then each response may come from another early dialog (a forking
+    // proxy downstream), and the latest one is followed; the 2xx decides.
+    pub fn update_from_response(&mut self, response: &SipResponse) {
+        if response.status_code >= 300 {
+            return;
+        }
+        let tag = response
+            .headers
+            .get("To")
+            .and_then(without_tag)
+            .and_then(|(_, tag)| tag);
+        if self.state == DialogState::Early && tag.is_some() {
+            self.remote_tag = tag;
+        }
+        if let Some(target) = contact_target(response) {
+            self.remote_target = target;
+        }
+        // UAC route set: Record-Route in reverse order; fixed once the
+        // dialog is confirmed
+        if self.state == DialogState::Early {
+            let mut route_set = record_route(response);
+            route_set.reverse();
+            self.route_set = route_set;
+        }
+        if (200..300).contains(&response.status_code) {
+            self.state = DialogState::Confirmed;
+        }
+    }
+
+    // Target refresh from a request within the dialog (e.g. re-INVITE).
+    pub fn update_from_request(&mut self, request: &SipRequest) {
+        if let Some(target) = contact_target(request) {
+            self.remote_target = target;
+        }
+    }
+
+    // True if `request` belongs to this dialog: same Call-ID, the From-tag
+    // is the remote tag and the To-tag is ours (RFC 3261 §12.2.2).
+    pub fn matches(&self, request: &SipRequest) -> bool {
+        let tag = |name: &str| {
+            request
+                .headers
+                .get(name)
+                .and_then(without_tag)
+                .and_then(|(_, tag)| tag)
+        };
+        request.call_id() == Some(self.call_id.as_str())
+            && tag("From") == self.remote_tag
+            && tag("To").as_deref() == Some(self.local_tag.as_str())
+    }
+
+    // Checks and records the CSeq of a request received in the dialog.
+    // Lower numbers than the last one seen are out of order (RFC 3261 §12.2.2).
+    pub fn accept_remote_cseq(&mut self, request: &SipRequest) -> bool {
+        let cseq = match request.cseq() {
+            Some((number, _)) => number,
+            None => return false,
+        };
+        if self.remote_cseq.is_some_and(|last| cseq < last) {
+            return false;
+        }
+        self.remote_cseq = Some(cseq);
+        true
+    }
+
+    // Starts a request within the dialog with the next local CSeq
+    // (RFC 3261 §12.2.1.1).
+    pub fn request(&mut self, method: &str) -> RequestBuilder {
+        self.local_cseq += 1;
+        self.request_with_cseq(method, self.local_cseq)
+    }
+
+    // ACK for a 2xx to the INVITE sent with CSeq `invite_cseq`. It is a new
+    // transaction, so it gets a branch of its own (RFC 3261 §13.2.2.4).
+    pub fn ack(&self, invite_cseq: u32) -> RequestBuilder {
+        self.request_with_cseq("ACK", invite_cseq)
+    }
+
+    // Transport address of the first route entry; requests within the dialog
+    // go there instead of the remote target when a route set exists.
+    pub fn next_hop(&self) -> Option<SocketAddr> {
+        route_uri(self.route_set.first()?)?.socket_addr()
+    }
+
+    // Request-URI and Route headers per RFC 3261 §12.2.1.1: with a strict
+    // router first in the route set, its URI becomes the Request-URI and the
+    // remote target is appended as the last Route.
+    fn request_with_cseq(&self, method: &str, cseq: u32) -> RequestBuilder {
+        let strict = match self.route_set.first() {
+            Some(first) => !is_loose_route(first),
+            None => false,
+        };
+        let (uri, routes) = if strict {
+            let uri = route_uri(&self.route_set[0])
+                .map(|uri| uri.to_string())
+                .unwrap_or_else(|| self.remote_target.clone());
+            let mut routes = self.route_set[1..].to_vec();
+            routes.push(format!("<{}>", self.remote_target));
+            (uri, routes)
+        } else {
+            (self.remote_target.clone(), self.route_set.clone())
+        };
+        let mut builder = RequestBuilder::new(method, &uri)
+            .via(&generate_branch())
+            .from(&self.local_party())
+            .to(&self.remote_party())
+            .call_id(&self.call_id)
+            .cseq(cseq);
+        for route in &routes {
+            builder = builder.header("Route", route);
+        }
+        builder
+    }
+}
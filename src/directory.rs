@@ -0,0 +1,308 @@
+use crate::auth::DigestAlgorithm;
+use crate::location::location_service;
+use crate::sip_defs::*;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// User directory: the users of the server, read from a CSV file into
+// LOCATION_ENTRIES. One user per line, '#' starts a comment:
+//
+//   username,secret,display name,allowed networks,features
+//   1001,secret1001,Alice,192.168.0.0/16 10.1.0.0/16,forwarding messaging transfer pickup
+//   1002,md5:605d5b360853990503b18ac8bf08d2ed,Bob,,messaging
+//
+// The secret is the password, or "md5:"/"sha-256:" and the HA1 over
+// AUTH_REALM. Allowed networks are the sources REGISTER and INVITE may come
+// from (empty for anywhere). Features list what the user may use, out of
+// "forwarding", "messaging", "transfer" and "pickup". Fields cannot contain
+// ',' or '#'.
+//
+// Reloading keeps the bindings of users that are still listed; calls are
+// not touched.
+
+#[derive(Debug)]
+pub enum DirectoryError {
+    Io(io::Error),
+    Line(usize, String), // 1-based line number and what is wrong with it
+}
+
+impl fmt::Display for DirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirectoryError::Io(e) => write!(f, "{}", e),
+            DirectoryError::Line(line, reason) => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+impl From<io::Error> for DirectoryError {
+    fn from(e: io::Error) -> Self {
+        DirectoryError::Io(e)
+    }
+}
+
+impl SourceNetwork {
+    // "192.168.1.0/24", "2001:db8::/32", or a single address.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (value, None),
+        };
+        let addr = addr.parse::<IpAddr>().ok()?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)?,
+            None => max_len,
+        };
+        Some(SourceNetwork { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (
+                u128::from(u32::from(network)),
+                u128::from(u32::from(ip)),
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        let host_bits = bits - u32::from(self.prefix_len);
+        host_bits >= bits || (network ^ ip) >> host_bits == 0
+    }
+}
+
+impl fmt::Display for SourceNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn parse_secret(value: &str) -> Option<UserSecret> {
+    if let Some((algorithm, ha1)) = value.split_once(':') {
+        let algorithm = DigestAlgorithm::parse(Some(algorithm))?;
+        let hex_len = match algorithm {
+            DigestAlgorithm::Md5 => 32,
+            DigestAlgorithm::Sha256 => 64,
+        };
+        if ha1.len() != hex_len || !ha1.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        return Some(UserSecret::Ha1(algorithm, ha1.to_ascii_lowercase()));
+    }
+    if value.is_empty() {
+        return None;
+    }
+    Some(UserSecret::Password(value.to_string()))
+}
+
+fn parse_features(value: &str) -> Result<UserFeatures, String> {
+    let mut features = UserFeatures {
+        forwarding: false,
+        messaging: false,
+        transfer: false,
+        pickup: false,
+    };
+    for feature in value.split_whitespace() {
+        match feature {
+            "forwarding" => features.forwarding = true,
+            "messaging" => features.messaging = true,
+            "transfer" => features.transfer = true,
+            "pickup" => features.pickup = true,
+            _ => return Err(format!("unknown feature '{}'", feature)),
+        }
+    }
+    Ok(features)
+}
+
+// The lines of a configuration file in this format (the user directory,
+// ring groups, call forwarding) with their 1-based numbers, each split into
+// `count` trimmed fields. Comments and blank lines are left out.
+pub fn config_lines(text: &str, count: usize) -> Result<Vec<(usize, Vec<&str>)>, DirectoryError> {
+    let mut lines = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        if fields.len() != count {
+            return Err(DirectoryError::Line(
+                index + 1,
+                format!("expected {} fields, found {}", count, fields.len()),
+            ));
+        }
+        lines.push((index + 1, fields));
+    }
+    Ok(lines)
+}
+
+// The users listed in the text of a directory file.
+pub fn parse_directory(text: &str) -> Result<Vec<LocationEntry>, DirectoryError> {
+    let mut users: Vec<LocationEntry> = Vec::new();
+    for (number, fields) in config_lines(text, 5)? {
+        let error = |reason: String| DirectoryError::Line(number, reason);
+        let username = fields[0];
+        if username.is_empty() {
+            return Err(error("missing username".to_string()));
+        }
+        if users.iter().any(|user| user.username == username) {
+            return Err(error(format!("user {} listed twice", username)));
+        }
+        let secret = parse_secret(fields[1]).ok_or_else(|| error("bad secret".to_string()))?;
+        let allowed_networks = fields[3]
+            .split_whitespace()
+            .map(|network| {
+                SourceNetwork::parse(network)
+                    .ok_or_else(|| error(format!("bad network '{}'", network)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        users.push(LocationEntry {
+            username: username.to_string(),
+            secret,
+            display_name: Some(fields[2].to_string()).filter(|name| !name.is_empty()),
+            allowed_networks,
+            features: parse_features(fields[4]).map_err(error)?,
+        });
+    }
+    Ok(users)
+}
+
+// Replaces the users of LOCATION_ENTRIES with `users`. Users that stay keep
+// their bindings; users that are gone lose theirs, as do bindings restored
+// at startup for users no longer listed.
+pub fn apply_directory(users: Vec<LocationEntry>) {
+    {
+        let mut entries = lock_entries();
+        for entry in entries.iter() {
+            if !users.iter().any(|user| user.username == entry.username) {
+                println!("User {} removed from the directory.", entry.username);
+            }
+        }
+        *entries = users;
+    }
+    let mut location = location_service();
+    let aors = match location.list() {
+        Ok(bindings) => bindings.into_iter().map(|(aor, _)| aor).collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Failed to list bindings: {}", e);
+            Vec::new()
+        }
+    };
+    for aor in aors {
+        if !is_known_user(&aor) {
+            if let Err(e) = location.remove(&aor, None) {
+                eprintln!("Failed to drop the bindings of {}: {}", aor, e);
+            }
+        }
+    }
+}
+
+// Reads the directory file at `path` and makes it the user list. On error
+// the current users are kept.
+pub fn load_directory(path: &Path) -> Result<usize, DirectoryError> {
+    let users = parse_directory(&fs::read_to_string(path)?)?;
+    let count = users.len();
+    apply_directory(users);
+    Ok(count)
+}
+
+// Watches a configuration file (the user directory, ring groups, call
+// forwarding) and reloads it with `load` when its modification time
+// changes. Called from the main loop.
+pub struct FileWatcher {
+    path: PathBuf,
+    what: &'static str, // What the file lists, for the log, e.g. "user(s)"
+    load: fn(&Path) -> Result<usize, DirectoryError>,
+    modified: Option<SystemTime>,
+}
+
+impl FileWatcher {
+    pub fn new(
+        path: PathBuf,
+        what: &'static str,
+        load: fn(&Path) -> Result<usize, DirectoryError>,
+    ) -> Self {
+        FileWatcher {
+            path,
+            what,
+            load,
+            modified: None,
+        }
+    }
+
+    // Loads the file if it is new or changed since the last poll. A missing
+    // file leaves the current settings in place.
+    pub fn poll(&mut self) {
+        let modified = match fs::metadata(&self.path).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return,
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+        self.modified = Some(modified);
+        match (self.load)(&self.path) {
+            Ok(count) => println!(
+                "Loaded {} {} from {}.",
+                count,
+                self.what,
+                self.path.display()
+            ),
+            Err(e) => eprintln!(
+                "Failed to load {}: {}. Keeping the current {}.",
+                self.path.display(),
+                e,
+                self.what
+            ),
+        }
+    }
+}
+
+// The digest secret of `username`.
+pub fn user_secret(username: &str) -> Option<UserSecret> {
+    lock_entries()
+        .iter()
+        .find(|entry| entry.username == username)
+        .map(|entry| entry.secret.clone())
+}
+
+// Whether `username` may send REGISTER or INVITE from `ip`. Unknown users
+// are left to authentication.
+pub fn source_allowed(username: &str, ip: IpAddr) -> bool {
+    lock_entries()
+        .iter()
+        .find(|entry| entry.username == username)
+        .is_none_or(|entry| {
+            entry.allowed_networks.is_empty()
+                || entry
+                    .allowed_networks
+                    .iter()
+                    .any(|network| network.contains(ip))
+        })
+}
+
+// The features of `username`; None for an unknown user.
+pub fn user_features(username: &str) -> Option<UserFeatures> {
+    lock_entries()
+        .iter()
+        .find(|entry| entry.username == username)
+        .map(|entry| entry.features)
+}
+
+fn lock_entries() -> std::sync::MutexGuard<'static, Vec<LocationEntry>> {
+    match LOCATION_ENTRIES.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            eprintln!("LOCATION_ENTRIES mutex poisoned; continuing with existing data.");
+            poisoned.into_inner()
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::sdp::MediaDescription;
+
+// DTMF as seen by the B2BUA. SIP INFO digits are relayed in-dialog and
+// recorded on the call; RFC 2833 telephone-events travel in RTP, so only
+// their negotiation in the relayed SDP is visible here.
+
+pub const DTMF_RELAY_CONTENT_TYPE: &str = "application/dtmf-relay";
+pub const DTMF_CONTENT_TYPE: &str = "application/dtmf";
+
+// One digit received on a leg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DtmfDigit {
+    pub leg: i32,
+    pub signal: char,
+    pub duration_ms: Option<u32>,
+}
+
+// Signal of an INFO body: a digit, '*', '#', 'A'-'D', or the event codes
+// 10-15 some phones send for '*', '#' and 'A'-'D' (RFC 4733 numbering).
+fn parse_signal(value: &str) -> Option<char> {
+    let value = value.trim();
+    match value.parse::<u8>() {
+        Ok(code @ 0..=9) => char::from_digit(code as u32, 10),
+        Ok(10) => Some('*'),
+        Ok(11) => Some('#'),
+        Ok(code @ 12..=15) => Some((b'A' + code - 12) as char),
+        Ok(_) => None,
+        Err(_) => {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if matches!(c.to_ascii_uppercase(), '*' | '#' | 'A'..='D') => {
+                    Some(c.to_ascii_uppercase())
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+// Digit and duration of an INFO body. application/dtmf-relay carries
+// "Signal=" and "Duration=" lines; application/dtmf just the digit.
+pub fn parse_dtmf_info(content_type: &str, body: &str) -> Option<(char, Option<u32>)> {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    if content_type.eq_ignore_ascii_case(DTMF_CONTENT_TYPE) {
+        return parse_signal(body).map(|signal| (signal, None));
+    }
+    if !content_type.eq_ignore_ascii_case(DTMF_RELAY_CONTENT_TYPE) {
+        return None;
+    }
+    let mut signal = None;
+    let mut duration = None;
+    for line in body.lines() {
+        let (name, value) = match line.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "signal" => signal = parse_signal(value),
+            "duration" => duration = value.trim().parse::<u32>().ok(),
+            _ => {}
+        }
+    }
+    signal.map(|signal| (signal, duration))
+}
+
+// Payload type of RFC 2833 telephone-event on a media line, if offered.
+pub fn telephone_event_payload(media: &MediaDescription) -> Option<String> {
+    media
+        .rtpmaps()
+        .into_iter()
+        .find(|map| map.encoding.eq_ignore_ascii_case("telephone-event"))
+        .map(|map| map.payload_type)
+}
@@ -0,0 +1,129 @@
+use crate::directory::{config_lines, user_features, DirectoryError};
+use crate::sip_defs::*;
+use crate::uri::{SipUri, UriScheme};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+// Call forwarding: a user's calls go elsewhere unconditionally, when the
+// user is busy, or when the user does not answer in time. Each forward is
+// recorded on the call and announced to the new target in a Diversion header
+// (RFC 5806).
+//
+// The settings are read from a CSV file next to the user directory,
+// reloaded when it changes; they apply to users with the forwarding feature.
+// One user per line, '#' starts a comment:
+//
+//   username,unconditional,busy,no answer,no answer timeout
+//   1002,,1001,2000,20
+//
+// Targets left empty are not forwarded to. The no answer timeout is in
+// seconds, and only needed with a no answer target.
+
+impl DiversionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiversionReason::Unconditional => "unconditional",
+            DiversionReason::UserBusy => "user-busy",
+            DiversionReason::NoAnswer => "no-answer",
+        }
+    }
+}
+
+// The forwarding settings listed in the text of a forwarding file.
+pub fn parse_call_forwarding(text: &str) -> Result<Vec<CallForwarding>, DirectoryError> {
+    let mut settings: Vec<CallForwarding> = Vec::new();
+    for (number, fields) in config_lines(text, 5)? {
+        let error = |reason: String| DirectoryError::Line(number, reason);
+        let username = fields[0];
+        if username.is_empty() {
+            return Err(error("missing username".to_string()));
+        }
+        if settings
+            .iter()
+            .any(|forwarding| forwarding.username == username)
+        {
+            return Err(error(format!("user {} listed twice", username)));
+        }
+        let target = |field: &str| Some(field.to_string()).filter(|target| !target.is_empty());
+        let no_answer = target(fields[3]);
+        let no_answer_timeout = match (&no_answer, fields[4]) {
+            (None, "") => Duration::ZERO,
+            (_, seconds) => seconds
+                .parse::<u64>()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| error(format!("bad no answer timeout '{}'", seconds)))?,
+        };
+        settings.push(CallForwarding {
+            username: username.to_string(),
+            unconditional: target(fields[1]),
+            busy: target(fields[2]),
+            no_answer,
+            no_answer_timeout,
+        });
+    }
+    Ok(settings)
+}
+
+// Reads the forwarding file at `path` and makes it the forwarding settings.
+// On error the current settings are kept.
+pub fn load_call_forwarding(path: &Path) -> Result<usize, DirectoryError> {
+    let settings = parse_call_forwarding(&fs::read_to_string(path)?)?;
+    let count = settings.len();
+    match CALL_FORWARDING.lock() {
+        Ok(mut guard) => *guard = settings,
+        Err(poisoned) => {
+            eprintln!("CALL_FORWARDING mutex poisoned while writing; replacing its data.");
+            *poisoned.into_inner() = settings;
+        }
+    }
+    Ok(count)
+}
+
+// Forwarding settings of `username`, if it has any and may forward calls.
+pub fn forwarding_of(username: &str) -> Option<CallForwarding> {
+    if user_features(username).is_some_and(|features| !features.forwarding) {
+        return None;
+    }
+    let settings = match CALL_FORWARDING.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            eprintln!(
+                "CALL_FORWARDING mutex poisoned while reading; continuing with existing data."
+            );
+            poisoned.into_inner()
+        }
+    };
+    settings
+        .iter()
+        .find(|forwarding| forwarding.username == username)
+        .cloned()
+}
+
+// Where to send the INVITE for a forward target that is a SIP URI rather than
+// a local user. Only IP literal hosts can be reached; there is no DNS here.
+pub fn outside_binding(target: &str) -> Option<Binding> {
+    let uri = SipUri::parse(target).ok()?;
+    if uri.scheme == UriScheme::Tel {
+        return None;
+    }
+    Some(Binding::new(target, uri.socket_addr()?))
+}
+
+// Diversion header values for the forwards a call took, most recent first.
+pub fn diversion_headers(diversions: &[Diversion]) -> Vec<String> {
+    diversions
+        .iter()
+        .rev()
+        .map(|diversion| {
+            format!(
+                "<sip:{}@{}>;reason={};counter=1",
+                diversion.user,
+                SIP_SERVER_IP_ADDRESS,
+                diversion.reason.as_str()
+            )
+        })
+        .collect()
+}
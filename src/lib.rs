@@ -1,5 +1,22 @@
+pub mod auth;
+pub mod builder;
 pub mod call_map;
+pub mod dialog;
+pub mod directory;
+pub mod dtmf;
+pub mod forwarding;
+pub mod location;
+pub mod message;
+pub mod message_store;
 pub mod network_utils;
 pub mod parsing;
+pub mod registrar;
+pub mod ring_group;
+pub mod routing;
+pub mod sdp;
+pub mod session_timer;
 pub mod sip_defs;
+pub mod transaction;
+pub mod transfer;
+pub mod uri;
 pub mod worker;
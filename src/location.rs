@@ -0,0 +1,485 @@
+use crate::sip_defs::*;
+use rusqlite::{params, Connection};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Location service: where the bindings of each address-of-record (AOR) are
+// kept. The registrar writes them, routing reads them. The server uses the
+// one in LOCATION_SERVICE: in memory (optionally with a snapshot file), or
+// an SQLite database, so that registrations survive a restart.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocationError(pub String);
+
+impl fmt::Display for LocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "location service: {}", self.0)
+    }
+}
+
+impl From<rusqlite::Error> for LocationError {
+    fn from(e: rusqlite::Error) -> Self {
+        LocationError(e.to_string())
+    }
+}
+
+pub trait LocationService: Send {
+    // Adds `binding` to `aor`, replacing the binding it refreshes (see
+    // Binding::refreshes) and any other with the same contact URI.
+    fn register_binding(&mut self, aor: &str, binding: Binding) -> Result<(), LocationError>;
+
+    // The bindings of `aor` that have not expired at `now`, in the order
+    // they were first registered.
+    fn lookup(&self, aor: &str, now: Instant) -> Result<Vec<Binding>, LocationError>;
+
+    // Removes the binding of `aor` with `contact`, or all of its bindings.
+    fn remove(&mut self, aor: &str, contact: Option<&str>) -> Result<(), LocationError>;
+
+    // Every AOR that has bindings, with all of them (expired or not).
+    fn list(&self) -> Result<Vec<(String, Vec<Binding>)>, LocationError>;
+
+    // Drops the bindings that expired by `now`; returns how many each AOR lost.
+    fn expire(&mut self, now: Instant) -> Result<Vec<(String, usize)>, LocationError>;
+
+    // Writes out changes the service holds back; called from the main loop
+    // every BINDING_SWEEP_INTERVAL. Services that store each change as it
+    // happens have nothing to do.
+    fn flush(&mut self) -> Result<(), LocationError> {
+        Ok(())
+    }
+}
+
+impl Binding {
+    // Whether registering `self` replaces `existing`: the same instance-id
+    // when `self` has one (RFC 5626), the same contact URI otherwise.
+    pub fn refreshes(&self, existing: &Binding) -> bool {
+        match &self.instance_id {
+            Some(instance_id) => existing.instance_id.as_ref() == Some(instance_id),
+            None => existing.contact == self.contact,
+        }
+    }
+}
+
+// Bindings in a Vec per AOR; what the server has always done. With a
+// snapshot file, changes are written out on flush and the bindings that are
+// still valid are read back on startup.
+#[derive(Debug, Default)]
+pub struct InMemoryLocationService {
+    entries: Vec<(String, Vec<Binding>)>,
+    snapshot: Option<PathBuf>,
+    dirty: bool, // Changed since the snapshot was last written
+}
+
+// First line of a snapshot file; one binding per line follows, its fields
+// separated by tabs: aor, contact, addr, expiry (Unix time in ms), Call-ID,
+// CSeq, q and instance-id ("-" for none).
+const SNAPSHOT_HEADER: &str = "# sip_server_rust bindings v1";
+
+impl InMemoryLocationService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A service that flushes to the snapshot file at `path`, starting with
+    // the bindings in it that have not expired. A missing file starts empty;
+    // lines that cannot be read are skipped, and a file that is not a
+    // snapshot at all is left alone.
+    pub fn with_snapshot(path: PathBuf) -> Result<Self, LocationError> {
+        let mut service = InMemoryLocationService::new();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                if text.lines().next() != Some(SNAPSHOT_HEADER) {
+                    return Err(LocationError(format!(
+                        "{} is not a bindings snapshot",
+                        path.display()
+                    )));
+                }
+                let now = Instant::now();
+                for (number, line) in text.lines().enumerate().skip(1) {
+                    match parse_snapshot_line(line, now) {
+                        Some((aor, binding)) if binding.expires > now => {
+                            service.register_binding(&aor, binding)?
+                        }
+                        Some(_) => {} // Lapsed while the server was down
+                        None => eprintln!(
+                            "Skipping unreadable line {} of {}",
+                            number + 1,
+                            path.display()
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(LocationError(format!("{}: {}", path.display(), e))),
+        }
+        service.snapshot = Some(path);
+        service.dirty = false;
+        Ok(service)
+    }
+
+    // Writes the snapshot. The new contents go to a temporary file that
+    // replaces the old one only once it is complete, and the rename is
+    // synced too, so a crash leaves either the old or the new snapshot.
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = format!("{}\n", SNAPSHOT_HEADER);
+        let now = Instant::now();
+        for (aor, bindings) in &self.entries {
+            for binding in bindings {
+                text.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                    aor,
+                    binding.contact,
+                    binding.addr,
+                    unix_millis_from_instant(binding.expires, now),
+                    binding.call_id,
+                    binding.cseq,
+                    binding.q,
+                    binding.instance_id.as_deref().unwrap_or("-")
+                ));
+            }
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::File::open(parent)?.sync_all()
+    }
+}
+
+fn parse_snapshot_line(line: &str, now: Instant) -> Option<(String, Binding)> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    if fields.len() != 8 {
+        return None;
+    }
+    let binding = Binding {
+        contact: fields[1].to_string(),
+        addr: fields[2].parse().ok()?,
+        expires: instant_from_unix_millis(fields[3].parse().ok()?, now),
+        call_id: fields[4].to_string(),
+        cseq: fields[5].parse().ok()?,
+        q: fields[6].parse().ok()?,
+        instance_id: Some(fields[7].to_string()).filter(|id| id != "-"),
+    };
+    Some((fields[0].to_string(), binding))
+}
+
+impl LocationService for InMemoryLocationService {
+    fn register_binding(&mut self, aor: &str, binding: Binding) -> Result<(), LocationError> {
+        let index = match self.entries.iter().position(|(name, _)| name == aor) {
+            Some(index) => index,
+            None => {
+                self.entries.push((aor.to_string(), Vec::new()));
+                self.entries.len() - 1
+            }
+        };
+        // The binding takes the place of the first one it replaces
+        let bindings = &mut self.entries[index].1;
+        let replaced = bindings
+            .iter()
+            .enumerate()
+            .filter(|(_, existing)| {
+                binding.refreshes(existing) || existing.contact == binding.contact
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        match replaced.split_first() {
+            Some((&first, others)) => {
+                for &index in others.iter().rev() {
+                    bindings.remove(index);
+                }
+                bindings[first] = binding;
+            }
+            None => bindings.push(binding),
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn lookup(&self, aor: &str, now: Instant) -> Result<Vec<Binding>, LocationError> {
+        Ok(self
+            .entries
+            .iter()
+            .find(|(name, _)| name == aor)
+            .map(|(_, bindings)| {
+                bindings
+                    .iter()
+                    .filter(|binding| binding.expires > now)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn remove(&mut self, aor: &str, contact: Option<&str>) -> Result<(), LocationError> {
+        if let Some((_, bindings)) = self.entries.iter_mut().find(|(name, _)| name == aor) {
+            bindings.retain(|binding| contact.is_some_and(|contact| binding.contact != contact));
+        }
+        self.entries.retain(|(_, bindings)| !bindings.is_empty());
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<(String, Vec<Binding>)>, LocationError> {
+        Ok(self.entries.clone())
+    }
+
+    fn expire(&mut self, now: Instant) -> Result<Vec<(String, usize)>, LocationError> {
+        let mut expired = Vec::new();
+        for (aor, bindings) in self.entries.iter_mut() {
+            let before = bindings.len();
+            bindings.retain(|binding| binding.expires > now);
+            if bindings.len() != before {
+                expired.push((aor.clone(), before - bindings.len()));
+            }
+        }
+        self.entries.retain(|(_, bindings)| !bindings.is_empty());
+        if !expired.is_empty() {
+            self.dirty = true;
+        }
+        Ok(expired)
+    }
+
+    fn flush(&mut self) -> Result<(), LocationError> {
+        let path = match &self.snapshot {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+        // Left dirty on failure, so the next flush tries again
+        self.save(path)
+            .map_err(|e| LocationError(format!("{}: {}", path.display(), e)))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// Bindings in an SQLite database. Expiry times are stored as Unix time in
+// milliseconds, since an Instant means nothing after a restart.
+pub struct SqliteLocationService {
+    connection: Connection,
+}
+
+const BINDING_COLUMNS: &str = "aor, contact, addr, expires_at, call_id, cseq, q, instance_id";
+
+impl SqliteLocationService {
+    // Opens (creating if needed) the database at `path`.
+    pub fn open(path: &Path) -> Result<Self, LocationError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    // A database that lives only as long as the service.
+    pub fn open_in_memory() -> Result<Self, LocationError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, LocationError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS bindings (
+                aor TEXT NOT NULL,
+                contact TEXT NOT NULL,
+                addr TEXT NOT NULL,
+                expires_at INTEGER NOT NULL,
+                call_id TEXT NOT NULL,
+                cseq INTEGER NOT NULL,
+                q INTEGER NOT NULL,
+                instance_id TEXT,
+                PRIMARY KEY (aor, contact)
+            );",
+        )?;
+        Ok(SqliteLocationService { connection })
+    }
+
+    // The bindings of `aor` (of every AOR for None) in registration order.
+    fn bindings(&self, aor: Option<&str>) -> Result<Vec<(String, Binding)>, LocationError> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {} FROM bindings WHERE ?1 IS NULL OR aor = ?1 ORDER BY aor, rowid",
+            BINDING_COLUMNS
+        ))?;
+        let rows = statement.query_map([aor], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, u32>(5)?,
+                row.get::<_, u16>(6)?,
+                row.get::<_, Option<String>>(7)?,
+            ))
+        })?;
+        let now = Instant::now();
+        let mut bindings = Vec::new();
+        for row in rows {
+            let (aor, contact, addr, expires_at, call_id, cseq, q, instance_id) = row?;
+            let addr = addr
+                .parse::<SocketAddr>()
+                .map_err(|_| LocationError(format!("bad address '{}' for {}", addr, contact)))?;
+            bindings.push((
+                aor,
+                Binding {
+                    contact,
+                    addr,
+                    expires: instant_from_unix_millis(expires_at, now),
+                    call_id,
+                    cseq,
+                    q,
+                    instance_id,
+                },
+            ));
+        }
+        Ok(bindings)
+    }
+}
+
+impl LocationService for SqliteLocationService {
+    fn register_binding(&mut self, aor: &str, binding: Binding) -> Result<(), LocationError> {
+        // As in memory, the binding takes the row of the first one it
+        // replaces, which keeps its place in the registration order
+        let transaction = self.connection.transaction()?;
+        let replaced = "aor = ?1 AND (contact = ?2 OR instance_id = ?3)";
+        let first = transaction.query_row(
+            &format!("SELECT MIN(rowid) FROM bindings WHERE {}", replaced),
+            params![aor, binding.contact, binding.instance_id],
+            |row| row.get::<_, Option<i64>>(0),
+        )?;
+        transaction.execute(
+            &format!(
+                "DELETE FROM bindings WHERE {} AND rowid IS NOT ?4",
+                replaced
+            ),
+            params![aor, binding.contact, binding.instance_id, first],
+        )?;
+        let values = params![
+            aor,
+            binding.contact,
+            binding.addr.to_string(),
+            unix_millis_from_instant(binding.expires, Instant::now()),
+            binding.call_id,
+            binding.cseq,
+            binding.q,
+            binding.instance_id,
+            first,
+        ];
+        match first {
+            Some(_) => transaction.execute(
+                "UPDATE bindings SET aor = ?1, contact = ?2, addr = ?3, expires_at = ?4,
+                 call_id = ?5, cseq = ?6, q = ?7, instance_id = ?8 WHERE rowid = ?9",
+                values,
+            )?,
+            None => transaction.execute(
+                &format!(
+                    "INSERT INTO bindings ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    BINDING_COLUMNS
+                ),
+                &values[..8],
+            )?,
+        };
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn lookup(&self, aor: &str, now: Instant) -> Result<Vec<Binding>, LocationError> {
+        Ok(self
+            .bindings(Some(aor))?
+            .into_iter()
+            .map(|(_, binding)| binding)
+            .filter(|binding| binding.expires > now)
+            .collect())
+    }
+
+    fn remove(&mut self, aor: &str, contact: Option<&str>) -> Result<(), LocationError> {
+        match contact {
+            Some(contact) => self.connection.execute(
+                "DELETE FROM bindings WHERE aor = ?1 AND contact = ?2",
+                params![aor, contact],
+            )?,
+            None => self
+                .connection
+                .execute("DELETE FROM bindings WHERE aor = ?1", params![aor])?,
+        };
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<(String, Vec<Binding>)>, LocationError> {
+        let mut entries: Vec<(String, Vec<Binding>)> = Vec::new();
+        for (aor, binding) in self.bindings(None)? {
+            match entries.last_mut() {
+                Some((last, bindings)) if *last == aor => bindings.push(binding),
+                _ => entries.push((aor, vec![binding])),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn expire(&mut self, now: Instant) -> Result<Vec<(String, usize)>, LocationError> {
+        let cutoff = unix_millis_from_instant(now, Instant::now());
+        let transaction = self.connection.transaction()?;
+        let expired = {
+            let mut statement = transaction.prepare(
+                "SELECT aor, COUNT(*) FROM bindings WHERE expires_at <= ?1 GROUP BY aor ORDER BY aor",
+            )?;
+            let rows = statement.query_map([cutoff], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        transaction.execute("DELETE FROM bindings WHERE expires_at <= ?1", [cutoff])?;
+        transaction.commit()?;
+        Ok(expired)
+    }
+}
+
+// Unix time in milliseconds of `instant`, given that `now` is the present.
+fn unix_millis_from_instant(instant: Instant, now: Instant) -> i64 {
+    let wall_now = SystemTime::now();
+    let wall = if instant >= now {
+        wall_now + (instant - now)
+    } else {
+        wall_now - (now - instant)
+    };
+    match wall.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(before) => -(before.duration().as_millis() as i64),
+    }
+}
+
+// The Instant at Unix time `millis`, given that `now` is the present. Times
+// too far in the past to represent come out as `now`, which has expired.
+fn instant_from_unix_millis(millis: i64, now: Instant) -> Instant {
+    let wall = match u64::try_from(millis) {
+        Ok(millis) => UNIX_EPOCH + Duration::from_millis(millis),
+        Err(_) => UNIX_EPOCH,
+    };
+    match wall.duration_since(SystemTime::now()) {
+        Ok(ahead) => now + ahead,
+        Err(behind) => now.checked_sub(behind.duration()).unwrap_or(now),
+    }
+}
+
+// The location service the server uses.
+pub fn location_service() -> MutexGuard<'static, Box<dyn LocationService>> {
+    match LOCATION_SERVICE.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            eprintln!("LOCATION_SERVICE mutex poisoned; continuing with existing data.");
+            poisoned.into_inner()
+        }
+    }
+}
+
+// Replaces the location service, e.g. with an SQLite one at startup or a
+// test's own. Bindings in the old one are not carried over.
+pub fn set_location_service(service: Box<dyn LocationService>) {
+    *location_service() = service;
+}
@@ -1,12 +1,22 @@
 #![deny(warnings)]
 
+use sip_server_rust::directory::{load_directory, FileWatcher};
+use sip_server_rust::forwarding::load_call_forwarding;
+use sip_server_rust::location::{
+    location_service, set_location_service, InMemoryLocationService, LocationError,
+    LocationService, SqliteLocationService,
+};
+use sip_server_rust::registrar::sweep_expired_bindings;
+use sip_server_rust::ring_group::load_ring_groups;
 use sip_server_rust::sip_defs::CallMap;
 use sip_server_rust::sip_defs::*;
 use sip_server_rust::worker::process_sip_messages;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
 fn main() -> io::Result<()> {
     println!("Starting SIP server on port {}...", SIP_PORT);
@@ -20,6 +30,64 @@ fn main() -> io::Result<()> {
     // Wrap socket in Arc for sharing with sender utility (and potentially workers if they sent directly)
     let shared_socket = Arc::new(socket);
 
+    // Bindings go to the SQLite database given as the second argument, or
+    // else stay in memory with a snapshot in DEFAULT_BINDINGS_SNAPSHOT, so
+    // that they survive a restart. This comes before the first directory
+    // poll, which drops restored bindings of users no longer listed.
+    let location: Result<Box<dyn LocationService>, _> = match std::env::args().nth(2) {
+        Some(path) => SqliteLocationService::open(Path::new(&path))
+            .map(|service| Box::new(service) as Box<dyn LocationService>)
+            .map_err(|e| LocationError(format!("{}: {}", path, e))),
+        None => InMemoryLocationService::with_snapshot(PathBuf::from(DEFAULT_BINDINGS_SNAPSHOT))
+            .map(|service| Box::new(service) as Box<dyn LocationService>),
+    };
+    match location {
+        Ok(service) => {
+            let restored = service
+                .list()
+                .map(|entries| entries.iter().map(|(_, bindings)| bindings.len()).sum())
+                .unwrap_or(0);
+            set_location_service(service);
+            println!("{} binding(s) restored.", restored);
+        }
+        Err(e) => eprintln!("{}. Keeping bindings in memory only.", e),
+    }
+
+    // Users come from the directory file given as the first argument (or
+    // DEFAULT_USER_DIRECTORY); there are none until it exists. Ring groups
+    // and call forwarding are read from the files next to it.
+    let directory_path = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_USER_DIRECTORY));
+    let mut config_files = [
+        FileWatcher::new(directory_path.clone(), "user(s)", load_directory),
+        FileWatcher::new(
+            directory_path.with_file_name(RING_GROUPS_FILE),
+            "ring group(s)",
+            load_ring_groups,
+        ),
+        FileWatcher::new(
+            directory_path.with_file_name(CALL_FORWARDING_FILE),
+            "forwarding setting(s)",
+            load_call_forwarding,
+        ),
+    ];
+    for file in config_files.iter_mut() {
+        file.poll();
+    }
+    if LOCATION_ENTRIES
+        .lock()
+        .map_or(true, |entries| entries.is_empty())
+    {
+        println!(
+            "No users in {}; every REGISTER and INVITE is refused until some are added.",
+            directory_path.display()
+        );
+    }
+    let mut last_directory_poll = Instant::now();
+    let mut last_binding_sweep = Instant::now();
+
     // 2. Initialize Shared Call Map
     let call_map = Arc::new(Mutex::new(CallMap::new()));
     println!("Call map initialized with capacity {}.", MAX_CALLS);
@@ -98,6 +166,24 @@ fn main() -> io::Result<()> {
                 thread::sleep(std::time::Duration::from_millis(100)); // Avoid busy-looping on persistent errors
             }
         }
+
+        // Pick up edits of the user directory and the files next to it
+        // without a restart
+        if last_directory_poll.elapsed() >= DIRECTORY_POLL_INTERVAL {
+            for file in config_files.iter_mut() {
+                file.poll();
+            }
+            last_directory_poll = Instant::now();
+        }
+        // Expired bindings are dropped here, once for all workers, and the
+        // changes of the last interval are written out together
+        if last_binding_sweep.elapsed() >= BINDING_SWEEP_INTERVAL {
+            sweep_expired_bindings(Instant::now());
+            if let Err(e) = location_service().flush() {
+                eprintln!("Failed to write bindings: {}. Retrying later.", e);
+            }
+            last_binding_sweep = Instant::now();
+        }
         // Add a condition to break the loop for graceful shutdown if needed
     }
 
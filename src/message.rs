@@ -0,0 +1,549 @@
+use std::fmt;
+
+// Typed SIP message model (RFC 3261 §7).
+// The worker parses every datagram into a `ParsedMessage` once and then works on
+// the start line, header multimap and body instead of re-scanning the raw text.
+
+// A single header line. The name is kept exactly as received so it can be echoed back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.value)
+    }
+}
+
+// Ordered header multimap with case-insensitive name lookup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<Header>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers::default()
+    }
+
+    // Returns true if `a` and `b` name the same header, treating compact
+    // forms (e.g. "i" for Call-ID) as equal to their long names.
+    pub fn names_match(a: &str, b: &str) -> bool {
+        canonical_header_name(a).eq_ignore_ascii_case(canonical_header_name(b))
+    }
+
+    // First value of the named header, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_header(name).map(|h| h.value.as_str())
+    }
+
+    // First header line with the given name, keeping the received name.
+    pub fn get_header(&self, name: &str) -> Option<&Header> {
+        self.entries
+            .iter()
+            .find(|h| Headers::names_match(&h.name, name))
+    }
+
+    // Every header line with the given name, in message order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Header> + 'a {
+        self.entries
+            .iter()
+            .filter(move |h| Headers::names_match(&h.name, name))
+    }
+
+    // All values of a list-valued header (Via, Contact, Route, ...), splitting
+    // comma-separated values as well as repeated header lines.
+    pub fn get_list<'a>(&'a self, name: &str) -> Vec<&'a str> {
+        self.entries
+            .iter()
+            .filter(|h| Headers::names_match(&h.name, name))
+            .flat_map(|h| split_header_list(&h.value))
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get_header(name).is_some()
+    }
+
+    // Appends a header line after the existing ones.
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.entries.push(Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    // Inserts a header line before all other lines of the same name
+    // (or at the end if there are none), e.g. to add our own Via on top.
+    pub fn push_front(&mut self, name: &str, value: &str) {
+        let header = Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        match self
+            .entries
+            .iter()
+            .position(|h| Headers::names_match(&h.name, name))
+        {
+            Some(pos) => self.entries.insert(pos, header),
+            None => self.entries.push(header),
+        }
+    }
+
+    // Replaces the first line of the named header (keeping its position and
+    // received name) and drops any further lines; appends if it was missing.
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self
+            .entries
+            .iter()
+            .position(|h| Headers::names_match(&h.name, name))
+        {
+            Some(pos) => {
+                self.entries[pos].value = value.to_string();
+                let mut index = 0;
+                self.entries.retain(|h| {
+                    let keep = index <= pos || !Headers::names_match(&h.name, name);
+                    index += 1;
+                    keep
+                });
+            }
+            None => self.push(name, value),
+        }
+    }
+
+    // Removes every line of the named header, returning how many were removed.
+    pub fn remove(&mut self, name: &str) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|h| !Headers::names_match(&h.name, name));
+        before - self.entries.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Header> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a Header;
+    type IntoIter = std::slice::Iter<'a, Header>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+// Compact header forms (RFC 3261 §7.3.3 and the extensions that register one).
+pub const COMPACT_HEADER_FORMS: &[(&str, &str)] = &[
+    ("i", "Call-ID"),
+    ("f", "From"),
+    ("t", "To"),
+    ("v", "Via"),
+    ("m", "Contact"),
+    ("l", "Content-Length"),
+    ("c", "Content-Type"),
+    ("k", "Supported"),
+    ("s", "Subject"),
+    ("e", "Content-Encoding"),
+    ("o", "Event"),
+    ("r", "Refer-To"),
+    ("b", "Referred-By"),
+    ("u", "Allow-Events"),
+    ("x", "Session-Expires"),
+];
+
+// Maps a compact header name to its long form; other names are returned unchanged.
+pub fn canonical_header_name(name: &str) -> &str {
+    if name.len() == 1 {
+        if let Some((_, long)) = COMPACT_HEADER_FORMS
+            .iter()
+            .find(|(compact, _)| compact.eq_ignore_ascii_case(name))
+        {
+            return long;
+        }
+    }
+    name
+}
+
+// Splits a header value on commas that are not inside quotes or angle brackets.
+pub fn split_header_list(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut in_angle = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_angle = true,
+            '>' if !in_quotes => in_angle = false,
+            ',' if !in_quotes && !in_angle => {
+                let part = value[start..i].trim();
+                if !part.is_empty() {
+                    parts.push(part);
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = value[start..].trim();
+    if !last.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SipRequest {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Headers,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SipResponse {
+    pub version: String,
+    pub status_code: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedMessage {
+    Request(SipRequest),
+    Response(SipResponse),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    InvalidStartLine(String),
+    InvalidHeader(String),
+    InvalidContentLength(String),
+    BodyTruncated { expected: usize, actual: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty message"),
+            ParseError::InvalidStartLine(line) => write!(f, "invalid start line: {}", line),
+            ParseError::InvalidHeader(line) => write!(f, "invalid header line: {}", line),
+            ParseError::InvalidContentLength(value) => {
+                write!(f, "invalid Content-Length: {}", value)
+            }
+            ParseError::BodyTruncated { expected, actual } => write!(
+                f,
+                "body shorter than Content-Length ({} < {})",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Accessors shared by requests and responses.
+pub trait MessageHeaders {
+    fn headers(&self) -> &Headers;
+    fn headers_mut(&mut self) -> &mut Headers;
+    fn body(&self) -> &str;
+
+    fn call_id(&self) -> Option<&str> {
+        self.headers().get("Call-ID")
+    }
+
+    // CSeq as (sequence number, method).
+    fn cseq(&self) -> Option<(u32, String)> {
+        let value = self.headers().get("CSeq")?;
+        let mut parts = value.split_whitespace();
+        let number = parts.next()?.parse::<u32>().ok()?;
+        let method = parts.next()?.to_string();
+        Some((number, method))
+    }
+
+    // Topmost Via value.
+    fn top_via(&self) -> Option<&str> {
+        self.headers().get_list("Via").into_iter().next()
+    }
+
+    fn max_forwards(&self) -> Option<u32> {
+        self.headers()
+            .get("Max-Forwards")
+            .and_then(|v| v.trim().parse::<u32>().ok())
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.headers().get("Content-Type")
+    }
+
+    // True if an option-tag header (Supported, Require, ...) lists `tag`.
+    fn has_option_tag(&self, header: &str, tag: &str) -> bool {
+        self.headers()
+            .get_list(header)
+            .iter()
+            .any(|value| value.trim().eq_ignore_ascii_case(tag))
+    }
+
+    // RSeq of a reliable provisional response (RFC 3262 §7.1).
+    fn rseq(&self) -> Option<u32> {
+        self.headers().get("RSeq")?.trim().parse::<u32>().ok()
+    }
+
+    // RAck of a PRACK as (RSeq, CSeq number, method) (RFC 3262 §7.2).
+    fn rack(&self) -> Option<(u32, u32, String)> {
+        let value = self.headers().get("RAck")?;
+        let mut parts = value.split_whitespace();
+        let rseq = parts.next()?.parse::<u32>().ok()?;
+        let cseq = parts.next()?.parse::<u32>().ok()?;
+        let method = parts.next()?.to_string();
+        Some((rseq, cseq, method))
+    }
+
+    // Body if the message carries an SDP payload.
+    fn sdp_body(&self) -> Option<&str> {
+        let is_sdp = self.content_type().is_some_and(|ct| {
+            ct.split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/sdp"))
+        });
+        if is_sdp && !self.body().is_empty() {
+            Some(self.body())
+        } else {
+            None
+        }
+    }
+}
+
+impl MessageHeaders for SipRequest {
+    fn headers(&self) -> &Headers {
+        &self.headers
+    }
+    fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+    fn body(&self) -> &str {
+        &self.body
+    }
+}
+
+impl MessageHeaders for SipResponse {
+    fn headers(&self) -> &Headers {
+        &self.headers
+    }
+    fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+    fn body(&self) -> &str {
+        &self.body
+    }
+}
+
+impl MessageHeaders for ParsedMessage {
+    fn headers(&self) -> &Headers {
+        match self {
+            ParsedMessage::Request(req) => &req.headers,
+            ParsedMessage::Response(resp) => &resp.headers,
+        }
+    }
+    fn headers_mut(&mut self) -> &mut Headers {
+        match self {
+            ParsedMessage::Request(req) => &mut req.headers,
+            ParsedMessage::Response(resp) => &mut resp.headers,
+        }
+    }
+    fn body(&self) -> &str {
+        match self {
+            ParsedMessage::Request(req) => &req.body,
+            ParsedMessage::Response(resp) => &resp.body,
+        }
+    }
+}
+
+fn write_headers_and_body(
+    f: &mut fmt::Formatter<'_>,
+    headers: &Headers,
+    body: &str,
+) -> fmt::Result {
+    for header in headers {
+        write!(f, "{}\r\n", header)?;
+    }
+    write!(f, "\r\n{}", body)
+}
+
+impl fmt::Display for SipRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}\r\n", self.method, self.uri, self.version)?;
+        write_headers_and_body(f, &self.headers, &self.body)
+    }
+}
+
+impl fmt::Display for SipResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}\r\n",
+            self.version, self.status_code, self.reason
+        )?;
+        write_headers_and_body(f, &self.headers, &self.body)
+    }
+}
+
+impl fmt::Display for ParsedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParsedMessage::Request(req) => req.fmt(f),
+            ParsedMessage::Response(resp) => resp.fmt(f),
+        }
+    }
+}
+
+// Parses a complete datagram into a request or response.
+// Header lines must be CRLF-terminated; continuation lines (leading SP/HT) are
+// folded into the previous header. The body is framed by Content-Length when
+// present, otherwise it runs to the end of the datagram.
+pub fn parse_message(buffer: &[u8]) -> Result<ParsedMessage, ParseError> {
+    // Skip leading CRLFs (RFC 3261 §7.5 / keep-alive pings).
+    let mut start = 0;
+    while buffer[start..].starts_with(b"\r\n") {
+        start += 2;
+    }
+    let buffer = &buffer[start..];
+    if buffer.is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    let (head_bytes, body_bytes) = match find_subslice(buffer, b"\r\n\r\n") {
+        Some(pos) => (&buffer[..pos], &buffer[pos + 4..]),
+        None => (buffer, &buffer[buffer.len()..]),
+    };
+    let head = String::from_utf8_lossy(head_bytes);
+    let mut lines = head.split("\r\n");
+
+    let start_line = lines.next().unwrap_or("").trim_end();
+    let mut headers = Headers::new();
+    for line in lines {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Folded continuation of the previous header value.
+            match headers.entries.last_mut() {
+                Some(last) => {
+                    let continuation = line.trim();
+                    if !continuation.is_empty() {
+                        if !last.value.is_empty() {
+                            last.value.push(' ');
+                        }
+                        last.value.push_str(continuation);
+                    }
+                }
+                None => return Err(ParseError::InvalidHeader(line.to_string())),
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| ParseError::InvalidHeader(line.to_string()))?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(ParseError::InvalidHeader(line.to_string()));
+        }
+        headers.push(name, value.trim());
+    }
+
+    let body = match headers.get("Content-Length") {
+        Some(value) => {
+            let expected = value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| ParseError::InvalidContentLength(value.to_string()))?;
+            if body_bytes.len() < expected {
+                return Err(ParseError::BodyTruncated {
+                    expected,
+                    actual: body_bytes.len(),
+                });
+            }
+            &body_bytes[..expected]
+        }
+        None => body_bytes,
+    };
+    let body = String::from_utf8_lossy(body).into_owned();
+
+    parse_start_line(start_line, headers, body)
+}
+
+fn parse_start_line(
+    line: &str,
+    headers: Headers,
+    body: String,
+) -> Result<ParsedMessage, ParseError> {
+    let invalid = || ParseError::InvalidStartLine(line.to_string());
+    if line.starts_with("SIP/") {
+        // Status-Line = SIP-Version SP Status-Code SP Reason-Phrase
+        let mut parts = line.splitn(3, ' ');
+        let version = parts.next().ok_or_else(invalid)?;
+        let code = parts.next().ok_or_else(invalid)?;
+        let reason = parts.next().unwrap_or("");
+        let status_code = code.parse::<u16>().map_err(|_| invalid())?;
+        if !(100..=699).contains(&status_code) {
+            return Err(invalid());
+        }
+        Ok(ParsedMessage::Response(SipResponse {
+            version: version.to_string(),
+            status_code,
+            reason: reason.to_string(),
+            headers,
+            body,
+        }))
+    } else {
+        // Request-Line = Method SP Request-URI SP SIP-Version
+        let parts: Vec<&str> = line.split(' ').collect();
+        if parts.len() != 3 || !parts[2].starts_with("SIP/") {
+            return Err(invalid());
+        }
+        let method = parts[0];
+        if method.is_empty()
+            || !method
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.!%*_+`'~".contains(c))
+        {
+            return Err(invalid());
+        }
+        if parts[1].is_empty() {
+            return Err(invalid());
+        }
+        Ok(ParsedMessage::Request(SipRequest {
+            method: method.to_string(),
+            uri: parts[1].to_string(),
+            version: parts[2].to_string(),
+            headers,
+            body,
+        }))
+    }
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,116 @@
+use crate::message::{parse_message, ParsedMessage, SipRequest};
+use crate::sip_defs::*;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Offline store for SIP MESSAGE requests (RFC 3428) addressed to users with
+// no binding: one file per message, holding the request as received, in a
+// directory per user. They are delivered oldest first when the user
+// registers again, and removed once a binding accepts them.
+//
+// Anyone may send a MESSAGE, so each user's queue is capped at
+// MAX_STORED_MESSAGES messages and MAX_STORED_MESSAGE_BYTES bytes; only
+// users in the directory have one, which bounds the store as a whole.
+
+#[derive(Debug)]
+pub enum StoreError {
+    Full, // The user's queue is at its cap
+    Io(io::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Full => write!(f, "queue full"),
+            StoreError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+fn user_dir(username: &str) -> PathBuf {
+    let dir = match MESSAGE_STORE_DIR.lock() {
+        Ok(guard) => guard.clone(),
+        Err(poisoned) => {
+            eprintln!(
+                "MESSAGE_STORE_DIR mutex poisoned while reading; continuing with existing data."
+            );
+            poisoned.into_inner().clone()
+        }
+    };
+    dir.join(username)
+}
+
+// Number and total size of the messages queued in `dir`.
+fn queue_size(dir: &Path) -> io::Result<(usize, u64)> {
+    let mut count = 0;
+    let mut bytes = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().extension().is_some_and(|ext| ext == "sip") {
+            count += 1;
+            bytes += entry.metadata()?.len();
+        }
+    }
+    Ok((count, bytes))
+}
+
+// Queues `request` for `username`; returns the file it was written to.
+pub fn store_message(username: &str, request: &SipRequest) -> Result<PathBuf, StoreError> {
+    let dir = user_dir(username);
+    fs::create_dir_all(&dir)?;
+    let text = request.to_string();
+    let (count, bytes) = queue_size(&dir)?;
+    if count >= MAX_STORED_MESSAGES || bytes + text.len() as u64 > MAX_STORED_MESSAGE_BYTES {
+        return Err(StoreError::Full);
+    }
+    // Names sort in arrival order
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    let path = dir.join(format!("{:024}-{:08x}.sip", nanos, rand::random::<u32>()));
+    fs::write(&path, text)?;
+    Ok(path)
+}
+
+// Messages queued for `username`, oldest first. Files that no longer parse
+// are skipped (and left for inspection).
+pub fn stored_messages(username: &str) -> Vec<(PathBuf, SipRequest)> {
+    let entries = match fs::read_dir(user_dir(username)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(), // Nothing was ever stored
+    };
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sip"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(
+            |path| match fs::read(&path).map(|data| parse_message(&data)) {
+                Ok(Ok(ParsedMessage::Request(request))) => Some((path, request)),
+                _ => {
+                    eprintln!("Skipping unreadable stored message {}", path.display());
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+// Drops a delivered message from the store.
+pub fn remove_stored_message(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        eprintln!("Failed to remove stored message {}: {}", path.display(), e);
+    }
+}
@@ -22,13 +22,10 @@ pub fn parse_first_line(first_line: &str) -> Option<(i32, String)> {
             // Request: INVITE sip:user@host SIP/2.0
             // Check if it's a known method (case-insensitive check might be better)
             let method = parts[0].to_uppercase();
-            match method.as_str() {
-                "INVITE" | "ACK" | "BYE" | "CANCEL" | "REGISTER" | "OPTIONS" => {
-                    // Add other methods if needed
-                    return Some((REQUEST_METHOD, parts[0].to_string())); // Return original case
-                }
-                _ => return None, // Unknown method
+            if SUPPORTED_METHODS.contains(&method.as_str()) {
+                return Some((REQUEST_METHOD, parts[0].to_string())); // Return original case
             }
+            return None; // Unknown method
         }
     }
     None
@@ -50,22 +47,36 @@ pub fn get_header_value<'a>(message_str: &'a str, header_name: &str) -> Option<&
     }
     None
 }
+
+// Same as get_header_value, but falls back to the compact form of the header
+// (e.g. 'i' for "Call-ID:"), matched only at the start of a line.
+pub fn get_header_value_or_compact<'a>(
+    message_str: &'a str,
+    header_name: &str,
+    compact: char,
+) -> Option<&'a str> {
+    get_header_value(message_str, header_name)
+        .or_else(|| get_header_value(message_str, &format!("\r\n{}:", compact)))
+        .or_else(|| get_header_value(message_str, &format!("\r\n{} :", compact)))
+}
+
 // Extracts Call-ID
 pub fn get_call_id(message_str: &str) -> Option<String> {
-    get_header_value(message_str, "Call-ID:").map(|s| s.trim().to_string())
+    get_header_value_or_compact(message_str, "Call-ID:", 'i').map(|s| s.trim().to_string())
 }
 
 // Extracts From header value
 pub fn get_from_header(message_str: &str) -> Option<String> {
-    get_header_value(message_str, "From:").map(|s| format!("From: {}", s)) // Reconstruct full header for storage
+    // Reconstruct full header for storage
+    get_header_value_or_compact(message_str, "From:", 'f').map(|s| format!("From: {}", s))
 }
 // Extracts To header value
 pub fn get_to_header(message_str: &str) -> Option<String> {
-    get_header_value(message_str, "To:").map(|s| format!("To: {}", s))
+    get_header_value_or_compact(message_str, "To:", 't').map(|s| format!("To: {}", s))
 }
 // Extracts Via header value (first Via)
 pub fn get_via_header(message_str: &str) -> Option<String> {
-    get_header_value(message_str, "Via:").map(|s| format!("Via: {}", s))
+    get_header_value_or_compact(message_str, "Via:", 'v').map(|s| format!("Via: {}", s))
 }
 // Extracts CSeq header value
 pub fn get_cseq_header(message_str: &str) -> Option<String> {
@@ -74,7 +85,7 @@ pub fn get_cseq_header(message_str: &str) -> Option<String> {
 
 // Extracts Contact header value
 pub fn get_contact_header(message_str: &str) -> Option<String> {
-    get_header_value(message_str, "Contact:").map(|s| format!("Contact: {}", s))
+    get_header_value_or_compact(message_str, "Contact:", 'm').map(|s| format!("Contact: {}", s))
 }
 
 // Extracts Max-Forwards header value
@@ -84,7 +95,7 @@ pub fn get_max_forwards(message_str: &str) -> Option<u32> {
 
 // Extracts SDP (checks Content-Type and returns content after blank line)
 pub fn get_sdp_body(message_str: &str) -> Option<&str> {
-    if get_header_value(message_str, "Content-Type:")
+    if get_header_value_or_compact(message_str, "Content-Type:", 'c')
         .is_some_and(|ct| ct.trim().contains("application/sdp"))
     {
         message_str.split_once("\r\n\r\n").map(|(_, body)| body)
@@ -108,7 +119,7 @@ pub fn extract_username_from_uri(uri_header: &str) -> Option<String> {
                 .unwrap_or(uri.len());
 
             let username = &uri[user_part_start..user_part_end];
-            if !username.is_empty() && username.len() < MAX_USERNAME_LENGTH {
+            if !username.is_empty() {
                 return Some(username.to_string());
             }
         }
@@ -0,0 +1,244 @@
+use crate::location::{location_service, LocationError};
+use crate::message::{MessageHeaders, SipRequest};
+use crate::sip_defs::*;
+use crate::uri::NameAddr;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// RFC 3261 §10.3 registrar: REGISTER adds, refreshes and removes the
+// bindings of a user, each with its own expiry, q-value and (RFC 5626)
+// instance-id. Bindings that are not refreshed in time are swept away.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    UnknownUser,            // 404: no such address-of-record
+    BadContact,             // 400: malformed Contact, q or "*" misuse
+    IntervalTooBrief,       // 423: an expiry below MIN_REGISTER_EXPIRES
+    OutOfOrder,             // 500: not newer than the REGISTER that set a binding
+    Storage(LocationError), // 500: the location service failed
+}
+
+impl RegisterError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            RegisterError::UnknownUser => 404,
+            RegisterError::BadContact => 400,
+            RegisterError::IntervalTooBrief => 423,
+            RegisterError::OutOfOrder => 500,
+            RegisterError::Storage(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::UnknownUser => write!(f, "unknown user"),
+            RegisterError::BadContact => write!(f, "malformed Contact"),
+            RegisterError::IntervalTooBrief => write!(f, "expiry below {}s", MIN_REGISTER_EXPIRES),
+            RegisterError::OutOfOrder => write!(f, "CSeq not newer than the binding's"),
+            RegisterError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<LocationError> for RegisterError {
+    fn from(e: LocationError) -> Self {
+        RegisterError::Storage(e)
+    }
+}
+
+impl Binding {
+    // A binding of `contact` reached at `addr`, valid for the default
+    // registration interval.
+    pub fn new(contact: &str, addr: SocketAddr) -> Self {
+        Binding {
+            contact: contact.to_string(),
+            addr,
+            expires: Instant::now() + Duration::from_secs(u64::from(REGISTER_CONTACT_EXPIRES)),
+            call_id: String::new(),
+            cseq: 0,
+            q: 1000,
+            instance_id: None,
+        }
+    }
+
+    // Whether a Contact of a REGISTER names this binding: by instance-id when
+    // the Contact carries one, by URI otherwise.
+    fn is_named_by(&self, requested: &RequestedBinding) -> bool {
+        match &requested.instance_id {
+            Some(instance_id) => self.instance_id.as_ref() == Some(instance_id),
+            None => self.contact == requested.contact,
+        }
+    }
+}
+
+// A Contact of a REGISTER, with the expiry it asks for.
+struct RequestedBinding {
+    contact: String,
+    expires: u32,
+    q: u16,
+    instance_id: Option<String>,
+}
+
+// The Contacts of `request`: None for "*" (remove all bindings), an empty
+// list for a query.
+fn requested_bindings(
+    request: &SipRequest,
+) -> Result<Option<Vec<RequestedBinding>>, RegisterError> {
+    let expires_header = match request.headers.get("Expires") {
+        Some(value) => Some(
+            value
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| RegisterError::BadContact)?,
+        ),
+        None => None,
+    };
+    let contacts = request.headers.get_list("Contact");
+    if contacts.contains(&"*") {
+        // Only valid alone and with Expires: 0 (RFC 3261 §10.2.2)
+        if contacts.len() != 1 || expires_header != Some(0) {
+            return Err(RegisterError::BadContact);
+        }
+        return Ok(None);
+    }
+
+    let mut requested = Vec::new();
+    for contact in contacts {
+        let contact = NameAddr::parse(contact).map_err(|_| RegisterError::BadContact)?;
+        let expires = match contact.param("expires") {
+            Some(value) => value
+                .parse::<u32>()
+                .map_err(|_| RegisterError::BadContact)?,
+            None => expires_header.unwrap_or(REGISTER_CONTACT_EXPIRES),
+        };
+        if expires != 0 && expires < MIN_REGISTER_EXPIRES {
+            return Err(RegisterError::IntervalTooBrief);
+        }
+        let q = match contact.param("q") {
+            Some(value) => parse_q(value).ok_or(RegisterError::BadContact)?,
+            None => 1000,
+        };
+        requested.push(RequestedBinding {
+            contact: contact.uri.to_string(),
+            expires: expires.min(MAX_REGISTER_EXPIRES),
+            q,
+            instance_id: contact.param("+sip.instance").map(str::to_string),
+        });
+    }
+    Ok(Some(requested))
+}
+
+// q-value ("0.5", "1", "0.125") in thousandths.
+fn parse_q(value: &str) -> Option<u16> {
+    let q = value.parse::<f32>().ok()?;
+    if !(0.0..=1.0).contains(&q) {
+        return None;
+    }
+    Some((q * 1000.0).round() as u16)
+}
+
+// Applies a REGISTER from `source` to the bindings of `username` and returns
+// the bindings the user has afterwards. Nothing changes when any Contact is
+// rejected.
+pub fn register(
+    username: &str,
+    request: &SipRequest,
+    source: SocketAddr,
+    now: Instant,
+) -> Result<Vec<Binding>, RegisterError> {
+    if !is_known_user(username) {
+        return Err(RegisterError::UnknownUser);
+    }
+    let requested = requested_bindings(request)?;
+    let call_id = request.call_id().unwrap_or_default();
+    let cseq = request.cseq().map(|(number, _)| number).unwrap_or(0);
+
+    // Held throughout so that the REGISTER applies as a whole
+    let mut location = location_service();
+    let bindings = location.lookup(username, now)?;
+
+    // Within one Call-ID a REGISTER must be newer than the one that set the
+    // binding (RFC 3261 §10.3 step 7)
+    let out_of_order = |binding: &Binding| binding.call_id == call_id && cseq <= binding.cseq;
+    let requested = match requested {
+        Some(requested) => requested,
+        None => {
+            if bindings.iter().any(out_of_order) {
+                return Err(RegisterError::OutOfOrder);
+            }
+            println!("All bindings of {} removed.", username);
+            location.remove(username, None)?;
+            return Ok(Vec::new());
+        }
+    };
+    let stale = requested.iter().any(|requested| {
+        bindings
+            .iter()
+            .any(|binding| binding.is_named_by(requested) && out_of_order(binding))
+    });
+    if stale {
+        return Err(RegisterError::OutOfOrder);
+    }
+
+    for requested in requested {
+        let existing = bindings
+            .iter()
+            .find(|binding| binding.is_named_by(&requested));
+        if requested.expires == 0 {
+            if let Some(existing) = existing {
+                println!("Binding {} of {} removed.", existing.contact, username);
+                location.remove(username, Some(&existing.contact))?;
+            }
+            continue;
+        }
+        let binding = Binding {
+            contact: requested.contact,
+            addr: source,
+            expires: now + Duration::from_secs(u64::from(requested.expires)),
+            call_id: call_id.to_string(),
+            cseq,
+            q: requested.q,
+            instance_id: requested.instance_id,
+        };
+        println!(
+            "Binding {} of {} registered from {} for {}s.",
+            binding.contact, username, source, requested.expires
+        );
+        location.register_binding(username, binding)?;
+    }
+    Ok(location.lookup(username, now)?)
+}
+
+// Contact header value listing `binding` in a 200 to REGISTER, with the
+// seconds it has left.
+pub fn binding_contact(binding: &Binding, now: Instant) -> String {
+    let remaining = binding.expires.saturating_duration_since(now);
+    let mut contact = format!(
+        "<{}>;expires={}",
+        binding.contact,
+        remaining.as_millis().div_ceil(1000)
+    );
+    if binding.q != 1000 {
+        contact.push_str(&format!(";q={}", f32::from(binding.q) / 1000.0));
+    }
+    if let Some(instance_id) = &binding.instance_id {
+        contact.push_str(&format!(";+sip.instance={}", instance_id));
+    }
+    contact
+}
+
+// Drops bindings that were not refreshed in time. Called from the main
+// loop every BINDING_SWEEP_INTERVAL.
+pub fn sweep_expired_bindings(now: Instant) {
+    match location_service().expire(now) {
+        Ok(expired) => {
+            for (username, count) in expired {
+                println!("{} binding(s) of {} expired.", count, username);
+            }
+        }
+        Err(e) => eprintln!("Failed to sweep expired bindings: {}", e),
+    }
+}
@@ -0,0 +1,143 @@
+use crate::directory::{config_lines, DirectoryError};
+use crate::sip_defs::*;
+use std::fs;
+use std::path::Path;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+// Ring groups (hunt groups): a call to a group number rings its members
+// sequentially, in parallel or round-robin, each step for the group's member
+// timeout, and then the overflow destination.
+//
+// They are read from a CSV file next to the user directory, reloaded when it
+// changes. One group per line, '#' starts a comment:
+//
+//   number,strategy,members,member timeout,overflow
+//   2000,sequential,1003 1004 1005,15,1001
+//
+// The strategy is "sequential", "parallel" or "round-robin", members are
+// separated by spaces, the member timeout is in seconds and the overflow
+// destination may be left empty.
+
+fn lock_groups() -> MutexGuard<'static, Vec<RingGroup>> {
+    match RING_GROUPS.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            eprintln!("RING_GROUPS mutex poisoned while reading; continuing with existing data.");
+            poisoned.into_inner()
+        }
+    }
+}
+
+// The ring groups listed in the text of a ring groups file.
+pub fn parse_ring_groups(text: &str) -> Result<Vec<RingGroup>, DirectoryError> {
+    let mut groups: Vec<RingGroup> = Vec::new();
+    for (number, fields) in config_lines(text, 5)? {
+        let error = |reason: String| DirectoryError::Line(number, reason);
+        let group_number = fields[0];
+        if group_number.is_empty() {
+            return Err(error("missing number".to_string()));
+        }
+        if groups.iter().any(|group| group.number == group_number) {
+            return Err(error(format!("group {} listed twice", group_number)));
+        }
+        let strategy = match fields[1] {
+            "sequential" => HuntStrategy::Sequential,
+            "parallel" => HuntStrategy::Parallel,
+            "round-robin" => HuntStrategy::RoundRobin,
+            other => return Err(error(format!("unknown strategy '{}'", other))),
+        };
+        let members = fields[2]
+            .split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            return Err(error("no members".to_string()));
+        }
+        let member_timeout = fields[3]
+            .parse::<u64>()
+            .ok()
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(|| error(format!("bad member timeout '{}'", fields[3])))?;
+        groups.push(RingGroup {
+            number: group_number.to_string(),
+            strategy,
+            members,
+            member_timeout,
+            overflow: Some(fields[4].to_string()).filter(|overflow| !overflow.is_empty()),
+            next_start: 0,
+        });
+    }
+    Ok(groups)
+}
+
+// Replaces RING_GROUPS with `groups`. Round-robin groups that stay carry on
+// with the member they would have started the next call with.
+pub fn apply_ring_groups(mut groups: Vec<RingGroup>) {
+    let mut current = lock_groups();
+    for group in groups.iter_mut() {
+        if let Some(old) = current.iter().find(|old| old.number == group.number) {
+            group.next_start = old.next_start;
+        }
+    }
+    *current = groups;
+}
+
+// Reads the ring groups file at `path` and makes it the group list. On error
+// the current groups are kept.
+pub fn load_ring_groups(path: &Path) -> Result<usize, DirectoryError> {
+    let groups = parse_ring_groups(&fs::read_to_string(path)?)?;
+    let count = groups.len();
+    apply_ring_groups(groups);
+    Ok(count)
+}
+
+// The hunt of a call to `number`: the members of its ring group, or just
+// `number` itself when it is not a group. Round-robin groups start each
+// call one member further than the previous one.
+pub fn start_hunt(number: &str) -> Hunt {
+    let mut groups = lock_groups();
+    let group = match groups.iter_mut().find(|group| group.number == number) {
+        Some(group) => group,
+        None => {
+            return Hunt {
+                members: vec![number.to_string()],
+                ..Hunt::default()
+            }
+        }
+    };
+    let mut members = group.members.clone();
+    if group.strategy == HuntStrategy::RoundRobin && !members.is_empty() {
+        let start = group.next_start % members.len();
+        members.rotate_left(start);
+        group.next_start = (start + 1) % members.len();
+    }
+    Hunt {
+        group: Some(group.number.clone()),
+        strategy: group.strategy,
+        members,
+        next_member: 0,
+        first_fork: 0,
+        member_timeout: Some(group.member_timeout),
+        overflow: group.overflow.clone(),
+        deadline: None,
+    }
+}
+
+impl Hunt {
+    // Users to ring next and for how long (None: until they answer or
+    // fail); None once members and overflow have all been rung.
+    pub fn next_step(&mut self) -> Option<(Vec<String>, Option<Duration>)> {
+        if self.next_member < self.members.len() {
+            let step = if self.strategy == HuntStrategy::Parallel {
+                self.members[self.next_member..].to_vec()
+            } else {
+                vec![self.members[self.next_member].clone()]
+            };
+            self.next_member += step.len();
+            return Some((step, self.member_timeout));
+        }
+        self.overflow.take().map(|overflow| (vec![overflow], None))
+    }
+}
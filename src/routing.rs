@@ -0,0 +1,72 @@
+use crate::message::*;
+use crate::sip_defs::*;
+use crate::uri::{NameAddr, SipUri};
+
+// Record-Route / Route handling for RFC 3261 §16.12 loose routing.
+// The server record-routes the INVITEs it sends, so peers and proxies in
+// front of them put our URI back into the Route of later requests; it is
+// removed here before the request reaches the call logic.
+
+// Record-Route value naming this server as a loose router.
+pub fn server_route() -> String {
+    format!("<sip:{}:{};lr>", SIP_SERVER_IP_ADDRESS, SIP_PORT)
+}
+
+// True if the URI is one this server put into a Record-Route: our address
+// and no user part (user URIs on our host are call targets).
+pub fn is_server_route_uri(uri: &SipUri) -> bool {
+    uri.user.is_none()
+        && uri.host.eq_ignore_ascii_case(SIP_SERVER_IP_ADDRESS)
+        && uri.port.unwrap_or(SIP_PORT) == SIP_PORT
+}
+
+// URI of a Route/Record-Route value.
+pub fn route_uri(value: &str) -> Option<SipUri> {
+    NameAddr::parse(value).ok().map(|addr| addr.uri)
+}
+
+// True if the route entry is a loose router (has the lr parameter).
+pub fn is_loose_route(value: &str) -> bool {
+    route_uri(value).is_some_and(|uri| uri.has_param("lr"))
+}
+
+// Route pre-processing of a received request (RFC 3261 §16.4). A previous
+// hop that is a strict router put our Record-Route URI into the
+// Request-URI: the real one is the last Route value. A Route entry naming
+// us on top is removed. Returns true if the request was changed.
+pub fn preprocess_routes(request: &mut SipRequest) -> bool {
+    let mut routes: Vec<String> = request
+        .headers
+        .get_list("Route")
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    let mut changed = false;
+
+    let request_uri_is_ours = SipUri::parse(&request.uri)
+        .map(|uri| is_server_route_uri(&uri))
+        .unwrap_or(false);
+    if request_uri_is_ours {
+        if let Some(uri) = routes.pop().as_deref().and_then(route_uri) {
+            request.uri = uri.to_string();
+            changed = true;
+        }
+    }
+
+    let top_is_ours = routes
+        .first()
+        .and_then(|value| route_uri(value))
+        .is_some_and(|uri| is_server_route_uri(&uri));
+    if top_is_ours {
+        routes.remove(0);
+        changed = true;
+    }
+
+    if changed {
+        request.headers.remove("Route");
+        for route in &routes {
+            request.headers.push("Route", route);
+        }
+    }
+    changed
+}
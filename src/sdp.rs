@@ -0,0 +1,658 @@
+use std::fmt;
+use std::str::FromStr;
+
+// SDP (RFC 4566) session descriptions and the offer/answer model (RFC 3264).
+// Lines we do not model explicitly are kept verbatim so a parsed session
+// serializes back without losing information.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SdpError {
+    Empty,
+    InvalidLine(String),
+    MissingVersion,
+    MissingOrigin,
+    InvalidOrigin(String),
+    InvalidConnection(String),
+    InvalidMedia(String),
+}
+
+impl fmt::Display for SdpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SdpError::Empty => write!(f, "empty session description"),
+            SdpError::InvalidLine(line) => write!(f, "invalid SDP line: {}", line),
+            SdpError::MissingVersion => write!(f, "session description must start with v="),
+            SdpError::MissingOrigin => write!(f, "missing o= line"),
+            SdpError::InvalidOrigin(value) => write!(f, "invalid o= line: {}", value),
+            SdpError::InvalidConnection(value) => write!(f, "invalid c= line: {}", value),
+            SdpError::InvalidMedia(value) => write!(f, "invalid m= line: {}", value),
+        }
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+// o=<username> <sess-id> <sess-version> <nettype> <addrtype> <unicast-address>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub username: String,
+    pub session_id: String,
+    pub session_version: u64,
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+impl Origin {
+    fn parse(value: &str) -> Result<Self, SdpError> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        if parts.len() != 6 {
+            return Err(SdpError::InvalidOrigin(value.to_string()));
+        }
+        let session_version = parts[2]
+            .parse::<u64>()
+            .map_err(|_| SdpError::InvalidOrigin(value.to_string()))?;
+        Ok(Origin {
+            username: parts[0].to_string(),
+            session_id: parts[1].to_string(),
+            session_version,
+            net_type: parts[3].to_string(),
+            addr_type: parts[4].to_string(),
+            address: parts[5].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {}",
+            self.username,
+            self.session_id,
+            self.session_version,
+            self.net_type,
+            self.addr_type,
+            self.address
+        )
+    }
+}
+
+// c=<nettype> <addrtype> <connection-address>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection {
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+impl Connection {
+    pub fn new(address: &str) -> Self {
+        let addr_type = if address.contains(':') { "IP6" } else { "IP4" };
+        Connection {
+            net_type: "IN".to_string(),
+            addr_type: addr_type.to_string(),
+            address: address.to_string(),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, SdpError> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(SdpError::InvalidConnection(value.to_string()));
+        }
+        Ok(Connection {
+            net_type: parts[0].to_string(),
+            addr_type: parts[1].to_string(),
+            address: parts[2].to_string(),
+        })
+    }
+
+    // RFC 2543 style hold: c=IN IP4 0.0.0.0
+    pub fn is_unspecified(&self) -> bool {
+        self.address == "0.0.0.0" || self.address == "::"
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.net_type, self.addr_type, self.address)
+    }
+}
+
+// a=<name>[:<value>]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Attribute {
+    pub fn new(name: &str, value: Option<&str>) -> Self {
+        Attribute {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value.split_once(':') {
+            Some((name, v)) => Attribute::new(name, Some(v)),
+            None => Attribute::new(value, None),
+        }
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}:{}", self.name, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Media direction attributes (RFC 3264 §5.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    #[default]
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+
+    pub fn from_attribute(name: &str) -> Option<Self> {
+        match name {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    // Direction an answerer uses for a stream offered with this direction.
+    pub fn reversed(&self) -> Self {
+        match self {
+            Direction::SendOnly => Direction::RecvOnly,
+            Direction::RecvOnly => Direction::SendOnly,
+            other => *other,
+        }
+    }
+}
+
+// a=rtpmap:<payload type> <encoding name>/<clock rate>[/<channels>]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpMap {
+    pub payload_type: String,
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub channels: Option<u32>,
+}
+
+impl RtpMap {
+    fn parse(value: &str) -> Option<Self> {
+        let (payload_type, rest) = value.trim().split_once(' ')?;
+        let mut parts = rest.trim().split('/');
+        let encoding = parts.next()?.to_string();
+        let clock_rate = parts.next()?.parse::<u32>().ok()?;
+        let channels = parts.next().and_then(|c| c.parse::<u32>().ok());
+        Some(RtpMap {
+            payload_type: payload_type.to_string(),
+            encoding,
+            clock_rate,
+            channels,
+        })
+    }
+}
+
+// m=<media> <port>[/<number of ports>] <proto> <fmt> ...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaDescription {
+    pub media: String,
+    pub port: u16,
+    pub port_count: Option<u16>,
+    pub protocol: String,
+    pub formats: Vec<String>,
+    pub title: Option<String>,
+    pub connection: Option<Connection>,
+    pub bandwidth: Vec<String>,
+    pub attributes: Vec<Attribute>,
+}
+
+impl MediaDescription {
+    fn parse(value: &str) -> Result<Self, SdpError> {
+        let invalid = || SdpError::InvalidMedia(value.to_string());
+        let mut parts = value.split_whitespace();
+        let media = parts.next().ok_or_else(invalid)?.to_string();
+        let port_field = parts.next().ok_or_else(invalid)?;
+        let (port, port_count) = match port_field.split_once('/') {
+            Some((port, count)) => (
+                port.parse::<u16>().map_err(|_| invalid())?,
+                Some(count.parse::<u16>().map_err(|_| invalid())?),
+            ),
+            None => (port_field.parse::<u16>().map_err(|_| invalid())?, None),
+        };
+        let protocol = parts.next().ok_or_else(invalid)?.to_string();
+        let formats: Vec<String> = parts.map(str::to_string).collect();
+        Ok(MediaDescription {
+            media,
+            port,
+            port_count,
+            protocol,
+            formats,
+            title: None,
+            connection: None,
+            bandwidth: Vec::new(),
+            attributes: Vec::new(),
+        })
+    }
+
+    // First value of the named attribute ("" for flag attributes).
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| a.value.as_deref().unwrap_or(""))
+    }
+
+    pub fn rtpmaps(&self) -> Vec<RtpMap> {
+        self.attributes
+            .iter()
+            .filter(|a| a.name == "rtpmap")
+            .filter_map(|a| a.value.as_deref().and_then(RtpMap::parse))
+            .collect()
+    }
+
+    pub fn rtpmap(&self, payload_type: &str) -> Option<RtpMap> {
+        self.rtpmaps()
+            .into_iter()
+            .find(|m| m.payload_type == payload_type)
+    }
+
+    // Format parameters (a=fmtp) for a payload type, without the type itself.
+    pub fn fmtp(&self, payload_type: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .filter(|a| a.name == "fmtp")
+            .filter_map(|a| a.value.as_deref())
+            .find_map(|v| {
+                let (pt, params) = v.split_once(' ')?;
+                (pt == payload_type).then(|| params.trim())
+            })
+    }
+
+    // Direction attribute set on this media line, if any.
+    pub fn direction(&self) -> Option<Direction> {
+        self.attributes
+            .iter()
+            .find_map(|a| Direction::from_attribute(&a.name))
+    }
+
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.attributes
+            .retain(|a| Direction::from_attribute(&a.name).is_none());
+        self.attributes
+            .push(Attribute::new(direction.as_str(), None));
+    }
+
+    // A port of zero rejects or disables the stream (RFC 3264 §6).
+    pub fn is_disabled(&self) -> bool {
+        self.port == 0
+    }
+}
+
+impl fmt::Display for MediaDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m={} {}", self.media, self.port)?;
+        if let Some(count) = self.port_count {
+            write!(f, "/{}", count)?;
+        }
+        write!(f, " {}", self.protocol)?;
+        for format in &self.formats {
+            write!(f, " {}", format)?;
+        }
+        write!(f, "\r\n")?;
+        if let Some(title) = &self.title {
+            write!(f, "i={}\r\n", title)?;
+        }
+        if let Some(connection) = &self.connection {
+            write!(f, "c={}\r\n", connection)?;
+        }
+        for bandwidth in &self.bandwidth {
+            write!(f, "b={}\r\n", bandwidth)?;
+        }
+        for attribute in &self.attributes {
+            write!(f, "a={}\r\n", attribute)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDescription {
+    pub version: u32,
+    pub origin: Origin,
+    pub session_name: String,
+    // i=, u=, e=, p= lines, in received order
+    pub info: Vec<(char, String)>,
+    pub connection: Option<Connection>,
+    pub bandwidth: Vec<String>,
+    // t= lines, each with any r= lines that follow it
+    pub timing: Vec<(String, Vec<String>)>,
+    // z= and k= lines
+    pub other: Vec<(char, String)>,
+    pub attributes: Vec<Attribute>,
+    pub media: Vec<MediaDescription>,
+}
+
+impl SessionDescription {
+    // Accepts CRLF or bare LF line endings, as many endpoints send the latter.
+    pub fn parse(text: &str) -> Result<Self, SdpError> {
+        let mut lines = text
+            .split('\n')
+            .map(|l| l.trim_end_matches('\r'))
+            .filter(|l| !l.trim().is_empty())
+            .peekable();
+        if lines.peek().is_none() {
+            return Err(SdpError::Empty);
+        }
+
+        let mut version = None;
+        let mut origin = None;
+        let mut session_name = String::new();
+        let mut info = Vec::new();
+        let mut connection = None;
+        let mut bandwidth = Vec::new();
+        let mut timing: Vec<(String, Vec<String>)> = Vec::new();
+        let mut other = Vec::new();
+        let mut attributes = Vec::new();
+        let mut media: Vec<MediaDescription> = Vec::new();
+
+        for line in lines {
+            let (kind, value) = match line.split_once('=') {
+                Some((kind, value)) if kind.len() == 1 => {
+                    (kind.chars().next().unwrap_or(' '), value)
+                }
+                _ => return Err(SdpError::InvalidLine(line.to_string())),
+            };
+            if version.is_none() {
+                if kind != 'v' {
+                    return Err(SdpError::MissingVersion);
+                }
+                version = Some(
+                    value
+                        .trim()
+                        .parse::<u32>()
+                        .map_err(|_| SdpError::InvalidLine(line.to_string()))?,
+                );
+                continue;
+            }
+
+            // Everything after the first m= line belongs to that media section.
+            if let Some(current) = media.last_mut() {
+                match kind {
+                    'm' => media.push(MediaDescription::parse(value)?),
+                    'i' => current.title = Some(value.to_string()),
+                    'c' => current.connection = Some(Connection::parse(value)?),
+                    'b' => current.bandwidth.push(value.to_string()),
+                    'a' => current.attributes.push(Attribute::parse(value)),
+                    // k= is obsolete; other lines are not valid at media level
+                    _ => {}
+                }
+                continue;
+            }
+
+            match kind {
+                'o' => origin = Some(Origin::parse(value)?),
+                's' => session_name = value.to_string(),
+                'i' | 'u' | 'e' | 'p' => info.push((kind, value.to_string())),
+                'c' => connection = Some(Connection::parse(value)?),
+                'b' => bandwidth.push(value.to_string()),
+                't' => timing.push((value.to_string(), Vec::new())),
+                'r' => {
+                    if let Some((_, repeats)) = timing.last_mut() {
+                        repeats.push(value.to_string());
+                    }
+                }
+                'z' | 'k' => other.push((kind, value.to_string())),
+                'a' => attributes.push(Attribute::parse(value)),
+                'm' => media.push(MediaDescription::parse(value)?),
+                _ => return Err(SdpError::InvalidLine(line.to_string())),
+            }
+        }
+
+        Ok(SessionDescription {
+            version: version.unwrap_or(0),
+            origin: origin.ok_or(SdpError::MissingOrigin)?,
+            session_name,
+            info,
+            connection,
+            bandwidth,
+            timing,
+            other,
+            attributes,
+            media,
+        })
+    }
+
+    // Session-level direction attribute, if any.
+    pub fn direction(&self) -> Option<Direction> {
+        self.attributes
+            .iter()
+            .find_map(|a| Direction::from_attribute(&a.name))
+    }
+
+    // Direction in effect for a media line: its own attribute, else the
+    // session-level one, else sendrecv (RFC 3264 §5.1).
+    pub fn media_direction(&self, media: &MediaDescription) -> Direction {
+        media
+            .direction()
+            .or_else(|| self.direction())
+            .unwrap_or_default()
+    }
+
+    // Connection address in effect for a media line.
+    pub fn media_connection<'a>(&'a self, media: &'a MediaDescription) -> Option<&'a Connection> {
+        media.connection.as_ref().or(self.connection.as_ref())
+    }
+
+    // True if every active stream is held: sendonly/inactive (RFC 3264 §8.4)
+    // or a 0.0.0.0 connection address (RFC 2543).
+    pub fn is_hold(&self) -> bool {
+        let mut active = self.media.iter().filter(|m| !m.is_disabled()).peekable();
+        if active.peek().is_none() {
+            return false;
+        }
+        active.all(|m| {
+            matches!(
+                self.media_direction(m),
+                Direction::SendOnly | Direction::Inactive
+            ) || self.media_connection(m).is_some_and(|c| c.is_unspecified())
+        })
+    }
+
+    // Origin fields that must stay the same for later versions of this
+    // session (RFC 4566 §5.2); everything but the version number.
+    fn same_session(&self, other: &SessionDescription) -> bool {
+        self.origin.username == other.origin.username
+            && self.origin.session_id == other.origin.session_id
+            && self.origin.address == other.origin.address
+    }
+
+    // Same session and same version: a repeat of an earlier description
+    // (RFC 3264 §8), e.g. the answer in a 183 repeated in the 200 OK.
+    pub fn is_same_version(&self, other: &SessionDescription) -> bool {
+        self.same_session(other) && self.origin.session_version == other.origin.session_version
+    }
+}
+
+impl FromStr for SessionDescription {
+    type Err = SdpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SessionDescription::parse(s)
+    }
+}
+
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v={}\r\n", self.version)?;
+        write!(f, "o={}\r\n", self.origin)?;
+        write!(f, "s={}\r\n", self.session_name)?;
+        for (kind, value) in &self.info {
+            write!(f, "{}={}\r\n", kind, value)?;
+        }
+        if let Some(connection) = &self.connection {
+            write!(f, "c={}\r\n", connection)?;
+        }
+        for bandwidth in &self.bandwidth {
+            write!(f, "b={}\r\n", bandwidth)?;
+        }
+        for (value, repeats) in &self.timing {
+            write!(f, "t={}\r\n", value)?;
+            for repeat in repeats {
+                write!(f, "r={}\r\n", repeat)?;
+            }
+        }
+        for (kind, value) in &self.other {
+            write!(f, "{}={}\r\n", kind, value)?;
+        }
+        for attribute in &self.attributes {
+            write!(f, "a={}\r\n", attribute)?;
+        }
+        for media in &self.media {
+            write!(f, "{}", media)?;
+        }
+        Ok(())
+    }
+}
+
+// --- Offer/Answer (RFC 3264) ---
+
+// Negotiation state of one leg, seen from this server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NegotiationState {
+    #[default]
+    Idle, // no offer outstanding, nothing negotiated yet
+    LocalOffer,  // we sent an offer and wait for the answer
+    RemoteOffer, // the peer sent an offer and waits for our answer
+    Complete,    // an offer/answer exchange has completed
+}
+
+// What an SDP body turned out to be when fed into the negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdpRole {
+    Offer,
+    Answer,
+    // Same version of a description we already have; no state change.
+    Repeat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OfferAnswerError {
+    // A new offer while one is still outstanding (RFC 3264 §4 glare).
+    OfferPending,
+}
+
+impl fmt::Display for OfferAnswerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OfferAnswerError::OfferPending => write!(f, "an SDP offer is already outstanding"),
+        }
+    }
+}
+
+impl std::error::Error for OfferAnswerError {}
+
+// Offer/answer tracking for one leg: the state plus the last local and remote
+// descriptions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OfferAnswer {
+    pub state: NegotiationState,
+    pub local: Option<SessionDescription>,
+    pub remote: Option<SessionDescription>,
+}
+
+impl OfferAnswer {
+    // Records a description we sent on this leg.
+    pub fn local_sdp(&mut self, sdp: SessionDescription) -> Result<SdpRole, OfferAnswerError> {
+        let (role, state) = Self::transition(self.state, true, self.local.as_ref(), &sdp)?;
+        self.state = state;
+        if role != SdpRole::Repeat {
+            self.local = Some(sdp);
+        }
+        Ok(role)
+    }
+
+    // Records a description received from the peer of this leg.
+    pub fn remote_sdp(&mut self, sdp: SessionDescription) -> Result<SdpRole, OfferAnswerError> {
+        let (role, state) = Self::transition(self.state, false, self.remote.as_ref(), &sdp)?;
+        self.state = state;
+        if role != SdpRole::Repeat {
+            self.remote = Some(sdp);
+        }
+        Ok(role)
+    }
+
+    // An exchange is under way: an offer was sent or received but not answered.
+    pub fn offer_pending(&self) -> bool {
+        matches!(
+            self.state,
+            NegotiationState::LocalOffer | NegotiationState::RemoteOffer
+        )
+    }
+
+    // Drops an unanswered offer, e.g. when the transaction carrying it failed.
+    pub fn rollback(&mut self) {
+        if self.offer_pending() {
+            self.state = if self.local.is_some() && self.remote.is_some() {
+                NegotiationState::Complete
+            } else {
+                NegotiationState::Idle
+            };
+        }
+    }
+
+    fn transition(
+        state: NegotiationState,
+        local: bool,
+        previous: Option<&SessionDescription>,
+        sdp: &SessionDescription,
+    ) -> Result<(SdpRole, NegotiationState), OfferAnswerError> {
+        let repeat = previous.is_some_and(|p| p.is_same_version(sdp));
+        let own_offer = if local {
+            NegotiationState::LocalOffer
+        } else {
+            NegotiationState::RemoteOffer
+        };
+        let peer_offer = if local {
+            NegotiationState::RemoteOffer
+        } else {
+            NegotiationState::LocalOffer
+        };
+        match state {
+            s if s == peer_offer => Ok((SdpRole::Answer, NegotiationState::Complete)),
+            s if s == own_offer => {
+                if repeat {
+                    Ok((SdpRole::Repeat, state))
+                } else {
+                    Err(OfferAnswerError::OfferPending)
+                }
+            }
+            NegotiationState::Complete if repeat => Ok((SdpRole::Repeat, state)),
+            _ => Ok((SdpRole::Offer, own_offer)),
+        }
+    }
+}
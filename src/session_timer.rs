@@ -0,0 +1,89 @@
+use crate::message::*;
+use std::fmt;
+use std::time::Duration;
+
+// RFC 4028 session timer headers. The call-level state (interval,
+// refresher, deadlines) lives in sip_defs::SessionTimer.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refresher {
+    Uac,
+    Uas,
+}
+
+impl Refresher {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Refresher::Uac => "uac",
+            Refresher::Uas => "uas",
+        }
+    }
+}
+
+// Session-Expires: delta-seconds[;refresher=uac|uas]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionExpires {
+    pub delta: u32,
+    pub refresher: Option<Refresher>,
+}
+
+impl SessionExpires {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(';');
+        let delta = parts.next()?.trim().parse::<u32>().ok()?;
+        let refresher = parts.find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            if !name.trim().eq_ignore_ascii_case("refresher") {
+                return None;
+            }
+            match value.trim().to_ascii_lowercase().as_str() {
+                "uac" => Some(Refresher::Uac),
+                "uas" => Some(Refresher::Uas),
+                _ => None,
+            }
+        });
+        Some(SessionExpires { delta, refresher })
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.delta as u64)
+    }
+}
+
+impl fmt::Display for SessionExpires {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.delta)?;
+        if let Some(refresher) = self.refresher {
+            write!(f, ";refresher={}", refresher.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+// Session-Expires of a message (also in its compact form "x").
+pub fn session_expires<M: MessageHeaders>(message: &M) -> Option<SessionExpires> {
+    SessionExpires::parse(message.headers().get("Session-Expires")?)
+}
+
+// Min-SE of a message, in seconds.
+pub fn min_se<M: MessageHeaders>(message: &M) -> Option<u32> {
+    message
+        .headers()
+        .get("Min-SE")?
+        .split(';')
+        .next()?
+        .trim()
+        .parse::<u32>()
+        .ok()
+}
+
+// True if the peer can take part in session timers.
+pub fn supports_timer<M: MessageHeaders>(message: &M) -> bool {
+    message.has_option_tag("Supported", "timer") || message.has_option_tag("Require", "timer")
+}
+
+// How long before expiry the side that does not refresh sends its BYE:
+// the smaller of 32 seconds and a third of the interval (RFC 4028 §10).
+pub fn expiry_margin(interval: Duration) -> Duration {
+    (interval / 3).min(Duration::from_secs(32))
+}
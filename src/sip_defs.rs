@@ -1,5 +1,15 @@
-use std::net::SocketAddr;
-use std::sync::Mutex; // Keep Mutex for CallMap and LOCATION_ENTRIES
+use crate::auth::DigestAlgorithm;
+use crate::dialog::Dialog;
+use crate::dtmf::DtmfDigit;
+use crate::location::{location_service, InMemoryLocationService, LocationService};
+use crate::message::{SipRequest, SipResponse};
+use crate::sdp::{Direction, OfferAnswer};
+use crate::session_timer::Refresher;
+use crate::transaction::TransactionTable;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant}; // Keep Mutex for CallMap and LOCATION_ENTRIES
 
 // --- Constants ---
 pub const BUFFER_SIZE: usize = 1400;
@@ -8,22 +18,78 @@ pub const QUEUE_CAPACITY: usize = 10;
 pub const SIP_PORT: u16 = 5060;
 pub const MAX_CALLS: usize = 32;
 pub const MAX_UUID_LENGTH: usize = 128;
-pub const MAX_USERNAME_LENGTH: usize = 16;
 pub const DEFAULT_MAX_FORWARDS: u32 = 70;
-pub const REGISTER_CONTACT_EXPIRES: u32 = 7200;
+// RFC 3261 §17 timer values for UDP
+pub const TIMER_T1: Duration = Duration::from_millis(500); // RTT estimate
+pub const TIMER_T2: Duration = Duration::from_secs(4); // Max non-INVITE retransmit interval
+pub const TIMER_T4: Duration = Duration::from_secs(5); // Max time a message stays in the network
+pub const TIMER_D: Duration = Duration::from_secs(32); // Wait for response retransmits
+pub const TIMER_TICK: Duration = Duration::from_millis(50); // Worker timer polling interval
+pub const REGISTER_CONTACT_EXPIRES: u32 = 7200; // Binding lifetime when REGISTER asks for none
+pub const MIN_REGISTER_EXPIRES: u32 = 60; // Shorter ones get 423 Interval Too Brief
+pub const MAX_REGISTER_EXPIRES: u32 = 86400; // Longer ones are cut down to this
+pub const BINDING_SWEEP_INTERVAL: Duration = Duration::from_secs(1); // Expired bindings are dropped this often
 pub const RPORT_FLAG_VALUE: u16 = 0;
 
 // NOTE: Set this to your server's actual IP address!
 pub const SIP_SERVER_IP_ADDRESS: &str = "192.168.32.131"; // Example, change as needed
 
+// Sent as User-Agent on requests and Server on responses
+pub const SERVER_USER_AGENT: &str = "TinySIP-Rust";
+// User part of the Contact URI the server puts in its own messages
+pub const SERVER_CONTACT_USER: &str = "TinySIP";
+
 // Define a-leg and b-leg constants
 pub const A_LEG: i32 = 1;
 pub const B_LEG: i32 = 2;
+// The new call placed for a blind transfer, until it replaces the transferor
+pub const TRANSFER_LEG: i32 = 3;
 
 // Define message type constants
 pub const REQUEST_METHOD: i32 = 1;
 pub const STATUS_CODE: i32 = 2;
 
+// Request methods the server understands
+pub const SUPPORTED_METHODS: &[&str] = &[
+    "INVITE", "ACK", "BYE", "CANCEL", "REGISTER", "OPTIONS", "UPDATE", "INFO", "PRACK", "REFER",
+    "MESSAGE",
+];
+
+// Message bodies the server understands
+pub const ACCEPTED_CONTENT_TYPES: &[&str] = &["application/sdp"];
+
+// SIP extensions (option tags) the server implements
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["100rel", "replaces", "timer"];
+
+// RFC 4028 session timer
+pub const DEFAULT_SESSION_EXPIRES: u32 = 1800; // Interval asked for when A does not ask for one
+pub const MIN_SESSION_EXPIRES: u32 = 90; // Our Min-SE (the RFC minimum)
+
+// RFC 3515 transfer
+pub const REFER_NOTIFY_EXPIRES: u32 = 60; // Subscription-State expires while the transfer runs
+
+// Call forwarding
+pub const MAX_DIVERSIONS: usize = 5; // Forwards a call may take before it fails
+
+// Digest authentication (RFC 7616, RFC 8760)
+pub const AUTH_REALM: &str = SIP_SERVER_IP_ADDRESS;
+pub const NONCE_LIFETIME: Duration = Duration::from_secs(300); // Older nonces are answered stale=true
+pub const MAX_AUTH_NONCES: usize = 1024; // Nonces remembered at once; the oldest are dropped
+
+// RFC 3428 instant messages for users with no binding are kept here
+pub const DEFAULT_MESSAGE_STORE_DIR: &str = "offline_messages";
+pub const MAX_STORED_MESSAGES: usize = 50; // Per user; more are answered 486 Busy Here
+pub const MAX_STORED_MESSAGE_BYTES: u64 = 64 * 1024; // Per user, likewise
+
+// User directory file, reloaded when it changes, like the files next to it
+pub const DEFAULT_USER_DIRECTORY: &str = "users.csv";
+pub const RING_GROUPS_FILE: &str = "ring_groups.csv";
+pub const CALL_FORWARDING_FILE: &str = "forwarding.csv";
+pub const DIRECTORY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Registrations kept in memory are also written here, to survive a restart
+pub const DEFAULT_BINDINGS_SNAPSHOT: &str = "bindings.snapshot";
+
 // --- Structs ---
 
 // Holds received message and client address
@@ -33,22 +99,188 @@ pub struct SipMessage {
     pub client_addr: SocketAddr,
 }
 
-// User location information
+// A registered contact of a user. A user may register several (desk phone,
+// softphone); calls to the user fork to all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub contact: String,  // Contact URI as registered (identifies the binding)
+    pub addr: SocketAddr, // Where requests to it are sent (source of the REGISTER)
+    pub expires: Instant, // When it lapses unless refreshed
+    pub call_id: String,  // Call-ID and CSeq of the REGISTER that last set it
+    pub cseq: u32,
+    pub q: u16,                      // Preference in thousandths (q=0.5 is 500)
+    pub instance_id: Option<String>, // +sip.instance; identifies the binding when present
+}
+
+// User location information. Its bindings are kept by the location service.
 #[derive(Debug, Clone)]
 pub struct LocationEntry {
     pub username: String,
-    pub ip_str: String, // Keep as String for consistency with C
-    pub port: u16,
-    pub registered: bool,
-    // Runtime address (updated on REGISTER)
-    pub current_addr: Option<SocketAddr>,
+    pub secret: UserSecret, // Digest authentication secret
+    pub display_name: Option<String>,
+    pub allowed_networks: Vec<SourceNetwork>, // REGISTER/INVITE sources; empty allows any
+    pub features: UserFeatures,
+}
+
+// How a user's digest secret is kept: the password itself, or only its HA1
+// (H(username:realm:password)) for one algorithm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserSecret {
+    Password(String),
+    Ha1(DigestAlgorithm, String), // Lowercase hex, over AUTH_REALM
+}
+
+// An address prefix such as 192.168.1.0/24
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceNetwork {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+// Services a user may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserFeatures {
+    pub forwarding: bool, // Own CALL_FORWARDING settings apply
+    pub messaging: bool,  // Receives MESSAGE requests
+    pub transfer: bool,   // May transfer calls with REFER, or take them over with Replaces
+    pub pickup: bool,     // May pick up calls ringing for other users
+}
+
+impl UserFeatures {
+    pub const ALL: UserFeatures = UserFeatures {
+        forwarding: true,
+        messaging: true,
+        transfer: true,
+        pickup: true,
+    };
 }
 
 // Media state for a leg
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct MediaState {
     pub local_media: bool,
     pub remote_media: bool,
+    pub negotiation: OfferAnswer, // SDP offer/answer state on this leg
+    pub direction: Direction,     // Media direction in the last SDP received on this leg
+    pub on_hold: bool,            // That SDP put the call on hold (sendonly/inactive/0.0.0.0)
+    pub telephone_event: Option<String>, // RFC 2833 payload type in that SDP, if any
+}
+
+// An in-dialog request received on one leg and relayed on the other; its
+// responses (and the ACK for a re-INVITE 2xx) are mapped back through it.
+#[derive(Debug, Clone)]
+pub struct RelayedRequest {
+    pub from_leg: i32,
+    pub received: SipRequest, // As received (responses to the sender are built from it)
+    pub source: SocketAddr,   // Where responses to the sender go
+    pub relayed: SipRequest,  // As sent on the other leg
+    pub answered: bool,       // 2xx to a re-INVITE relayed, waiting for the ACK
+}
+
+// A nonce we challenged with, until it is older than NONCE_LIFETIME
+#[derive(Debug, Clone)]
+pub struct IssuedNonce {
+    pub value: String,
+    pub issued: Instant,
+    pub last_nc: u32, // Highest nonce count used with it (replays are refused)
+}
+
+// A MESSAGE forwarded to every binding of its recipient, until one of them
+// accepts it or all have failed.
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    pub recipient: String,
+    pub received: Option<(SipRequest, SocketAddr)>, // The sender's request and address; None when delivering from the offline store
+    pub stored: Option<PathBuf>,                    // Offline store file being delivered
+    pub forwarded: Vec<SipRequest>, // Our MESSAGEs still unanswered, one per binding
+    pub failure: Option<SipResponse>, // Lowest failure response so far
+}
+
+// One branch of the INVITE to B: a parallel INVITE per binding of the
+// callee. While the call is unanswered, the B-leg fields of the Call hold
+// the branch that responded last; the others are parked here.
+#[derive(Debug, Clone)]
+pub struct Fork {
+    pub addr: SocketAddr,                    // Binding the INVITE went to
+    pub invite: SipRequest,                  // INVITE sent on this branch (CANCEL mirrors it)
+    pub dialog: Dialog,                      // Early dialog of the branch
+    pub b_leg_rseq: Option<u32>,             // Last RSeq accepted on the branch
+    pub final_response: Option<SipResponse>, // Failure that ended the branch
+}
+
+// How a ring group rings its members
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HuntStrategy {
+    Sequential, // One after the other, in the listed order
+    #[default]
+    Parallel, // All at once
+    RoundRobin, // One after the other, each call starting one member further
+}
+
+// A number that rings a group of users (hunt group)
+#[derive(Debug, Clone)]
+pub struct RingGroup {
+    pub number: String,
+    pub strategy: HuntStrategy,
+    pub members: Vec<String>,
+    pub member_timeout: Duration, // How long a member (all of them when parallel) rings
+    pub overflow: Option<String>, // Rung when no member answered
+    pub next_start: usize,        // Round-robin: member the next call starts with
+}
+
+// Who a call rings, step by step: the members of a ring group then its
+// overflow destination, or just the callee for a call to a user.
+#[derive(Debug, Clone, Default)]
+pub struct Hunt {
+    pub group: Option<String>, // Ring group number; None for a call to a user
+    pub strategy: HuntStrategy,
+    pub members: Vec<String>, // In ringing order (rotated for round-robin)
+    pub next_member: usize,   // First member not rung yet
+    pub first_fork: usize,    // First branch of the step ringing now
+    pub member_timeout: Option<Duration>,
+    pub overflow: Option<String>,  // Taken when rung
+    pub deadline: Option<Instant>, // When the step ringing now gives up
+}
+
+// Per-user call forwarding. A target is an extension, a ring group or a
+// SIP URI outside the server ("sip:+4930123@203.0.113.5").
+#[derive(Debug, Clone)]
+pub struct CallForwarding {
+    pub username: String,
+    pub unconditional: Option<String>, // Every call, without ringing the user
+    pub busy: Option<String>,          // The user's phones answer 486 or 600
+    pub no_answer: Option<String>,     // The user does not answer in time
+    pub no_answer_timeout: Duration,
+}
+
+// Why a call was forwarded (RFC 5806 Diversion reason)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiversionReason {
+    Unconditional,
+    UserBusy,
+    NoAnswer,
+}
+
+// A forward the call has taken: from `user`, for `reason`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diversion {
+    pub user: String,
+    pub reason: DiversionReason,
+}
+
+// A blind transfer in progress (RFC 3515). The leg that sent REFER is told
+// how the call to the Refer-To target goes, and is replaced by that call
+// once the transferee has taken the target's session description.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub transferor: i32, // Leg that sent the REFER (the other one is the transferee)
+    pub refer_cseq: u32, // CSeq of the REFER, the id of its implicit subscription
+    pub transferor_gone: bool, // The transferor hung up; no more NOTIFYs
+    pub uuid: String,    // Call-ID of the call to the target
+    pub dialog: Dialog,  // We are the UAC towards the target
+    pub addr: SocketAddr,
+    pub invite: SipRequest,          // INVITE sent to the target (no SDP)
+    pub answer: Option<SipResponse>, // Target's 2xx, held until the transferee answers our re-INVITE
 }
 
 // Call states enum
@@ -63,14 +295,32 @@ pub enum CallState {
     Disconnecting,
 }
 
-// Key SIP headers for a leg
+// RFC 3262 state of a call. Reliable provisional responses from B are relayed
+// reliably to A when A supports 100rel (A's PRACK is relayed to B), and are
+// PRACKed by the server itself otherwise.
 #[derive(Debug, Clone, Default)]
-pub struct SipHeaderInfo {
-    pub from: String,
-    pub via: String,
-    pub cseq: String,
-    pub to: String,
-    // Add others as needed, e.g., Contact
+pub struct ReliableProvisionals {
+    pub a_leg_supported: bool, // A's INVITE listed 100rel in Supported or Require
+    pub a_leg_required: bool,  // ... in Require: every provisional to A is reliable
+    pub a_leg_rseq: u32,       // Last RSeq used towards A
+    pub a_leg_unacked: Option<(u32, Option<u32>)>, // (our RSeq, B's RSeq) sent to A, not yet PRACKed
+    pub queued: Vec<SipResponse>,                  // Provisionals from B held until that PRACK
+    pub b_leg_rseq: Option<u32>,                   // Last RSeq accepted from B
+}
+
+// RFC 4028 state of a call. Refreshes are end-to-end re-INVITEs/UPDATEs
+// relayed by the server; when neither party refreshes, the server sends
+// UPDATEs on both legs itself. Without a refresh before `expires_at` the
+// call is torn down.
+#[derive(Debug, Clone, Default)]
+pub struct SessionTimer {
+    pub interval: Option<Duration>, // Session interval; None until negotiated with B
+    pub min_se: u32,                // Min-SE of the INVITE to B
+    pub a_leg_supported: bool,      // A's INVITE listed timer in Supported or Require
+    pub a_leg_refresher: Option<Refresher>, // Refresher A asked for in its Session-Expires
+    pub refresher: i32,             // A_LEG, B_LEG, or 0 when the server refreshes
+    pub expires_at: Option<Instant>, // BYE both legs if no refresh by then
+    pub refresh_at: Option<Instant>, // Next server refresh (server refresher only)
 }
 
 // Represents an ongoing call
@@ -84,11 +334,20 @@ pub struct Call {
     pub a_leg_addr: Option<SocketAddr>, // Store Option<SocketAddr> directly
     pub b_leg_addr: Option<SocketAddr>, // Store Option<SocketAddr> directly
     pub index: usize,                   // Index within the CallMap's Vec
-    pub a_leg_header: SipHeaderInfo,
-    pub b_leg_header: SipHeaderInfo,
-    pub callee: String,        // Max 32 in C
-    pub a_leg_contact: String, // Store full contact header or parsed URI
-    pub b_leg_contact: String, // Store full contact header or parsed URI
+    pub a_leg_dialog: Dialog,           // We are the UAS towards A
+    pub b_leg_dialog: Dialog,           // We are the UAC towards B
+    pub callee: String,                 // Max 32 in C
+    pub a_leg_invite: Option<SipRequest>, // INVITE received from A (responses to A are built from it)
+    pub b_leg_invite: Option<SipRequest>, // INVITE sent to B (CANCEL/ACK mirror it)
+    pub forks: Vec<Fork>,                 // Branches of the INVITE to B until one answers
+    pub active_fork: usize,               // Branch the B-leg fields currently hold
+    pub hunt: Hunt,                       // Ring group members and overflow still to ring
+    pub diversions: Vec<Diversion>,       // Forwards taken so far, oldest first
+    pub transfer: Option<Transfer>,       // Blind transfer waiting for the target to answer
+    pub relayed_requests: Vec<RelayedRequest>, // In-dialog requests relayed between the legs
+    pub dtmf_digits: Vec<DtmfDigit>,      // DTMF received as SIP INFO, in order (for CDRs)
+    pub prack: ReliableProvisionals,      // 100rel state of A's INVITE and B's provisionals
+    pub session_timer: SessionTimer,      // Session-Expires negotiation and refresh deadlines
     pub is_active: bool,
     // Mutex per call removed as requested; access controlled by CallMap's Mutex
 }
@@ -98,70 +357,37 @@ pub struct Call {
 pub struct CallMap {
     pub calls: Vec<Call>, // Use a Vec, manage is_active flag
     pub size: usize,      // Number of active calls
-                          // Mutex moved here, wraps the entire CallMap
+    // Mutex moved here, wraps the entire CallMap
+    pub transactions: TransactionTable, // SIP transactions of all calls and registrations
+    pub messages: Vec<PendingMessage>,  // Out-of-dialog MESSAGEs being forwarded
 }
 
 // --- Static Data ---
 use lazy_static::lazy_static;
 
-// NOTE: Define your user entries here, similar to the C code.
-// IPs/Ports in the static definition are defaults; `current_addr` is updated by REGISTER.
+// No users are built in; they all come from the user directory file (see
+// src/directory.rs). Their bindings are kept in LOCATION_SERVICE.
 lazy_static! {
-    pub static ref LOCATION_ENTRIES: Mutex<Vec<LocationEntry>> = Mutex::new(vec![
-        LocationEntry { username: "1001".to_string(), ip_str: "192.168.32.10".to_string(), port: 5060, registered: false, current_addr: None },
-        LocationEntry { username: "1002".to_string(), ip_str: "192.168.32.10".to_string(), port: 5070, registered: false, current_addr: None },
-        LocationEntry { username: "1003".to_string(), ip_str: "192.168.1.103".to_string(), port: 5060, registered: false, current_addr: None },
-        LocationEntry { username: "1004".to_string(), ip_str: "192.168.1.104".to_string(), port: 5060, registered: false, current_addr: None },
-        LocationEntry { username: "1005".to_string(), ip_str: "192.168.184.1".to_string(), port: 5060, registered: false, current_addr: None },
-        LocationEntry { username: "1006".to_string(), ip_str: "192.168.184.1".to_string(), port: 5070, registered: false, current_addr: None },
-        // Add more users as needed
-    ]);
-}
-
-// --- Global CSeq ---
-// Use atomic for thread-safe incrementing without a full mutex just for this counter.
-use std::sync::atomic::{AtomicUsize, Ordering};
-pub static CSEQ_NUMBER: AtomicUsize = AtomicUsize::new(1);
-
-// Helper to get next CSeq
-pub fn next_cseq() -> usize {
-    CSEQ_NUMBER.fetch_add(1, Ordering::SeqCst)
-}
-
-// Helper function to update location entry's address and registration status
-// Returns true if update was successful, false if user not found
-pub fn update_location_entry_addr(username: &str, addr: SocketAddr) -> bool {
-    let mut entries = match LOCATION_ENTRIES.lock() {
-        Ok(guard) => guard,
-        Err(poisoned) => {
-            eprintln!(
-                "LOCATION_ENTRIES mutex poisoned while updating; continuing with existing data."
-            );
-            poisoned.into_inner()
-        }
-    };
-    if let Some(entry) = entries.iter_mut().find(|entry| entry.username == username) {
-        entry.current_addr = Some(addr);
-        // Update ip_str and port as well, based on the received addr for consistency?
-        entry.ip_str = addr.ip().to_string();
-        entry.port = addr.port();
-        entry.registered = true;
-        println!(
-            "User {} registered successfully from {}",
-            entry.username, addr
-        );
-        println!(
-            "Location entry for user '{}' updated to IP: {}, Port: {}",
-            entry.username, entry.ip_str, entry.port
-        );
-        true
-    } else {
-        false
-    }
-}
-
-// Helper function to get a registered user's current address
-pub fn get_registered_addr(username: &str) -> Option<SocketAddr> {
+    pub static ref LOCATION_ENTRIES: Mutex<Vec<LocationEntry>> = Mutex::new(Vec::new());
+
+    // Ring groups, from the ring groups file (see src/ring_group.rs)
+    pub static ref RING_GROUPS: Mutex<Vec<RingGroup>> = Mutex::new(Vec::new());
+
+    // Call forwarding settings, from the forwarding file (see src/forwarding.rs)
+    pub static ref CALL_FORWARDING: Mutex<Vec<CallForwarding>> = Mutex::new(Vec::new());
+
+    // Where bindings are kept; main may swap in an SQLite one
+    pub static ref LOCATION_SERVICE: Mutex<Box<dyn LocationService>> = Mutex::new(Box::new(InMemoryLocationService::new()));
+
+    // Nonces of outstanding 401/407 challenges
+    pub static ref AUTH_NONCES: Mutex<Vec<IssuedNonce>> = Mutex::new(Vec::new());
+
+    // Directory of the offline MESSAGE store
+    pub static ref MESSAGE_STORE_DIR: Mutex<PathBuf> = Mutex::new(PathBuf::from(DEFAULT_MESSAGE_STORE_DIR));
+}
+
+// Helper function to check that a user exists, registered or not
+pub fn is_known_user(username: &str) -> bool {
     let entries = match LOCATION_ENTRIES.lock() {
         Ok(guard) => guard,
         Err(poisoned) => {
@@ -169,8 +395,18 @@ pub fn get_registered_addr(username: &str) -> Option<SocketAddr> {
             poisoned.into_inner()
         }
     };
-    entries
-        .iter()
-        .find(|entry| entry.username == username && entry.registered)
-        .and_then(|entry| entry.current_addr) // Return the Option<SocketAddr>
+    entries.iter().any(|entry| entry.username == username)
+}
+
+// Helper function to get a registered user's current bindings, most
+// preferred (highest q) first
+pub fn get_registered_bindings(username: &str) -> Vec<Binding> {
+    let mut bindings = location_service()
+        .lookup(username, Instant::now())
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to look up bindings of {}: {}", username, e);
+            Vec::new()
+        });
+    bindings.sort_by_key(|binding| std::cmp::Reverse(binding.q));
+    bindings
 }
@@ -0,0 +1,643 @@
+use crate::builder::RequestBuilder;
+use crate::message::*;
+use crate::sip_defs::*;
+use crate::uri::NameAddr;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// RFC 3261 §17 transaction layer (UDP only).
+//
+// The table does no I/O itself: the worker feeds it every message received and
+// sent, and it answers with what has to go on the wire. Retransmissions are
+// absorbed here, so the call state machine only sees each request and final
+// response once. Retransmission of 2xx responses to INVITE and the Accepted
+// state follow RFC 6026. Reliable provisional responses (RFC 3262) are
+// retransmitted by their INVITE server transaction until PRACKed.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    ClientInvite,
+    ClientNonInvite,
+    ServerInvite,
+    ServerNonInvite,
+}
+
+impl TransactionKind {
+    pub fn is_server(&self) -> bool {
+        matches!(
+            self,
+            TransactionKind::ServerInvite | TransactionKind::ServerNonInvite
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    Calling,
+    Trying,
+    Proceeding,
+    Accepted,
+    Completed,
+    Confirmed,
+}
+
+// Transactions are matched on the top Via branch, the sent-by of that Via
+// and the method, with ACK mapped to INVITE (RFC 3261 §17.1.3, §17.2.3).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TransactionKey {
+    pub branch: String,
+    pub sent_by: String,
+    pub method: String,
+    pub server: bool,
+}
+
+impl TransactionKey {
+    pub fn for_request(request: &SipRequest, server: bool) -> Option<Self> {
+        let via = request.top_via();
+        let method = if request.method == "ACK" {
+            "INVITE".to_string()
+        } else {
+            request.method.clone()
+        };
+        let branch = match via.and_then(via_branch) {
+            Some(branch) if branch.starts_with("z9hG4bK") => branch.to_string(),
+            // RFC 2543 peers without a usable branch: fall back to the
+            // request identity (Call-ID, CSeq number and From-tag).
+            _ => {
+                let (cseq, _) = request.cseq()?;
+                let from_tag = request
+                    .headers
+                    .get("From")
+                    .and_then(|from| NameAddr::parse(from).ok())
+                    .and_then(|from| from.tag().map(str::to_string))
+                    .unwrap_or_default();
+                format!("{};{};{}", request.call_id()?, cseq, from_tag)
+            }
+        };
+        Some(TransactionKey {
+            branch,
+            sent_by: via.map(via_sent_by).unwrap_or_default(),
+            method,
+            server,
+        })
+    }
+
+    pub fn for_response(response: &SipResponse, server: bool) -> Option<Self> {
+        let via = response.top_via()?;
+        let (_, method) = response.cseq()?;
+        Some(TransactionKey {
+            branch: via_branch(via)?.to_string(),
+            sent_by: via_sent_by(via),
+            method,
+            server,
+        })
+    }
+}
+
+// branch parameter of a Via value.
+pub fn via_branch(via: &str) -> Option<&str> {
+    via.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("branch")
+            .then(|| value.trim())
+    })
+}
+
+// sent-by (host[:port]) of a Via value, lowercased.
+pub fn via_sent_by(via: &str) -> String {
+    via.split(';')
+        .next()
+        .and_then(|protocol_and_host| protocol_and_host.split_whitespace().nth(1))
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+// A message the transaction layer wants sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outgoing {
+    pub payload: String,
+    pub destination: SocketAddr,
+}
+
+// Outcome of feeding a received message to the transaction layer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Received {
+    // Pass the message on to the call state machine.
+    pub deliver: bool,
+    // Messages to send on behalf of the transaction layer.
+    pub transmit: Vec<Outgoing>,
+}
+
+impl Received {
+    fn deliver() -> Self {
+        Received {
+            deliver: true,
+            transmit: Vec::new(),
+        }
+    }
+
+    fn absorb() -> Self {
+        Received::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimerEvent {
+    // Timers A, E and G, and 2xx retransmission: resend this message.
+    Retransmit(Outgoing),
+    // Timers B and F: a client transaction got no final response.
+    NoResponse(SipRequest),
+    // Timer L: a 2xx to this INVITE was never acknowledged.
+    NoAck(SipRequest),
+    // A reliable provisional response to this INVITE was never PRACKed
+    // (RFC 3262 §3).
+    NoPrack(SipRequest),
+}
+
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub kind: TransactionKind,
+    pub state: TransactionState,
+    pub request: SipRequest,
+    // Where requests (client) or responses (server) are sent.
+    pub destination: SocketAddr,
+    // Last response sent (server) or received (client). Servers repeat it
+    // when the request is retransmitted.
+    pub last_response: Option<SipResponse>,
+    // Client INVITE: the ACK sent for the final response.
+    pub ack: Option<SipRequest>,
+    // Server INVITE: RSeq of the reliable provisional awaiting its PRACK.
+    pub unacked_rseq: Option<u32>,
+    retransmit_at: Option<Instant>,
+    retransmit_interval: Duration,
+    // Timeout (B, F, H, L) or end of the wait state (D, I, J, K, M).
+    deadline: Option<Instant>,
+    // Server INVITE: when the reliable provisional is given up on.
+    prack_deadline: Option<Instant>,
+}
+
+impl Transaction {
+    fn new(
+        kind: TransactionKind,
+        state: TransactionState,
+        request: &SipRequest,
+        destination: SocketAddr,
+    ) -> Self {
+        Transaction {
+            kind,
+            state,
+            request: request.clone(),
+            destination,
+            last_response: None,
+            ack: None,
+            unacked_rseq: None,
+            retransmit_at: None,
+            retransmit_interval: TIMER_T1,
+            deadline: None,
+            prack_deadline: None,
+        }
+    }
+
+    fn start_retransmit(&mut self, now: Instant, interval: Duration) {
+        self.retransmit_interval = interval;
+        self.retransmit_at = Some(now + interval);
+    }
+
+    fn stop_retransmit(&mut self) {
+        self.retransmit_at = None;
+    }
+
+    fn enter(&mut self, state: TransactionState, deadline: Option<(Instant, Duration)>) {
+        self.state = state;
+        self.deadline = deadline.map(|(now, wait)| now + wait);
+    }
+
+    fn call_id(&self) -> &str {
+        self.request.call_id().unwrap_or_default()
+    }
+
+    fn cseq_number(&self) -> Option<u32> {
+        self.request.cseq().map(|(number, _)| number)
+    }
+
+    // Message resent when the retransmission timer fires.
+    fn retransmission(&self) -> Option<String> {
+        if self.kind.is_server() {
+            self.last_response.as_ref().map(|r| r.to_string())
+        } else {
+            Some(self.request.to_string())
+        }
+    }
+
+    // Next retransmission interval: Timer A and reliable provisionals
+    // (RFC 3262 §3) double without limit, the others double up to T2
+    // (RFC 3261 §17.1.1.2, §17.1.2.2, §17.2.1).
+    fn next_interval(&self) -> Duration {
+        let doubled = self.retransmit_interval * 2;
+        if self.kind == TransactionKind::ClientInvite || self.unacked_rseq.is_some() {
+            doubled
+        } else {
+            doubled.min(TIMER_T2)
+        }
+    }
+
+    fn outgoing(&self, payload: String) -> Outgoing {
+        Outgoing {
+            payload,
+            destination: self.destination,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TransactionTable {
+    transactions: HashMap<TransactionKey, Transaction>,
+}
+
+impl TransactionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn get(&self, key: &TransactionKey) -> Option<&Transaction> {
+        self.transactions.get(key)
+    }
+
+    // A request arrived from `source`.
+    pub fn receive_request(
+        &mut self,
+        request: &SipRequest,
+        source: SocketAddr,
+        now: Instant,
+    ) -> Received {
+        let key = match TransactionKey::for_request(request, true) {
+            Some(key) => key,
+            None => return Received::deliver(),
+        };
+
+        if request.method == "ACK" {
+            return self.receive_ack(&key, request, now);
+        }
+
+        if let Some(transaction) = self.transactions.get(&key) {
+            // Retransmission of a request we are already handling: repeat
+            // the last response sent on the transaction, provisional or
+            // final (RFC 3261 §17.2.1, §17.2.2)
+            let transmit = match (&transaction.last_response, transaction.state) {
+                (_, TransactionState::Confirmed) | (None, _) => Vec::new(),
+                (Some(response), _) => vec![transaction.outgoing(response.to_string())],
+            };
+            return Received {
+                deliver: false,
+                transmit,
+            };
+        }
+
+        // A non-INVITE request that is never answered does not keep its
+        // transaction forever: it ends when its client gives up (64*T1). An
+        // INVITE one stays in Proceeding until the final response.
+        let transaction = if request.method == "INVITE" {
+            Transaction::new(
+                TransactionKind::ServerInvite,
+                TransactionState::Proceeding,
+                request,
+                source,
+            )
+        } else {
+            let mut non_invite = Transaction::new(
+                TransactionKind::ServerNonInvite,
+                TransactionState::Trying,
+                request,
+                source,
+            );
+            non_invite.deadline = Some(now + TIMER_T1 * 64);
+            non_invite
+        };
+        self.transactions.insert(key, transaction);
+        Received::deliver()
+    }
+
+    // A PRACK arrived: stops retransmission of the reliable provisional it
+    // acknowledges. Returns false if its RAck matches no unacknowledged
+    // provisional, which the UAS answers with 481 (RFC 3262 §3).
+    pub fn receive_prack(&mut self, prack: &SipRequest) -> bool {
+        let (rseq, cseq, method) = match prack.rack() {
+            Some(rack) => rack,
+            None => return false,
+        };
+        let call_id = prack.call_id().unwrap_or_default();
+        match self.transactions.values_mut().find(|t| {
+            t.kind == TransactionKind::ServerInvite
+                && method == "INVITE"
+                && t.call_id() == call_id
+                && t.cseq_number() == Some(cseq)
+                && t.unacked_rseq == Some(rseq)
+        }) {
+            Some(invite) => {
+                invite.unacked_rseq = None;
+                invite.stop_retransmit();
+                invite.prack_deadline = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn receive_ack(&mut self, key: &TransactionKey, ack: &SipRequest, now: Instant) -> Received {
+        // ACK for a non-2xx final response shares the INVITE's branch.
+        if let Some(transaction) = self.transactions.get_mut(key) {
+            match transaction.state {
+                TransactionState::Completed => {
+                    transaction.stop_retransmit();
+                    transaction.enter(TransactionState::Confirmed, Some((now, TIMER_T4)));
+                    return Received::absorb();
+                }
+                TransactionState::Confirmed => return Received::absorb(),
+                _ => {}
+            }
+        }
+
+        // ACK for a 2xx is a new transaction of its own; it stops our 2xx
+        // retransmissions and goes to the call.
+        let call_id = ack.call_id().unwrap_or_default();
+        let cseq = ack.cseq().map(|(number, _)| number);
+        let invite = self.transactions.values_mut().find(|t| {
+            t.kind == TransactionKind::ServerInvite
+                && matches!(
+                    t.state,
+                    TransactionState::Accepted | TransactionState::Confirmed
+                )
+                && t.call_id() == call_id
+                && t.cseq_number() == cseq
+        });
+        match invite {
+            Some(t) if t.state == TransactionState::Accepted => {
+                t.stop_retransmit();
+                t.enter(TransactionState::Confirmed, Some((now, TIMER_T4)));
+                Received::deliver()
+            }
+            // Duplicate ACK for a 2xx we already saw acknowledged
+            Some(_) => Received::absorb(),
+            None => Received::deliver(),
+        }
+    }
+
+    // A response arrived. Responses that match no client transaction are
+    // passed on to the call (RFC 3261 §18.1.2).
+    pub fn receive_response(&mut self, response: &SipResponse, now: Instant) -> Received {
+        let transaction = match TransactionKey::for_response(response, false)
+            .and_then(|key| self.transactions.get_mut(&key))
+        {
+            Some(transaction) => transaction,
+            None => return Received::deliver(),
+        };
+        let code = response.status_code;
+
+        match (transaction.kind, transaction.state) {
+            (
+                TransactionKind::ClientInvite,
+                TransactionState::Calling | TransactionState::Proceeding,
+            ) => {
+                transaction.last_response = Some(response.clone());
+                match code {
+                    100..=199 => {
+                        transaction.stop_retransmit();
+                        transaction.enter(TransactionState::Proceeding, None);
+                        Received::deliver()
+                    }
+                    200..=299 => {
+                        transaction.stop_retransmit();
+                        transaction.enter(TransactionState::Accepted, Some((now, TIMER_T1 * 64)));
+                        Received::deliver()
+                    }
+                    _ => {
+                        // The transaction acknowledges non-2xx finals itself
+                        let ack = RequestBuilder::ack_for(&transaction.request, response);
+                        let outgoing = transaction.outgoing(ack.to_string());
+                        transaction.ack = Some(ack);
+                        transaction.stop_retransmit();
+                        transaction.enter(TransactionState::Completed, Some((now, TIMER_D)));
+                        Received {
+                            deliver: true,
+                            transmit: vec![outgoing],
+                        }
+                    }
+                }
+            }
+            (TransactionKind::ClientInvite, TransactionState::Accepted) => {
+                if (200..300).contains(&code) {
+                    // 2xx retransmission: repeat our ACK if we already sent it
+                    let transmit = transaction
+                        .ack
+                        .as_ref()
+                        .map(|ack| transaction.outgoing(ack.to_string()))
+                        .into_iter()
+                        .collect();
+                    Received {
+                        deliver: transaction.ack.is_none(),
+                        transmit,
+                    }
+                } else {
+                    Received::absorb()
+                }
+            }
+            (TransactionKind::ClientInvite, TransactionState::Completed) => {
+                // Final response retransmitted: our ACK was lost
+                let transmit = match (&transaction.ack, code >= 300) {
+                    (Some(ack), true) => vec![transaction.outgoing(ack.to_string())],
+                    _ => Vec::new(),
+                };
+                Received {
+                    deliver: false,
+                    transmit,
+                }
+            }
+            (
+                TransactionKind::ClientNonInvite,
+                TransactionState::Trying | TransactionState::Proceeding,
+            ) => {
+                transaction.last_response = Some(response.clone());
+                if code < 200 {
+                    if transaction.state == TransactionState::Trying {
+                        transaction.start_retransmit(now, TIMER_T2);
+                        transaction.state = TransactionState::Proceeding;
+                    }
+                } else {
+                    transaction.stop_retransmit();
+                    transaction.enter(TransactionState::Completed, Some((now, TIMER_T4)));
+                }
+                Received::deliver()
+            }
+            _ => Received::absorb(),
+        }
+    }
+
+    // We are sending `request` to `destination`: start a client transaction.
+    // ACKs for 2xx responses get none; they are remembered so a
+    // retransmitted 2xx can be acknowledged again.
+    pub fn send_request(&mut self, request: &SipRequest, destination: SocketAddr, now: Instant) {
+        if request.method == "ACK" {
+            let call_id = request.call_id().unwrap_or_default();
+            let cseq = request.cseq().map(|(number, _)| number);
+            if let Some(invite) = self.transactions.values_mut().find(|t| {
+                t.kind == TransactionKind::ClientInvite
+                    && t.state == TransactionState::Accepted
+                    && t.call_id() == call_id
+                    && t.cseq_number() == cseq
+            }) {
+                invite.ack = Some(request.clone());
+            }
+            return;
+        }
+        let key = match TransactionKey::for_request(request, false) {
+            Some(key) => key,
+            None => return,
+        };
+        let mut transaction = if request.method == "INVITE" {
+            Transaction::new(
+                TransactionKind::ClientInvite,
+                TransactionState::Calling,
+                request,
+                destination,
+            )
+        } else {
+            Transaction::new(
+                TransactionKind::ClientNonInvite,
+                TransactionState::Trying,
+                request,
+                destination,
+            )
+        };
+        // Timer A / Timer E, and Timer B / Timer F
+        transaction.start_retransmit(now, TIMER_T1);
+        transaction.deadline = Some(now + TIMER_T1 * 64);
+        self.transactions.insert(key, transaction);
+    }
+
+    // We are sending `response`: update the server transaction it belongs to.
+    pub fn send_response(&mut self, response: &SipResponse, destination: SocketAddr, now: Instant) {
+        let key = TransactionKey::for_response(response, true)
+            .filter(|key| self.transactions.contains_key(key))
+            .or_else(|| {
+                // Requests keyed on the RFC 2543 fallback have no usable branch
+                let call_id = response.call_id().unwrap_or_default();
+                let cseq = response.cseq();
+                self.transactions
+                    .iter()
+                    .find(|(_, t)| {
+                        t.kind.is_server() && t.call_id() == call_id && t.request.cseq() == cseq
+                    })
+                    .map(|(key, _)| key.clone())
+            });
+        let transaction = match key.and_then(|key| self.transactions.get_mut(&key)) {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        if matches!(
+            transaction.state,
+            TransactionState::Completed | TransactionState::Confirmed
+        ) {
+            return;
+        }
+        transaction.destination = destination;
+        transaction.last_response = Some(response.clone());
+        let code = response.status_code;
+
+        match transaction.kind {
+            TransactionKind::ServerInvite => match code {
+                100..=199 => {
+                    transaction.state = TransactionState::Proceeding;
+                    if response.has_option_tag("Require", "100rel") {
+                        // Reliable provisional: retransmit until PRACKed,
+                        // give up after 64*T1 (RFC 3262 §3)
+                        transaction.unacked_rseq = response.rseq();
+                        transaction.start_retransmit(now, TIMER_T1);
+                        transaction.prack_deadline = Some(now + TIMER_T1 * 64);
+                    }
+                }
+                200..=299 => {
+                    transaction.unacked_rseq = None;
+                    transaction.prack_deadline = None;
+                    // Retransmit the 2xx until the ACK arrives (RFC 3261 §13.3.1.4)
+                    transaction.start_retransmit(now, TIMER_T1);
+                    transaction.enter(TransactionState::Accepted, Some((now, TIMER_T1 * 64)));
+                }
+                _ => {
+                    // Timer G and Timer H
+                    transaction.unacked_rseq = None;
+                    transaction.prack_deadline = None;
+                    transaction.start_retransmit(now, TIMER_T1);
+                    transaction.enter(TransactionState::Completed, Some((now, TIMER_T1 * 64)));
+                }
+            },
+            TransactionKind::ServerNonInvite => {
+                if code < 200 {
+                    // The 64*T1 set when the request arrived still applies
+                    transaction.state = TransactionState::Proceeding;
+                } else {
+                    // Timer J
+                    transaction.enter(TransactionState::Completed, Some((now, TIMER_T1 * 64)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Runs due timers and drops terminated transactions.
+    pub fn poll_timers(&mut self, now: Instant) -> Vec<TimerEvent> {
+        let mut events = Vec::new();
+        let mut terminated = Vec::new();
+
+        for (key, transaction) in self.transactions.iter_mut() {
+            if transaction
+                .prack_deadline
+                .is_some_and(|deadline| now >= deadline)
+            {
+                // The INVITE itself stays open for the final response
+                transaction.unacked_rseq = None;
+                transaction.stop_retransmit();
+                transaction.prack_deadline = None;
+                events.push(TimerEvent::NoPrack(transaction.request.clone()));
+                continue;
+            }
+            if transaction.deadline.is_some_and(|deadline| now >= deadline) {
+                match (transaction.kind, transaction.state) {
+                    (TransactionKind::ClientInvite, TransactionState::Calling)
+                    | (
+                        TransactionKind::ClientNonInvite,
+                        TransactionState::Trying | TransactionState::Proceeding,
+                    ) => events.push(TimerEvent::NoResponse(transaction.request.clone())),
+                    (TransactionKind::ServerInvite, TransactionState::Accepted) => {
+                        events.push(TimerEvent::NoAck(transaction.request.clone()))
+                    }
+                    _ => {}
+                }
+                terminated.push(key.clone());
+                continue;
+            }
+
+            if transaction.retransmit_at.is_some_and(|at| now >= at) {
+                if let Some(payload) = transaction.retransmission() {
+                    events.push(TimerEvent::Retransmit(transaction.outgoing(payload)));
+                }
+                let interval = transaction.next_interval();
+                transaction.start_retransmit(now, interval);
+            }
+        }
+
+        for key in terminated {
+            self.transactions.remove(&key);
+        }
+        events
+    }
+}
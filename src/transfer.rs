@@ -0,0 +1,71 @@
+use crate::dialog::Dialog;
+use crate::message::SipRequest;
+use crate::uri::{NameAddr, SipUri};
+
+// Call transfer (RFC 3515 REFER). The server carries out a REFER itself: it
+// calls the Refer-To target, reports progress to the transferor in NOTIFYs
+// carrying message/sipfrag (RFC 3420), and bridges the transferee to the
+// target. An INVITE with Replaces (RFC 3891) takes the place of a leg of an
+// existing call instead: attended transfer, and pickup of a ringing phone.
+
+// URI of the Refer-To header of a REFER, embedded headers included.
+pub fn refer_target(refer: &SipRequest) -> Option<SipUri> {
+    let refer_to = refer.headers.get("Refer-To")?;
+    NameAddr::parse(refer_to).ok().map(|addr| addr.uri)
+}
+
+// message/sipfrag body reporting the status line of a response.
+pub fn sipfrag(code: u16, reason: &str) -> String {
+    format!("SIP/2.0 {} {}\r\n", code, reason)
+}
+
+// The Replaces header of an INVITE (RFC 3891): the dialog the new one takes
+// the place of. The tags are as the recipient sees them: `to_tag` is its
+// local tag, `from_tag` the remote one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replaces {
+    pub call_id: String,
+    pub to_tag: String,
+    pub from_tag: String,
+    pub early_only: bool, // Only an early dialog may be replaced
+}
+
+impl Replaces {
+    // Parses "call-id;to-tag=a;from-tag=b[;early-only]".
+    pub fn parse(value: &str) -> Option<Replaces> {
+        let mut parts = value.split(';').map(str::trim);
+        let call_id = parts.next().filter(|id| !id.is_empty())?.to_string();
+        let (mut to_tag, mut from_tag, mut early_only) = (None, None, false);
+        for part in parts {
+            match part.split_once('=') {
+                Some((name, tag)) if name.trim().eq_ignore_ascii_case("to-tag") => {
+                    to_tag = Some(tag.trim().to_string())
+                }
+                Some((name, tag)) if name.trim().eq_ignore_ascii_case("from-tag") => {
+                    from_tag = Some(tag.trim().to_string())
+                }
+                None if part.eq_ignore_ascii_case("early-only") => early_only = true,
+                _ => {}
+            }
+        }
+        Some(Replaces {
+            call_id,
+            to_tag: to_tag?,
+            from_tag: from_tag?,
+            early_only,
+        })
+    }
+
+    // True if this names `dialog`. An early dialog whose remote tag is not
+    // known yet matches an empty from-tag.
+    pub fn matches(&self, dialog: &Dialog) -> bool {
+        dialog.call_id == self.call_id
+            && dialog.local_tag == self.to_tag
+            && dialog.remote_tag.as_deref().unwrap_or("") == self.from_tag
+    }
+}
+
+// Replaces header of a request, if it has a well-formed one.
+pub fn replaces(request: &SipRequest) -> Option<Replaces> {
+    Replaces::parse(request.headers.get("Replaces")?)
+}
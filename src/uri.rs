@@ -0,0 +1,520 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+// Structured SIP/SIPS/TEL URIs (RFC 3261 §19.1, RFC 3966) and the name-addr /
+// addr-spec form used by From, To, Contact, Route and Record-Route (§20.10).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UriScheme {
+    Sip,
+    Sips,
+    Tel,
+}
+
+impl UriScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UriScheme::Sip => "sip",
+            UriScheme::Sips => "sips",
+            UriScheme::Tel => "tel",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriError {
+    MissingScheme(String),
+    UnsupportedScheme(String),
+    MissingHost(String),
+    InvalidPort(String),
+    Unterminated(String),
+}
+
+impl fmt::Display for UriError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UriError::MissingScheme(s) => write!(f, "URI has no scheme: {}", s),
+            UriError::UnsupportedScheme(s) => write!(f, "unsupported URI scheme: {}", s),
+            UriError::MissingHost(s) => write!(f, "URI has no host: {}", s),
+            UriError::InvalidPort(s) => write!(f, "invalid port in URI: {}", s),
+            UriError::Unterminated(s) => write!(f, "missing '>' in name-addr: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for UriError {}
+
+// A generic ";name[=value]" parameter list, order preserved.
+pub type Params = Vec<(String, Option<String>)>;
+
+fn find_param<'a>(params: &'a Params, name: &str) -> Option<&'a (String, Option<String>)> {
+    params.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))
+}
+
+fn set_param_in(params: &mut Params, name: &str, value: Option<&str>) {
+    let value = value.map(|v| v.to_string());
+    match params
+        .iter_mut()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+    {
+        Some(entry) => entry.1 = value,
+        None => params.push((name.to_string(), value)),
+    }
+}
+
+fn remove_param_in(params: &mut Params, name: &str) {
+    params.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+}
+
+// Parses ";a=1;b;c=2" (leading ';' optional) into a parameter list.
+pub fn parse_params(input: &str) -> Params {
+    input
+        .split(';')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.split_once('=') {
+            Some((name, value)) => (name.trim().to_string(), Some(value.trim().to_string())),
+            None => (p.to_string(), None),
+        })
+        .collect()
+}
+
+fn write_params(f: &mut fmt::Formatter<'_>, params: &Params) -> fmt::Result {
+    for (name, value) in params {
+        match value {
+            Some(value) => write!(f, ";{}={}", name, value)?,
+            None => write!(f, ";{}", name)?,
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SipUri {
+    pub scheme: UriScheme,
+    // User part with %-escapes decoded (the subscriber number for tel: URIs).
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // Empty for tel: URIs.
+    pub host: String,
+    pub port: Option<u16>,
+    pub params: Params,
+    // "?name=value&..." headers embedded in the URI (e.g. Replaces in Refer-To).
+    pub headers: Vec<(String, String)>,
+}
+
+impl SipUri {
+    // Convenience constructor for "sip:user@host[:port]".
+    pub fn new(user: Option<&str>, host: &str, port: Option<u16>) -> Self {
+        SipUri {
+            scheme: UriScheme::Sip,
+            user: user.map(|u| u.to_string()),
+            password: None,
+            host: host.to_string(),
+            port,
+            params: Vec::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<SipUri, UriError> {
+        let input = input.trim();
+        let (scheme, rest) = input
+            .split_once(':')
+            .ok_or_else(|| UriError::MissingScheme(input.to_string()))?;
+        let scheme = match scheme.to_ascii_lowercase().as_str() {
+            "sip" => UriScheme::Sip,
+            "sips" => UriScheme::Sips,
+            "tel" => UriScheme::Tel,
+            _ => return Err(UriError::UnsupportedScheme(scheme.to_string())),
+        };
+
+        if scheme == UriScheme::Tel {
+            let (rest, headers) = split_uri_headers(rest);
+            let (number, params) = match rest.split_once(';') {
+                Some((number, params)) => (number, parse_params(params)),
+                None => (rest, Vec::new()),
+            };
+            if number.is_empty() {
+                return Err(UriError::MissingHost(input.to_string()));
+            }
+            return Ok(SipUri {
+                scheme,
+                user: Some(percent_decode(number)),
+                password: None,
+                host: String::new(),
+                port: None,
+                params,
+                headers,
+            });
+        }
+
+        // userinfo is everything up to the first '@' ('@' must be escaped
+        // inside the user part, but ';' and '?' may be).
+        let (userinfo, hostpart) = match rest.find('@') {
+            Some(pos) => (Some(&rest[..pos]), &rest[pos + 1..]),
+            None => (None, rest),
+        };
+        let (user, password) = match userinfo {
+            Some(info) => match info.split_once(':') {
+                Some((user, password)) => {
+                    (Some(percent_decode(user)), Some(percent_decode(password)))
+                }
+                None => (Some(percent_decode(info)), None),
+            },
+            None => (None, None),
+        };
+
+        // "?headers" follow the host and its parameters.
+        let (hostpart, headers) = split_uri_headers(hostpart);
+        let (hostport, params) = match hostpart.split_once(';') {
+            Some((hostport, params)) => (hostport, parse_params(params)),
+            None => (hostpart, Vec::new()),
+        };
+        let (host, port) =
+            split_host_port(hostport).map_err(|_| UriError::InvalidPort(input.to_string()))?;
+        if host.is_empty() {
+            return Err(UriError::MissingHost(input.to_string()));
+        }
+
+        Ok(SipUri {
+            scheme,
+            user: user.filter(|u| !u.is_empty()),
+            password,
+            host: host.to_string(),
+            port,
+            params,
+            headers,
+        })
+    }
+
+    // Value of a URI parameter; Some("") for flag parameters such as ";lr".
+    pub fn param(&self, name: &str) -> Option<&str> {
+        find_param(&self.params, name).map(|(_, v)| v.as_deref().unwrap_or(""))
+    }
+
+    pub fn has_param(&self, name: &str) -> bool {
+        find_param(&self.params, name).is_some()
+    }
+
+    pub fn set_param(&mut self, name: &str, value: Option<&str>) {
+        set_param_in(&mut self.params, name, value);
+    }
+
+    pub fn remove_param(&mut self, name: &str) {
+        remove_param_in(&mut self.params, name);
+    }
+
+    // Value of an embedded URI header (e.g. "Replaces"), %-decoded.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // "host[:port]" as written in the URI.
+    pub fn host_port(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}:{}", format_host(&self.host), port),
+            None => format_host(&self.host),
+        }
+    }
+
+    // Transport address for URIs whose host is an IP literal. The port falls
+    // back to the scheme default (5060 / 5061).
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let ip = host.parse::<IpAddr>().ok()?;
+        let default_port = if self.scheme == UriScheme::Sips {
+            5061
+        } else {
+            5060
+        };
+        Some(SocketAddr::new(ip, self.port.unwrap_or(default_port)))
+    }
+
+    // Address-of-record form "scheme:user@host" without port or parameters,
+    // used as a registrar/location key.
+    pub fn aor(&self) -> String {
+        match &self.user {
+            Some(user) if self.scheme == UriScheme::Tel => format!("tel:{}", user),
+            Some(user) => format!(
+                "{}:{}@{}",
+                self.scheme.as_str(),
+                percent_encode_user(user),
+                self.host.to_ascii_lowercase()
+            ),
+            None => format!(
+                "{}:{}",
+                self.scheme.as_str(),
+                self.host.to_ascii_lowercase()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for SipUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.scheme.as_str())?;
+        if self.scheme == UriScheme::Tel {
+            write!(f, "{}", self.user.as_deref().unwrap_or(""))?;
+        } else {
+            if let Some(user) = &self.user {
+                write!(f, "{}", percent_encode_user(user))?;
+                if let Some(password) = &self.password {
+                    write!(f, ":{}", percent_encode_user(password))?;
+                }
+                write!(f, "@")?;
+            }
+            write!(f, "{}", self.host_port())?;
+        }
+        write_params(f, &self.params)?;
+        for (i, (name, value)) in self.headers.iter().enumerate() {
+            let sep = if i == 0 { '?' } else { '&' };
+            write!(f, "{}{}={}", sep, name, percent_encode_header(value))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for SipUri {
+    type Err = UriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SipUri::parse(s)
+    }
+}
+
+fn format_host(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host) // IPv6 reference
+    } else {
+        host.to_string()
+    }
+}
+
+fn split_host_port(hostport: &str) -> Result<(&str, Option<u16>), ()> {
+    if let Some(rest) = hostport.strip_prefix('[') {
+        // IPv6 reference: [addr]:port
+        let end = rest.find(']').ok_or(())?;
+        let host = &rest[..end];
+        let after = &rest[end + 1..];
+        return match after.strip_prefix(':') {
+            Some(port) => port.parse().map(|p| (host, Some(p))).map_err(|_| ()),
+            None if after.is_empty() => Ok((host, None)),
+            None => Err(()),
+        };
+    }
+    match hostport.rsplit_once(':') {
+        Some((host, port)) => port.parse().map(|p| (host, Some(p))).map_err(|_| ()),
+        None => Ok((hostport, None)),
+    }
+}
+
+// Splits "?headers" off the end of a URI.
+fn split_uri_headers(input: &str) -> (&str, Vec<(String, String)>) {
+    match input.split_once('?') {
+        Some((before, headers)) => (before, parse_uri_headers(headers)),
+        None => (input, Vec::new()),
+    }
+}
+
+fn parse_uri_headers(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|h| !h.is_empty())
+        .map(|h| match h.split_once('=') {
+            Some((name, value)) => (percent_decode(name), percent_decode(value)),
+            None => (percent_decode(h), String::new()),
+        })
+        .collect()
+}
+
+// Decodes %HH escapes; malformed escapes are kept literally.
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // from_str_radix alone would take a sign, as in "%+1"
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(value) = u8::from_str_radix(hex, 16) {
+                out.push(value);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn percent_encode_with(input: &str, allowed: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        let c = byte as char;
+        if c.is_ascii_alphanumeric() || allowed.contains(c) {
+            out.push(c);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+// user = 1*( unreserved / escaped / user-unreserved )
+fn percent_encode_user(input: &str) -> String {
+    percent_encode_with(input, "-_.!~*'()&=+$,;?/")
+}
+
+fn percent_encode_header(input: &str) -> String {
+    percent_encode_with(input, "-_.!~*'()[]/?:+$")
+}
+
+// name-addr / addr-spec with header parameters (tag, expires, q, ...).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameAddr {
+    pub display_name: Option<String>,
+    pub uri: SipUri,
+    pub params: Params,
+}
+
+impl NameAddr {
+    pub fn new(uri: SipUri) -> Self {
+        NameAddr {
+            display_name: None,
+            uri,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<NameAddr, UriError> {
+        let input = input.trim();
+        match find_unquoted(input, '<') {
+            Some(open) => {
+                let close = input[open..]
+                    .find('>')
+                    .map(|i| open + i)
+                    .ok_or_else(|| UriError::Unterminated(input.to_string()))?;
+                let display = input[..open].trim();
+                let display_name = if display.is_empty() {
+                    None
+                } else {
+                    Some(unquote(display))
+                };
+                let uri = SipUri::parse(&input[open + 1..close])?;
+                let params = parse_params(&input[close + 1..]);
+                Ok(NameAddr {
+                    display_name,
+                    uri,
+                    params,
+                })
+            }
+            None => {
+                // addr-spec: parameters after the URI belong to the header (§20.10).
+                let (uri, params) = match input.split_once(';') {
+                    Some((uri, params)) => (uri, parse_params(params)),
+                    None => (input, Vec::new()),
+                };
+                Ok(NameAddr {
+                    display_name: None,
+                    uri: SipUri::parse(uri)?,
+                    params,
+                })
+            }
+        }
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.param("tag").filter(|t| !t.is_empty())
+    }
+
+    pub fn set_tag(&mut self, tag: &str) {
+        self.set_param("tag", Some(tag));
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        find_param(&self.params, name).map(|(_, v)| v.as_deref().unwrap_or(""))
+    }
+
+    pub fn set_param(&mut self, name: &str, value: Option<&str>) {
+        set_param_in(&mut self.params, name, value);
+    }
+
+    pub fn remove_param(&mut self, name: &str) {
+        remove_param_in(&mut self.params, name);
+    }
+
+    // User part of the URI (decoded), e.g. the extension number.
+    pub fn user(&self) -> Option<&str> {
+        self.uri.user.as_deref()
+    }
+}
+
+impl fmt::Display for NameAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.display_name {
+            write!(
+                f,
+                "\"{}\" ",
+                name.replace('\\', "\\\\").replace('"', "\\\"")
+            )?;
+        }
+        write!(f, "<{}>", self.uri)?;
+        write_params(f, &self.params)
+    }
+}
+
+impl std::str::FromStr for NameAddr {
+    type Err = UriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NameAddr::parse(s)
+    }
+}
+
+fn find_unquoted(input: &str, target: char) -> Option<usize> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            c if c == target && !in_quotes => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(display: &str) -> String {
+    let display = display.trim();
+    match display.strip_prefix('"').and_then(|d| d.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    if let Some(next) = chars.next() {
+                        out.push(next);
+                    }
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+        None => display.to_string(),
+    }
+}
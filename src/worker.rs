@@ -1,9 +1,28 @@
+use crate::auth::{authenticate, challenge, user_algorithms, AuthError};
+use crate::builder::*;
+use crate::dialog::{Dialog, DialogState};
+use crate::directory::{source_allowed, user_features};
+use crate::dtmf::{parse_dtmf_info, telephone_event_payload, DtmfDigit};
+use crate::forwarding::{diversion_headers, forwarding_of, outside_binding};
+use crate::message::*;
+use crate::message_store::{remove_stored_message, store_message, stored_messages, StoreError};
 use crate::network_utils::send_sip_message;
 use crate::parsing::*; // Import parsing helpers
+use crate::registrar::{binding_contact, register, RegisterError};
+use crate::ring_group::start_hunt;
+use crate::routing::{preprocess_routes, server_route};
+use crate::sdp::{NegotiationState, SessionDescription};
+use crate::session_timer::{
+    expiry_margin, min_se, session_expires, supports_timer, Refresher, SessionExpires,
+};
 use crate::sip_defs::*;
+use crate::transaction::{via_branch, Outgoing, TimerEvent, TransactionTable};
+use crate::transfer::{refer_target, replaces, sipfrag, Replaces};
+use crate::uri::{NameAddr, SipUri};
 use std::net::{SocketAddr, UdpSocket};
-use std::sync::{mpsc::Receiver, Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
 
 // Main function for worker threads
 pub fn process_sip_messages(
@@ -13,133 +32,13 @@ pub fn process_sip_messages(
 ) {
     println!("Worker thread started.");
     loop {
-        match receiver.recv() {
-            // Blocks until a message is received
+        // Wake up at least every timer tick to drive transaction timers
+        match receiver.recv_timeout(TIMER_TICK) {
             Ok(message) => {
-                let source_addr = message.client_addr;
-                let message_str = String::from_utf8_lossy(&message.buffer); // For parsing
-
-                println!("\n================ RX from {} ================\n{}\n================================================",
-                    source_addr, message_str);
-
-                // Basic Parsing
-                let first_line = message_str.lines().next().unwrap_or("");
-                let call_id = get_call_id(&message_str).unwrap_or_default();
-                let has_sdp = get_sdp_body(&message_str).is_some();
-
-                // Determine message type (Request/Response) and method/code
-                if let Some((msg_type, method_or_code)) = parse_first_line(first_line) {
-                    // Handle REGISTER separately (doesn't use CallMap in the same way)
-                    if msg_type == REQUEST_METHOD && method_or_code == "REGISTER" {
-                        handle_register(&message, &socket, &message_str);
-                    } else {
-                        // Lock CallMap for find/allocate/update operations
-                        let mut map_guard = match call_map.lock() {
-                            Ok(guard) => guard,
-                            Err(poisoned) => {
-                                eprintln!("CallMap mutex poisoned: {}", poisoned);
-                                poisoned.into_inner()
-                            }
-                        };
-
-                        // Find existing call or allocate new one for INVITE
-                        let (call_index_opt, leg_type) =
-                            CallMap::find_call_by_callid(&map_guard, &call_id);
-
-                        if let Some(call_index) = call_index_opt {
-                            // Existing call found
-                            let should_release = {
-                                let call = &mut map_guard.calls[call_index];
-                                handle_state_machine(
-                                    call,
-                                    msg_type,
-                                    &method_or_code,
-                                    has_sdp,
-                                    &message, // Pass original SipMessage with SocketAddr
-                                    &message_str,
-                                    leg_type,
-                                    &socket,
-                                );
-                                !call.is_active
-                            };
-                            if should_release {
-                                map_guard.release_call(call_index);
-                            }
-                        } else if msg_type == REQUEST_METHOD && method_or_code == "INVITE" {
-                            // No existing call, but it's an INVITE - try to allocate
-                            println!(
-                                "  Call-ID [{}] not found, processing INVITE to allocate.",
-                                call_id
-                            );
-                            // Allocate returns index now
-                            if let Some(new_call_index) =
-                                CallMap::allocate_new_call_mut(&mut map_guard)
-                            {
-                                println!("  Allocated new call at index {}", new_call_index);
-                                let should_release = {
-                                    let new_call = &mut map_guard.calls[new_call_index];
-                                    handle_state_machine(
-                                        new_call, // Pass the mutable ref to the new call
-                                        msg_type,
-                                        &method_or_code,
-                                        has_sdp,
-                                        &message,
-                                        &message_str,
-                                        A_LEG, // Initial INVITE is always A_LEG perspective
-                                        &socket,
-                                    );
-                                    !new_call.is_active
-                                };
-                                if should_release {
-                                    map_guard.release_call(new_call_index);
-                                }
-                            } else {
-                                eprintln!(
-                                    "Error: CallMap full, cannot allocate for INVITE Call-ID [{}]",
-                                    call_id
-                                );
-                                // Send 503 Service Unavailable?
-                                // Need headers from the INVITE to respond properly
-                                if let (Some(via), Some(from), Some(to), Some(cseq)) = (
-                                    get_via_header(&message_str),
-                                    get_from_header(&message_str),
-                                    get_to_header(&message_str),
-                                    get_cseq_header(&message_str),
-                                ) {
-                                    // Corrected format! usage
-                                    let response_503 = format!(
-                                        "SIP/2.0 503 Service Unavailable\r\n\
-                                        {}\r\n\
-                                        {}\r\n\
-                                        {}\r\n\
-                                        Call-ID: {}\r\n\
-                                        {}\r\n\
-                                        User-Agent: TinySIP-Rust\r\n\
-                                        Content-Length: 0\r\n\r\n",
-                                        via,
-                                        from,
-                                        to,
-                                        call_id, // Use the extracted call_id here
-                                        cseq
-                                    );
-                                    send_sip_message(
-                                        &socket,
-                                        response_503.as_bytes(),
-                                        &source_addr,
-                                    );
-                                }
-                            }
-                        } else {
-                            // Message for a non-existent call, and not an INVITE
-                            println!("  Ignoring message for non-existent Call-ID [{}], Method/Code [{}], Type [{}]", call_id, method_or_code, msg_type);
-                            // Maybe send a 481 Call/Transaction Does Not Exist response? Requires CSeq etc.
-                        }
-                        // MutexGuard is dropped here, releasing the lock
-                    }
-                } else {
-                    eprintln!("Failed to parse first line: {}", first_line);
-                }
+                process_message(&message, &call_map, &socket);
+                process_timers(&call_map, &socket);
             }
+            Err(RecvTimeoutError::Timeout) => process_timers(&call_map, &socket),
             Err(e) => {
                 eprintln!("Worker thread receive error: {}. Stopping.", e);
                 break; // Exit loop if channel disconnects
@@ -149,68 +48,687 @@ pub fn process_sip_messages(
     println!("Worker thread finished.");
 }
 
-// --- REGISTER Handling ---
-fn handle_register(message: &SipMessage, socket: &Arc<UdpSocket>, message_str: &str) {
-    println!("Handling REGISTER request.");
+fn lock_call_map(call_map: &Mutex<CallMap>) -> MutexGuard<'_, CallMap> {
+    match call_map.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            eprintln!("CallMap mutex poisoned: {}", poisoned);
+            poisoned.into_inner()
+        }
+    }
+}
 
-    // Extract necessary headers for response
-    let via = get_via_header(message_str).unwrap_or_default();
-    let from = get_from_header(message_str).unwrap_or_default();
-    let to = get_to_header(message_str).unwrap_or_default();
-    let call_id = get_call_id(message_str).unwrap_or_default();
-    let cseq = get_cseq_header(message_str).unwrap_or_default();
-    let contact = get_contact_header(message_str).unwrap_or_default(); // Get Contact for 200 OK
-
-    // Extract username from From header
-    let username = extract_username_from_uri(&from);
-
-    if let Some(uname) = username {
-        // Update the location entry with the source address of the REGISTER request
-        if update_location_entry_addr(&uname, message.client_addr) {
-            // User found and updated, send 200 OK
-            // Add Expires header to Contact if needed (parsing original helps)
-            let contact_with_expires = if contact.is_empty() {
-                String::new()
-            } else {
-                format!("{};expires={REGISTER_CONTACT_EXPIRES}", contact)
-            }; // Simplified
-
-            // Corrected format! usage
-            let response_200 = format!(
-                "SIP/2.0 200 OK\r\n\
-                {}\r\n\
-                {}\r\n\
-                {}\r\n\
-                Call-ID: {}\r\n\
-                {}\r\n\
-                {}\r\n\
-                User-Agent: TinySIP-Rust\r\n\
-                Content-Length: 0\r\n\r\n",
-                via, from, to, call_id, cseq, contact_with_expires
+fn process_message(message: &SipMessage, call_map: &Mutex<CallMap>, socket: &Arc<UdpSocket>) {
+    let source_addr = message.client_addr;
+    let message_str = String::from_utf8_lossy(&message.buffer); // For logging
+
+    println!("\n================ RX from {} ================\n{}\n================================================",
+        source_addr, message_str);
+
+    // Parse once into the typed message model
+    let mut parsed = match parse_message(&message.buffer) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Failed to parse SIP message from {}: {}", source_addr, e);
+            return;
+        }
+    };
+    let call_id = parsed.call_id().unwrap_or_default().to_string();
+
+    // Strip our own entry from the Route set before anything else looks at it
+    if let ParsedMessage::Request(req) = &mut parsed {
+        if preprocess_routes(req) {
+            println!(
+                "  Route pre-processing: Request-URI {}, {} Route(s) left.",
+                req.uri,
+                req.headers.get_list("Route").len()
             );
-            println!("REGISTER successful for {}. Sending 200 OK.", uname);
-            send_sip_message(socket, response_200.as_bytes(), &message.client_addr);
+        }
+    }
+
+    // Determine message type (Request/Response) and method/code
+    let (msg_type, method_or_code) = match &parsed {
+        ParsedMessage::Request(req) => (REQUEST_METHOD, req.method.clone()),
+        ParsedMessage::Response(resp) => (STATUS_CODE, resp.status_code.to_string()),
+    };
+
+    // Lock CallMap for find/allocate/update operations; it also holds the
+    // transaction table
+    let mut map_guard = lock_call_map(call_map);
+
+    // Let the transaction layer absorb retransmissions first
+    let now = Instant::now();
+    let received = match &parsed {
+        ParsedMessage::Request(req) => {
+            map_guard
+                .transactions
+                .receive_request(req, source_addr, now)
+        }
+        ParsedMessage::Response(resp) => map_guard.transactions.receive_response(resp, now),
+    };
+    transmit(socket, &received.transmit);
+    if !received.deliver {
+        println!(
+            "  Retransmission of {} for Call-ID [{}] absorbed by transaction layer ({} message(s) resent).",
+            method_or_code,
+            call_id,
+            received.transmit.len()
+        );
+        return;
+    }
+
+    if let ParsedMessage::Request(req) = &parsed {
+        let CallMap {
+            transactions,
+            messages,
+            ..
+        } = &mut *map_guard;
+        let mut sender = SipSender {
+            socket,
+            transactions,
+        };
+        if !SUPPORTED_METHODS.contains(&req.method.as_str()) {
+            eprintln!("Unsupported request method: {}", req.method);
+            // Unknown methods get 501 Not Implemented (RFC 3261 §8.2.1)
+            let not_implemented = ResponseBuilder::from_request(req, 501)
+                .header("Allow", &SUPPORTED_METHODS.join(", "))
+                .build();
+            sender.response(&not_implemented, &source_addr);
+            return;
+        }
+
+        // Handle REGISTER separately (doesn't use CallMap in the same way)
+        if req.method == "REGISTER" {
+            handle_register(message, &mut sender, req, messages);
+            return;
+        }
+
+        // An OPTIONS outside a dialog asks about the server itself:
+        // capability queries and keepalives from monitors and trunk peers
+        let in_dialog = req
+            .headers
+            .get("To")
+            .and_then(|to| NameAddr::parse(to).ok())
+            .is_some_and(|to| to.tag().is_some());
+        if req.method == "OPTIONS" && !in_dialog {
+            handle_options(&mut sender, req, source_addr);
+            return;
+        }
+        if req.method == "MESSAGE" && !in_dialog {
+            handle_instant_message(req, source_addr, messages, &mut sender);
+            return;
+        }
+    }
+
+    // Responses to MESSAGEs we forwarded are not part of any call
+    if let ParsedMessage::Response(resp) = &parsed {
+        let CallMap {
+            transactions,
+            messages,
+            ..
+        } = &mut *map_guard;
+        let mut sender = SipSender {
+            socket,
+            transactions,
+        };
+        if resp.cseq().is_some_and(|(_, method)| method == "MESSAGE")
+            && message_answered(messages, resp, &mut sender)
+        {
+            return;
+        }
+    }
+
+    // Find existing call or allocate new one for INVITE
+    let (call_index_opt, leg_type) = CallMap::find_call_by_callid(&map_guard, &call_id);
+
+    // A new INVITE with Replaces joins the call whose dialog it names
+    if let ParsedMessage::Request(req) = &parsed {
+        let new_invite = req.method == "INVITE" && call_index_opt.is_none();
+        if let Some(replaces) = replaces(req).filter(|_| new_invite) {
+            handle_replacing_invite(&mut map_guard, req, &replaces, source_addr, socket);
+            return;
+        }
+    }
+
+    let target = if let Some(call_index) = call_index_opt {
+        // Existing call found
+        Some((call_index, leg_type))
+    } else if msg_type == REQUEST_METHOD && method_or_code == "INVITE" {
+        // No existing call, but it's an INVITE - try to allocate
+        println!(
+            "  Call-ID [{}] not found, processing INVITE to allocate.",
+            call_id
+        );
+        // Allocate returns index now
+        if let Some(new_call_index) = CallMap::allocate_new_call_mut(&mut map_guard) {
+            println!("  Allocated new call at index {}", new_call_index);
+            Some((new_call_index, A_LEG)) // Initial INVITE is always A_LEG perspective
         } else {
-            // User not found in static list
-            // Corrected format! usage
-            let response_404 = format!(
-                "SIP/2.0 404 Not Found\r\n\
-                {}\r\n\
-                {}\r\n\
-                {}\r\n\
-                Call-ID: {}\r\n\
-                {}\r\n\
-                User-Agent: TinySIP-Rust\r\n\
-                Content-Length: 0\r\n\r\n",
-                via, from, to, call_id, cseq
+            eprintln!(
+                "Error: CallMap full, cannot allocate for INVITE Call-ID [{}]",
+                call_id
+            );
+            // Send 503 Service Unavailable
+            if let ParsedMessage::Request(req) = &parsed {
+                let response_503 = ResponseBuilder::from_request(req, 503).build();
+                let mut sender = SipSender {
+                    socket,
+                    transactions: &mut map_guard.transactions,
+                };
+                sender.response(&response_503, &source_addr);
+            }
+            None
+        }
+    } else {
+        // Message for a non-existent call, and not an INVITE
+        println!(
+            "  Ignoring message for non-existent Call-ID [{}], Method/Code [{}], Type [{}]",
+            call_id, method_or_code, msg_type
+        );
+        if let ParsedMessage::Request(req) = &parsed {
+            let mut sender = SipSender {
+                socket,
+                transactions: &mut map_guard.transactions,
+            };
+            answer_unhandled(req, 481, &source_addr, &mut sender);
+        }
+        None
+    };
+
+    if let Some((call_index, leg_type)) = target {
+        let should_release = {
+            let CallMap {
+                calls,
+                transactions,
+                ..
+            } = &mut *map_guard;
+            let mut sender = SipSender {
+                socket,
+                transactions,
+            };
+            let call = &mut calls[call_index];
+            handle_state_machine(
+                call,
+                msg_type,
+                &method_or_code,
+                message, // Pass original SipMessage with SocketAddr
+                &parsed,
+                leg_type,
+                &mut sender,
+            );
+            !call.is_active
+        };
+        if should_release {
+            map_guard.release_call(call_index);
+        }
+    }
+    // MutexGuard is dropped here, releasing the lock
+}
+
+// Drives transaction timers: retransmissions go out directly, timeouts are
+// handed to the call they belong to.
+fn process_timers(call_map: &Mutex<CallMap>, socket: &Arc<UdpSocket>) {
+    let now = Instant::now();
+    let mut map_guard = lock_call_map(call_map);
+    let events = map_guard.transactions.poll_timers(now);
+
+    for event in events {
+        let request = match event {
+            TimerEvent::Retransmit(outgoing) => {
+                transmit(socket, &[outgoing]);
+                continue;
+            }
+            TimerEvent::NoResponse(ref request)
+            | TimerEvent::NoAck(ref request)
+            | TimerEvent::NoPrack(ref request) => request,
+        };
+        let call_id = request.call_id().unwrap_or_default();
+        if request.method == "MESSAGE" {
+            let CallMap {
+                transactions,
+                messages,
+                ..
+            } = &mut *map_guard;
+            let mut sender = SipSender {
+                socket,
+                transactions,
+            };
+            let timeout = ResponseBuilder::from_request(request, 408).build();
+            message_answered(messages, &timeout, &mut sender);
+            continue;
+        }
+        let (call_index, leg_type) = match CallMap::find_call_by_callid(&map_guard, call_id) {
+            (Some(index), leg_type) => (index, leg_type),
+            _ => continue,
+        };
+        let should_release = {
+            let CallMap {
+                calls,
+                transactions,
+                ..
+            } = &mut *map_guard;
+            let mut sender = SipSender {
+                socket,
+                transactions,
+            };
+            let call = &mut calls[call_index];
+            match event {
+                TimerEvent::NoResponse(_) => {
+                    handle_transaction_timeout(call, request, leg_type, &mut sender)
+                }
+                TimerEvent::NoPrack(_) => handle_prack_timeout(call, &mut sender),
+                _ => handle_ack_timeout(call, &mut sender),
+            }
+            !call.is_active
+        };
+        if should_release {
+            map_guard.release_call(call_index);
+        }
+    }
+    check_session_timers(&mut map_guard, socket, Instant::now());
+    check_hunt_timers(&mut map_guard, socket, Instant::now());
+}
+
+// Ring group steps that rang out: the hunt moves on, and a call with nothing
+// left to ring is released.
+fn check_hunt_timers(map_guard: &mut CallMap, socket: &Arc<UdpSocket>, now: Instant) {
+    for index in 0..map_guard.calls.len() {
+        let should_release = {
+            let CallMap {
+                calls,
+                transactions,
+                ..
+            } = &mut *map_guard;
+            let call = &mut calls[index];
+            let ringing =
+                call.call_state == CallState::Routing || call.call_state == CallState::Ringing;
+            if !call.is_active || !ringing || call.hunt.deadline.is_none_or(|at| at > now) {
+                continue;
+            }
+            let mut sender = SipSender {
+                socket,
+                transactions,
+            };
+            hunt_timeout(call, &mut sender)
+        };
+        if should_release {
+            map_guard.calls[index].is_active = false;
+            println!("  Call {} released, nobody answered.", index);
+            map_guard.release_call(index);
+        }
+    }
+}
+
+// Session timers of connected calls: a call whose refresh is overdue is torn
+// down, and the calls the server refreshes itself get their refresh.
+fn check_session_timers(map_guard: &mut CallMap, socket: &Arc<UdpSocket>, now: Instant) {
+    let CallMap {
+        calls,
+        transactions,
+        ..
+    } = map_guard;
+    let mut sender = SipSender {
+        socket,
+        transactions,
+    };
+    for call in calls
+        .iter_mut()
+        .filter(|call| call.is_active && call.call_state == CallState::Connected)
+    {
+        if call.session_timer.expires_at.is_some_and(|at| at <= now) {
+            println!(
+                "  Session timer of call {} expired without a refresh; terminating.",
+                call.index
             );
-            println!("User '{}' not found. Sending 404 Not Found.", uname);
-            send_sip_message(socket, response_404.as_bytes(), &message.client_addr);
+            terminate_call(call, &mut sender);
+        } else if call.session_timer.refresh_at.is_some_and(|at| at <= now) {
+            send_session_refresh(call, &mut sender);
+        }
+    }
+}
+
+// --- OPTIONS Handling ---
+// Answers an out-of-dialog OPTIONS with what the server supports (RFC 3261
+// §11.2).
+fn handle_options(sender: &mut SipSender, request: &SipRequest, source: SocketAddr) {
+    println!("Answering OPTIONS from {}.", source);
+    let ok_200 = ResponseBuilder::from_request(request, 200)
+        .to_tag(&generate_tag())
+        .header("Allow", &SUPPORTED_METHODS.join(", "))
+        .header("Accept", &ACCEPTED_CONTENT_TYPES.join(", "))
+        .header("Supported", &SUPPORTED_EXTENSIONS.join(", "))
+        .contact(&server_contact())
+        .build();
+    sender.response(&ok_200, &source);
+}
+
+// --- MESSAGE Handling ---
+// Routes an out-of-dialog MESSAGE (RFC 3428) to every binding of the user
+// it is addressed to. For a user with no binding it goes into the offline
+// store and is answered 202 Accepted, or 486 once the user's queue is full.
+fn handle_instant_message(
+    request: &SipRequest,
+    source: SocketAddr,
+    messages: &mut Vec<PendingMessage>,
+    sender: &mut SipSender,
+) {
+    if out_of_hops(request, &source, sender) {
+        return;
+    }
+    let recipient = SipUri::parse(&request.uri)
+        .ok()
+        .and_then(|uri| uri.user)
+        .filter(|user| is_known_user(user));
+    let recipient = match recipient {
+        Some(recipient) => recipient,
+        None => {
+            println!("MESSAGE for unknown user {}; sending 404.", request.uri);
+            let response_404 = ResponseBuilder::from_request(request, 404).build();
+            sender.response(&response_404, &source);
+            return;
+        }
+    };
+    if user_features(&recipient).is_some_and(|features| !features.messaging) {
+        println!("{} does not take instant messages; sending 403.", recipient);
+        let response_403 = ResponseBuilder::from_request(request, 403).build();
+        sender.response(&response_403, &source);
+        return;
+    }
+
+    let bindings = get_registered_bindings(&recipient);
+    if bindings.is_empty() {
+        let code = match store_message(&recipient, request) {
+            Ok(path) => {
+                println!(
+                    "{} is offline; MESSAGE stored as {}.",
+                    recipient,
+                    path.display()
+                );
+                202
+            }
+            Err(StoreError::Full) => {
+                println!(
+                    "Stored messages of {} are at their limit; sending 486.",
+                    recipient
+                );
+                486
+            }
+            Err(StoreError::Io(e)) => {
+                eprintln!("Failed to store MESSAGE for {}: {}", recipient, e);
+                500
+            }
+        };
+        let response = ResponseBuilder::from_request(request, code)
+            .to_tag(&generate_tag())
+            .build();
+        sender.response(&response, &source);
+        return;
+    }
+
+    println!(
+        "Forwarding MESSAGE to {} binding(s) of {}.",
+        bindings.len(),
+        recipient
+    );
+    messages.push(PendingMessage {
+        recipient,
+        received: Some((request.clone(), source)),
+        stored: None,
+        forwarded: forward_message(request, &bindings, sender),
+        failure: None,
+    });
+}
+
+// Sends the messages stored for `username` to its bindings, oldest first.
+// Ones already on their way are left alone.
+fn deliver_stored_messages(
+    username: &str,
+    messages: &mut Vec<PendingMessage>,
+    sender: &mut SipSender,
+) {
+    let bindings = get_registered_bindings(username);
+    if bindings.is_empty() {
+        return;
+    }
+    for (path, request) in stored_messages(username) {
+        if messages
+            .iter()
+            .any(|pending| pending.stored.as_ref() == Some(&path))
+        {
+            continue;
+        }
+        println!(
+            "Delivering stored MESSAGE {} to {}.",
+            path.display(),
+            username
+        );
+        messages.push(PendingMessage {
+            recipient: username.to_string(),
+            received: None,
+            stored: Some(path),
+            forwarded: forward_message(&request, &bindings, sender),
+            failure: None,
+        });
+    }
+}
+
+// Sends a copy of `request` to each binding, each as a new transaction of
+// our own with the sender's identity and the original body.
+fn forward_message(
+    request: &SipRequest,
+    bindings: &[Binding],
+    sender: &mut SipSender,
+) -> Vec<SipRequest> {
+    let from = request
+        .headers
+        .get("From")
+        .and_then(|from| NameAddr::parse(from).ok())
+        .map(|mut from| {
+            from.remove_param("tag");
+            from.to_string()
+        })
+        .unwrap_or_default();
+    let to = request.headers.get("To").unwrap_or_default();
+    let max_forwards = request.max_forwards().unwrap_or(DEFAULT_MAX_FORWARDS);
+    let mut forwarded = Vec::new();
+    for binding in bindings {
+        let mut dialog = Dialog::uac(&generate_call_id(), &from, to, &binding.contact);
+        let mut message = dialog
+            .request("MESSAGE")
+            .max_forwards(max_forwards.saturating_sub(1));
+        if let Some(content_type) = request.content_type() {
+            message = message.body(content_type, &request.body);
+        }
+        let message = message.build();
+        sender.request(&message, &binding.addr);
+        forwarded.push(message);
+    }
+    forwarded
+}
+
+// A final response (or a timeout, as 408) to one of our forwarded MESSAGEs.
+// The first 2xx is relayed to the sender and removes a stored message from
+// the store; failures are relayed, lowest first, once every binding has
+// failed. Returns false if the response is not to a forwarded MESSAGE.
+fn message_answered(
+    messages: &mut Vec<PendingMessage>,
+    response: &SipResponse,
+    sender: &mut SipSender,
+) -> bool {
+    let call_id = response.call_id();
+    let index = messages.iter().position(|pending| {
+        pending
+            .forwarded
+            .iter()
+            .any(|forwarded| forwarded.call_id() == call_id)
+    });
+    let index = match index {
+        Some(index) => index,
+        None => return false,
+    };
+    let code = response.status_code;
+    if code < 200 {
+        return true;
+    }
+    let pending = &mut messages[index];
+    pending
+        .forwarded
+        .retain(|forwarded| forwarded.call_id() != call_id);
+    if code >= 300 {
+        if pending
+            .failure
+            .as_ref()
+            .is_none_or(|failure| code < failure.status_code)
+        {
+            pending.failure = Some(response.clone());
         }
+        if !pending.forwarded.is_empty() {
+            return true;
+        }
+    }
+
+    let pending = messages.remove(index);
+    let final_response = if code < 300 {
+        response
     } else {
-        eprintln!("Failed to extract username from From header: {}", from);
-        // Optionally send a 400 Bad Request
+        pending.failure.as_ref().unwrap_or(response)
+    };
+    println!(
+        "MESSAGE for {} answered {}.",
+        pending.recipient, final_response.status_code
+    );
+    if let Some((request, source)) = &pending.received {
+        let relayed = ResponseBuilder::from_request(request, final_response.status_code)
+            .reason(&final_response.reason)
+            .to_tag(&generate_tag())
+            .build();
+        sender.response(&relayed, source);
+    }
+    if let Some(path) = pending.stored.as_ref().filter(|_| code < 300) {
+        remove_stored_message(path);
+    }
+    true
+}
+
+// --- REGISTER Handling ---
+fn handle_register(
+    message: &SipMessage,
+    sender: &mut SipSender,
+    request: &SipRequest,
+    messages: &mut Vec<PendingMessage>,
+) {
+    println!("Handling REGISTER request.");
+
+    // The To header carries the address-of-record being registered (RFC 3261 §10.2)
+    let username = request
+        .headers
+        .get("To")
+        .and_then(|to| NameAddr::parse(to).ok())
+        .and_then(|to| to.user().map(str::to_string));
+
+    let uname = match username {
+        Some(uname) => uname,
+        None => {
+            eprintln!(
+                "Failed to extract username from To header: {}",
+                request.headers.get("To").unwrap_or_default()
+            );
+            let response_400 = ResponseBuilder::from_request(request, 400).build();
+            sender.response(&response_400, &message.client_addr);
+            return;
+        }
+    };
+
+    // An unknown user is challenged like any other, so that the answer does
+    // not tell which users exist
+    let now = Instant::now();
+    if let Err(e) = authenticate_from(request, &uname, &message.client_addr, now) {
+        reject_unauthenticated(request, &uname, e, &message.client_addr, sender);
+        return;
+    }
+    match register(&uname, request, message.client_addr, now) {
+        Ok(bindings) => {
+            // The 200 OK lists all current bindings of the user with the
+            // time they have left (RFC 3261 §10.3 step 8)
+            let mut response_200 = ResponseBuilder::from_request(request, 200);
+            for binding in &bindings {
+                response_200 = response_200.header("Contact", &binding_contact(binding, now));
+            }
+            println!(
+                "REGISTER successful for {} ({} binding(s)). Sending 200 OK.",
+                uname,
+                bindings.len()
+            );
+            sender.response(&response_200.build(), &message.client_addr);
+            deliver_stored_messages(&uname, messages, sender);
+        }
+        Err(e) => {
+            println!("REGISTER for '{}' rejected: {}.", uname, e);
+            let mut response = ResponseBuilder::from_request(request, e.status_code());
+            if e == RegisterError::IntervalTooBrief {
+                response = response.header("Min-Expires", &MIN_REGISTER_EXPIRES.to_string());
+            }
+            sender.response(&response.build(), &message.client_addr);
+        }
+    }
+}
+
+// Authenticates `username` as the sender of `request`, which must also come
+// from one of the user's allowed networks.
+fn authenticate_from(
+    request: &SipRequest,
+    username: &str,
+    source: &SocketAddr,
+    now: Instant,
+) -> Result<(), AuthError> {
+    if !source_allowed(username, source.ip()) {
+        println!(
+            "  {} from {} is outside the allowed networks of {}.",
+            request.method, source, username
+        );
+        return Err(AuthError::Forbidden);
     }
+    authenticate(request, username, now)
+}
+
+// The user part of the From URI of `request`; empty if it has none.
+fn from_user(request: &SipRequest) -> String {
+    request
+        .headers
+        .get("From")
+        .and_then(|from| NameAddr::parse(from).ok())
+        .and_then(|from| from.user().map(str::to_string))
+        .unwrap_or_default()
+}
+
+// Answers a request that failed digest authentication: a new challenge
+// (401 for REGISTER, 407 otherwise), or 403 for credentials of another user
+// or a source outside the user's networks.
+fn reject_unauthenticated(
+    request: &SipRequest,
+    username: &str,
+    error: AuthError,
+    source: &SocketAddr,
+    sender: &mut SipSender,
+) {
+    println!("  {} not authenticated: {:?}", request.method, error);
+    let mut response = if error == AuthError::Forbidden {
+        ResponseBuilder::from_request(request, 403)
+    } else {
+        let (code, header) = if request.method == "REGISTER" {
+            (401, "WWW-Authenticate")
+        } else {
+            (407, "Proxy-Authenticate")
+        };
+        let mut response = ResponseBuilder::from_request(request, code);
+        let algorithms = user_algorithms(username);
+        for value in challenge(error == AuthError::Stale, &algorithms, Instant::now()) {
+            response = response.header(header, &value);
+        }
+        response
+    };
+    response = response.to_tag(&generate_tag());
+    sender.response(&response.build(), source);
 }
 
 // --- State Machine ---
@@ -221,242 +739,283 @@ fn handle_state_machine(
     call: &mut Call, // Takes mutable reference, lock is held outside
     message_type: i32,
     method_or_code: &str,
-    has_sdp: bool,
     message: &SipMessage, // Contains client_addr
-    raw_sip_message: &str,
+    parsed: &ParsedMessage,
     leg_type: i32,
-    socket: &Arc<UdpSocket>,
+    sender: &mut SipSender,
 ) {
     println!(
         "  State Machine: Call Index [{}], State [{:?}], RX Msg Type [{}], Code/Method [{}], Leg [{}]",
-        call.index, call.call_state, message_type, method_or_code, if leg_type == A_LEG {"A"} else {"B"}
+        call.index, call.call_state, message_type, method_or_code, match leg_type { A_LEG => "A", B_LEG => "B", _ => "transfer" }
     );
 
-    // Extract common headers from incoming message for potential use in responses
-    let via_header = get_via_header(raw_sip_message).unwrap_or_default();
-    let from_header = get_from_header(raw_sip_message).unwrap_or_default();
-    let to_header = get_to_header(raw_sip_message).unwrap_or_default();
-    let call_id_header = get_call_id(raw_sip_message).unwrap_or_default(); // Already have call.a/b_leg_uuid
-    let cseq_header = get_cseq_header(raw_sip_message).unwrap_or_default();
-    let contact_header = get_contact_header(raw_sip_message).unwrap_or_default(); // Contact from incoming message
-    let max_forwards = get_max_forwards(raw_sip_message).unwrap_or(DEFAULT_MAX_FORWARDS);
+    // Extract common headers from incoming message for later use
+    let call_id_header = parsed.call_id().unwrap_or_default().to_string(); // Already have call.a/b_leg_uuid
+    let sdp_body = parsed.sdp_body();
+    let (request, response) = match parsed {
+        ParsedMessage::Request(req) => (Some(req), None),
+        ParsedMessage::Response(resp) => (None, Some(resp)),
+    };
 
     // --- State Machine Logic ---
     // This closely follows the C logic, adapted for Rust types and helpers.
     // Locking Note: The `call` is already mutable, implying the lock is held.
 
-    // Refresh To header for B leg if message came from B leg (needed for ACK/BYE construction)
-    if leg_type == B_LEG && !to_header.is_empty() {
-        call.b_leg_header.to = to_header.clone();
+    // The call placed for a blind transfer is handled on its own until it
+    // takes the transferor's place
+    if leg_type == TRANSFER_LEG {
+        handle_transfer_leg(call, parsed, message.client_addr, sender);
+        return;
+    }
+
+    // Responses to in-dialog requests we relayed go back to the other leg,
+    // as does the ACK for a relayed re-INVITE 2xx
+    if let Some(resp) = response {
+        if relay_response_back(call, resp, leg_type, sender) {
+            return;
+        }
+    }
+    if let Some(req) = request {
+        if req.method == "ACK" && relay_ack(call, req, leg_type, sender) {
+            return;
+        }
+    }
+
+    // Responses to our INVITE carry B's tag and target (needed for ACK/BYE
+    // construction). While B's contacts are being forked to, they belong
+    // to the branch they answer.
+    if let Some(resp) = response {
+        let is_invite_response = resp.cseq().is_some_and(|(_, method)| method == "INVITE");
+        if leg_type == B_LEG && is_invite_response {
+            if !call.forks.is_empty() {
+                match fork_of(call, resp) {
+                    Some(index) if call.forks[index].final_response.is_some() => {
+                        // A branch given up while hunting
+                        if (200..300).contains(&resp.status_code) {
+                            end_losing_dialog(call, resp, message.client_addr, sender);
+                        }
+                        return;
+                    }
+                    Some(index) => select_fork(call, index),
+                    None => {
+                        println!(
+                            "  Ignoring response for an unknown branch of call {}",
+                            call.index
+                        );
+                        return;
+                    }
+                }
+                if remote_tag(resp) != call.b_leg_dialog.remote_tag
+                    && call.b_leg_dialog.remote_tag.is_some()
+                {
+                    // Another early dialog: its RSeq numbering starts afresh
+                    call.prack.b_leg_rseq = None;
+                }
+            } else if (200..300).contains(&resp.status_code)
+                && call.b_leg_dialog.state == DialogState::Confirmed
+                && remote_tag(resp) != call.b_leg_dialog.remote_tag
+            {
+                end_losing_dialog(call, resp, message.client_addr, sender);
+                return;
+            }
+            call.b_leg_dialog.update_from_response(resp);
+        }
     }
-    // Refresh To header for A leg if message came from A leg
-    if leg_type == A_LEG && !to_header.is_empty() {
-        call.a_leg_header.to = to_header.clone();
+
+    // In-dialog requests must match the dialog of their leg (RFC 3261 §12.2.2)
+    if let Some(req) = request {
+        if call.call_state != CallState::Idle && req.method != "ACK" && req.method != "CANCEL" {
+            let dialog = if leg_type == A_LEG {
+                &mut call.a_leg_dialog
+            } else {
+                &mut call.b_leg_dialog
+            };
+            if !dialog.matches(req) {
+                println!(
+                    "  {} from leg {} does not match the dialog of call {}; sending 481.",
+                    method_or_code, leg_type, call.index
+                );
+                let response_481 = ResponseBuilder::from_request(req, 481).build();
+                sender.response(&response_481, &message.client_addr);
+                return;
+            }
+            if !dialog.accept_remote_cseq(req) {
+                println!(
+                    "  {} from leg {} on call {} has an out-of-order CSeq; sending 500.",
+                    method_or_code, leg_type, call.index
+                );
+                let response_500 = ResponseBuilder::from_request(req, 500).build();
+                sender.response(&response_500, &message.client_addr);
+                return;
+            }
+        }
     }
 
     match call.call_state {
         CallState::Idle => {
             // Should only happen for the initial INVITE, which is handled before this match
             // by the allocation logic calling handle_state_machine the first time.
-            if message_type == REQUEST_METHOD && method_or_code == "INVITE" && leg_type == A_LEG {
+            let invite = match request {
+                Some(req) if req.method == "INVITE" && leg_type == A_LEG => req,
+                _ => {
+                    println!(
+                        "  Ignoring message type {} code/method {} in IDLE state.",
+                        message_type, method_or_code
+                    );
+                    if let Some(req) = request {
+                        answer_unhandled(req, 481, &message.client_addr, sender);
+                    }
+                    return;
+                }
+            };
+            println!(
+                "  Processing initial INVITE for allocated call {}",
+                call.index
+            );
+            if out_of_hops(invite, &message.client_addr, sender) {
+                call.is_active = false;
+                return;
+            }
+
+            // The caller must prove to be the user in From
+            let caller = from_user(invite);
+            if let Err(e) = authenticate_from(invite, &caller, &message.client_addr, Instant::now())
+            {
+                reject_unauthenticated(invite, &caller, e, &message.client_addr, sender);
+                call.is_active = false;
+                return;
+            }
+
+            // 1. Store A-leg info
+            call.a_leg_addr = Some(message.client_addr);
+            call.a_leg_uuid = call_id_header.clone();
+            // Create unique B-leg ID - Ensure it fits within MAX_UUID_LENGTH
+            let base_id = if call_id_header.len() > 6 {
+                &call_id_header[6..]
+            } else {
+                &call_id_header
+            };
+            call.b_leg_uuid = format!("b-leg-{}", base_id);
+            if call.b_leg_uuid.len() >= MAX_UUID_LENGTH {
+                call.b_leg_uuid.truncate(MAX_UUID_LENGTH - 1);
+            }
+
+            // Extensions A requires must all be ours (RFC 3261 §8.2.2.3)
+            let unsupported: Vec<&str> = invite
+                .headers
+                .get_list("Require")
+                .into_iter()
+                .filter(|tag| {
+                    !SUPPORTED_EXTENSIONS
+                        .iter()
+                        .any(|ours| ours.eq_ignore_ascii_case(tag))
+                })
+                .collect();
+            if !unsupported.is_empty() {
+                println!(
+                    "  INVITE requires unsupported extension(s) {:?}",
+                    unsupported
+                );
+                let response_420 = ResponseBuilder::from_request(invite, 420)
+                    .to_tag(&generate_tag())
+                    .header("Unsupported", &unsupported.join(", "))
+                    .build();
+                sender.response(&response_420, &message.client_addr);
+                call.is_active = false;
+                return;
+            }
+            // Session timer (RFC 4028 §8.1): an interval below our Min-SE gets 422
+            let a_session_expires = session_expires(invite);
+            if a_session_expires.is_some_and(|se| se.delta < MIN_SESSION_EXPIRES) {
                 println!(
-                    "  Processing initial INVITE for allocated call {}",
-                    call.index
+                    "  INVITE Session-Expires is below our Min-SE of {}",
+                    MIN_SESSION_EXPIRES
                 );
+                let response_422 = ResponseBuilder::from_request(invite, 422)
+                    .to_tag(&generate_tag())
+                    .header("Min-SE", &MIN_SESSION_EXPIRES.to_string())
+                    .build();
+                sender.response(&response_422, &message.client_addr);
+                call.is_active = false;
+                return;
+            }
+            call.session_timer.a_leg_supported = supports_timer(invite);
+            call.session_timer.a_leg_refresher = a_session_expires.and_then(|se| se.refresher);
+            call.session_timer.min_se = min_se(invite).unwrap_or_default().max(MIN_SESSION_EXPIRES);
 
-                // 1. Store A-leg info
-                call.a_leg_addr = Some(message.client_addr);
-                call.a_leg_uuid = call_id_header.clone();
-                // Create unique B-leg ID - Ensure it fits within MAX_UUID_LENGTH
-                let base_id = if call_id_header.len() > 6 {
-                    &call_id_header[6..]
-                } else {
-                    &call_id_header
-                };
-                call.b_leg_uuid = format!("b-leg-{}", base_id);
-                if call.b_leg_uuid.len() >= MAX_UUID_LENGTH {
-                    call.b_leg_uuid.truncate(MAX_UUID_LENGTH - 1);
-                }
+            call.prack.a_leg_required = invite.has_option_tag("Require", "100rel");
+            call.prack.a_leg_supported =
+                call.prack.a_leg_required || invite.has_option_tag("Supported", "100rel");
 
-                // Store A-leg headers
-                // Update Via with received/rport before storing
-                let (via_received, via_rport) = extract_via_received_rport(&via_header);
-                let mut updated_via = via_header.clone();
-                if via_rport.is_some() || via_received.is_none() {
-                    // Add received if rport present or received missing
-                    if !via_header.contains(";received=") {
-                        updated_via = updated_via.trim_end_matches("\r\n").to_string(); // Remove existing CRLF
-                        updated_via.push_str(&format!(";received={}", message.client_addr.ip()));
-                        updated_via.push_str("\r\n"); // Add CRLF back
-                    }
-                }
-                if via_rport == Some(RPORT_FLAG_VALUE) {
-                    // rport flag without value
-                    if !via_header.contains(";rport=") {
-                        updated_via = updated_via.trim_end_matches("\r\n").to_string();
-                        updated_via.push_str(&format!(";rport={}", message.client_addr.port()));
-                        updated_via.push_str("\r\n");
-                    }
-                }
-                call.a_leg_header.via = updated_via;
-                call.a_leg_header.from = from_header.clone();
-                call.a_leg_header.to = to_header.clone();
-                call.a_leg_header.cseq = cseq_header.clone();
-
-                // Extract and store A-leg Contact URI
-                if let Some(start_idx) = contact_header.find('<') {
-                    if let Some(end_idx) = contact_header.find('>') {
-                        if start_idx < end_idx {
-                            call.a_leg_contact = contact_header[start_idx + 1..end_idx].to_string();
-                        }
-                    }
-                } else {
-                    // Maybe extract from "Contact: sip:user@host:port" format
-                    if let Some(stripped) =
-                        contact_header.strip_prefix("Contact:").map(|s| s.trim())
-                    {
-                        call.a_leg_contact = stripped.to_string();
-                    }
-                }
+            // Store the A-leg INVITE (all responses to A are built from it), with
+            // received/rport added to its top Via before storing
+            let mut a_invite = invite.clone();
+            if let Some(top_via) = a_invite.top_via().map(str::to_string) {
+                let updated_via = via_with_received(&top_via, message.client_addr);
+                replace_top_via(&mut a_invite.headers, &updated_via);
+            }
 
-                if has_sdp {
-                    call.a_leg_media.remote_media = true; // A-leg received remote SDP (from its perspective)
-                    call.b_leg_media.local_media = true; // B-leg will send local SDP (based on A's offer)
+            // We are the UAS of the A-leg dialog; our To-tag goes on every
+            // response but 100 Trying
+            call.a_leg_dialog = match Dialog::uas(&a_invite, &generate_tag()) {
+                Some(dialog) => dialog,
+                None => {
+                    eprintln!("  INVITE lacks From/To/Call-ID; rejecting.");
+                    let response_400 = ResponseBuilder::from_request(invite, 400).build();
+                    sender.response(&response_400, &message.client_addr);
+                    call.is_active = false;
+                    return;
                 }
+            };
+            call.a_leg_invite = Some(a_invite);
 
-                // 2. Find Callee (B-leg) address
-                if let Some(callee_username) = extract_username_from_uri(&to_header) {
-                    call.callee = callee_username.clone(); // Store callee username
-                    if let Some(callee_addr) = get_registered_addr(&callee_username) {
-                        call.b_leg_addr = Some(callee_addr);
-                        println!(
-                            "  Found registered location for callee '{}': {}",
-                            callee_username, callee_addr
-                        );
-
-                        // 3. Send 100 Trying to A-leg
-                        // Corrected format! usage
-                        let trying_100 = format!(
-                            "SIP/2.0 100 Trying\r\n\
-                            {}\
-                            {}\r\n\
-                            {}\r\n\
-                            Call-ID: {}\r\n\
-                            {}\r\n\
-                            User-Agent: TinySIP-Rust\r\n\
-                            Content-Length: 0\r\n\r\n",
-                            call.a_leg_header.via,
-                            call.a_leg_header.from,
-                            call.a_leg_header.to,
-                            call.a_leg_uuid,
-                            call.a_leg_header.cseq
-                        );
-                        send_if_addr(
-                            socket,
-                            call.a_leg_addr,
-                            &trying_100,
-                            "100 Trying not sent to A leg",
-                        );
-
-                        // 4. Prepare and send INVITE to B-leg
-                        let b_branch = monotonic_millis();
-                        let b_via = format!(
-                            "Via: SIP/2.0/UDP {}:{};branch=z9hG4bK{}",
-                            SIP_SERVER_IP_ADDRESS, SIP_PORT, b_branch
-                        );
-                        let b_cseq_num = next_cseq();
-                        let b_cseq = format!("CSeq: {} INVITE", b_cseq_num);
-                        let b_contact = format!(
-                            "Contact: <sip:TinySIP@{}:{}>",
-                            SIP_SERVER_IP_ADDRESS, SIP_PORT
-                        );
-                        let b_to = format!("To: <sip:{}@{}>", call.callee, callee_addr.ip()); // Simple To for B
-
-                        // Store B-leg headers we generate
-                        call.b_leg_header.via = format!("{}{}", b_via, "\r\n");
-                        call.b_leg_header.from = call.a_leg_header.from.clone(); // Use A's From
-                        call.b_leg_header.to = format!("{}{}", b_to, "\r\n");
-                        call.b_leg_header.cseq = format!("{}{}", b_cseq, "\r\n");
-
-                        let sdp_body = get_sdp_body(raw_sip_message).unwrap_or("");
-                        let content_length = sdp_body.len();
-
-                        // Corrected format! usage
-                        let invite_to_b = format!(
-                            "INVITE sip:{}@{} SIP/2.0\r\n\
-                            {}\r\n\
-                            {}\r\n\
-                            {}\r\n\
-                            Call-ID: {}\r\n\
-                            {}\r\n\
-                            Max-Forwards: {}\r\n\
-                            {}\r\n\
-                            User-Agent: TinySIP-Rust\r\n\
-                            Content-Type: application/sdp\r\n\
-                            Content-Length: {}\r\n\r\n\
-                            {}",
-                            call.callee,
-                            callee_addr,            // Request-URI target
-                            b_via,                  // B-leg Via
-                            call.a_leg_header.from, // A-leg From
-                            b_to,                   // B-leg To
-                            call.b_leg_uuid,        // B-leg Call-ID
-                            b_cseq,                 // B-leg CSeq
-                            max_forwards.saturating_sub(1),
-                            b_contact, // Server Contact
-                            content_length,
-                            sdp_body
-                        );
-                        send_sip_message(socket, invite_to_b.as_bytes(), &callee_addr);
+            // A's offer is passed on to B unchanged (late offer if there is none)
+            if let Some(sdp) = sdp_body {
+                track_relayed_sdp(call, A_LEG, sdp);
+            }
 
-                        // 5. Update State
-                        call.call_state = CallState::Routing;
-                        println!("  Call {} state transitioned to ROUTING.", call.index);
-                    } else {
-                        println!(
-                            "  Callee '{}' not found or not registered.",
-                            callee_username
-                        );
-                        // Send 404 Not Found to A-leg
-                        // Corrected format! usage
-                        let response_404 = format!(
-                            "SIP/2.0 404 Not Found\r\n\
-                            {}\
-                            {}\r\n\
-                            {}\r\n\
-                            Call-ID: {}\r\n\
-                            {}\r\n\
-                            User-Agent: TinySIP-Rust\r\n\
-                            Content-Length: 0\r\n\r\n",
-                            call.a_leg_header.via,
-                            call.a_leg_header.from,
-                            call.a_leg_header.to,
-                            call.a_leg_uuid,
-                            call.a_leg_header.cseq
-                        );
-                        send_if_addr(
-                            socket,
-                            call.a_leg_addr,
-                            &response_404,
-                            "404 Not Found not sent to A leg",
-                        );
-                        // Release the allocated call
-                        call.is_active = false;
-                        println!("  Call {} released due to callee not found.", call.index);
-                    }
-                } else {
-                    eprintln!("  Failed to extract callee username from To: {}", to_header);
-                    // Send 400 Bad Request?
+            // 2. Find Callee (B-leg) address from the Request-URI
+            let callee_username = match SipUri::parse(&invite.uri).ok().and_then(|uri| uri.user) {
+                Some(user) => user,
+                None => {
+                    eprintln!("  Failed to extract callee username from Request-URI");
+                    respond_to_a_invite(call, 400, sender);
                     // Release the allocated call
                     call.is_active = false;
-                    println!("  Call {} released due to bad To header.", call.index);
+                    println!("  Call {} released due to bad Request-URI.", call.index);
+                    return;
                 }
-            } else {
+            };
+            call.callee = callee_username.clone(); // Store callee username
+
+            // 3. Send 100 Trying to A-leg
+            respond_to_a_invite(call, 100, sender);
+
+            // 4. Prepare and send INVITE to B-leg: the callee's contacts (or
+            // where the callee forwards to), or the first step of a ring
+            // group's hunt
+            if !ring_target(call, &callee_username, sender) {
                 println!(
-                    "  Ignoring message type {} code/method {} in IDLE state.",
-                    message_type, method_or_code
+                    "  Callee '{}' not found or not registered.",
+                    callee_username
                 );
+                // Send 404 Not Found to A-leg (480 for a ring group nobody is
+                // logged into or a forward that leads nowhere)
+                let code = if call.hunt.group.is_some() || !call.diversions.is_empty() {
+                    480
+                } else {
+                    404
+                };
+                respond_to_a_invite(call, code, sender);
+                // Release the allocated call
+                call.is_active = false;
+                println!("  Call {} released due to callee not found.", call.index);
+                return;
             }
+
+            // 5. Update State
+            call.call_state = CallState::Routing;
+            println!("  Call {} state transitioned to ROUTING.", call.index);
         }
 
         CallState::Routing | CallState::Ringing => {
@@ -467,361 +1026,133 @@ fn handle_state_machine(
                 println!("  Current State: RINGING");
             }
 
-            match message_type {
-                REQUEST_METHOD => {
-                    if method_or_code == "CANCEL" && leg_type == A_LEG {
-                        println!("  Processing CANCEL from A leg");
-                        // Action 6
-                        // 1. Send 200 OK for CANCEL to A leg
-                        // Corrected format! usage
-                        let ok_200_cancel = format!(
-                            "SIP/2.0 200 OK\r\n\
-                            {}\r\n\
-                            {}\r\n\
-                            {}\r\n\
-                            Call-ID: {}\r\n\
-                            {}\r\n\
-                            User-Agent: TinySIP-Rust\r\n\
-                            Content-Length: 0\r\n\r\n",
-                            via_header,     // Via from CANCEL
-                            from_header,    // From from CANCEL
-                            to_header,      // To from CANCEL
-                            call_id_header, // Call-ID from CANCEL (should match A-leg)
-                            cseq_header     // CSeq from CANCEL
-                        );
-                        send_if_addr(
-                            socket,
-                            call.a_leg_addr,
-                            &ok_200_cancel,
-                            "200 OK for CANCEL not sent to A leg",
-                        );
+            if let Some(req) = request {
+                if req.method == "CANCEL" && leg_type == A_LEG {
+                    println!("  Processing CANCEL from A leg");
+                    // Action 6
+                    // 1. Send 200 OK for CANCEL to A leg
+                    let ok_200_cancel = ResponseBuilder::from_request(req, 200).build();
+                    sender.response(&ok_200_cancel, &message.client_addr);
 
-                        // 2. Send 487 for original INVITE to A leg
-                        // Corrected format! usage
-                        let terminated_487 = format!(
-                            "SIP/2.0 487 Request Terminated\r\n\
-                            {}\
-                            {}\r\n\
-                            {}\r\n\
-                            Call-ID: {}\r\n\
-                            {}\r\n\
-                            User-Agent: TinySIP-Rust\r\n\
-                            Content-Length: 0\r\n\r\n",
-                            call.a_leg_header.via,  // Stored A-leg Via
-                            call.a_leg_header.from, // Stored A-leg From
-                            call.a_leg_header.to,   // Stored A-leg To
-                            call.a_leg_uuid,        // Stored A-leg Call-ID
-                            call.a_leg_header.cseq  // Stored A-leg CSeq
-                        );
-                        send_if_addr(
-                            socket,
-                            call.a_leg_addr,
-                            &terminated_487,
-                            "487 response not sent to A leg",
-                        );
+                    // 2. Send 487 for original INVITE to A leg
+                    respond_to_a_invite(call, 487, sender);
 
-                        // 3. Send CANCEL to B leg
-                        if let Some(b_addr) = call.b_leg_addr {
-                            if let Some(b_cseq_val) = extract_cseq_number(&call.b_leg_header.cseq) {
-                                // Corrected format! usage
-                                let cancel_b = format!(
-                                    "CANCEL sip:{}@{} SIP/2.0\r\n\
-                                    {}\
-                                    {}\r\n\
-                                    {}\r\n\
-                                    Call-ID: {}\r\n\
-                                    CSeq: {} CANCEL\r\n\
-                                    Max-Forwards: {}\r\n\
-                                    User-Agent: TinySIP-Rust\r\n\
-                                    Content-Length: 0\r\n\r\n",
-                                    call.callee,
-                                    b_addr,                 // B leg target URI
-                                    call.b_leg_header.via,  // Stored B-leg Via
-                                    call.b_leg_header.from, // Stored B-leg From
-                                    call.b_leg_header.to,   // Stored B-leg To
-                                    call.b_leg_uuid,        // Stored B-leg Call-ID
-                                    b_cseq_val,             // Match B's INVITE CSeq num
-                                    DEFAULT_MAX_FORWARDS
-                                );
-                                send_sip_message(socket, cancel_b.as_bytes(), &b_addr);
-                            } else {
-                                eprintln!(
-                                    "  Missing B-leg CSeq while building CANCEL for call {}",
-                                    call.index
-                                );
-                            }
-                        } else {
-                            eprintln!(
-                                "  Missing B-leg address while handling CANCEL for call {}",
-                                call.index
-                            );
-                        }
+                    // 3. Send CANCEL to every pending branch of the B leg
+                    cancel_forks(call, None, sender);
 
-                        // 4. Set state to DISCONNECTING
-                        call.call_state = CallState::Disconnecting;
-                        println!("  Call {} state transitioned to DISCONNECTING.", call.index);
-                    } else {
-                        println!(
-                            "  Ignoring METHOD {} from leg {} in state {:?}",
-                            method_or_code, leg_type, call.call_state
-                        );
-                    }
+                    // 4. Set state to DISCONNECTING
+                    call.call_state = CallState::Disconnecting;
+                    println!("  Call {} state transitioned to DISCONNECTING.", call.index);
+                } else if req.method == "PRACK" && leg_type == A_LEG {
+                    handle_prack_from_a(call, req, message.client_addr, sender);
+                } else if req.method == "UPDATE" {
+                    // UPDATE may change the session before the call is answered
+                    relay_in_dialog_request(call, req, message.client_addr, leg_type, &[], sender);
+                } else {
+                    println!(
+                        "  Ignoring METHOD {} from leg {} in state {:?}",
+                        method_or_code, leg_type, call.call_state
+                    );
+                    answer_unhandled(req, 405, &message.client_addr, sender);
                 }
-                STATUS_CODE => {
-                    if leg_type == B_LEG {
-                        match method_or_code.parse::<u16>() {
-                            Ok(code) => match code {
-                                100 => { /* Ignore 100 Trying */ }
-                                180 => {
-                                    // Ringing
-                                    println!("  Processing 180 Ringing from B leg");
-                                    // Action 2
-                                    // 1. Forward 180 Ringing to A leg
-                                    // Corrected format! usage
-                                    let ringing_180_a = format!(
-                                        "SIP/2.0 180 Ringing\r\n\
-                                    {}\
-                                    {}\r\n\
-                                    {}\r\n\
-                                    Call-ID: {}\r\n\
-                                    {}\r\n\
-                                    Contact: <sip:TinySIP@{}:{}>\r\n\
-                                    User-Agent: TinySIP-Rust\r\n\
-                                    {}\r\n", // Placeholder for potential SDP/Content-Length
-                                        call.a_leg_header.via,
-                                        call.a_leg_header.from,
-                                        call.a_leg_header.to,
-                                        call.a_leg_uuid,
-                                        call.a_leg_header.cseq,
-                                        SIP_SERVER_IP_ADDRESS,
-                                        SIP_PORT,
-                                        // Pass through SDP if present in 180? Usually not.
-                                        if let Some(sdp) = get_sdp_body(raw_sip_message) {
-                                            format!("Content-Type: application/sdp\r\nContent-Length: {}\r\n\r\n{}", sdp.len(), sdp)
-                                        } else {
-                                            "Content-Length: 0\r\n\r\n".to_string()
-                                        }
-                                    );
-                                    send_if_addr(
-                                        socket,
-                                        call.a_leg_addr,
-                                        &ringing_180_a,
-                                        "180 Ringing not sent to A leg",
-                                    );
-
-                                    // 2. Update media state if SDP present in 180 (less common)
-                                    if has_sdp {
-                                        call.a_leg_media.local_media = true; // Server has A's perspective
-                                        call.b_leg_media.remote_media = true; // Server has B's perspective
-                                    }
-
-                                    // 3. Set state to Ringing
-                                    call.call_state = CallState::Ringing;
-                                    println!(
-                                        "  Call {} state transitioned to RINGING.",
-                                        call.index
-                                    );
-                                }
-                                183 => {
-                                    // Session Progress
-                                    println!("  Processing 183 Session Progress from B leg");
-                                    // Action: Forward 183 to A leg
-                                    // Corrected format! usage
-                                    let progress_183_a = format!(
-                                        "SIP/2.0 183 Session Progress\r\n\
-                                    {}\
-                                    {}\r\n\
-                                    {}\r\n\
-                                    Call-ID: {}\r\n\
-                                    {}\r\n\
-                                    Contact: <sip:TinySIP@{}:{}>\r\n\
-                                    User-Agent: TinySIP-Rust\r\n\
-                                    {}\r\n", // Placeholder for potential SDP/Content-Length
-                                        call.a_leg_header.via,
-                                        call.a_leg_header.from,
-                                        call.a_leg_header.to,
-                                        call.a_leg_uuid,
-                                        call.a_leg_header.cseq,
-                                        SIP_SERVER_IP_ADDRESS,
-                                        SIP_PORT,
-                                        // Pass through SDP if present in 183
-                                        if let Some(sdp) = get_sdp_body(raw_sip_message) {
-                                            call.a_leg_media.local_media = true;
-                                            call.b_leg_media.remote_media = true;
-                                            format!("Content-Type: application/sdp\r\nContent-Length: {}\r\n\r\n{}", sdp.len(), sdp)
-                                        } else {
-                                            "Content-Length: 0\r\n\r\n".to_string()
-                                        }
-                                    );
-                                    send_if_addr(
-                                        socket,
-                                        call.a_leg_addr,
-                                        &progress_183_a,
-                                        "183 Session Progress not sent to A leg",
-                                    );
-                                    // State remains Routing or Ringing
-                                }
-                                200..=299 => {
-                                    // 2xx Success (typically 200 OK for INVITE)
-                                    println!("  Processing 200 OK from B leg");
-                                    // Action 3
-                                    // Extract B-leg Contact for future use (e.g. Re-INVITE, BYE)
-                                    if let Some(start_idx) = contact_header.find('<') {
-                                        if let Some(end_idx) = contact_header.find('>') {
-                                            if start_idx < end_idx {
-                                                call.b_leg_contact = contact_header
-                                                    [start_idx + 1..end_idx]
-                                                    .to_string();
-                                            }
-                                        }
-                                    } else if let Some(stripped) =
-                                        contact_header.strip_prefix("Contact:").map(|s| s.trim())
-                                    {
-                                        call.b_leg_contact = stripped.to_string();
-                                    }
-                                    println!("  Extracted B-leg Contact: {}", call.b_leg_contact);
-
-                                    // 1. Forward 200 OK to A leg
-                                    // Corrected format! usage
-                                    let ok_200_a = format!(
-                                        "SIP/2.0 200 OK\r\n\
-                                    {}\
-                                    {}\r\n\
-                                    {}\r\n\
-                                    Call-ID: {}\r\n\
-                                    {}\r\n\
-                                    Contact: <sip:TinySIP@{}:{}>\r\n\
-                                    User-Agent: TinySIP-Rust\r\n\
-                                    {}\r\n", // Placeholder for potential SDP/Content-Length
-                                        call.a_leg_header.via,
-                                        call.a_leg_header.from,
-                                        call.a_leg_header.to,
-                                        call.a_leg_uuid,
-                                        call.a_leg_header.cseq,
-                                        SIP_SERVER_IP_ADDRESS,
-                                        SIP_PORT,
-                                        // Pass through SDP if present in 200 OK
-                                        if let Some(sdp) = get_sdp_body(raw_sip_message) {
-                                            call.a_leg_media.local_media = true;
-                                            call.b_leg_media.remote_media = true;
-                                            format!("Content-Type: application/sdp\r\nContent-Length: {}\r\n\r\n{}", sdp.len(), sdp)
-                                        } else {
-                                            "Content-Length: 0\r\n\r\n".to_string()
-                                        }
-                                    );
-                                    send_if_addr(
-                                        socket,
-                                        call.a_leg_addr,
-                                        &ok_200_a,
-                                        "200 OK not sent to A leg",
-                                    );
-
-                                    // 2. Set state to Answered
-                                    call.call_state = CallState::Answered;
-                                    println!(
-                                        "  Call {} state transitioned to ANSWERED.",
-                                        call.index
-                                    );
-                                }
-                                300..=699 => {
-                                    // Failure Response from B leg
-                                    println!("  Processing Failure Code {} from B leg", code);
-                                    // Action 7
-                                    // 1. Send ACK to B leg for the failure response
-                                    if let Some(b_addr) = call.b_leg_addr {
-                                        if let Some(b_cseq_val) =
-                                            extract_cseq_number(&call.b_leg_header.cseq)
-                                        {
-                                            // Corrected format! usage
-                                            let b_ack = format!(
-                                                "ACK sip:{}@{} SIP/2.0\r\n\
-                                            Via: SIP/2.0/UDP {}:{};branch=z9hG4bKack{}\r\n\
-                                            {}\r\n\
-                                            {}\r\n\
-                                            Call-ID: {}\r\n\
-                                            CSeq: {} ACK\r\n\
-                                            Max-Forwards: {}\r\n\
-                                            User-Agent: TinySIP-Rust\r\n\
-                                            Content-Length: 0\r\n\r\n",
-                                                call.callee,
-                                                b_addr, // B leg target URI
-                                                SIP_SERVER_IP_ADDRESS,
-                                                SIP_PORT,
-                                                b_cseq_val, // Unique branch for ACK
-                                                call.b_leg_header.from, // B leg From
-                                                call.b_leg_header.to, // B leg To (potentially updated from response)
-                                                call.b_leg_uuid,      // B leg Call ID
-                                                b_cseq_val,           // Match B's INVITE CSeq num
-                                                DEFAULT_MAX_FORWARDS
-                                            );
-                                            send_sip_message(socket, b_ack.as_bytes(), &b_addr);
-                                        } else {
-                                            eprintln!("  Missing B-leg CSeq while acknowledging failure for call {}", call.index);
-                                        }
-                                    } else {
-                                        eprintln!("  Missing B-leg address while acknowledging failure for call {}", call.index);
-                                    }
-
-                                    // 2. Forward the failure response to A leg
-                                    // Corrected format! usage
-                                    let failure_a = format!(
-                                        "SIP/2.0 {}\r\n\
-                                    {}\
-                                    {}\r\n\
-                                    {}\r\n\
-                                    Call-ID: {}\r\n\
-                                    {}\r\n\
-                                    User-Agent: TinySIP-Rust\r\n\
-                                    Content-Length: 0\r\n\r\n",
-                                        method_or_code, // The failure code and reason phrase from B
-                                        call.a_leg_header.via,
-                                        call.a_leg_header.from,
-                                        call.a_leg_header.to,
-                                        call.a_leg_uuid,
-                                        call.a_leg_header.cseq
-                                    );
-                                    send_if_addr(
-                                        socket,
-                                        call.a_leg_addr,
-                                        &failure_a,
-                                        "Failure response not forwarded to A leg",
-                                    );
-
-                                    // 3. Set state back to Idle (release call)
-                                    call.is_active = false;
-                                    println!(
-                                        "  Call {} state transitioned back to IDLE due to failure.",
-                                        call.index
-                                    );
-                                }
-                                _ => {
-                                    println!(
-                                        "  Ignoring unhandled status code {} from B leg.",
-                                        code
-                                    );
-                                }
-                            },
-                            Err(err) => {
-                                eprintln!(
-                                    "  Unable to parse status code '{}' from B leg ({})",
-                                    method_or_code, err
-                                );
-                            }
-                        }
-                    } else {
-                        // Status code from A leg? Unexpected in these states.
-                        println!(
-                            "  Ignoring STATUS {} from A leg in state {:?}",
-                            method_or_code, call.call_state
-                        );
-                    }
+            } else if let Some(resp) = response {
+                if leg_type != B_LEG {
+                    // Status code from A leg? Unexpected in these states.
+                    println!(
+                        "  Ignoring STATUS {} from A leg in state {:?}",
+                        method_or_code, call.call_state
+                    );
+                    return;
                 }
-                _ => {
-                    // Should not happen
+                if resp.cseq().is_none_or(|(_, method)| method != "INVITE") {
+                    // e.g. the 200 to a PRACK we sent ourselves
                     println!(
-                        "  Invalid message_type {} in state {:?}",
-                        message_type, call.call_state
+                        "  Ignoring STATUS {} for {} from B leg",
+                        method_or_code,
+                        resp.cseq().map(|(_, method)| method).unwrap_or_default()
                     );
+                    return;
+                }
+                if (101..200).contains(&resp.status_code)
+                    && !accept_b_provisional(call, resp, sender)
+                {
+                    println!("  Dropping repeated reliable provisional from B leg");
+                    return;
+                }
+                match resp.status_code {
+                    100 => { /* Ignore 100 Trying */ }
+                    180 => {
+                        // Ringing
+                        println!("  Processing 180 Ringing from B leg");
+                        // Action 2
+                        // 1. Forward 180 Ringing to A leg
+                        relay_response_to_a(call, resp, sender);
+
+                        // 2. Update media state if SDP present in 180 (less common)
+                        if let Some(sdp) = sdp_body {
+                            track_relayed_sdp(call, B_LEG, sdp);
+                        }
+
+                        // 3. Set state to Ringing
+                        call.call_state = CallState::Ringing;
+                        println!("  Call {} state transitioned to RINGING.", call.index);
+                    }
+                    101..=199 => {
+                        // Session Progress and other provisional responses
+                        println!(
+                            "  Processing {} {} from B leg",
+                            resp.status_code, resp.reason
+                        );
+                        // Action: Forward to A leg
+                        relay_response_to_a(call, resp, sender);
+                        if let Some(sdp) = sdp_body {
+                            track_relayed_sdp(call, B_LEG, sdp);
+                        }
+                        // State remains Routing or Ringing
+                    }
+                    200..=299 => {
+                        // 2xx Success (typically 200 OK for INVITE)
+                        println!("  Processing 200 OK from B leg");
+                        // Action 3
+                        // B-leg Contact was stored as the dialog's remote target
+                        // for future use (e.g. Re-INVITE, BYE)
+                        println!("  B-leg remote target: {}", call.b_leg_dialog.remote_target);
+
+                        // 1. Forward 200 OK to A leg; this confirms the A-leg dialog.
+                        // It carries the session interval negotiated with B.
+                        start_session_timer(call, resp);
+                        relay_response_to_a(call, resp, sender);
+
+                        // The first 2xx wins; the other branches are cancelled
+                        cancel_forks(call, Some(call.active_fork), sender);
+                        call.forks.clear();
+                        call.a_leg_dialog.state = DialogState::Confirmed;
+                        if let Some(sdp) = sdp_body {
+                            track_relayed_sdp(call, B_LEG, sdp);
+                        }
+
+                        // 2. Set state to Answered
+                        call.call_state = CallState::Answered;
+                        println!("  Call {} state transitioned to ANSWERED.", call.index);
+                    }
+                    _ => {
+                        // Failure Response from B leg
+                        println!("  Processing Failure Code {} from B leg", resp.status_code);
+                        // Action 7
+                        // 1. The B-leg INVITE transaction has already sent the ACK
+                        // 2. Once no branch is left, forward the failure response
+                        // to A leg
+                        if !fail_fork(call, call.active_fork, resp.clone(), sender) {
+                            return;
+                        }
+
+                        // 3. Set state back to Idle (release call)
+                        call.is_active = false;
+                        println!(
+                            "  Call {} state transitioned back to IDLE due to failure.",
+                            call.index
+                        );
+                    }
                 }
             }
         }
@@ -830,43 +1161,36 @@ fn handle_state_machine(
             println!("  Current State: ANSWERED");
             if message_type == REQUEST_METHOD && method_or_code == "ACK" && leg_type == A_LEG {
                 println!("  Processing ACK from A leg");
+                if let Some(sdp) = sdp_body {
+                    track_relayed_sdp(call, A_LEG, sdp);
+                }
                 // Action 4
                 // 1. Forward ACK to B leg
-                if let Some(b_addr) = call.b_leg_addr {
-                    if let Some(b_cseq_val) = extract_cseq_number(&call.b_leg_header.cseq) {
-                        // Corrected format! usage
-                        let b_ack = format!(
-                            "ACK {} SIP/2.0\r\n\
-                            Via: SIP/2.0/UDP {}:{};branch=z9hG4bKackB{}\r\n\
-                            {}\r\n\
-                            {}\r\n\
-                            Call-ID: {}\r\n\
-                            CSeq: {} ACK\r\n\
-                            Max-Forwards: {}\r\n\
-                            User-Agent: TinySIP-Rust\r\n\
-                            Content-Length: 0\r\n\r\n", // ACK has no body
-                            call.b_leg_contact, // Target URI from B's Contact in 200 OK
-                            SIP_SERVER_IP_ADDRESS,
-                            SIP_PORT,
-                            b_cseq_val,             // Unique branch
-                            call.b_leg_header.from, // B leg From
-                            call.b_leg_header.to,   // B leg To
-                            call.b_leg_uuid,        // B leg Call ID
-                            b_cseq_val,             // Match B's INVITE CSeq num
-                            DEFAULT_MAX_FORWARDS
-                        );
-                        send_sip_message(socket, b_ack.as_bytes(), &b_addr);
-                    } else {
+                match (
+                    call.b_leg_dialog.next_hop().or(call.b_leg_addr),
+                    &call.b_leg_invite,
+                ) {
+                    (_, None) => {
+                        // A picked-up call: we answered the B-leg phone
+                        // ourselves, and it acknowledges that on its own
+                    }
+                    (Some(b_addr), Some(b_invite)) => {
+                        let b_cseq_val = b_invite.cseq().map(|(n, _)| n).unwrap_or(1);
+                        // Built from the B-leg dialog; matches B's INVITE CSeq num
+                        let mut b_ack = call.b_leg_dialog.ack(b_cseq_val);
+
+                        // ACK carries the answer when the 2xx carried the offer
+                        if let Some(sdp) = sdp_body {
+                            b_ack = b_ack.body("application/sdp", sdp);
+                        }
+                        sender.request(&b_ack.build(), &b_addr);
+                    }
+                    _ => {
                         eprintln!(
-                            "  Missing B-leg CSeq while forwarding ACK for call {}",
+                            "  Missing B-leg address while forwarding ACK for call {}",
                             call.index
                         );
                     }
-                } else {
-                    eprintln!(
-                        "  Missing B-leg address while forwarding ACK for call {}",
-                        call.index
-                    );
                 }
 
                 // 2. Set state to Connected
@@ -878,27 +1202,97 @@ fn handle_state_machine(
                     "  Received BYE from leg {} in ANSWERED state (before ACK). Processing BYE.",
                     leg_type
                 );
-                handle_bye(call, message, raw_sip_message, leg_type, socket);
+                handle_bye(call, message, parsed, leg_type, sender);
+            } else if message_type == REQUEST_METHOD && method_or_code == "UPDATE" {
+                if let Some(req) = request {
+                    relay_in_dialog_request(call, req, message.client_addr, leg_type, &[], sender);
+                }
             } else {
                 println!(
                     "  Ignoring message type {} code/method {} from leg {} in ANSWERED state.",
                     message_type, method_or_code, leg_type
                 );
+                if let Some(req) = request {
+                    answer_unhandled(req, 405, &message.client_addr, sender);
+                }
             }
         }
 
         CallState::Connected => {
             println!("  Current State: CONNECTED");
-            if message_type == REQUEST_METHOD && method_or_code == "BYE" {
+            let transferee_leg = call
+                .transfer
+                .as_ref()
+                .filter(|transfer| transfer.answer.is_some())
+                .map(|transfer| other_leg(transfer.transferor));
+            if message_type == REQUEST_METHOD
+                && method_or_code == "BYE"
+                && call
+                    .transfer
+                    .as_ref()
+                    .is_some_and(|transfer| transfer.transferor == leg_type)
+            {
+                // A blind transferor usually hangs up once its REFER is
+                // accepted; the transferee stays for the target
+                println!("  Transferor on leg {} hung up", leg_type);
+                if let Some(req) = request {
+                    let ok_200_bye = ResponseBuilder::from_request(req, 200).build();
+                    sender.response(&ok_200_bye, &message.client_addr);
+                }
+                if let Some(transfer) = call.transfer.as_mut() {
+                    transfer.transferor_gone = true;
+                }
+            } else if message_type == REQUEST_METHOD && method_or_code == "BYE" {
                 println!("  Processing BYE from leg {}", leg_type);
-                handle_bye(call, message, raw_sip_message, leg_type, socket);
-            }
-            // Handle re-INVITE, UPDATE, INFO etc. here if needed
-            else {
+                handle_bye(call, message, parsed, leg_type, sender);
+            } else if let Some(req) = request.filter(|req| req.method == "REFER") {
+                handle_refer(call, req, message.client_addr, leg_type, sender);
+            } else if let Some(resp) = response.filter(|resp| {
+                Some(leg_type) == transferee_leg
+                    && resp.cseq().is_some_and(|(_, method)| method == "INVITE")
+            }) {
+                // The transferee's answer to the target's session
+                handle_transferee_answer(call, resp, sender);
+            } else if let Some(req) = request.filter(|req| req.method == "INFO") {
+                println!("  Relaying INFO from leg {}", leg_type);
+                record_dtmf(call, req, leg_type);
+                relay_in_dialog_request(call, req, message.client_addr, leg_type, &[], sender);
+            } else if let Some(req) =
+                request.filter(|req| req.method == "OPTIONS" || req.method == "MESSAGE")
+            {
+                // The peer phone answers for its own capabilities, and takes
+                // chat sent within the call
+                println!("  Relaying {} from leg {}", req.method, leg_type);
+                let accept = req.headers.get("Accept").map(|accept| ("Accept", accept));
+                let extra_headers = accept.as_slice();
+                relay_in_dialog_request(
+                    call,
+                    req,
+                    message.client_addr,
+                    leg_type,
+                    extra_headers,
+                    sender,
+                );
+            } else if let Some(req) =
+                request.filter(|req| req.method == "INVITE" || req.method == "UPDATE")
+            {
+                // re-INVITE / UPDATE: hold, resume, codec change, target
+                // refresh, session refresh
+                println!("  Relaying {} from leg {}", req.method, leg_type);
+                relay_in_dialog_request(call, req, message.client_addr, leg_type, &[], sender);
+            } else if let Some(resp) =
+                response.filter(|resp| resp.cseq().is_some_and(|(_, method)| method == "UPDATE"))
+            {
+                // Response to a session refresh the server sent itself
+                handle_refresh_response(call, resp, leg_type, sender);
+            } else {
                 println!(
                     "  Ignoring message type {} code/method {} from leg {} in CONNECTED state.",
                     message_type, method_or_code, leg_type
                 );
+                if let Some(req) = request {
+                    answer_unhandled(req, 405, &message.client_addr, sender);
+                }
             }
         }
 
@@ -906,8 +1300,8 @@ fn handle_state_machine(
             println!("  Current State: DISCONNECTING");
             if message_type == STATUS_CODE && method_or_code == "200" {
                 // Check if it's 200 OK for BYE or CANCEL
-                if let Some(cseq_val) = get_cseq_header(raw_sip_message) {
-                    if cseq_val.contains("BYE") || cseq_val.contains("CANCEL") {
+                if let Some((_, cseq_method)) = parsed.cseq() {
+                    if cseq_method == "BYE" || cseq_method == "CANCEL" {
                         println!(
                             "  Received 200 OK for BYE/CANCEL from leg {}. Call cleanup.",
                             leg_type
@@ -926,6 +1320,9 @@ fn handle_state_machine(
                     "  Ignoring message type {} code/method {} from leg {} in DISCONNECTING state.",
                     message_type, method_or_code, leg_type
                 );
+                if let Some(req) = request {
+                    answer_unhandled(req, 481, &message.client_addr, sender);
+                }
             }
         }
     }
@@ -935,132 +1332,1793 @@ fn handle_state_machine(
 fn handle_bye(
     call: &mut Call,
     message: &SipMessage,
-    raw_sip_message: &str,
+    parsed: &ParsedMessage,
     leg_type: i32,
-    socket: &Arc<UdpSocket>,
+    sender: &mut SipSender,
 ) {
     // Action 5
     // 1. Send 200 OK for BYE to the sender
-    let via_header = get_via_header(raw_sip_message).unwrap_or_default();
-    let from_header = get_from_header(raw_sip_message).unwrap_or_default();
-    let to_header = get_to_header(raw_sip_message).unwrap_or_default();
-    let call_id_header = get_call_id(raw_sip_message).unwrap_or_default();
-    let cseq_header = get_cseq_header(raw_sip_message).unwrap_or_default();
-
-    // Corrected format! usage
-    let ok_200_bye = format!(
-        "SIP/2.0 200 OK\r\n\
-        {}\r\n\
-        {}\r\n\
-        {}\r\n\
-        Call-ID: {}\r\n\
-        {}\r\n\
-        User-Agent: TinySIP-Rust\r\n\
-        Content-Length: 0\r\n\r\n",
-        via_header,     // Via from BYE
-        from_header,    // From from BYE
-        to_header,      // To from BYE
-        call_id_header, // Call-ID from BYE
-        cseq_header     // CSeq from BYE
-    );
-    // Send response back to the source of the BYE
-    send_sip_message(socket, ok_200_bye.as_bytes(), &message.client_addr);
+    if let ParsedMessage::Request(bye) = parsed {
+        let ok_200_bye = ResponseBuilder::from_request(bye, 200).build();
+        // Send response back to the source of the BYE
+        sender.response(&ok_200_bye, &message.client_addr);
+    }
+
+    // A transfer in progress is called off; with the transferor gone too,
+    // nobody is left
+    let transferor_gone = call
+        .transfer
+        .as_ref()
+        .is_some_and(|transfer| transfer.transferor_gone);
+    abandon_transfer(call, sender);
+    if transferor_gone {
+        call.is_active = false;
+        println!("  Call {} state transitioned to IDLE.", call.index);
+        return;
+    }
 
     // 2. Construct and send BYE to the *other* leg
-    let (target_addr, bye_other_leg) = if leg_type == A_LEG {
-        if let Some(b_addr) = call.b_leg_addr {
-            let b_cseq_num = next_cseq();
-            let b_branch = monotonic_millis();
-            let payload = format!(
-                "BYE {} SIP/2.0\r\n\
-                Via: SIP/2.0/UDP {}:{};branch=z9hG4bKbyeB{}\r\n\
-                {}\r\n\
-                {}\r\n\
-                Call-ID: {}\r\n\
-                CSeq: {} BYE\r\n\
-                Max-Forwards: {}\r\n\
-                User-Agent: TinySIP-Rust\r\n\
-                Content-Length: 0\r\n\r\n",
-                call.b_leg_contact, // Target B using its Contact URI
-                SIP_SERVER_IP_ADDRESS,
-                SIP_PORT,
-                b_branch,               // Server's Via
-                call.b_leg_header.from, // Stored B-leg From
-                call.b_leg_header.to,   // Stored B-leg To
-                call.b_leg_uuid,        // Stored B-leg Call-ID
-                b_cseq_num,             // New CSeq for BYE
-                DEFAULT_MAX_FORWARDS
+    send_bye(call, if leg_type == A_LEG { B_LEG } else { A_LEG }, sender);
+
+    // 3. Set state to Disconnecting
+    call.call_state = CallState::Disconnecting;
+    println!("  Call {} state transitioned to DISCONNECTING.", call.index);
+}
+
+// Sends a BYE on the given leg of the call, built from that leg's dialog.
+// It follows the dialog's route set if there is one.
+fn send_bye(call: &mut Call, to_leg: i32, sender: &mut SipSender) {
+    let (dialog, leg_addr) = if to_leg == B_LEG {
+        (&mut call.b_leg_dialog, call.b_leg_addr)
+    } else {
+        (&mut call.a_leg_dialog, call.a_leg_addr)
+    };
+    let target_addr = dialog.next_hop().or(leg_addr);
+    let bye = dialog.request("BYE").build();
+
+    match target_addr {
+        Some(addr) => sender.request(&bye, &addr),
+        None => eprintln!(
+            "  Missing leg {} address while sending BYE for call {}",
+            to_leg, call.index
+        ),
+    }
+}
+
+// A client transaction of this call timed out without a final response
+// (Timer B or F).
+fn handle_transaction_timeout(
+    call: &mut Call,
+    request: &SipRequest,
+    leg_type: i32,
+    sender: &mut SipSender,
+) {
+    println!(
+        "  {} on leg {} of call {} timed out in state {:?}",
+        request.method, leg_type, call.index, call.call_state
+    );
+    if let Some(position) = find_relayed(call, request, leg_type) {
+        // The other leg never answered a request we relayed: 408 to its sender
+        let pending = call.relayed_requests.remove(position);
+        let response_408 = ResponseBuilder::from_request(&pending.received, 408).build();
+        sender.response(&response_408, &pending.source);
+        call.a_leg_media.negotiation.rollback();
+        call.b_leg_media.negotiation.rollback();
+        return;
+    }
+    match request.method.as_str() {
+        "INVITE"
+            if leg_type == B_LEG
+                && (call.call_state == CallState::Routing
+                    || call.call_state == CallState::Ringing) =>
+        {
+            // A branch of B never answered our INVITE: fail A's INVITE once
+            // no branch is left
+            let timeout = ResponseBuilder::from_request(request, 408).build();
+            let index = fork_of(call, &timeout).unwrap_or(call.active_fork);
+            if fail_fork(call, index, timeout, sender) {
+                call.is_active = false;
+                println!("  Call {} released, callee did not respond.", call.index);
+            }
+        }
+        "INVITE" if leg_type == TRANSFER_LEG => {
+            // The transfer target never answered
+            notify_transferor(call, 408, reason_phrase(408), sender);
+            transfer_failed(call, sender);
+        }
+        "INVITE" if call.call_state == CallState::Connected && call.transfer.is_some() => {
+            // The transferee never answered the re-INVITE with the target's
+            // session
+            abandon_transfer(call, sender);
+            terminate_call(call, sender);
+        }
+        "UPDATE" if call.call_state == CallState::Connected => {
+            // Our session refresh went unanswered: that leg is gone
+            println!(
+                "  Session refresh on call {} timed out; terminating.",
+                call.index
             );
-            (Some(b_addr), payload)
-        } else {
-            eprintln!(
-                "  Missing B-leg address while forwarding BYE for call {}",
+            terminate_call(call, sender);
+        }
+        "BYE" | "CANCEL" if call.call_state == CallState::Disconnecting => {
+            // The other side is gone; nothing left to wait for
+            call.is_active = false;
+            println!("  Call {} state transitioned to IDLE.", call.index);
+        }
+        _ => {}
+    }
+}
+
+// Our 2xx to an INVITE (A's initial one or a relayed re-INVITE) was never
+// acknowledged: the session is torn down with a BYE on both legs
+// (RFC 3261 §13.3.1.4).
+fn handle_ack_timeout(call: &mut Call, sender: &mut SipSender) {
+    if call.call_state != CallState::Answered && call.call_state != CallState::Connected {
+        return;
+    }
+    println!("  No ACK for 2xx on call {}; terminating.", call.index);
+    terminate_call(call, sender);
+}
+
+// Branch of the INVITE to B that `response` answers, matched on the top Via
+// branch. With a single branch, a response that does not echo our Via is
+// still taken as its own.
+fn fork_of(call: &Call, response: &SipResponse) -> Option<usize> {
+    let branch = response.top_via().and_then(via_branch);
+    call.forks
+        .iter()
+        .position(|fork| branch.is_some() && fork.invite.top_via().and_then(via_branch) == branch)
+        .or((call.forks.len() == 1).then_some(0))
+}
+
+// Makes branch `index` the call's B leg. The B-leg fields of the branch that
+// held them are parked in its Fork first.
+fn select_fork(call: &mut Call, index: usize) {
+    if index != call.active_fork {
+        if let Some(active) = call.forks.get_mut(call.active_fork) {
+            active.dialog = call.b_leg_dialog.clone();
+            active.b_leg_rseq = call.prack.b_leg_rseq;
+        }
+    }
+    let fork = &call.forks[index];
+    call.b_leg_dialog = fork.dialog.clone();
+    call.prack.b_leg_rseq = fork.b_leg_rseq;
+    call.b_leg_addr = Some(fork.addr);
+    call.b_leg_invite = Some(fork.invite.clone());
+    call.active_fork = index;
+}
+
+// CANCELs the branches of the INVITE to B that are still pending, but
+// `except`.
+fn cancel_forks(call: &Call, except: Option<usize>, sender: &mut SipSender) {
+    for (index, fork) in call.forks.iter().enumerate() {
+        if Some(index) == except || fork.final_response.is_some() {
+            continue;
+        }
+        let cancel_b = RequestBuilder::cancel_for(&fork.invite);
+        sender.request(&cancel_b, &fork.addr);
+    }
+}
+
+// Records the failure that ended branch `index`. Once every branch has
+// failed, the hunt moves on; when nothing is left to ring, the best of their
+// responses goes to A and true is returned.
+fn fail_fork(call: &mut Call, index: usize, response: SipResponse, sender: &mut SipSender) -> bool {
+    match call.forks.get_mut(index) {
+        Some(fork) if fork.final_response.is_some() => return false,
+        Some(fork) => fork.final_response = Some(response.clone()),
+        None => {}
+    }
+    if call.forks.iter().any(|fork| fork.final_response.is_none()) {
+        println!(
+            "  Branch {} of call {} failed with {}; waiting for the others.",
+            index, call.index, response.status_code
+        );
+        return false;
+    }
+    forks_exhausted(call, sender)
+}
+
+// Every branch of the current step has ended: the next hunt step rings, or
+// the callee's busy / no-answer forward, or A gets the best response of all
+// branches and true is returned.
+fn forks_exhausted(call: &mut Call, sender: &mut SipSender) -> bool {
+    if ring_next(call, sender) {
+        return false;
+    }
+    if let Some((user, target, reason)) = failure_forward(call) {
+        if forward_call(call, &user, &target, reason, sender) {
+            return false;
+        }
+    }
+    match best_fork_response(&call.forks).cloned() {
+        Some(best) => relay_response_to_a(call, &best, sender),
+        None => respond_to_a_invite(call, 480, sender),
+    }
+    true
+}
+
+// The step ringing now was not answered within the ring group's member
+// timeout: its branches are cancelled (and count as 480) and the hunt moves
+// on. Returns true when nothing is left to ring and A has been answered.
+fn hunt_timeout(call: &mut Call, sender: &mut SipSender) -> bool {
+    println!(
+        "  No answer on call {} within the ring time; hunting on.",
+        call.index
+    );
+    call.hunt.deadline = None;
+    cancel_forks(call, None, sender);
+    for fork in call
+        .forks
+        .iter_mut()
+        .filter(|fork| fork.final_response.is_none())
+    {
+        fork.final_response = Some(ResponseBuilder::from_request(&fork.invite, 480).build());
+    }
+    forks_exhausted(call, sender)
+}
+
+// Starts ringing `number`: a ring group's hunt, or a user's contacts. A
+// user's unconditional forward applies at once; with a no-answer forward the
+// user rings only for its timeout. Returns false when nothing can be rung.
+fn ring_target(call: &mut Call, number: &str, sender: &mut SipSender) -> bool {
+    call.hunt = start_hunt(number);
+    if call.hunt.group.is_none() {
+        if let Some(forwarding) = forwarding_of(number) {
+            if let Some(target) = forwarding.unconditional {
+                return forward_call(
+                    call,
+                    number,
+                    &target,
+                    DiversionReason::Unconditional,
+                    sender,
+                );
+            }
+            if forwarding.no_answer.is_some() {
+                call.hunt.member_timeout = Some(forwarding.no_answer_timeout);
+            }
+        }
+    }
+    ring_next(call, sender)
+}
+
+// The forward that applies now that the user the call rang did not take
+// it: its busy forward when the step's best response is 486 or 600, its
+// no-answer forward on 408 or 480 (which is also what ringing out counts
+// as).
+fn failure_forward(call: &Call) -> Option<(String, String, DiversionReason)> {
+    if call.hunt.group.is_some() {
+        return None;
+    }
+    let user = call.hunt.members.first()?;
+    let forwarding = forwarding_of(user)?;
+    let step = call.forks.get(call.hunt.first_fork..)?;
+    let (target, reason) = match best_fork_response(step)?.status_code {
+        486 | 600 => (forwarding.busy?, DiversionReason::UserBusy),
+        408 | 480 => (forwarding.no_answer?, DiversionReason::NoAnswer),
+        _ => return None,
+    };
+    Some((user.clone(), target, reason))
+}
+
+// Re-targets the B leg from `user` to `target`. A forward back to a user
+// the call was already forwarded from, or one past MAX_DIVERSIONS, is not
+// taken. Returns false when the forward does not ring anything.
+fn forward_call(
+    call: &mut Call,
+    user: &str,
+    target: &str,
+    reason: DiversionReason,
+    sender: &mut SipSender,
+) -> bool {
+    if call.diversions.len() >= MAX_DIVERSIONS
+        || call
+            .diversions
+            .iter()
+            .any(|diversion| diversion.user == target)
+    {
+        println!(
+            "  Not forwarding call {} from {} to {}: forwarding loop or too many forwards.",
+            call.index, user, target
+        );
+        return false;
+    }
+    println!(
+        "  Forwarding call {} from {} to {} ({}).",
+        call.index,
+        user,
+        target,
+        reason.as_str()
+    );
+    call.diversions.push(Diversion {
+        user: user.to_string(),
+        reason,
+    });
+    ring_target(call, target, sender)
+}
+
+// Rings the next step of the call's hunt: the INVITE to B is forked to every
+// contact of the step's users (or to a SIP URI outside the server). Users
+// without a contact are skipped. Returns false when nothing is left to ring.
+fn ring_next(call: &mut Call, sender: &mut SipSender) -> bool {
+    while let Some((users, ring_time)) = call.hunt.next_step() {
+        let bindings: Vec<Binding> = users
+            .iter()
+            .flat_map(|user| match outside_binding(user) {
+                Some(binding) => vec![binding],
+                None => get_registered_bindings(user),
+            })
+            .collect();
+        if bindings.is_empty() {
+            println!("  No registered contact for {:?}; skipping.", users);
+            continue;
+        }
+        println!(
+            "  Ringing {:?} at {} contact(s) for call {}",
+            users,
+            bindings.len(),
+            call.index
+        );
+        fork_invite(call, bindings, sender);
+        call.hunt.deadline = ring_time.map(|ring_time| Instant::now() + ring_time);
+        return true;
+    }
+    false
+}
+
+// Sends the INVITE to B to all of `bindings` in parallel, a branch each. We
+// are the UAC of the B-leg dialog; B sees the caller and callee as A
+// addressed them. Later hunt steps keep our tag and go on with the CSeq.
+fn fork_invite(call: &mut Call, bindings: Vec<Binding>, sender: &mut SipSender) {
+    let a_invite = match &call.a_leg_invite {
+        Some(a_invite) => a_invite.clone(),
+        None => return,
+    };
+    let max_forwards = a_invite.max_forwards().unwrap_or(DEFAULT_MAX_FORWARDS);
+    let requested_interval = session_expires(&a_invite)
+        .map_or(DEFAULT_SESSION_EXPIRES, |se| se.delta)
+        .max(call.session_timer.min_se);
+    let mut b_leg_dialog = Dialog::uac(
+        &call.b_leg_uuid,
+        &call.a_leg_dialog.remote_uri, // A-leg From
+        &call.a_leg_dialog.local_uri,  // A-leg To
+        "",
+    );
+    if !call.forks.is_empty() {
+        b_leg_dialog.local_tag = call.b_leg_dialog.local_tag.clone();
+        b_leg_dialog.local_cseq = call
+            .forks
+            .iter()
+            .map(|fork| fork.dialog.local_cseq)
+            .fold(call.b_leg_dialog.local_cseq, u32::max);
+    }
+
+    let first = call.forks.len();
+    call.hunt.first_fork = first;
+    let diversions = diversion_headers(&call.diversions);
+    for binding in bindings {
+        let mut dialog = b_leg_dialog.clone();
+        dialog.remote_target = binding.contact;
+        let mut invite_to_b = dialog
+            .request("INVITE")
+            .max_forwards(max_forwards.saturating_sub(1))
+            .header("Record-Route", &server_route())
+            .header("Supported", &SUPPORTED_EXTENSIONS.join(", "))
+            .header("Session-Expires", &requested_interval.to_string())
+            .header("Min-SE", &call.session_timer.min_se.to_string())
+            .contact(&server_contact());
+        for diversion in &diversions {
+            invite_to_b = invite_to_b.header("Diversion", diversion);
+        }
+        if let Some(sdp) = a_invite.sdp_body() {
+            invite_to_b = invite_to_b.body("application/sdp", sdp);
+        }
+        let invite_to_b = invite_to_b.build();
+        sender.request(&invite_to_b, &binding.addr);
+        call.forks.push(Fork {
+            addr: binding.addr,
+            invite: invite_to_b,
+            dialog,
+            b_leg_rseq: None,
+            final_response: None,
+        });
+    }
+    select_fork(call, first);
+}
+
+// Response to relay when all branches failed (RFC 3261 §16.7): a 6xx if
+// there is one, else one from the lowest class.
+fn best_fork_response(forks: &[Fork]) -> Option<&SipResponse> {
+    let responses = forks.iter().filter_map(|fork| fork.final_response.as_ref());
+    responses
+        .clone()
+        .find(|resp| resp.status_code >= 600)
+        .or_else(|| responses.min_by_key(|resp| resp.status_code / 100))
+}
+
+// To-tag of a response.
+fn remote_tag(response: &SipResponse) -> Option<String> {
+    response
+        .headers
+        .get("To")
+        .and_then(|to| NameAddr::parse(to).ok())
+        .and_then(|to| to.tag().map(str::to_string))
+}
+
+// A 2xx from a branch that lost the race to answer: its dialog is
+// acknowledged and ended at once (RFC 3261 §13.2.2.4).
+fn end_losing_dialog(
+    call: &Call,
+    response: &SipResponse,
+    source: SocketAddr,
+    sender: &mut SipSender,
+) {
+    println!(
+        "  Late 2xx from another branch of call {}; sending ACK and BYE.",
+        call.index
+    );
+    let mut dialog = Dialog {
+        state: DialogState::Early,
+        remote_tag: None,
+        route_set: Vec::new(),
+        ..call.b_leg_dialog.clone()
+    };
+    dialog.update_from_response(response);
+    let invite_cseq = response.cseq().map(|(number, _)| number).unwrap_or(1);
+    dialog.local_cseq = invite_cseq;
+    let target = dialog.next_hop().unwrap_or(source);
+    sender.request(&dialog.ack(invite_cseq).build(), &target);
+    sender.request(&dialog.request("BYE").build(), &target);
+}
+
+// An INVITE with Replaces for the dialog of `leg` of a call (RFC 3891).
+// The INVITE's dialog takes that leg's place: in a connected call the old
+// leg is hung up, and a ringing B leg is picked up.
+fn handle_replacing_invite(
+    map_guard: &mut CallMap,
+    invite: &SipRequest,
+    replaces: &Replaces,
+    source: SocketAddr,
+    socket: &Arc<UdpSocket>,
+) {
+    let mut sender = SipSender {
+        socket,
+        transactions: &mut map_guard.transactions,
+    };
+    // Like any initial INVITE, it must come from the user in From; only
+    // then is the dialog it names looked at
+    let caller = from_user(invite);
+    if let Err(e) = authenticate_from(invite, &caller, &source, Instant::now()) {
+        reject_unauthenticated(invite, &caller, e, &source, &mut sender);
+        return;
+    }
+
+    let found = CallMap::find_call_by_dialog(map_guard, replaces);
+    let CallMap {
+        calls,
+        transactions,
+        ..
+    } = map_guard;
+    let mut sender = SipSender {
+        socket,
+        transactions,
+    };
+    let reject = |code: u16, sender: &mut SipSender| {
+        let response = ResponseBuilder::from_request(invite, code).build();
+        sender.response(&response, &source);
+    };
+    let (index, leg) = match found {
+        (Some(index), leg) => (index, leg),
+        _ => {
+            println!(
+                "  Replaces names no dialog we know (Call-ID [{}]); sending 481.",
+                replaces.call_id
+            );
+            reject(481, &mut sender);
+            return;
+        }
+    };
+    let call = &mut calls[index];
+    let ringing = call.call_state == CallState::Routing || call.call_state == CallState::Ringing;
+    if !may_replace(call, invite, &caller, ringing && leg == B_LEG) {
+        println!(
+            "  {} may not replace a dialog of call {}; sending 403.",
+            caller, call.index
+        );
+        reject(403, &mut sender);
+        return;
+    }
+    match call.call_state {
+        CallState::Connected if replaces.early_only => {
+            // The dialog was answered meanwhile (RFC 3891 §3)
+            reject(486, &mut sender);
+        }
+        CallState::Connected => replace_leg(call, invite, source, leg, &mut sender),
+        _ if ringing && leg == B_LEG => pick_up(call, invite, source, &mut sender),
+        // The caller's own INVITE, or a call coming or going
+        _ => reject(481, &mut sender),
+    }
+}
+
+// Whether `caller` may take over a dialog of `call` (RFC 3891 §6): a user
+// with the transfer feature who is a party to the call, was sent by one
+// (Referred-By), or picks up a ringing call with the pickup feature.
+fn may_replace(call: &Call, invite: &SipRequest, caller: &str, pickup: bool) -> bool {
+    let features = match user_features(caller) {
+        Some(features) if features.transfer => features,
+        _ => return false,
+    };
+    let a_user = call.a_leg_invite.as_ref().map(from_user);
+    let is_party = |user: &str| a_user.as_deref() == Some(user) || call.callee == user;
+    let referrer = invite
+        .headers
+        .get("Referred-By")
+        .and_then(|referred_by| NameAddr::parse(referred_by).ok())
+        .and_then(|referred_by| referred_by.user().map(str::to_string));
+    is_party(caller)
+        || referrer.is_some_and(|referrer| is_party(&referrer))
+        || (pickup && features.pickup)
+}
+
+// Makes a new dialog, created by `invite` from `source`, the call's `leg`:
+// the old one is hung up, and the new one's offer goes to the other leg in a
+// re-INVITE whose answer is the answer to `invite` (an attended transfer).
+fn replace_leg(
+    call: &mut Call,
+    invite: &SipRequest,
+    source: SocketAddr,
+    leg: i32,
+    sender: &mut SipSender,
+) {
+    let dialog = match Dialog::uas(invite, &generate_tag()) {
+        Some(dialog) => dialog,
+        None => {
+            let response_400 = ResponseBuilder::from_request(invite, 400).build();
+            sender.response(&response_400, &source);
+            return;
+        }
+    };
+    println!(
+        "  Leg {} of call {} is replaced by Call-ID [{}]",
+        leg, call.index, dialog.call_id
+    );
+    abandon_transfer(call, sender);
+    send_bye(call, leg, sender);
+    install_leg(call, leg, dialog, source);
+    call.relayed_requests.clear();
+    if call.session_timer.refresher == leg {
+        call.session_timer.refresher = 0;
+        refresh_session_timer(call);
+    }
+    relay_in_dialog_request(call, invite, source, leg, &[], sender);
+}
+
+// Directed call pickup: the ringing B leg of a call is answered by the
+// phone that sent `invite` instead. B's branches are cancelled, and the
+// caller and the new phone each get the other's session description.
+fn pick_up(call: &mut Call, invite: &SipRequest, source: SocketAddr, sender: &mut SipSender) {
+    let a_offer = call
+        .a_leg_invite
+        .as_ref()
+        .and_then(|a_invite| a_invite.sdp_body())
+        .map(str::to_string);
+    let (a_sdp, new_sdp) = match (a_offer, invite.sdp_body()) {
+        (Some(a_sdp), Some(new_sdp)) => (a_sdp, new_sdp.to_string()),
+        _ => {
+            // With a late offer on either side there is nothing to answer with
+            println!(
+                "  Pickup of call {} needs both offers; sending 488.",
                 call.index
             );
-            (None, String::new())
-        }
-    } else if let Some(a_addr) = call.a_leg_addr {
-        let a_cseq_num = next_cseq();
-        let a_branch = monotonic_millis();
-        // For BYE to A leg, From is B's original To, To is B's original From
-        let bye_a_from = call.a_leg_header.to.replace("To:", "From:"); // Swap To->From
-        let bye_a_to = call.a_leg_header.from.replace("From:", "To:"); // Swap From->To
-        let payload = format!(
-            "BYE {} SIP/2.0\r\n\
-            Via: SIP/2.0/UDP {}:{};branch=z9hG4bKbyeA{}\r\n\
-            {}\r\n\
-            {}\r\n\
-            Call-ID: {}\r\n\
-            CSeq: {} BYE\r\n\
-            Max-Forwards: {}\r\n\
-            User-Agent: TinySIP-Rust\r\n\
-            Content-Length: 0\r\n\r\n",
-            call.a_leg_contact, // Target A using its Contact URI
-            SIP_SERVER_IP_ADDRESS,
-            SIP_PORT,
-            a_branch,        // Server's Via
-            bye_a_to,        // Swapped From header
-            bye_a_from,      // Swapped To header
-            call.a_leg_uuid, // A leg Call ID
-            a_cseq_num,      // New CSeq for BYE
-            DEFAULT_MAX_FORWARDS
+            let response_488 = ResponseBuilder::from_request(invite, 488).build();
+            sender.response(&response_488, &source);
+            return;
+        }
+    };
+    let mut dialog = match Dialog::uas(invite, &generate_tag()) {
+        Some(dialog) => dialog,
+        None => {
+            let response_400 = ResponseBuilder::from_request(invite, 400).build();
+            sender.response(&response_400, &source);
+            return;
+        }
+    };
+    println!(
+        "  Call {} is picked up by Call-ID [{}]",
+        call.index, dialog.call_id
+    );
+    cancel_forks(call, None, sender);
+    call.forks.clear();
+    call.hunt = Hunt::default();
+
+    let mut ok_to_new = ResponseBuilder::from_request(invite, 200)
+        .to_tag(&dialog.local_tag)
+        .contact(&server_contact());
+    for record_route in invite.headers.get_all("Record-Route") {
+        ok_to_new = ok_to_new.header(&record_route.name, &record_route.value);
+    }
+    sender.response(&ok_to_new.body("application/sdp", &a_sdp).build(), &source);
+    dialog.state = DialogState::Confirmed;
+    install_leg(call, B_LEG, dialog, source);
+    call.b_leg_invite = None;
+
+    if let Some(ok_to_a) = a_invite_response(call, 200) {
+        let ok_to_a = ok_to_a
+            .contact(&server_contact())
+            .body("application/sdp", &new_sdp)
+            .build();
+        send_if_addr(
+            sender,
+            call.a_leg_addr,
+            &ok_to_a,
+            "200 response not sent to A leg",
         );
-        (Some(a_addr), payload)
+    }
+    call.a_leg_dialog.state = DialogState::Confirmed;
+    call.a_leg_media = MediaState::default();
+    track_relayed_sdp(call, A_LEG, &a_sdp);
+    track_relayed_sdp(call, B_LEG, &new_sdp);
+    call.call_state = CallState::Answered;
+    println!("  Call {} state transitioned to ANSWERED.", call.index);
+}
+
+// Puts a dialog we are the UAS of, created by an INVITE from `addr`, in
+// place of the call's `leg`.
+fn install_leg(call: &mut Call, leg: i32, dialog: Dialog, addr: SocketAddr) {
+    if leg == A_LEG {
+        call.a_leg_uuid = dialog.call_id.clone();
+        call.a_leg_dialog = dialog;
+        call.a_leg_addr = Some(addr);
+        call.a_leg_media = MediaState::default();
+    } else {
+        call.b_leg_uuid = dialog.call_id.clone();
+        call.b_leg_dialog = dialog;
+        call.b_leg_addr = Some(addr);
+        call.b_leg_media = MediaState::default();
+    }
+}
+
+// The leg across the call from `leg`.
+fn other_leg(leg: i32) -> i32 {
+    if leg == A_LEG {
+        B_LEG
     } else {
-        eprintln!(
-            "  Missing A-leg address while forwarding BYE for call {}",
+        A_LEG
+    }
+}
+
+// REFER from `leg` of a connected call (RFC 3515): the other leg, the
+// transferee, is to be connected to the Refer-To target. The server places
+// that call itself, without an offer, so that the target's answer can be
+// offered to the transferee in a re-INVITE (RFC 3725 §4.1).
+fn handle_refer(
+    call: &mut Call,
+    refer: &SipRequest,
+    source: SocketAddr,
+    leg: i32,
+    sender: &mut SipSender,
+) {
+    let reject = |code: u16, sender: &mut SipSender| {
+        let response = ResponseBuilder::from_request(refer, code).build();
+        sender.response(&response, &source);
+    };
+    let referrer = refer
+        .headers
+        .get("From")
+        .and_then(|from| NameAddr::parse(from).ok())
+        .and_then(|from| from.user().map(str::to_string))
+        .unwrap_or_default();
+    if user_features(&referrer).is_some_and(|features| !features.transfer) {
+        println!("  {} may not transfer calls; rejecting REFER.", referrer);
+        reject(403, sender);
+        return;
+    }
+    if call.transfer.is_some() {
+        println!(
+            "  Call {} is already being transferred; rejecting REFER.",
             call.index
         );
-        (None, String::new())
+        reject(491, sender);
+        return;
+    }
+    let mut target = match refer_target(refer) {
+        Some(target) => target,
+        None => {
+            eprintln!("  REFER without a usable Refer-To; rejecting.");
+            reject(400, sender);
+            return;
+        }
+    };
+    let binding = target
+        .user
+        .as_deref()
+        .and_then(|user| get_registered_bindings(user).into_iter().next());
+    let binding = match binding {
+        Some(binding) => binding,
+        None => {
+            println!("  Transfer target {} not found or not registered.", target);
+            reject(404, sender);
+            return;
+        }
     };
+    println!(
+        "  Leg {} of call {} transfers the other leg to {}",
+        leg, call.index, target
+    );
+    let accepted = ResponseBuilder::from_request(refer, 202)
+        .contact(&server_contact())
+        .build();
+    sender.response(&accepted, &source);
 
-    if let Some(addr) = target_addr {
-        send_sip_message(socket, bye_other_leg.as_bytes(), &addr);
+    // The target sees the transferee calling
+    let transferee = if leg == A_LEG {
+        &call.b_leg_dialog
+    } else {
+        &call.a_leg_dialog
+    };
+    target.headers.clear();
+    let uuid = generate_call_id();
+    let mut dialog = Dialog::uac(
+        &uuid,
+        &transferee.remote_uri,
+        &format!("<{}>", target),
+        &binding.contact,
+    );
+    let mut invite = dialog
+        .request("INVITE")
+        .header("Record-Route", &server_route())
+        .contact(&server_contact());
+    if let Some(referred_by) = refer.headers.get("Referred-By") {
+        invite = invite.header("Referred-By", referred_by);
     }
+    let invite = invite.build();
+    sender.request(&invite, &binding.addr);
+    call.transfer = Some(Transfer {
+        transferor: leg,
+        refer_cseq: refer.cseq().map(|(number, _)| number).unwrap_or(0),
+        transferor_gone: false,
+        uuid,
+        dialog,
+        addr: binding.addr,
+        invite,
+        answer: None,
+    });
+    notify_transferor(call, 100, reason_phrase(100), sender);
+}
 
-    // 3. Set state to Disconnecting
+// Reports the status of the call to the transfer target to the transferor
+// in a NOTIFY of the REFER's implicit subscription. A final status ends the
+// subscription.
+fn notify_transferor(call: &mut Call, code: u16, reason: &str, sender: &mut SipSender) {
+    let (transferor, refer_cseq) = match &call.transfer {
+        Some(transfer) if !transfer.transferor_gone => (transfer.transferor, transfer.refer_cseq),
+        _ => return,
+    };
+    let (dialog, leg_addr) = if transferor == A_LEG {
+        (&mut call.a_leg_dialog, call.a_leg_addr)
+    } else {
+        (&mut call.b_leg_dialog, call.b_leg_addr)
+    };
+    let subscription_state = if code >= 200 {
+        "terminated;reason=noresource".to_string()
+    } else {
+        format!("active;expires={}", REFER_NOTIFY_EXPIRES)
+    };
+    let notify = dialog
+        .request("NOTIFY")
+        .header("Event", &format!("refer;id={}", refer_cseq))
+        .header("Subscription-State", &subscription_state)
+        .contact(&server_contact())
+        .body("message/sipfrag;version=2.0", &sipfrag(code, reason))
+        .build();
+    match dialog.next_hop().or(leg_addr) {
+        Some(addr) => sender.request(&notify, &addr),
+        None => eprintln!(
+            "  Missing leg {} address while notifying transfer progress on call {}",
+            transferor, call.index
+        ),
+    }
+}
+
+// A message on the call to the transfer target. Its provisional and final
+// responses are reported to the transferor; its 2xx is offered to the
+// transferee. Requests from the target before it is bridged end the
+// transfer (BYE) or are refused.
+fn handle_transfer_leg(
+    call: &mut Call,
+    parsed: &ParsedMessage,
+    source: SocketAddr,
+    sender: &mut SipSender,
+) {
+    let resp = match parsed {
+        ParsedMessage::Request(req) if req.method == "ACK" => return,
+        ParsedMessage::Request(req) => {
+            let code = if req.method == "BYE" { 200 } else { 481 };
+            let response = ResponseBuilder::from_request(req, code).build();
+            sender.response(&response, &source);
+            if req.method == "BYE" {
+                println!("  Transfer target hung up before being connected");
+                notify_transferor(call, 487, reason_phrase(487), sender);
+                call.transfer = None;
+            }
+            return;
+        }
+        ParsedMessage::Response(resp) => resp,
+    };
+    if resp.cseq().is_none_or(|(_, method)| method != "INVITE") {
+        return;
+    }
+    let transfer = match call.transfer.as_mut() {
+        Some(transfer) if transfer.answer.is_none() => transfer,
+        // Retransmitted 2xx while the transferee answers our re-INVITE
+        _ => return,
+    };
+    let code = resp.status_code;
+    println!(
+        "  Transfer target answered {} {} on call {}",
+        code, resp.reason, call.index
+    );
+    transfer.dialog.update_from_response(resp);
+    match code {
+        100 => {}
+        101..=199 => notify_transferor(call, code, &resp.reason, sender),
+        200..=299 => {
+            let offer = match resp.sdp_body() {
+                Some(offer) => offer.to_string(),
+                None => {
+                    // Nothing to offer the transferee (RFC 3261 §13.2.1)
+                    eprintln!("  Transfer target answered without an offer; hanging up.");
+                    transfer.answer = Some(resp.clone());
+                    hang_up_target(transfer, sender);
+                    notify_transferor(call, 488, reason_phrase(488), sender);
+                    transfer_failed(call, sender);
+                    return;
+                }
+            };
+            transfer.answer = Some(resp.clone());
+            let transferee = other_leg(transfer.transferor);
+            notify_transferor(call, code, &resp.reason, sender);
+
+            // The transferee gets the target's session
+            let (dialog, leg_addr) = if transferee == A_LEG {
+                (&mut call.a_leg_dialog, call.a_leg_addr)
+            } else {
+                (&mut call.b_leg_dialog, call.b_leg_addr)
+            };
+            let reinvite = dialog
+                .request("INVITE")
+                .contact(&server_contact())
+                .body("application/sdp", &offer)
+                .build();
+            match dialog.next_hop().or(leg_addr) {
+                Some(addr) => sender.request(&reinvite, &addr),
+                None => eprintln!(
+                    "  Missing leg {} address while re-INVITing the transferee of call {}",
+                    transferee, call.index
+                ),
+            }
+        }
+        _ => {
+            // The INVITE transaction has already sent the ACK
+            notify_transferor(call, code, &resp.reason, sender);
+            transfer_failed(call, sender);
+        }
+    }
+}
+
+// The transferee answered the re-INVITE offering the target's session. On
+// a 2xx both get their ACK (the target's carries the transferee's answer),
+// the transferor is released and the target's call takes its place.
+fn handle_transferee_answer(call: &mut Call, resp: &SipResponse, sender: &mut SipSender) {
+    if resp.status_code < 200 {
+        return;
+    }
+    let transfer = match call.transfer.take() {
+        Some(transfer) => transfer,
+        None => return,
+    };
+    let transferor = transfer.transferor;
+    let transferee = other_leg(transferor);
+    if resp.status_code >= 300 {
+        println!(
+            "  Transferee of call {} refused the target's session ({}).",
+            call.index, resp.status_code
+        );
+        hang_up_target(&transfer, sender);
+        call.transfer = Some(transfer);
+        transfer_failed(call, sender);
+        return;
+    }
+
+    let (dialog, leg_addr) = if transferee == A_LEG {
+        (&mut call.a_leg_dialog, call.a_leg_addr)
+    } else {
+        (&mut call.b_leg_dialog, call.b_leg_addr)
+    };
+    dialog.update_from_response(resp);
+    let reinvite_cseq = resp.cseq().map(|(number, _)| number).unwrap_or(1);
+    if let Some(addr) = dialog.next_hop().or(leg_addr) {
+        sender.request(&dialog.ack(reinvite_cseq).build(), &addr);
+    }
+    let invite_cseq = transfer
+        .invite
+        .cseq()
+        .map(|(number, _)| number)
+        .unwrap_or(1);
+    let mut ack_to_target = transfer.dialog.ack(invite_cseq);
+    if let Some(sdp) = resp.sdp_body() {
+        ack_to_target = ack_to_target.body("application/sdp", sdp);
+    }
+    let target_addr = transfer.dialog.next_hop().unwrap_or(transfer.addr);
+    sender.request(&ack_to_target.build(), &target_addr);
+    if !transfer.transferor_gone {
+        send_bye(call, transferor, sender);
+    }
+
+    println!(
+        "  Call {}: leg {} is now the transfer target.",
+        call.index, transferor
+    );
+    if transferor == A_LEG {
+        call.a_leg_uuid = transfer.uuid;
+        call.a_leg_dialog = transfer.dialog;
+        call.a_leg_addr = Some(transfer.addr);
+        call.a_leg_media = MediaState::default();
+    } else {
+        call.b_leg_uuid = transfer.uuid;
+        call.b_leg_dialog = transfer.dialog;
+        call.b_leg_addr = Some(transfer.addr);
+        call.b_leg_invite = Some(transfer.invite);
+        call.b_leg_media = MediaState::default();
+    }
+    call.relayed_requests.clear();
+    if let Some(offer) = transfer
+        .answer
+        .as_ref()
+        .and_then(|answer| answer.sdp_body())
+    {
+        track_relayed_sdp(call, transferor, offer);
+    }
+    if let Some(answer) = resp.sdp_body() {
+        track_relayed_sdp(call, transferee, answer);
+    }
+    // The target knows nothing of the session timer the transferor ran
+    if call.session_timer.refresher == transferor {
+        call.session_timer.refresher = 0;
+        refresh_session_timer(call);
+    }
+}
+
+// Ends the call to the transfer target: CANCEL while it has not answered,
+// ACK and BYE once it has.
+fn hang_up_target(transfer: &Transfer, sender: &mut SipSender) {
+    if transfer.answer.is_none() {
+        sender.request(
+            &RequestBuilder::cancel_for(&transfer.invite),
+            &transfer.addr,
+        );
+        return;
+    }
+    let mut dialog = transfer.dialog.clone();
+    let invite_cseq = transfer
+        .invite
+        .cseq()
+        .map(|(number, _)| number)
+        .unwrap_or(1);
+    let target = dialog.next_hop().unwrap_or(transfer.addr);
+    sender.request(&dialog.ack(invite_cseq).build(), &target);
+    sender.request(&dialog.request("BYE").build(), &target);
+}
+
+// Calls off a transfer in progress, hanging up the target.
+fn abandon_transfer(call: &mut Call, sender: &mut SipSender) {
+    if let Some(transfer) = call.transfer.take() {
+        println!("  Transfer on call {} abandoned", call.index);
+        hang_up_target(&transfer, sender);
+    }
+}
+
+// The transfer target could not be connected. The call goes on between the
+// transferor and the transferee, unless the transferor has hung up already.
+fn transfer_failed(call: &mut Call, sender: &mut SipSender) {
+    let transfer = match call.transfer.take() {
+        Some(transfer) => transfer,
+        None => return,
+    };
+    if transfer.transferor_gone {
+        println!(
+            "  Transfer on call {} failed and the transferor is gone; hanging up.",
+            call.index
+        );
+        send_bye(call, other_leg(transfer.transferor), sender);
+        call.call_state = CallState::Disconnecting;
+        println!("  Call {} state transitioned to DISCONNECTING.", call.index);
+    } else {
+        println!(
+            "  Transfer on call {} failed; the call goes on.",
+            call.index
+        );
+    }
+}
+
+// Tears down an established call from the middle: BYE on both legs.
+fn terminate_call(call: &mut Call, sender: &mut SipSender) {
+    call.relayed_requests.clear();
+    call.session_timer.expires_at = None;
+    call.session_timer.refresh_at = None;
+    send_bye(call, A_LEG, sender);
+    send_bye(call, B_LEG, sender);
     call.call_state = CallState::Disconnecting;
-    // The unterminated string error pointed here, but should be resolved now
     println!("  Call {} state transitioned to DISCONNECTING.", call.index);
 }
 
-fn send_if_addr(socket: &Arc<UdpSocket>, addr: Option<SocketAddr>, payload: &str, context: &str) {
-    if let Some(target) = addr {
-        send_sip_message(socket, payload.as_bytes(), &target);
+// Starts the session timer when B answers our INVITE (RFC 4028 §7.2, §9).
+// The interval is the one in B's 2xx, else the one we asked for. A refreshes
+// when it supports session timers and did not ask us to; otherwise B when it
+// chose to, and the server when neither does.
+fn start_session_timer(call: &mut Call, ok: &SipResponse) {
+    let b_session_expires = session_expires(ok);
+    let interval = match b_session_expires.or(call.b_leg_invite.as_ref().and_then(session_expires))
+    {
+        Some(se) => se.interval(),
+        None => return,
+    };
+    let timer = &mut call.session_timer;
+    timer.refresher = if timer.a_leg_supported && timer.a_leg_refresher != Some(Refresher::Uas) {
+        A_LEG
+    } else if b_session_expires.and_then(|se| se.refresher) == Some(Refresher::Uas) {
+        B_LEG
     } else {
-        eprintln!("{}; missing transport address, message dropped.", context);
+        0
+    };
+    timer.interval = Some(interval);
+    println!(
+        "  Session interval of call {} is {}s, refreshed by {}",
+        call.index,
+        interval.as_secs(),
+        match timer.refresher {
+            A_LEG => "A",
+            B_LEG => "B",
+            _ => "the server",
+        }
+    );
+    refresh_session_timer(call);
+}
+
+// A refresh went through: the session lasts another interval. The side that
+// does not refresh ends it a little before expiry (RFC 4028 §10).
+fn refresh_session_timer(call: &mut Call) {
+    let timer = &mut call.session_timer;
+    if let Some(interval) = timer.interval {
+        let now = Instant::now();
+        timer.expires_at = Some(now + interval - expiry_margin(interval));
+        if timer.refresher == 0 {
+            timer.refresh_at = Some(now + interval / 2);
+        }
     }
 }
 
-fn monotonic_millis() -> u128 {
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(duration) => duration.as_millis(),
-        Err(err) => {
+// Session refresh by the server: an UPDATE without a body on both legs
+// (RFC 4028 §7.4, §9).
+fn send_session_refresh(call: &mut Call, sender: &mut SipSender) {
+    let interval = match call.session_timer.interval {
+        Some(interval) => interval,
+        None => return,
+    };
+    call.session_timer.refresh_at = None;
+    let session_expires = SessionExpires {
+        delta: interval.as_secs() as u32,
+        refresher: Some(Refresher::Uac),
+    }
+    .to_string();
+    println!("  Refreshing the session of call {}", call.index);
+    for (dialog, leg_addr) in [
+        (&mut call.a_leg_dialog, call.a_leg_addr),
+        (&mut call.b_leg_dialog, call.b_leg_addr),
+    ] {
+        let update = dialog
+            .request("UPDATE")
+            .header("Session-Expires", &session_expires)
+            .contact(&server_contact())
+            .build();
+        match dialog.next_hop().or(leg_addr) {
+            Some(addr) => sender.request(&update, &addr),
+            None => eprintln!(
+                "  Missing address while refreshing the session of call {}",
+                call.index
+            ),
+        }
+    }
+}
+
+// Response to a session refresh the server sent on `leg`. 408 and 481 mean
+// the session is gone on that leg (RFC 4028 §10); any other final response
+// shows the peer is still there.
+fn handle_refresh_response(call: &mut Call, resp: &SipResponse, leg: i32, sender: &mut SipSender) {
+    match resp.status_code {
+        100..=199 => {}
+        408 | 481 => {
+            println!(
+                "  Session refresh on leg {} of call {} failed with {}; terminating.",
+                leg, call.index, resp.status_code
+            );
+            terminate_call(call, sender);
+        }
+        _ => refresh_session_timer(call),
+    }
+}
+
+// A never PRACKed a reliable provisional response: its INVITE is rejected
+// (RFC 3262 §3) and B's INVITE cancelled.
+fn handle_prack_timeout(call: &mut Call, sender: &mut SipSender) {
+    if call.call_state != CallState::Routing && call.call_state != CallState::Ringing {
+        return;
+    }
+    println!(
+        "  No PRACK from A on call {}; rejecting its INVITE.",
+        call.index
+    );
+    call.prack.a_leg_unacked = None;
+    call.prack.queued.clear();
+    respond_to_a_invite(call, 500, sender);
+    cancel_forks(call, None, sender);
+    call.call_state = CallState::Disconnecting;
+    println!("  Call {} state transitioned to DISCONNECTING.", call.index);
+}
+
+// RFC 3262 checks for a provisional response from B. Returns false for a
+// retransmitted or out-of-order reliable provisional, which is dropped. A
+// reliable one that A will not PRACK is PRACKed here.
+fn accept_b_provisional(call: &mut Call, resp: &SipResponse, sender: &mut SipSender) -> bool {
+    let rseq = match resp.rseq() {
+        Some(rseq) if resp.has_option_tag("Require", "100rel") => rseq,
+        _ => return true,
+    };
+    if call
+        .prack
+        .b_leg_rseq
+        .is_some_and(|last| rseq != last.wrapping_add(1))
+    {
+        return false;
+    }
+    call.prack.b_leg_rseq = Some(rseq);
+    if call.prack.a_leg_supported {
+        // A's PRACK will be relayed
+        return true;
+    }
+    let invite_cseq = call
+        .b_leg_invite
+        .as_ref()
+        .and_then(|invite| invite.cseq())
+        .map(|(number, _)| number)
+        .unwrap_or(1);
+    let target = call.b_leg_dialog.next_hop().or(call.b_leg_addr);
+    let prack = call
+        .b_leg_dialog
+        .request("PRACK")
+        .header("RAck", &format!("{} {} INVITE", rseq, invite_cseq))
+        .build();
+    match target {
+        Some(target) => sender.request(&prack, &target),
+        None => eprintln!("  Missing B-leg address for PRACK on call {}", call.index),
+    }
+    true
+}
+
+// PRACK from A for a reliable provisional we relayed. It goes on to B when
+// B's provisional was reliable too, and is answered here otherwise. The next
+// held provisional can then be sent.
+fn handle_prack_from_a(
+    call: &mut Call,
+    prack: &SipRequest,
+    source: SocketAddr,
+    sender: &mut SipSender,
+) {
+    let rseq = prack.rack().map(|(rseq, _, _)| rseq);
+    let unacked = call
+        .prack
+        .a_leg_unacked
+        .filter(|(ours, _)| Some(*ours) == rseq);
+    let acknowledged = sender.transactions.receive_prack(prack);
+    let b_rseq = match unacked {
+        Some((_, b_rseq)) if acknowledged => b_rseq,
+        _ => {
+            println!("  PRACK from A matches no reliable provisional; sending 481.");
+            let response_481 = ResponseBuilder::from_request(prack, 481).build();
+            sender.response(&response_481, &source);
+            return;
+        }
+    };
+    call.prack.a_leg_unacked = None;
+
+    match b_rseq {
+        Some(b_rseq) => {
+            let invite_cseq = call
+                .b_leg_invite
+                .as_ref()
+                .and_then(|invite| invite.cseq())
+                .map(|(number, _)| number)
+                .unwrap_or(1);
+            let rack = format!("{} {} INVITE", b_rseq, invite_cseq);
+            relay_in_dialog_request(call, prack, source, A_LEG, &[("RAck", &rack)], sender);
+        }
+        None => {
+            let ok_200 = ResponseBuilder::from_request(prack, 200).build();
+            sender.response(&ok_200, &source);
+        }
+    }
+
+    if !call.prack.queued.is_empty() {
+        let next = call.prack.queued.remove(0);
+        relay_response_to_a(call, &next, sender);
+    }
+}
+
+// Gives a request the call does not act on a final response, so that its
+// server transaction ends: 481 when there is no dialog (or, for CANCEL, no
+// pending INVITE) for it, 405 when its method is not allowed in the call's
+// state. ACKs get no response.
+fn answer_unhandled(request: &SipRequest, code: u16, addr: &SocketAddr, sender: &mut SipSender) {
+    if request.method == "ACK" {
+        return;
+    }
+    let code = if request.method == "CANCEL" {
+        481
+    } else {
+        code
+    };
+    let mut response = ResponseBuilder::from_request(request, code);
+    if code == 405 {
+        response = response.header("Allow", &SUPPORTED_METHODS.join(", "));
+    }
+    sender.response(&response.build(), addr);
+}
+
+// Whether `request` arrived with Max-Forwards: 0, so that it may not be
+// passed on: it is answered 483 Too Many Hops, or dropped if it is an ACK
+// (RFC 3261 §16.3).
+fn out_of_hops(request: &SipRequest, source: &SocketAddr, sender: &mut SipSender) -> bool {
+    if request.max_forwards() != Some(0) {
+        return false;
+    }
+    println!(
+        "  {} from {} has no hops left; sending 483.",
+        request.method, source
+    );
+    if request.method != "ACK" {
+        let response_483 = ResponseBuilder::from_request(request, 483).build();
+        sender.response(&response_483, source);
+    }
+    true
+}
+
+// Sends a response with the given status code to A's INVITE.
+fn respond_to_a_invite(call: &Call, code: u16, sender: &mut SipSender) {
+    if let Some(response) = a_invite_response(call, code) {
+        let response = response.build();
+        send_if_addr(
+            sender,
+            call.a_leg_addr,
+            &response,
+            &format!("{} response not sent to A leg", code),
+        );
+    }
+}
+
+// Starts a response to A's INVITE. Everything but 100 Trying carries our
+// A-leg dialog tag; dialog-creating responses echo A's Record-Route
+// (RFC 3261 §12.1.1).
+fn a_invite_response(call: &Call, code: u16) -> Option<ResponseBuilder> {
+    let a_invite = call.a_leg_invite.as_ref()?;
+    let mut response = ResponseBuilder::from_request(a_invite, code);
+    if code == 100 {
+        return Some(response);
+    }
+    if code < 300 {
+        for record_route in a_invite.headers.get_all("Record-Route") {
+            response = response.header(&record_route.name, &record_route.value);
+        }
+    }
+    Some(response.to_tag(&call.a_leg_dialog.local_tag))
+}
+
+// Relays a response to our INVITE from the B leg as the matching response to A's
+// INVITE: same status and reason, our Contact on 18x/2xx, SDP passed through.
+// Provisionals go reliably to A when B sent them reliably (or A requires it)
+// and A supports 100rel; only one may be unacknowledged at a time
+// (RFC 3262 §3), later ones are held until its PRACK.
+fn relay_response_to_a(call: &mut Call, response: &SipResponse, sender: &mut SipSender) {
+    let code = response.status_code;
+    let mut relayed = match a_invite_response(call, code) {
+        Some(relayed) => relayed.reason(&response.reason),
+        None => {
+            eprintln!("  Missing A-leg INVITE for call {}", call.index);
+            return;
+        }
+    };
+    if code < 300 {
+        relayed = relayed.contact(&server_contact());
+    }
+    let b_rseq = response
+        .rseq()
+        .filter(|_| response.has_option_tag("Require", "100rel"));
+    let reliable = (101..200).contains(&code)
+        && call.prack.a_leg_supported
+        && (b_rseq.is_some() || call.prack.a_leg_required);
+    if reliable {
+        if call.prack.a_leg_unacked.is_some() {
+            call.prack.queued.push(response.clone());
+            return;
+        }
+        call.prack.a_leg_rseq = if call.prack.a_leg_rseq == 0 {
+            // Initial RSeq is random in 1..2^31 (RFC 3262 §3)
+            rand::random::<u32>() % 0x7fff_ffff + 1
+        } else {
+            call.prack.a_leg_rseq + 1
+        };
+        call.prack.a_leg_unacked = Some((call.prack.a_leg_rseq, b_rseq));
+        relayed = relayed
+            .header("Require", "100rel")
+            .header("RSeq", &call.prack.a_leg_rseq.to_string());
+    } else if code >= 200 {
+        // A final response ends the reliable provisionals of the INVITE
+        call.prack.a_leg_unacked = None;
+        call.prack.queued.clear();
+    }
+    if let Some(interval) = call
+        .session_timer
+        .interval
+        .filter(|_| (200..300).contains(&code) && call.session_timer.a_leg_supported)
+    {
+        let session_expires = SessionExpires {
+            delta: interval.as_secs() as u32,
+            refresher: Some(if call.session_timer.refresher == A_LEG {
+                Refresher::Uac
+            } else {
+                Refresher::Uas
+            }),
+        };
+        relayed = relayed
+            .header("Require", "timer")
+            .header("Session-Expires", &session_expires.to_string());
+    }
+    if code == 422 {
+        // B wants a longer session interval; A retries with its Min-SE
+        if let Some(min_se) = response.headers.get("Min-SE") {
+            relayed = relayed.header("Min-SE", min_se);
+        }
+    }
+    if let Some(sdp) = response.sdp_body() {
+        relayed = relayed.body("application/sdp", sdp);
+    }
+    send_if_addr(
+        sender,
+        call.a_leg_addr,
+        &relayed.build(),
+        &format!("{} response not forwarded to A leg", response.status_code),
+    );
+}
+
+// Relays an in-dialog request (re-INVITE, UPDATE, INFO, OPTIONS, MESSAGE,
+// PRACK) from `from_leg` to the other leg as a new request in that leg's
+// dialog, with `extra_headers` added. Responses and the ACK are mapped back
+// through `call.relayed_requests`.
+fn relay_in_dialog_request(
+    call: &mut Call,
+    req: &SipRequest,
+    source: SocketAddr,
+    from_leg: i32,
+    extra_headers: &[(&str, &str)],
+    sender: &mut SipSender,
+) {
+    if out_of_hops(req, &source, sender) {
+        return;
+    }
+    if let Some(code) = offer_conflict(call, req, from_leg) {
+        println!(
+            "  {} from leg {} on call {} crosses a pending offer; sending {}.",
+            req.method, from_leg, call.index, code
+        );
+        let mut response = ResponseBuilder::from_request(req, code);
+        if code == 500 {
+            // RFC 3261 §14.2: retry after a random 0-10 seconds
+            let retry_after = rand::random::<u8>() % 11;
+            response = response.header("Retry-After", &retry_after.to_string());
+        }
+        sender.response(&response.build(), &source);
+        return;
+    }
+
+    let is_target_refresh = req.method == "INVITE" || req.method == "UPDATE";
+    let (from_dialog, to_dialog, to_addr) = if from_leg == A_LEG {
+        (
+            &mut call.a_leg_dialog,
+            &mut call.b_leg_dialog,
+            call.b_leg_addr,
+        )
+    } else {
+        (
+            &mut call.b_leg_dialog,
+            &mut call.a_leg_dialog,
+            call.a_leg_addr,
+        )
+    };
+    let target = match to_dialog.next_hop().or(to_addr) {
+        Some(target) => target,
+        None => {
             eprintln!(
-                "System clock error while building branch id ({}). Falling back to 0.",
-                err
+                "  No address for the other leg of call {}; rejecting {}.",
+                call.index, req.method
             );
-            0
+            let response_500 = ResponseBuilder::from_request(req, 500).build();
+            sender.response(&response_500, &source);
+            return;
+        }
+    };
+    if is_target_refresh {
+        from_dialog.update_from_request(req);
+    }
+
+    let max_forwards = req.max_forwards().unwrap_or(DEFAULT_MAX_FORWARDS);
+    let mut relayed = to_dialog
+        .request(&req.method)
+        .max_forwards(max_forwards.saturating_sub(1));
+    if is_target_refresh {
+        relayed = relayed.contact(&server_contact());
+        // A session refresh passes its interval on (RFC 4028)
+        for name in ["Session-Expires", "Min-SE"] {
+            if let Some(value) = req.headers.get(name) {
+                relayed = relayed.header(name, value);
+            }
+        }
+    }
+    for (name, value) in extra_headers {
+        relayed = relayed.header(name, value);
+    }
+    if let Some(content_type) = req.content_type() {
+        relayed = relayed.body(content_type, req.body());
+    }
+    let relayed = relayed.build();
+    sender.request(&relayed, &target);
+
+    if let Some(sdp) = req.sdp_body() {
+        track_relayed_sdp(call, from_leg, sdp);
+    }
+    call.relayed_requests.push(RelayedRequest {
+        from_leg,
+        received: req.clone(),
+        source,
+        relayed,
+        answered: false,
+    });
+}
+
+// Records a DTMF digit carried by an INFO request from `leg`.
+fn record_dtmf(call: &mut Call, info: &SipRequest, leg: i32) {
+    let parsed = info
+        .content_type()
+        .and_then(|content_type| parse_dtmf_info(content_type, info.body()));
+    if let Some((signal, duration_ms)) = parsed {
+        println!(
+            "  DTMF '{}' from leg {} on call {} ({} ms)",
+            signal,
+            leg,
+            call.index,
+            duration_ms.map_or("?".to_string(), |d| d.to_string())
+        );
+        call.dtmf_digits.push(DtmfDigit {
+            leg,
+            signal,
+            duration_ms,
+        });
+    }
+}
+
+// Glare handling for a request that starts an offer/answer exchange
+// (RFC 3261 §14.2, RFC 3311 §5.2). An exchange we started towards that leg
+// is still open: 491. The leg's own earlier offer is still open: 500.
+fn offer_conflict(call: &Call, req: &SipRequest, from_leg: i32) -> Option<u16> {
+    if req.method != "INVITE" && req.sdp_body().is_none() {
+        return None;
+    }
+    let open_exchange = call.relayed_requests.iter().find(|pending| {
+        !pending.answered
+            && (pending.relayed.method == "INVITE" || pending.relayed.sdp_body().is_some())
+    });
+    if let Some(pending) = open_exchange {
+        return Some(if pending.from_leg == from_leg {
+            500
+        } else {
+            491
+        });
+    }
+    let media = if from_leg == A_LEG {
+        &call.a_leg_media
+    } else {
+        &call.b_leg_media
+    };
+    match media.negotiation.state {
+        NegotiationState::LocalOffer => Some(491),
+        NegotiationState::RemoteOffer => Some(500),
+        _ => None,
+    }
+}
+
+// Index of the relayed request that `request`, sent on `to_leg`, belongs to.
+fn find_relayed(call: &Call, request: &SipRequest, to_leg: i32) -> Option<usize> {
+    let cseq = request.cseq()?;
+    call.relayed_requests.iter().position(|pending| {
+        pending.from_leg != to_leg && pending.relayed.cseq() == Some(cseq.clone())
+    })
+}
+
+// Relays a response from `from_leg` to a request we relayed on that leg back
+// to the request's sender. Returns false if the response is not one of those.
+fn relay_response_back(
+    call: &mut Call,
+    resp: &SipResponse,
+    from_leg: i32,
+    sender: &mut SipSender,
+) -> bool {
+    let cseq = resp.cseq();
+    let position = match call.relayed_requests.iter().position(|pending| {
+        pending.from_leg != from_leg && cseq.is_some() && pending.relayed.cseq() == cseq
+    }) {
+        Some(position) => position,
+        None => return false,
+    };
+    let pending = &call.relayed_requests[position];
+    if pending.answered {
+        // Retransmitted 2xx; the ACK is re-sent by the transaction layer
+        return true;
+    }
+    let code = resp.status_code;
+    let method = pending.relayed.method.clone();
+    println!(
+        "  Relaying {} {} for {} from leg {} on call {}",
+        code, resp.reason, method, from_leg, call.index
+    );
+
+    let mut relayed = ResponseBuilder::from_request(&pending.received, code).reason(&resp.reason);
+    if code > 100 {
+        // Only an INVITE with Replaces comes without our tag
+        let local_tag = if pending.from_leg == A_LEG {
+            &call.a_leg_dialog.local_tag
+        } else {
+            &call.b_leg_dialog.local_tag
+        };
+        relayed = relayed.to_tag(local_tag);
+    }
+    if (method == "INVITE" || method == "UPDATE") && code > 100 && code < 300 {
+        relayed = relayed.contact(&server_contact());
+    }
+    // A refresh is answered with the timer only to a side that supports it;
+    // B sends Session-Expires in its own requests only if it does
+    let timer_supported = pending.from_leg != A_LEG || call.session_timer.a_leg_supported;
+    if let Some(session_expires) = resp
+        .headers
+        .get("Session-Expires")
+        .filter(|_| (200..300).contains(&code) && timer_supported)
+    {
+        relayed = relayed
+            .header("Require", "timer")
+            .header("Session-Expires", session_expires);
+    }
+    if code == 422 {
+        if let Some(min_se) = resp.headers.get("Min-SE") {
+            relayed = relayed.header("Min-SE", min_se);
+        }
+    }
+    if let Some(content_type) = resp.content_type() {
+        relayed = relayed.body(content_type, resp.body());
+    }
+    let source = pending.source;
+    sender.response(&relayed.build(), &source);
+
+    if (200..300).contains(&code) && (method == "INVITE" || method == "UPDATE") {
+        // Target refresh of the responding leg (RFC 3261 §12.2.1.2)
+        if from_leg == A_LEG {
+            call.a_leg_dialog.update_from_response(resp);
+        } else {
+            call.b_leg_dialog.update_from_response(resp);
         }
+        // ... and a session refresh (RFC 4028 §10), possibly with a new interval
+        if let Some(se) = session_expires(resp).filter(|_| call.session_timer.interval.is_some()) {
+            call.session_timer.interval = Some(se.interval());
+        }
+        refresh_session_timer(call);
+    }
+    if let Some(sdp) = resp.sdp_body() {
+        track_relayed_sdp(call, from_leg, sdp);
+    }
+    if code >= 300 {
+        // The offer carried by the failed request is void
+        call.a_leg_media.negotiation.rollback();
+        call.b_leg_media.negotiation.rollback();
+    }
+    if code >= 200 {
+        if method == "INVITE" && code < 300 {
+            call.relayed_requests[position].answered = true;
+        } else {
+            call.relayed_requests.remove(position);
+        }
+    }
+    true
+}
+
+// Relays the ACK for a re-INVITE 2xx we relayed to `from_leg` as the ACK of
+// the re-INVITE on the other leg. Returns false if there is no such re-INVITE.
+fn relay_ack(call: &mut Call, ack: &SipRequest, from_leg: i32, sender: &mut SipSender) -> bool {
+    let cseq_number = ack.cseq().map(|(number, _)| number);
+    let position = match call.relayed_requests.iter().position(|pending| {
+        pending.answered
+            && pending.from_leg == from_leg
+            && pending.received.cseq().map(|(number, _)| number) == cseq_number
+    }) {
+        Some(position) => position,
+        None => return false,
+    };
+    let pending = call.relayed_requests.remove(position);
+    let (dialog, addr) = if from_leg == A_LEG {
+        (&call.b_leg_dialog, call.b_leg_addr)
+    } else {
+        (&call.a_leg_dialog, call.a_leg_addr)
+    };
+    let invite_cseq = pending
+        .relayed
+        .cseq()
+        .map(|(number, _)| number)
+        .unwrap_or(1);
+    let mut relayed_ack = dialog.ack(invite_cseq);
+    if let Some(content_type) = ack.content_type() {
+        relayed_ack = relayed_ack.body(content_type, ack.body());
+    }
+    match dialog.next_hop().or(addr) {
+        Some(target) => sender.request(&relayed_ack.build(), &target),
+        None => eprintln!(
+            "  Missing address while relaying ACK on call {}",
+            call.index
+        ),
+    }
+    if let Some(sdp) = ack.sdp_body() {
+        track_relayed_sdp(call, from_leg, sdp);
+    }
+    true
+}
+
+// Records an SDP body received on `from_leg` and relayed unchanged to the
+// other leg in both legs' offer/answer state.
+fn track_relayed_sdp(call: &mut Call, from_leg: i32, body: &str) {
+    let sdp = match SessionDescription::parse(body) {
+        Ok(sdp) => sdp,
+        Err(e) => {
+            eprintln!("  Unparseable SDP on call {}: {}", call.index, e);
+            return;
+        }
+    };
+    let (received_on, sent_on) = if from_leg == A_LEG {
+        (&mut call.a_leg_media, &mut call.b_leg_media)
+    } else {
+        (&mut call.b_leg_media, &mut call.a_leg_media)
+    };
+    received_on.remote_media = true;
+    sent_on.local_media = true;
+    // Direction of the first active stream, e.g. sendonly when the phone
+    // on this leg puts the call on hold
+    received_on.direction = sdp
+        .media
+        .iter()
+        .find(|media| !media.is_disabled())
+        .map(|media| sdp.media_direction(media))
+        .unwrap_or_default();
+    let on_hold = sdp.is_hold();
+    if on_hold != received_on.on_hold {
+        println!(
+            "  Leg {} of call {} {} ({})",
+            from_leg,
+            call.index,
+            if on_hold { "holds" } else { "resumes" },
+            received_on.direction.as_str()
+        );
+    }
+    received_on.on_hold = on_hold;
+    received_on.telephone_event = sdp
+        .media
+        .iter()
+        .find(|media| !media.is_disabled())
+        .and_then(telephone_event_payload);
+    match received_on.negotiation.remote_sdp(sdp.clone()) {
+        Ok(role) => println!(
+            "  SDP {:?} from leg {} on call {}",
+            role, from_leg, call.index
+        ),
+        Err(e) => eprintln!("  SDP from leg {} on call {}: {}", from_leg, call.index, e),
+    }
+    if let Err(e) = sent_on.negotiation.local_sdp(sdp) {
+        eprintln!("  SDP relayed on call {}: {}", call.index, e);
+    }
+}
+
+// Adds received (and the rport value, if requested) to a Via value as
+// described in RFC 3261 §18.2.1 and RFC 3581.
+fn via_with_received(via: &str, source: SocketAddr) -> String {
+    let (via_received, via_rport) = extract_via_received_rport(via);
+    let mut updated_via = via.to_string();
+    if via_received.is_none() {
+        updated_via.push_str(&format!(";received={}", source.ip()));
+    }
+    if via_rport == Some(RPORT_FLAG_VALUE) {
+        // rport flag without value
+        updated_via = updated_via
+            .split(';')
+            .map(|param| {
+                if param.trim() == "rport" {
+                    format!("rport={}", source.port())
+                } else {
+                    param.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+    }
+    updated_via
+}
+
+// Replaces the topmost Via value, which may share a header line with others.
+fn replace_top_via(headers: &mut Headers, new_top: &str) {
+    let mut vias: Vec<String> = headers
+        .get_list("Via")
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    if vias.is_empty() {
+        return;
+    }
+    let name = headers
+        .get_header("Via")
+        .map(|h| h.name.clone())
+        .unwrap_or_else(|| "Via".to_string());
+    vias[0] = new_top.to_string();
+    headers.set(&name, &vias.join(", "));
+}
+
+// Sending side of a worker: the shared socket plus the transaction table
+// every outgoing request and response is recorded in.
+struct SipSender<'a> {
+    socket: &'a Arc<UdpSocket>,
+    transactions: &'a mut TransactionTable,
+}
+
+impl SipSender<'_> {
+    fn request(&mut self, request: &SipRequest, destination: &SocketAddr) {
+        self.transactions
+            .send_request(request, *destination, Instant::now());
+        send_sip_message(self.socket, request.to_string().as_bytes(), destination);
+    }
+
+    fn response(&mut self, response: &SipResponse, destination: &SocketAddr) {
+        self.transactions
+            .send_response(response, *destination, Instant::now());
+        send_sip_message(self.socket, response.to_string().as_bytes(), destination);
+    }
+}
+
+// Sends messages generated by the transaction layer itself.
+fn transmit(socket: &Arc<UdpSocket>, outgoing: &[Outgoing]) {
+    for message in outgoing {
+        send_sip_message(socket, message.payload.as_bytes(), &message.destination);
+    }
+}
+
+fn send_if_addr(
+    sender: &mut SipSender,
+    addr: Option<SocketAddr>,
+    response: &SipResponse,
+    context: &str,
+) {
+    if let Some(target) = addr {
+        sender.response(response, &target);
+    } else {
+        eprintln!("{}; missing transport address, message dropped.", context);
     }
 }
@@ -0,0 +1,186 @@
+mod common;
+
+use common::{
+    add_test_users, addr, authorize, bind, expect_message, get_password, response, sample_invite,
+    Worker,
+};
+use sip_server_rust::auth::Credentials;
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::{SourceNetwork, LOCATION_ENTRIES};
+use std::net::UdpSocket;
+
+fn register(user: &str, cseq: u32, phone: &UdpSocket) -> String {
+    format!(
+        "REGISTER sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {addr};branch=z9hG4bKreg{cseq}\r\n\
+From: <sip:{user}@server>;tag=reg\r\n\
+To: <sip:{user}@server>\r\n\
+Call-ID: reg-{user}\r\n\
+CSeq: {cseq} REGISTER\r\n\
+Contact: <sip:{user}@{addr}>\r\n\
+Content-Length: 0\r\n\r\n",
+        user = user,
+        cseq = cseq,
+        addr = addr(phone)
+    )
+}
+
+// Answers the first challenge of a 401 the way a phone would.
+fn answer_challenge(text: &str, challenged: &SipResponse, user: &str, password: &str) -> String {
+    let challenge = challenged.headers.get("WWW-Authenticate").unwrap();
+    let param = |name: &str| {
+        challenge
+            .split(&format!("{}=", name))
+            .nth(1)
+            .unwrap()
+            .split(',')
+            .next()
+            .unwrap()
+            .trim_matches('"')
+            .to_string()
+    };
+    let request = match parse_message(text.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => unreachable!(),
+    };
+    let mut credentials = Credentials {
+        username: user.to_string(),
+        realm: param("realm"),
+        nonce: param("nonce"),
+        uri: request.uri.clone(),
+        response: String::new(),
+        algorithm: Some(param("algorithm")),
+        qop: Some("auth".to_string()),
+        nc: Some("00000001".to_string()),
+        cnonce: Some("8d2a7c01".to_string()),
+    };
+    credentials.response = credentials
+        .expected_response(&request.method, password)
+        .unwrap();
+    let (request_line, rest) = text.split_once("\r\n").unwrap();
+    format!(
+        "{}\r\nAuthorization: {}\r\n{}",
+        request_line, credentials, rest
+    )
+}
+
+#[test]
+fn register_is_challenged_then_accepted() {
+    add_test_users();
+    let (Some(server), Some(phone)) = (bind(), bind()) else {
+        eprintln!("Skipping auth flow test; unable to bind UDP sockets");
+        return;
+    };
+    let worker = Worker::start(server);
+
+    worker.send(&register("1001", 1, &phone), &phone);
+    let challenged = response(expect_message(&phone, "SIP/2.0 401"));
+    let offers = challenged
+        .headers
+        .get_all("WWW-Authenticate")
+        .map(|h| h.value.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(offers.len(), 2);
+    assert!(offers[0].contains("algorithm=SHA-256"));
+    assert!(offers[1].contains("algorithm=MD5"));
+
+    let answered = answer_challenge(
+        &register("1001", 2, &phone),
+        &challenged,
+        "1001",
+        &get_password("1001").unwrap(),
+    );
+    worker.send(&answered, &phone);
+    response(expect_message(&phone, "SIP/2.0 200 OK"));
+
+    // Replaying the same nonce count is answered with a stale challenge
+    worker.send(
+        &answered
+            .replace("z9hG4bKreg2", "z9hG4bKreg3")
+            .replace("CSeq: 2", "CSeq: 3"),
+        &phone,
+    );
+    let stale = response(expect_message(&phone, "SIP/2.0 401"));
+    assert!(stale
+        .headers
+        .get("WWW-Authenticate")
+        .unwrap()
+        .contains("stale=true"));
+    worker.stop();
+}
+
+#[test]
+fn initial_invite_needs_proxy_credentials_of_the_caller() {
+    add_test_users();
+    let (Some(server), Some(phone)) = (bind(), bind()) else {
+        eprintln!("Skipping auth flow test; unable to bind UDP sockets");
+        return;
+    };
+    let worker = Worker::start(server);
+
+    let invite = sample_invite()
+        .replace("1001", "1003")
+        .replace("z9hG4bK776asdhds", "z9hG4bKnoauth")
+        .replace("a84b4c76e66710", "noauth");
+    worker.send(&invite, &phone);
+    let challenged = response(expect_message(&phone, "SIP/2.0 407"));
+    assert!(challenged.headers.contains("Proxy-Authenticate"));
+    assert!(challenged.headers.get("To").unwrap().contains("tag="));
+
+    // Credentials of another user are forbidden
+    let foreign = authorize(
+        &sample_invite()
+            .replace("1001", "1004")
+            .replace("z9hG4bK776asdhds", "z9hG4bKforeign")
+            .replace("a84b4c76e66710", "foreign"),
+    )
+    .replace(
+        "From: \"Alice\" <sip:1004@server>",
+        "From: \"Alice\" <sip:1003@server>",
+    );
+    worker.send(&foreign, &phone);
+    response(expect_message(&phone, "SIP/2.0 403"));
+    worker.stop();
+}
+
+#[test]
+fn register_from_outside_allowed_networks_is_forbidden() {
+    add_test_users();
+    let (Some(server), Some(phone)) = (bind(), bind()) else {
+        eprintln!("Skipping auth flow test; unable to bind UDP sockets");
+        return;
+    };
+    {
+        let mut entries = LOCATION_ENTRIES.lock().unwrap();
+        let entry = entries.iter_mut().find(|e| e.username == "1006").unwrap();
+        entry.allowed_networks = vec![SourceNetwork::parse("10.0.0.0/8").unwrap()];
+    }
+    let worker = Worker::start(server);
+
+    worker.send(&authorize(&register("1006", 1, &phone)), &phone);
+    let forbidden = response(expect_message(&phone, "SIP/2.0 403"));
+    assert!(!forbidden.headers.contains("WWW-Authenticate"));
+    worker.stop();
+}
+
+#[test]
+fn unknown_user_is_challenged_like_a_known_one() {
+    add_test_users();
+    let (Some(server), Some(phone)) = (bind(), bind()) else {
+        eprintln!("Skipping auth flow test; unable to bind UDP sockets");
+        return;
+    };
+    let worker = Worker::start(server);
+
+    for user in ["1002", "9999"] {
+        worker.send(&register(user, 1, &phone), &phone);
+        let challenged = response(expect_message(&phone, "SIP/2.0 401"));
+        assert_eq!(challenged.headers.get_all("WWW-Authenticate").count(), 2);
+
+        // A wrong password is challenged again, whether the user exists or not
+        let answered = answer_challenge(&register(user, 2, &phone), &challenged, user, "guess");
+        worker.send(&answered, &phone);
+        response(expect_message(&phone, "SIP/2.0 401"));
+    }
+    worker.stop();
+}
@@ -0,0 +1,17 @@
+use sip_server_rust::auth::issue_nonce;
+use sip_server_rust::sip_defs::{AUTH_NONCES, MAX_AUTH_NONCES};
+use std::time::Instant;
+
+// Kept apart from the other authentication tests, whose nonces it would
+// push out.
+#[test]
+fn unanswered_challenges_do_not_pile_up() {
+    let now = Instant::now();
+    let first = issue_nonce(now);
+    for _ in 0..MAX_AUTH_NONCES * 2 {
+        issue_nonce(now);
+    }
+    let nonces = AUTH_NONCES.lock().unwrap();
+    assert_eq!(nonces.len(), MAX_AUTH_NONCES);
+    assert!(!nonces.iter().any(|nonce| nonce.value == first));
+}
@@ -0,0 +1,228 @@
+mod common;
+
+use common::{add_test_users, get_password};
+use sip_server_rust::auth::{
+    authenticate, challenge, issue_nonce, user_algorithms, AuthError, Credentials, DigestAlgorithm,
+};
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::{UserSecret, AUTH_REALM, LOCATION_ENTRIES, NONCE_LIFETIME};
+use std::time::{Duration, Instant};
+
+// RFC 7616 §3.9.1
+fn rfc_credentials(algorithm: &str) -> Credentials {
+    Credentials {
+        username: "Mufasa".to_string(),
+        realm: "http-auth@example.org".to_string(),
+        nonce: "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".to_string(),
+        uri: "/dir/index.html".to_string(),
+        response: String::new(),
+        algorithm: Some(algorithm.to_string()),
+        qop: Some("auth".to_string()),
+        nc: Some("00000001".to_string()),
+        cnonce: Some("f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ".to_string()),
+    }
+}
+
+// A REGISTER from `user` whose credentials use `nonce`, `nc` and `password`.
+fn register_request(user: &str, nonce: &str, nc: u32, password: &str) -> SipRequest {
+    let mut credentials = Credentials {
+        username: user.to_string(),
+        realm: AUTH_REALM.to_string(),
+        nonce: nonce.to_string(),
+        uri: "sip:server".to_string(),
+        response: String::new(),
+        algorithm: Some("MD5".to_string()),
+        qop: Some("auth".to_string()),
+        nc: Some(format!("{:08x}", nc)),
+        cnonce: Some("0a4f113b".to_string()),
+    };
+    credentials.response = credentials.expected_response("REGISTER", password).unwrap();
+    let text = format!(
+        "REGISTER sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.10:5060;branch=z9hG4bKauth\r\n\
+From: <sip:{user}@server>;tag=auth\r\n\
+To: <sip:{user}@server>\r\n\
+Call-ID: auth-{user}\r\n\
+CSeq: 1 REGISTER\r\n\
+Authorization: {credentials}\r\n\
+Content-Length: 0\r\n\r\n",
+        user = user,
+        credentials = credentials
+    );
+    match parse_message(text.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn responses_match_rfc_7616_examples() {
+    assert_eq!(
+        rfc_credentials("MD5").expected_response("GET", "Circle of Life"),
+        Some("8ca523f5e9506fed4657c9700eebdbec".to_string())
+    );
+    assert_eq!(
+        rfc_credentials("SHA-256").expected_response("GET", "Circle of Life"),
+        Some("753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1".to_string())
+    );
+    assert_eq!(
+        rfc_credentials("SHA-512-256").expected_response("GET", "Circle of Life"),
+        None
+    );
+}
+
+#[test]
+fn credentials_round_trip_through_their_header_value() {
+    let mut credentials = rfc_credentials("SHA-256");
+    credentials.response = "753927fa".to_string();
+    assert_eq!(
+        Credentials::parse(&credentials.to_string()),
+        Some(credentials)
+    );
+    assert_eq!(Credentials::parse("Basic QWxhZGRpbjpvcGVu"), None);
+    assert_eq!(
+        Credentials::parse("Digest username=\"1001\", realm=\"x\""),
+        None
+    );
+}
+
+#[test]
+fn challenge_offers_sha256_before_md5_with_one_nonce() {
+    let values = challenge(
+        true,
+        &[DigestAlgorithm::Sha256, DigestAlgorithm::Md5],
+        Instant::now(),
+    );
+    assert_eq!(values.len(), 2);
+    assert!(values[0].contains("algorithm=SHA-256"));
+    assert!(values[1].contains("algorithm=MD5"));
+    for value in &values {
+        assert!(value.contains(&format!("realm=\"{}\"", AUTH_REALM)));
+        assert!(value.contains("qop=\"auth\""));
+        assert!(value.ends_with(", stale=true"));
+    }
+    let nonce = |value: &str| {
+        value
+            .split("nonce=\"")
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(nonce(&values[0]), nonce(&values[1]));
+}
+
+#[test]
+fn nonce_counts_must_grow() {
+    add_test_users();
+    let now = Instant::now();
+    let nonce = issue_nonce(now);
+    let password = get_password("1001").unwrap();
+    let first = register_request("1001", &nonce, 1, &password);
+    assert_eq!(authenticate(&first, "1001", now), Ok(()));
+    assert_eq!(authenticate(&first, "1001", now), Err(AuthError::Stale));
+    let next = register_request("1001", &nonce, 2, &password);
+    assert_eq!(authenticate(&next, "1001", now), Ok(()));
+}
+
+#[test]
+fn expired_or_unknown_nonces_are_stale() {
+    add_test_users();
+    let now = Instant::now();
+    let nonce = issue_nonce(now);
+    let password = get_password("1002").unwrap();
+    let request = register_request("1002", &nonce, 1, &password);
+    assert_eq!(
+        authenticate(
+            &request,
+            "1002",
+            now + NONCE_LIFETIME + Duration::from_secs(1)
+        ),
+        Err(AuthError::Stale)
+    );
+    let forged = register_request("1002", "0123456789abcdef", 1, &password);
+    assert_eq!(authenticate(&forged, "1002", now), Err(AuthError::Stale));
+}
+
+#[test]
+fn wrong_password_missing_or_foreign_credentials_are_rejected() {
+    add_test_users();
+    let now = Instant::now();
+    let nonce = issue_nonce(now);
+    let wrong = register_request("1003", &nonce, 1, "guess");
+    assert_eq!(authenticate(&wrong, "1003", now), Err(AuthError::Invalid));
+
+    let password = get_password("1004").unwrap();
+    let foreign = register_request("1004", &nonce, 1, &password);
+    assert_eq!(
+        authenticate(&foreign, "1003", now),
+        Err(AuthError::Forbidden)
+    );
+
+    let mut bare = foreign.clone();
+    bare.headers.remove("Authorization");
+    assert_eq!(authenticate(&bare, "1004", now), Err(AuthError::Missing));
+}
+
+#[test]
+fn ha1_secret_answers_only_its_own_algorithm() {
+    add_test_users();
+    let now = Instant::now();
+    let ha1 = DigestAlgorithm::Md5.ha1("1005", AUTH_REALM, "secret1005");
+    {
+        let mut entries = LOCATION_ENTRIES.lock().unwrap();
+        let entry = entries.iter_mut().find(|e| e.username == "1005").unwrap();
+        entry.secret = UserSecret::Ha1(DigestAlgorithm::Md5, ha1);
+    }
+    assert_eq!(user_algorithms("1005"), vec![DigestAlgorithm::Md5]);
+    assert_eq!(
+        user_algorithms("1006"),
+        vec![DigestAlgorithm::Sha256, DigestAlgorithm::Md5]
+    );
+
+    let nonce = issue_nonce(now);
+    let md5 = register_request("1005", &nonce, 1, "secret1005");
+    assert_eq!(authenticate(&md5, "1005", now), Ok(()));
+
+    let mut sha256 = md5.clone();
+    let mut credentials = Credentials::parse(sha256.headers.get("Authorization").unwrap()).unwrap();
+    credentials.algorithm = Some("SHA-256".to_string());
+    credentials.nc = Some("00000002".to_string());
+    credentials.response = credentials
+        .expected_response("REGISTER", "secret1005")
+        .unwrap();
+    sha256
+        .headers
+        .set("Authorization", &credentials.to_string());
+    assert_eq!(authenticate(&sha256, "1005", now), Err(AuthError::Invalid));
+}
+
+#[test]
+fn answers_without_a_nonce_count_are_refused() {
+    add_test_users();
+    let now = Instant::now();
+    let nonce = issue_nonce(now);
+    let password = get_password("1006").unwrap();
+    let request = register_request("1006", &nonce, 1, &password);
+    let mut credentials =
+        Credentials::parse(request.headers.get("Authorization").unwrap()).unwrap();
+
+    // RFC 2069 style: right password, but nothing stops a replay
+    credentials.qop = None;
+    credentials.nc = None;
+    credentials.cnonce = None;
+    credentials.response = credentials
+        .expected_response("REGISTER", &password)
+        .unwrap();
+    let mut legacy = request.clone();
+    legacy
+        .headers
+        .set("Authorization", &credentials.to_string());
+    assert_eq!(authenticate(&legacy, "1006", now), Err(AuthError::Invalid));
+    assert_eq!(authenticate(&legacy, "1006", now), Err(AuthError::Invalid));
+
+    // The nonce is still good for a proper answer
+    assert_eq!(authenticate(&request, "1006", now), Ok(()));
+}
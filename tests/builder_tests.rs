@@ -0,0 +1,161 @@
+mod common;
+
+use common::{sample_invite, sample_response};
+use sip_server_rust::builder::*;
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::SERVER_USER_AGENT;
+
+fn parse_request(text: &str) -> SipRequest {
+    match parse_message(text.as_bytes()).expect("message should parse") {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    }
+}
+
+fn parse_response(text: &str) -> SipResponse {
+    match parse_message(text.as_bytes()).expect("message should parse") {
+        ParsedMessage::Response(resp) => resp,
+        ParsedMessage::Request(_) => panic!("expected a response"),
+    }
+}
+
+#[test]
+fn response_copies_transaction_headers_from_request() {
+    let text = sample_invite().replace(
+        "Max-Forwards: 70\r\n",
+        "Via: SIP/2.0/UDP 10.0.0.2;branch=z9hG4bKproxy\r\nMax-Forwards: 70\r\n",
+    );
+    let invite = parse_request(&text);
+    let resp = ResponseBuilder::from_request(&invite, 486).build();
+
+    assert_eq!(resp.status_code, 486);
+    assert_eq!(resp.reason, "Busy Here");
+    assert_eq!(resp.headers.get_list("Via"), invite.headers.get_list("Via"));
+    assert_eq!(resp.headers.get("From"), invite.headers.get("From"));
+    assert_eq!(resp.headers.get("To"), invite.headers.get("To"));
+    assert_eq!(resp.call_id(), invite.call_id());
+    assert_eq!(resp.cseq(), Some((314159, "INVITE".to_string())));
+    assert_eq!(resp.headers.get("Server"), Some(SERVER_USER_AGENT));
+    assert_eq!(resp.headers.get("Content-Length"), Some("0"));
+    assert!(!resp.headers.contains("Contact"));
+}
+
+#[test]
+fn response_round_trips_through_parser() {
+    let invite = parse_request(&sample_invite());
+    let sdp = "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\n";
+    let resp = ResponseBuilder::from_request(&invite, 200)
+        .to_tag("srv1")
+        .contact("<sip:TinySIP@10.0.0.1:5060>")
+        .body("application/sdp", sdp)
+        .build();
+
+    let text = resp.to_string();
+    assert!(text.starts_with("SIP/2.0 200 OK\r\n"));
+    assert!(text.contains(&format!("Content-Length: {}\r\n\r\n", sdp.len())));
+    let reparsed = parse_response(&text);
+    assert_eq!(reparsed, resp);
+    assert_eq!(reparsed.sdp_body(), Some(sdp));
+    assert!(reparsed.headers.get("To").unwrap().ends_with(";tag=srv1"));
+}
+
+#[test]
+fn to_tag_is_not_added_twice() {
+    let text = sample_invite().replace(
+        "To: \"Bob\" <sip:1002@server>",
+        "To: \"Bob\" <sip:1002@server>;tag=existing",
+    );
+    let invite = parse_request(&text);
+    let resp = ResponseBuilder::from_request(&invite, 200)
+        .to_tag("new")
+        .build();
+    assert_eq!(
+        resp.headers.get("To"),
+        Some("\"Bob\" <sip:1002@server>;tag=existing")
+    );
+}
+
+#[test]
+fn trying_echoes_timestamp_and_compact_names() {
+    let text = sample_invite().replace("Call-ID:", "i:").replace(
+        "CSeq: 314159 INVITE\r\n",
+        "CSeq: 314159 INVITE\r\nTimestamp: 54\r\n",
+    );
+    let invite = parse_request(&text);
+
+    let trying = ResponseBuilder::from_request(&invite, 100).build();
+    assert_eq!(trying.headers.get("Timestamp"), Some("54"));
+    assert_eq!(trying.headers.get_header("Call-ID").unwrap().name, "i");
+
+    let ringing = ResponseBuilder::from_request(&invite, 180).build();
+    assert!(!ringing.headers.contains("Timestamp"));
+}
+
+#[test]
+fn custom_reason_and_unknown_codes() {
+    let invite = parse_request(&sample_invite());
+    let resp = ResponseBuilder::from_request(&invite, 480)
+        .reason("Away")
+        .build();
+    assert_eq!(resp.reason, "Away");
+    assert_eq!(reason_phrase(499), "Client Error");
+    assert_eq!(reason_phrase(599), "Server Error");
+}
+
+#[test]
+fn request_builder_fills_mandatory_headers() {
+    let bye = RequestBuilder::new("BYE", "sip:1002@192.168.1.20:5060")
+        .via("z9hG4bKtest")
+        .from("<sip:1001@server>;tag=a")
+        .to("<sip:1002@server>;tag=b")
+        .call_id("call-1")
+        .cseq(7)
+        .build();
+
+    let text = bye.to_string();
+    assert!(text.starts_with("BYE sip:1002@192.168.1.20:5060 SIP/2.0\r\n"));
+    let reparsed = parse_request(&text);
+    assert_eq!(reparsed, bye);
+    assert!(reparsed.top_via().unwrap().contains("branch=z9hG4bKtest"));
+    assert_eq!(reparsed.cseq(), Some((7, "BYE".to_string())));
+    assert_eq!(reparsed.max_forwards(), Some(70));
+    assert_eq!(reparsed.headers.get("User-Agent"), Some(SERVER_USER_AGENT));
+    assert_eq!(reparsed.headers.get("Content-Length"), Some("0"));
+}
+
+#[test]
+fn cancel_and_ack_mirror_the_invite() {
+    let invite = RequestBuilder::new("INVITE", "sip:1002@192.168.1.20:5060")
+        .via(&generate_branch())
+        .from("<sip:1001@server>;tag=a")
+        .to("<sip:1002@server>")
+        .call_id("call-2")
+        .cseq(11)
+        .body("application/sdp", "v=0\r\n")
+        .build();
+
+    let cancel = RequestBuilder::cancel_for(&invite);
+    assert_eq!(cancel.method, "CANCEL");
+    assert_eq!(cancel.uri, invite.uri);
+    assert_eq!(cancel.top_via(), invite.top_via());
+    assert_eq!(cancel.cseq(), Some((11, "CANCEL".to_string())));
+    assert_eq!(cancel.headers.get("To"), Some("<sip:1002@server>"));
+    assert!(cancel.body.is_empty());
+
+    let busy = parse_response(&sample_response(486, "Busy Here"));
+    let ack = RequestBuilder::ack_for(&invite, &busy);
+    assert_eq!(ack.top_via(), invite.top_via());
+    assert_eq!(ack.cseq(), Some((11, "ACK".to_string())));
+    assert_eq!(ack.headers.get("To"), busy.headers.get("To"));
+    assert_eq!(ack.call_id(), Some("call-2"));
+}
+
+#[test]
+fn generated_identifiers_are_unique() {
+    let a = generate_branch();
+    let b = generate_branch();
+    assert!(a.starts_with("z9hG4bK"));
+    assert_ne!(a, b);
+    assert_ne!(generate_tag(), generate_tag());
+    assert_ne!(generate_call_id(), generate_call_id());
+}
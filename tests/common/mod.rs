@@ -1,4 +1,54 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use sip_server_rust::auth::{issue_nonce, Credentials};
+use sip_server_rust::builder::ResponseBuilder;
+use sip_server_rust::location::location_service;
+use sip_server_rust::message::{
+    parse_message, MessageHeaders, ParsedMessage, SipRequest, SipResponse,
+};
+use sip_server_rust::sip_defs::{
+    Binding, CallMap, LocationEntry, SipMessage, UserFeatures, UserSecret, AUTH_REALM,
+    LOCATION_ENTRIES,
+};
+use sip_server_rust::uri::NameAddr;
+use sip_server_rust::worker::process_sip_messages;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The server has no built-in users; the tests call each other as 1001 to
+// 1006, whose password is "secret" and the username. Users already there
+// are left as they are.
+#[allow(dead_code)]
+pub fn add_test_users() {
+    let mut entries = LOCATION_ENTRIES.lock().unwrap();
+    for username in ["1001", "1002", "1003", "1004", "1005", "1006"] {
+        if entries.iter().any(|entry| entry.username == username) {
+            continue;
+        }
+        entries.push(LocationEntry {
+            username: username.to_string(),
+            secret: UserSecret::Password(format!("secret{}", username)),
+            display_name: None,
+            allowed_networks: Vec::new(),
+            features: UserFeatures::ALL,
+        });
+    }
+}
+
+// The digest password of `username`; None when only its HA1 is kept.
+#[allow(dead_code)]
+pub fn get_password(username: &str) -> Option<String> {
+    LOCATION_ENTRIES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|entry| entry.username == username)
+        .and_then(|entry| match &entry.secret {
+            UserSecret::Password(password) => Some(password.clone()),
+            UserSecret::Ha1(..) => None,
+        })
+}
 
 #[allow(dead_code)]
 pub fn sample_invite() -> String {
@@ -41,3 +91,328 @@ CSeq: 1 INVITE\r\n\
 Content-Length: 0\r\n\r\n"
         .to_string()
 }
+
+// Adds digest credentials of the From user to a request, as a phone does
+// once it has been challenged: Authorization for REGISTER,
+// Proxy-Authorization otherwise.
+#[allow(dead_code)]
+pub fn authorize(text: &str) -> String {
+    let request = match parse_message(text.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("only requests are authorized"),
+    };
+    let from = NameAddr::parse(request.headers.get("From").unwrap()).unwrap();
+    let user = from.user().unwrap().to_string();
+    let mut credentials = Credentials {
+        username: user.clone(),
+        realm: AUTH_REALM.to_string(),
+        nonce: issue_nonce(Instant::now()),
+        uri: request.uri.clone(),
+        response: String::new(),
+        algorithm: Some("SHA-256".to_string()),
+        qop: Some("auth".to_string()),
+        nc: Some("00000001".to_string()),
+        cnonce: Some("0a4f113b".to_string()),
+    };
+    let password = get_password(&user).unwrap();
+    credentials.response = credentials
+        .expected_response(&request.method, &password)
+        .unwrap();
+    let header = if request.method == "REGISTER" {
+        "Authorization"
+    } else {
+        "Proxy-Authorization"
+    };
+    let (request_line, rest) = text.split_once("\r\n").unwrap();
+    format!(
+        "{}\r\n{}: {}\r\n{}",
+        request_line, header, credentials, rest
+    )
+}
+
+#[allow(dead_code)]
+pub fn make_sip_message(body: &str, addr: SocketAddr) -> SipMessage {
+    SipMessage {
+        buffer: body.as_bytes().to_vec(),
+        client_addr: addr,
+    }
+}
+
+// Reads datagrams until one starts with `prefix`.
+#[allow(dead_code)]
+pub fn expect_message(sock: &UdpSocket, prefix: &str) -> ParsedMessage {
+    let mut buf = [0u8; 4096];
+    for _ in 0..20 {
+        if let Ok((len, _)) = sock.recv_from(&mut buf) {
+            let text = String::from_utf8_lossy(&buf[..len]).to_string();
+            if text.starts_with(prefix) {
+                return parse_message(text.as_bytes()).expect("server sent valid SIP");
+            }
+        }
+    }
+    panic!("did not receive {}", prefix);
+}
+
+#[allow(dead_code)]
+pub fn request(message: ParsedMessage) -> SipRequest {
+    match message {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    }
+}
+
+#[allow(dead_code)]
+pub fn response(message: ParsedMessage) -> SipResponse {
+    match message {
+        ParsedMessage::Response(resp) => resp,
+        ParsedMessage::Request(_) => panic!("expected a response"),
+    }
+}
+
+// A phone's answer to a request it received.
+#[allow(dead_code)]
+pub fn reply(
+    req: &SipRequest,
+    code: u16,
+    tag: &str,
+    contact: SocketAddr,
+    body: Option<&str>,
+) -> String {
+    let mut resp = ResponseBuilder::from_request(req, code)
+        .to_tag(tag)
+        .contact(&format!("<sip:phone@{}>", contact));
+    if let Some(body) = body {
+        resp = resp.body("application/sdp", body);
+    }
+    resp.build().to_string()
+}
+
+// Gives `user` the phone's address as its only binding.
+#[allow(dead_code)]
+pub fn register(user: &str, phone: &UdpSocket) {
+    let addr = phone.local_addr().unwrap();
+    let mut location = location_service();
+    location.remove(user, None).unwrap();
+    location
+        .register_binding(user, Binding::new(&format!("sip:{}@{}", user, addr), addr))
+        .unwrap();
+}
+
+// A phone's socket, or None if the sandbox does not allow UDP sockets.
+#[allow(dead_code)]
+pub fn bind() -> Option<Arc<UdpSocket>> {
+    let sock = UdpSocket::bind("127.0.0.1:0").ok()?;
+    sock.set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    Some(Arc::new(sock))
+}
+
+#[allow(dead_code)]
+pub fn addr(sock: &UdpSocket) -> SocketAddr {
+    sock.local_addr().unwrap()
+}
+
+// An audio session description of `owner`, sending and receiving.
+#[allow(dead_code)]
+pub fn sdp(owner: &str) -> String {
+    format!(
+        "v=0\r\no={} 1 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+m=audio 4000 RTP/AVP 0\r\na=sendrecv\r\n",
+        owner
+    )
+}
+
+// The next session description of `owner`, holding the call in `direction`.
+#[allow(dead_code)]
+pub fn hold_sdp(owner: &str, direction: &str) -> String {
+    sdp(owner)
+        .replace(" 1 1 IN", " 1 2 IN")
+        .replace("a=sendrecv", &format!("a={}", direction))
+}
+
+// `text`, a bodiless message, with `sdp` as its body.
+#[allow(dead_code)]
+pub fn with_sdp(text: &str, sdp: &str) -> String {
+    text.replace(
+        "Content-Length: 0\r\n\r\n",
+        &format!(
+            "Content-Type: application/sdp\r\nContent-Length: {}\r\n\r\n{}",
+            sdp.len(),
+            sdp
+        ),
+    )
+}
+
+// A bodiless request within a dialog, with the phone's own From/To (tags
+// included) and `extra` headers.
+#[allow(dead_code)]
+pub fn in_dialog(
+    method: &str,
+    from: &str,
+    to: &str,
+    call_id: &str,
+    cseq: u32,
+    phone: SocketAddr,
+    extra: &str,
+) -> String {
+    format!(
+        "{} sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {};branch=z9hG4bK{}{}{}\r\n\
+From: {}\r\n\
+To: {}\r\n\
+Call-ID: {}\r\n\
+CSeq: {} {}\r\n\
+Contact: <sip:phone@{}>\r\n\
+{}Content-Length: 0\r\n\r\n",
+        method,
+        phone,
+        method.to_lowercase(),
+        phone.port(),
+        cseq,
+        from,
+        to,
+        call_id,
+        cseq,
+        method,
+        phone,
+        extra
+    )
+}
+
+// A worker thread, fed what the server socket would receive.
+#[allow(dead_code)]
+pub struct Worker {
+    pub call_map: Arc<Mutex<CallMap>>,
+    pub tx: Sender<SipMessage>,
+    pub handle: thread::JoinHandle<()>,
+}
+
+#[allow(dead_code)]
+impl Worker {
+    // Starts a worker that sends from `server`.
+    pub fn start(server: Arc<UdpSocket>) -> Worker {
+        let call_map = Arc::new(Mutex::new(CallMap::new()));
+        let (tx, rx) = mpsc::channel();
+        let worker_map = Arc::clone(&call_map);
+        let handle = thread::spawn(move || process_sip_messages(rx, worker_map, server));
+        Worker {
+            call_map,
+            tx,
+            handle,
+        }
+    }
+
+    // Passes `text` to the worker as sent by `phone`.
+    pub fn send(&self, text: &str, phone: &UdpSocket) {
+        self.tx.send(make_sip_message(text, addr(phone))).unwrap();
+    }
+
+    // `phone` answers `req` with a bodiless `code`.
+    pub fn reply(&self, phone: &UdpSocket, req: &SipRequest, code: u16, tag: &str) {
+        self.send(&reply(req, code, tag, addr(phone), None), phone);
+    }
+
+    // A calls `callee` with an SDP offer and `extra` headers; the call is
+    // taken on with 100 Trying.
+    pub fn call(&self, phone_a: &UdpSocket, callee: &str, extra: &str) {
+        let invite = sample_invite()
+            .replace("sip:1002@server", &format!("sip:{}@server", callee))
+            .replace(
+                "Content-Length: 0\r\n",
+                &format!("{}Content-Length: 0\r\n", extra),
+            );
+        self.send(&authorize(&with_sdp(&invite, &sdp("alice"))), phone_a);
+        expect_message(phone_a, "SIP/2.0 100 Trying");
+    }
+
+    // Ends the worker once it has handled everything sent to it.
+    pub fn stop(self) {
+        drop(self.tx);
+        self.handle.join().unwrap();
+    }
+}
+
+// A worker, the caller's phone A and phone B of the user A calls.
+#[allow(dead_code)]
+pub struct Phones {
+    pub worker: Worker,
+    pub phone_a: Arc<UdpSocket>,
+    pub phone_b: Arc<UdpSocket>,
+}
+
+// A call A made and B answered, with From/To as each phone writes them in
+// its own requests.
+#[allow(dead_code)]
+pub struct Connected {
+    pub invite_to_b: SipRequest,
+    pub ok_to_a: SipResponse,
+    pub a_from: String,
+    pub a_to: String,
+    pub b_from: String,
+    pub b_to: String,
+    pub b_call_id: String,
+}
+
+#[allow(dead_code)]
+impl Phones {
+    // Starts a worker and registers B as `callee`, or None if the sandbox
+    // does not allow UDP sockets.
+    pub fn start(callee: &str) -> Option<Phones> {
+        let (server, phone_a, phone_b) = (bind()?, bind()?, bind()?);
+        register(callee, &phone_b);
+        Some(Phones {
+            worker: Worker::start(server),
+            phone_a,
+            phone_b,
+        })
+    }
+
+    // A calls `callee` with `extra` headers; returns the INVITE B received.
+    pub fn call(&self, callee: &str, extra: &str) -> SipRequest {
+        self.worker.call(&self.phone_a, callee, extra);
+        request(expect_message(&self.phone_b, "INVITE "))
+    }
+
+    // A calls `callee` with `a_extra` headers, B answers with `b_extra`
+    // headers and A acknowledges the answer.
+    pub fn connect_call(&self, callee: &str, a_extra: &str, b_extra: &[(&str, &str)]) -> Connected {
+        let invite_to_b = self.call(callee, a_extra);
+        let mut ok = ResponseBuilder::from_request(&invite_to_b, 200)
+            .to_tag("bobtag")
+            .contact(&format!("<sip:phone@{}>", addr(&self.phone_b)))
+            .body("application/sdp", &sdp("bob"));
+        for (name, value) in b_extra {
+            ok = ok.header(name, value);
+        }
+        self.worker.send(&ok.build().to_string(), &self.phone_b);
+        let ok_to_a = response(expect_message(&self.phone_a, "SIP/2.0 200 OK"));
+
+        let a_from = ok_to_a.headers.get("From").unwrap().to_string();
+        let a_to = ok_to_a.headers.get("To").unwrap().to_string();
+        let ack = in_dialog(
+            "ACK",
+            &a_from,
+            &a_to,
+            "a84b4c76e66710@pc33.atlanta.com",
+            314159,
+            addr(&self.phone_a),
+            "",
+        );
+        self.worker.send(&ack, &self.phone_a);
+        expect_message(&self.phone_b, "ACK ");
+
+        Connected {
+            a_from,
+            a_to,
+            b_from: format!("{};tag=bobtag", invite_to_b.headers.get("To").unwrap()),
+            b_to: invite_to_b.headers.get("From").unwrap().to_string(),
+            b_call_id: invite_to_b.call_id().unwrap().to_string(),
+            invite_to_b,
+            ok_to_a,
+        }
+    }
+
+    pub fn stop(self) {
+        self.worker.stop();
+    }
+}
@@ -0,0 +1,177 @@
+mod common;
+
+use common::{sample_invite, sample_response};
+use sip_server_rust::dialog::*;
+use sip_server_rust::message::*;
+
+fn parse_request(text: &str) -> SipRequest {
+    match parse_message(text.as_bytes()).expect("message should parse") {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    }
+}
+
+fn parse_response(text: &str) -> SipResponse {
+    match parse_message(text.as_bytes()).expect("message should parse") {
+        ParsedMessage::Response(resp) => resp,
+        ParsedMessage::Request(_) => panic!("expected a response"),
+    }
+}
+
+fn in_dialog_request(method: &str, to_tag: &str, cseq: u32) -> SipRequest {
+    parse_request(&format!(
+        "{} sip:1002@server SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.10:5060;branch=z9hG4bKindlg{}\r\n\
+From: \"Alice\" <sip:1001@server>;tag=1928301774\r\n\
+To: \"Bob\" <sip:1002@server>;tag={}\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: {} {}\r\n\
+Content-Length: 0\r\n\r\n",
+        method, cseq, to_tag, cseq, method
+    ))
+}
+
+#[test]
+fn uas_dialog_from_invite() {
+    let invite = parse_request(&sample_invite());
+    let dialog = Dialog::uas(&invite, "srvtag").expect("dialog");
+    assert_eq!(dialog.state, DialogState::Early);
+    assert_eq!(dialog.call_id, "a84b4c76e66710@pc33.atlanta.com");
+    assert_eq!(dialog.remote_tag.as_deref(), Some("1928301774"));
+    assert_eq!(dialog.remote_cseq, Some(314159));
+    assert_eq!(dialog.remote_target, "sip:1001@192.168.1.10:5060");
+    assert_eq!(dialog.local_party(), "\"Bob\" <sip:1002@server>;tag=srvtag");
+    assert_eq!(
+        dialog.remote_party(),
+        "\"Alice\" <sip:1001@server>;tag=1928301774"
+    );
+}
+
+#[test]
+fn matches_requests_by_call_id_and_tags() {
+    let invite = parse_request(&sample_invite());
+    let dialog = Dialog::uas(&invite, "srvtag").unwrap();
+    assert!(dialog.matches(&in_dialog_request("BYE", "srvtag", 314160)));
+    assert!(!dialog.matches(&in_dialog_request("BYE", "other", 314160)));
+    // the initial INVITE has no To-tag yet
+    assert!(!dialog.matches(&invite));
+}
+
+#[test]
+fn rejects_out_of_order_remote_cseq() {
+    let invite = parse_request(&sample_invite());
+    let mut dialog = Dialog::uas(&invite, "srvtag").unwrap();
+    assert!(dialog.accept_remote_cseq(&in_dialog_request("INFO", "srvtag", 314160)));
+    assert!(!dialog.accept_remote_cseq(&in_dialog_request("BYE", "srvtag", 314100)));
+    assert_eq!(dialog.remote_cseq, Some(314160));
+}
+
+#[test]
+fn uac_dialog_learns_tag_and_target_from_response() {
+    let mut dialog = Dialog::uac(
+        "b-leg@server",
+        "\"Bob\" <sip:1002@server>",
+        "\"Alice\" <sip:1001@server>",
+        "sip:1001@server",
+    );
+    assert!(dialog.remote_tag.is_none());
+
+    dialog.update_from_response(&parse_response(&sample_response(180, "Ringing")));
+    assert_eq!(dialog.remote_tag.as_deref(), Some("qwer"));
+    assert_eq!(dialog.state, DialogState::Early);
+
+    dialog.update_from_response(&parse_response(&sample_response(200, "OK")));
+    assert_eq!(dialog.state, DialogState::Confirmed);
+    assert_eq!(dialog.remote_target, "sip:1002@192.168.1.20:5060");
+}
+
+#[test]
+fn requests_use_next_cseq_and_route_set() {
+    let mut dialog = Dialog::uac(
+        "b-leg@server",
+        "<sip:1001@server>",
+        "<sip:1002@server>",
+        "sip:1002@192.168.1.20:5060",
+    );
+    dialog.remote_tag = Some("qwer".to_string());
+    dialog.route_set = vec!["<sip:proxy1;lr>".to_string(), "<sip:proxy2;lr>".to_string()];
+
+    let invite = dialog.request("INVITE").build();
+    assert_eq!(invite.uri, "sip:1002@192.168.1.20:5060");
+    assert_eq!(invite.cseq(), Some((1, "INVITE".to_string())));
+    let routes: Vec<&str> = invite
+        .headers
+        .get_all("Route")
+        .map(|h| h.value.as_str())
+        .collect();
+    assert_eq!(routes, vec!["<sip:proxy1;lr>", "<sip:proxy2;lr>"]);
+
+    let ack = dialog.ack(1).build();
+    assert_eq!(ack.cseq(), Some((1, "ACK".to_string())));
+    assert_ne!(ack.top_via(), invite.top_via());
+
+    let bye = dialog.request("BYE").build();
+    assert_eq!(bye.cseq(), Some((2, "BYE".to_string())));
+    assert_eq!(bye.headers.get("To"), Some("<sip:1002@server>;tag=qwer"));
+}
+
+#[test]
+fn uas_route_set_keeps_record_route_order() {
+    let invite = sample_invite().replace(
+        "Max-Forwards: 70\r\n",
+        "Max-Forwards: 70\r\nRecord-Route: <sip:p2.example.com;lr>, <sip:p1.example.com;lr>\r\n",
+    );
+    let dialog = Dialog::uas(&parse_request(&invite), "srvtag").unwrap();
+    assert_eq!(
+        dialog.route_set,
+        vec!["<sip:p2.example.com;lr>", "<sip:p1.example.com;lr>"]
+    );
+}
+
+#[test]
+fn uac_route_set_is_reversed_record_route() {
+    let mut dialog = Dialog::uac(
+        "b-leg@server",
+        "<sip:1001@server>",
+        "<sip:1002@server>",
+        "sip:1002@192.168.1.20:5060",
+    );
+    let ok = sample_response(200, "OK").replace(
+        "Content-Length",
+        "Record-Route: <sip:10.0.0.2;lr>\r\nRecord-Route: <sip:10.0.0.1;lr>\r\nContent-Length",
+    );
+    dialog.update_from_response(&parse_response(&ok));
+    assert_eq!(
+        dialog.route_set,
+        vec!["<sip:10.0.0.1;lr>", "<sip:10.0.0.2;lr>"]
+    );
+    assert_eq!(dialog.next_hop(), Some("10.0.0.1:5060".parse().unwrap()));
+
+    let bye = dialog.request("BYE").build();
+    assert_eq!(bye.uri, "sip:1002@192.168.1.20:5060");
+    assert_eq!(
+        bye.headers.get_list("Route"),
+        vec!["<sip:10.0.0.1;lr>", "<sip:10.0.0.2;lr>"]
+    );
+}
+
+#[test]
+fn strict_router_becomes_request_uri() {
+    let mut dialog = Dialog::uac(
+        "b-leg@server",
+        "<sip:1001@server>",
+        "<sip:1002@server>",
+        "sip:1002@192.168.1.20:5060",
+    );
+    dialog.route_set = vec![
+        "<sip:10.0.0.1>".to_string(),
+        "<sip:10.0.0.2;lr>".to_string(),
+    ];
+
+    let bye = dialog.request("BYE").build();
+    assert_eq!(bye.uri, "sip:10.0.0.1");
+    assert_eq!(
+        bye.headers.get_list("Route"),
+        vec!["<sip:10.0.0.2;lr>", "<sip:1002@192.168.1.20:5060>"]
+    );
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use common::add_test_users;
+use sip_server_rust::auth::DigestAlgorithm;
+use sip_server_rust::directory::{
+    apply_directory, load_directory, parse_directory, source_allowed, user_features, DirectoryError,
+};
+use sip_server_rust::location::location_service;
+use sip_server_rust::sip_defs::{
+    get_registered_bindings, Binding, SourceNetwork, UserFeatures, UserSecret,
+};
+use std::fs;
+
+const DIRECTORY: &str = "\
+# username,secret,display name,allowed networks,features
+1001,secret1001,Alice,192.168.0.0/16 10.1.2.3,forwarding messaging transfer pickup
+1002, md5:605D5B360853990503B18AC8BF08D2ED , Bob ,, messaging   # HA1 only
+
+1003,secret1003,,,
+";
+
+fn line_error(text: &str) -> usize {
+    match parse_directory(text) {
+        Err(DirectoryError::Line(line, _)) => line,
+        other => panic!(
+            "expected a line error, got {:?}",
+            other.map(|users| users.len())
+        ),
+    }
+}
+
+#[test]
+fn users_are_read_with_their_secrets_networks_and_features() {
+    let users = parse_directory(DIRECTORY).unwrap();
+    assert_eq!(users.len(), 3);
+
+    assert_eq!(users[0].username, "1001");
+    assert_eq!(
+        users[0].secret,
+        UserSecret::Password("secret1001".to_string())
+    );
+    assert_eq!(users[0].display_name.as_deref(), Some("Alice"));
+    assert_eq!(
+        users[0].allowed_networks,
+        vec![
+            SourceNetwork::parse("192.168.0.0/16").unwrap(),
+            SourceNetwork::parse("10.1.2.3/32").unwrap(),
+        ]
+    );
+    assert_eq!(users[0].features, UserFeatures::ALL);
+
+    assert_eq!(
+        users[1].secret,
+        UserSecret::Ha1(
+            DigestAlgorithm::Md5,
+            "605d5b360853990503b18ac8bf08d2ed".to_string()
+        )
+    );
+    assert_eq!(users[1].display_name.as_deref(), Some("Bob"));
+    assert!(users[1].allowed_networks.is_empty());
+    assert_eq!(
+        users[1].features,
+        UserFeatures {
+            forwarding: false,
+            messaging: true,
+            transfer: false,
+            pickup: false,
+        }
+    );
+
+    assert_eq!(users[2].display_name, None);
+    assert!(!users[2].features.messaging);
+}
+
+#[test]
+fn mistakes_are_reported_with_their_line() {
+    assert_eq!(line_error("1001,secret,,,\n1002,secret,,\n"), 2);
+    assert_eq!(line_error("1001,secret,,,\n1001,other,,,\n"), 2);
+    assert_eq!(line_error("# users\n1001,,,,\n"), 2);
+    assert_eq!(line_error("1001,md5:1234,,,\n"), 1);
+    assert_eq!(line_error("1001,sha-1:abcd,,,\n"), 1);
+    assert_eq!(line_error("1001,secret,,10.0.0.0/33,\n"), 1);
+    assert_eq!(line_error("1001,secret,,,voicemail\n"), 1);
+}
+
+#[test]
+fn networks_match_addresses_under_their_prefix() {
+    let lan = SourceNetwork::parse("192.168.1.0/24").unwrap();
+    assert!(lan.contains("192.168.1.77".parse().unwrap()));
+    assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+    assert!(lan.contains("::ffff:192.168.1.5".parse().unwrap()));
+    assert!(!lan.contains("2001:db8::1".parse().unwrap()));
+
+    let v6 = SourceNetwork::parse("2001:db8::/32").unwrap();
+    assert!(v6.contains("2001:db8:1::5".parse().unwrap()));
+    assert!(!v6.contains("2001:db9::5".parse().unwrap()));
+
+    let any = SourceNetwork::parse("0.0.0.0/0").unwrap();
+    assert!(any.contains("203.0.113.9".parse().unwrap()));
+    assert_eq!(any.to_string(), "0.0.0.0/0");
+
+    assert_eq!(SourceNetwork::parse("10.0.0.1/x"), None);
+    assert_eq!(SourceNetwork::parse("server/8"), None);
+}
+
+// The only test here that changes LOCATION_ENTRIES.
+#[test]
+fn reloading_keeps_bindings_of_users_that_stay() {
+    add_test_users();
+    let addr = "192.168.1.10:5060".parse().unwrap();
+    // 3001 was never listed, like a binding restored from before a restart
+    for user in ["1001", "1004", "3001"] {
+        location_service()
+            .register_binding(user, Binding::new(&format!("sip:{}@{}", user, addr), addr))
+            .unwrap();
+    }
+
+    let path = std::env::temp_dir().join(format!("sip_directory_{}.csv", std::process::id()));
+    fs::write(
+        &path,
+        "1001,newsecret,Alice,192.168.0.0/16,messaging\n2001,secret2001,Carol,,\n",
+    )
+    .unwrap();
+    assert_eq!(load_directory(&path).unwrap(), 2);
+    assert_eq!(get_registered_bindings("1001").len(), 1);
+    assert!(get_registered_bindings("1004").is_empty());
+    assert!(get_registered_bindings("3001").is_empty());
+    assert!(user_features("1004").is_none());
+    assert_eq!(user_features("2001").map(|f| f.transfer), Some(false));
+    assert!(source_allowed("1001", "192.168.7.7".parse().unwrap()));
+    assert!(!source_allowed("1001", "10.0.0.1".parse().unwrap()));
+    assert!(source_allowed("2001", "10.0.0.1".parse().unwrap()));
+
+    // A broken file leaves the users as they were
+    fs::write(&path, "1001,newsecret,Alice\n").unwrap();
+    assert!(load_directory(&path).is_err());
+    assert!(user_features("2001").is_some());
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(load_directory(&path), Err(DirectoryError::Io(_))));
+
+    apply_directory(Vec::new());
+    assert!(get_registered_bindings("1001").is_empty());
+}
+
+#[test]
+fn usernames_are_not_limited_in_length() {
+    let users = parse_directory("alice.smith.support.desk,secret,,,\n").unwrap();
+    assert_eq!(users[0].username, "alice.smith.support.desk");
+    assert_eq!(line_error("1001,secret,,,\n,secret,,,\n"), 2);
+}
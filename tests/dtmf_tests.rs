@@ -0,0 +1,61 @@
+use sip_server_rust::dtmf::*;
+use sip_server_rust::sdp::SessionDescription;
+
+#[test]
+fn parses_dtmf_relay_body() {
+    let body = "Signal=5\r\nDuration=160\r\n";
+    assert_eq!(
+        parse_dtmf_info("application/dtmf-relay", body),
+        Some(('5', Some(160)))
+    );
+    assert_eq!(
+        parse_dtmf_info("Application/DTMF-Relay; charset=utf-8", "Signal= #\r\n"),
+        Some(('#', None))
+    );
+}
+
+#[test]
+fn maps_numeric_event_codes() {
+    assert_eq!(
+        parse_dtmf_info("application/dtmf-relay", "Signal=10\r\nDuration=100"),
+        Some(('*', Some(100)))
+    );
+    assert_eq!(
+        parse_dtmf_info("application/dtmf-relay", "Signal=12"),
+        Some(('A', None))
+    );
+    assert_eq!(parse_dtmf_info("application/dtmf-relay", "Signal=16"), None);
+}
+
+#[test]
+fn parses_plain_dtmf_body() {
+    assert_eq!(
+        parse_dtmf_info("application/dtmf", "7\r\n"),
+        Some(('7', None))
+    );
+    assert_eq!(parse_dtmf_info("application/dtmf", "d"), Some(('D', None)));
+    assert_eq!(parse_dtmf_info("application/dtmf", "12x"), None);
+}
+
+#[test]
+fn other_content_types_are_not_dtmf() {
+    assert_eq!(parse_dtmf_info("application/sdp", "Signal=5"), None);
+    assert_eq!(
+        parse_dtmf_info("application/dtmf-relay", "Duration=100"),
+        None
+    );
+}
+
+#[test]
+fn finds_telephone_event_payload() {
+    let sdp = SessionDescription::parse(
+        "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\nc=IN IP4 10.0.0.1\r\nt=0 0\r\n\
+m=audio 4000 RTP/AVP 0 101\r\na=rtpmap:0 PCMU/8000\r\n\
+a=rtpmap:101 telephone-event/8000\r\na=fmtp:101 0-16\r\n",
+    )
+    .unwrap();
+    assert_eq!(
+        telephone_event_payload(&sdp.media[0]).as_deref(),
+        Some("101")
+    );
+}
@@ -0,0 +1,211 @@
+mod common;
+
+use common::{add_test_users, addr, authorize, bind, expect_message, request, response, Worker};
+use sip_server_rust::location::location_service;
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::{get_registered_bindings, Binding};
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// A worker, the caller and two phones registered for the same user.
+struct Phones {
+    worker: Worker,
+    phone_a: Arc<UdpSocket>,
+    desk: Arc<UdpSocket>,
+    soft: Arc<UdpSocket>,
+}
+
+impl Phones {
+    // Starts a worker and binds two contacts to `callee`, or None if the
+    // sandbox does not allow UDP sockets.
+    fn start(callee: &str) -> Option<Self> {
+        let (server, phone_a, desk, soft) = (bind()?, bind()?, bind()?, bind()?);
+        {
+            let mut location = location_service();
+            location.remove(callee, None).unwrap();
+            for sock in [&desk, &soft] {
+                let addr = addr(sock);
+                location
+                    .register_binding(
+                        callee,
+                        Binding::new(&format!("sip:{}@{}", callee, addr), addr),
+                    )
+                    .unwrap();
+            }
+        }
+        Some(Phones {
+            worker: Worker::start(server),
+            phone_a,
+            desk,
+            soft,
+        })
+    }
+
+    // Sends A's INVITE to `callee`; returns the INVITEs the desk phone and
+    // the softphone received.
+    fn invite(&self, callee: &str) -> (SipRequest, SipRequest) {
+        self.worker.call(&self.phone_a, callee, "");
+        (
+            request(expect_message(&self.desk, "INVITE ")),
+            request(expect_message(&self.soft, "INVITE ")),
+        )
+    }
+
+    // `phone` answers `invite` with `code`.
+    fn reply(&self, phone: &UdpSocket, invite: &SipRequest, code: u16, tag: &str) {
+        self.worker.reply(phone, invite, code, tag);
+    }
+
+    fn stop(self) {
+        self.worker.stop();
+    }
+}
+
+#[test]
+fn register_keeps_one_binding_per_contact() {
+    add_test_users();
+    let phones = match Phones::start("1001") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping forking test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    location_service().remove("1001", None).unwrap();
+
+    for (sock, cseq) in [(&phones.desk, 1), (&phones.soft, 2), (&phones.desk, 3)] {
+        let register = format!(
+            "REGISTER sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {addr};branch=z9hG4bKreg{cseq}\r\n\
+From: <sip:1001@server>;tag=reg{cseq}\r\n\
+To: <sip:1001@server>\r\n\
+Call-ID: reg-{addr}\r\n\
+CSeq: {cseq} REGISTER\r\n\
+Contact: <sip:1001@{addr}>\r\n\
+Content-Length: 0\r\n\r\n",
+            addr = addr(sock),
+            cseq = cseq
+        );
+        phones.worker.send(&authorize(&register), sock);
+        let ok = response(expect_message(sock, "SIP/2.0 200 OK"));
+        let contacts = ok.headers.get_list("Contact").len();
+        assert_eq!(contacts, if cseq == 1 { 1 } else { 2 });
+    }
+    let bindings = get_registered_bindings("1001");
+    assert_eq!(bindings.len(), 2, "re-registering a contact refreshes it");
+    assert_eq!(bindings[0].addr, addr(&phones.desk));
+    assert_eq!(bindings[1].addr, addr(&phones.soft));
+    phones.stop();
+}
+
+#[test]
+fn first_answer_wins_and_other_branches_are_cancelled() {
+    add_test_users();
+    let phones = match Phones::start("1002") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping forking test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let (to_desk, to_soft) = phones.invite("1002");
+    assert_eq!(to_desk.call_id(), to_soft.call_id());
+    assert_ne!(to_desk.top_via(), to_soft.top_via());
+    assert_eq!(to_soft.uri, format!("sip:1002@{}", addr(&phones.soft)));
+
+    // Both ring, each in its own early dialog
+    phones.reply(&phones.desk, &to_desk, 180, "desktag");
+    expect_message(&phones.phone_a, "SIP/2.0 180");
+    phones.reply(&phones.soft, &to_soft, 183, "softtag");
+    expect_message(&phones.phone_a, "SIP/2.0 183");
+
+    // The softphone answers; the desk phone's branch is cancelled
+    phones.reply(&phones.soft, &to_soft, 200, "softtag");
+    let ok_to_a = response(expect_message(&phones.phone_a, "SIP/2.0 200 OK"));
+    let cancel = request(expect_message(&phones.desk, "CANCEL "));
+    assert_eq!(cancel.top_via(), to_desk.top_via());
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let call = &guard.calls[0];
+        assert_eq!(call.b_leg_dialog.remote_tag.as_deref(), Some("softtag"));
+        assert_eq!(call.b_leg_addr, Some(addr(&phones.soft)));
+        assert!(call.forks.is_empty());
+    }
+
+    // A's BYE reaches the phone that answered
+    let bye = format!(
+        "BYE sip:TinySIP@server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {};branch=z9hG4bKbye1\r\n\
+From: {}\r\n\
+To: {}\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: 314160 BYE\r\n\
+Content-Length: 0\r\n\r\n",
+        addr(&phones.phone_a),
+        ok_to_a.headers.get("From").unwrap(),
+        ok_to_a.headers.get("To").unwrap()
+    );
+    phones.worker.send(&bye, &phones.phone_a);
+    let bye_to_b = request(expect_message(&phones.soft, "BYE "));
+    assert!(bye_to_b.headers.get("To").unwrap().contains("tag=softtag"));
+    phones.stop();
+}
+
+#[test]
+fn best_response_is_relayed_when_all_branches_fail() {
+    add_test_users();
+    let phones = match Phones::start("1003") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping forking test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let (to_desk, to_soft) = phones.invite("1003");
+
+    phones.reply(&phones.desk, &to_desk, 503, "desktag");
+    // Nothing goes to A while the softphone may still answer
+    thread::sleep(Duration::from_millis(100));
+    assert!(phones.worker.call_map.lock().unwrap().calls[0].is_active);
+
+    phones.reply(&phones.soft, &to_soft, 486, "softtag");
+    response(expect_message(&phones.phone_a, "SIP/2.0 486"));
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(phones.worker.call_map.lock().unwrap().size, 0);
+    phones.stop();
+}
+
+#[test]
+fn late_answer_from_cancelled_branch_is_ended() {
+    add_test_users();
+    let phones = match Phones::start("1004") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping forking test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let (to_desk, to_soft) = phones.invite("1004");
+    phones.reply(&phones.desk, &to_desk, 200, "desktag");
+    expect_message(&phones.phone_a, "SIP/2.0 200 OK");
+    expect_message(&phones.soft, "CANCEL ");
+
+    // The softphone answered before the CANCEL reached it
+    phones.reply(&phones.soft, &to_soft, 200, "softtag");
+    let ack = request(expect_message(&phones.soft, "ACK "));
+    let bye = request(expect_message(&phones.soft, "BYE "));
+    for req in [&ack, &bye] {
+        assert!(req.headers.get("To").unwrap().contains("tag=softtag"));
+    }
+    assert_eq!(
+        phones.worker.call_map.lock().unwrap().calls[0]
+            .b_leg_dialog
+            .remote_tag
+            .as_deref(),
+        Some("desktag"),
+        "the call stays with the branch that answered first"
+    );
+    phones.stop();
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use common::{add_test_users, bind, expect_message, register, request, Worker};
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::{CallForwarding, CALL_FORWARDING};
+use std::time::Duration;
+
+// Forwards calls for `user` as `setting` says.
+fn forward(user: &str, setting: impl FnOnce(&mut CallForwarding)) {
+    let mut forwarding = CallForwarding {
+        username: user.to_string(),
+        unconditional: None,
+        busy: None,
+        no_answer: None,
+        no_answer_timeout: Duration::from_millis(300),
+    };
+    setting(&mut forwarding);
+    CALL_FORWARDING.lock().unwrap().push(forwarding);
+}
+
+fn diversions(invite: &SipRequest) -> Vec<&str> {
+    invite
+        .headers
+        .get_all("Diversion")
+        .map(|h| h.value.as_str())
+        .collect()
+}
+
+#[test]
+fn busy_callee_forwards_with_diversion() {
+    add_test_users();
+    let (server, phone_a, busy, target) = match (bind(), bind(), bind(), bind()) {
+        (Some(server), Some(a), Some(busy), Some(target)) => (server, a, busy, target),
+        _ => {
+            eprintln!("Skipping forwarding test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    forward("1001", |f| f.busy = Some("1002".to_string()));
+    register("1001", &busy);
+    register("1002", &target);
+    let worker = Worker::start(server);
+
+    worker.call(&phone_a, "1001", "");
+    let to_busy = request(expect_message(&busy, "INVITE "));
+    assert!(diversions(&to_busy).is_empty());
+    worker.reply(&busy, &to_busy, 486, "phonetag");
+    expect_message(&busy, "ACK ");
+
+    let to_target = request(expect_message(&target, "INVITE "));
+    assert_eq!(
+        to_target.uri,
+        format!("sip:1002@{}", target.local_addr().unwrap())
+    );
+    assert_eq!(diversions(&to_target).len(), 1);
+    assert!(diversions(&to_target)[0].starts_with("<sip:1001@"));
+    assert!(diversions(&to_target)[0].contains(";reason=user-busy"));
+    assert!(to_target.cseq().unwrap().0 > to_busy.cseq().unwrap().0);
+
+    worker.reply(&target, &to_target, 200, "phonetag");
+    expect_message(&phone_a, "SIP/2.0 200 OK");
+    {
+        let guard = worker.call_map.lock().unwrap();
+        let call = &guard.calls[0];
+        assert_eq!(call.b_leg_addr, Some(target.local_addr().unwrap()));
+        assert_eq!(call.diversions.len(), 1);
+    }
+    worker.stop();
+}
+
+#[test]
+fn unanswered_callee_forwards_after_its_timeout() {
+    add_test_users();
+    let (server, phone_a, away, target) = match (bind(), bind(), bind(), bind()) {
+        (Some(server), Some(a), Some(away), Some(target)) => (server, a, away, target),
+        _ => {
+            eprintln!("Skipping forwarding test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    forward("1003", |f| f.no_answer = Some("1004".to_string()));
+    register("1003", &away);
+    register("1004", &target);
+    let worker = Worker::start(server);
+
+    worker.call(&phone_a, "1003", "");
+    let to_away = request(expect_message(&away, "INVITE "));
+    worker.reply(&away, &to_away, 180, "phonetag");
+    expect_message(&phone_a, "SIP/2.0 180");
+
+    // Ringing for longer than the no-answer timeout cancels the phone
+    let cancel = request(expect_message(&away, "CANCEL "));
+    assert_eq!(cancel.top_via(), to_away.top_via());
+    let to_target = request(expect_message(&target, "INVITE "));
+    assert!(diversions(&to_target)[0].contains(";reason=no-answer"));
+    worker.reply(&away, &to_away, 487, "phonetag");
+
+    worker.reply(&target, &to_target, 200, "phonetag");
+    expect_message(&phone_a, "SIP/2.0 200 OK");
+    worker.stop();
+}
+
+#[test]
+fn unconditional_forwards_chain_to_a_uri_outside_the_server() {
+    add_test_users();
+    let (server, phone_a, outside) = match (bind(), bind(), bind()) {
+        (Some(server), Some(a), Some(outside)) => (server, a, outside),
+        _ => {
+            eprintln!("Skipping forwarding test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let outside_uri = format!("sip:+4930123@{}", outside.local_addr().unwrap());
+    forward("1006", |f| f.unconditional = Some("1005".to_string()));
+    forward("1005", |f| f.unconditional = Some(outside_uri.clone()));
+    let worker = Worker::start(server);
+
+    // Neither 1006 nor 1005 is logged in; neither is rung
+    worker.call(&phone_a, "1006", "");
+    let to_outside = request(expect_message(&outside, "INVITE "));
+    assert_eq!(to_outside.uri, outside_uri);
+    let headers = diversions(&to_outside);
+    assert_eq!(headers.len(), 2);
+    assert!(headers[0].starts_with("<sip:1005@"));
+    assert!(headers[1].starts_with("<sip:1006@"));
+    assert!(headers
+        .iter()
+        .all(|header| header.contains(";reason=unconditional")));
+
+    worker.reply(&outside, &to_outside, 404, "phonetag");
+    expect_message(&phone_a, "SIP/2.0 404");
+    worker.stop();
+}
@@ -0,0 +1,80 @@
+use sip_server_rust::directory::DirectoryError;
+use sip_server_rust::forwarding::{diversion_headers, outside_binding, parse_call_forwarding};
+use sip_server_rust::sip_defs::{Diversion, DiversionReason, SIP_SERVER_IP_ADDRESS};
+use std::time::Duration;
+
+#[test]
+fn diversion_headers_list_the_latest_forward_first() {
+    let diversions = vec![
+        Diversion {
+            user: "1002".to_string(),
+            reason: DiversionReason::UserBusy,
+        },
+        Diversion {
+            user: "1001".to_string(),
+            reason: DiversionReason::NoAnswer,
+        },
+    ];
+    assert_eq!(
+        diversion_headers(&diversions),
+        vec![
+            format!(
+                "<sip:1001@{}>;reason=no-answer;counter=1",
+                SIP_SERVER_IP_ADDRESS
+            ),
+            format!(
+                "<sip:1002@{}>;reason=user-busy;counter=1",
+                SIP_SERVER_IP_ADDRESS
+            ),
+        ]
+    );
+}
+
+#[test]
+fn outside_binding_needs_a_sip_uri_with_an_ip_host() {
+    let binding = outside_binding("sip:+4930123@203.0.113.5:5070").unwrap();
+    assert_eq!(binding.contact, "sip:+4930123@203.0.113.5:5070");
+    assert_eq!(binding.addr, "203.0.113.5:5070".parse().unwrap());
+    assert_eq!(
+        outside_binding("sip:voicemail@10.0.0.9").unwrap().addr,
+        "10.0.0.9:5060".parse().unwrap()
+    );
+
+    assert!(outside_binding("1002").is_none(), "a local user");
+    assert!(outside_binding("sip:bob@example.com").is_none());
+    assert!(outside_binding("tel:+4930123").is_none());
+}
+
+#[test]
+fn forwarding_settings_are_read_from_their_file() {
+    let settings = parse_call_forwarding(
+        "# username,unconditional,busy,no answer,no answer timeout\n\
+1002,,1001,2000,20\n\
+1003,sip:+4930123@203.0.113.5,,,\n",
+    )
+    .unwrap();
+    assert_eq!(settings.len(), 2);
+    assert_eq!(settings[0].username, "1002");
+    assert_eq!(settings[0].unconditional, None);
+    assert_eq!(settings[0].busy.as_deref(), Some("1001"));
+    assert_eq!(settings[0].no_answer.as_deref(), Some("2000"));
+    assert_eq!(settings[0].no_answer_timeout, Duration::from_secs(20));
+    assert_eq!(
+        settings[1].unconditional.as_deref(),
+        Some("sip:+4930123@203.0.113.5")
+    );
+    assert_eq!(settings[1].no_answer, None);
+
+    for (text, line) in [
+        ("1002,,1001,2000\n", 1),
+        ("1002,,1001,,\n1002,,1003,,\n", 2),
+        (",,1001,,\n", 1),
+        ("1002,,,2000,\n", 1),
+        ("1002,,,2000,0\n", 1),
+    ] {
+        match parse_call_forwarding(text) {
+            Err(DirectoryError::Line(number, _)) => assert_eq!(number, line, "{:?}", text),
+            other => panic!("expected a line error for {:?}, got {:?}", text, other),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+mod common;
+
+use common::{
+    add_test_users, addr, authorize, bind, expect_message, hold_sdp, in_dialog, reply, request,
+    response, sample_invite, with_sdp, Phones, Worker,
+};
+use sip_server_rust::message::*;
+
+#[test]
+fn reinvite_hold_is_relayed_with_ack() {
+    add_test_users();
+    let phones = match Phones::start("1003") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping re-INVITE test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call("1003", "", &[]);
+    let a_addr = addr(&phones.phone_a);
+    let b_addr = addr(&phones.phone_b);
+
+    // B puts the call on hold
+    let hold = with_sdp(
+        &in_dialog(
+            "INVITE",
+            &call.b_from,
+            &call.b_to,
+            &call.b_call_id,
+            1,
+            b_addr,
+            "",
+        ),
+        &hold_sdp("bob", "sendonly"),
+    );
+    phones.worker.send(&hold, &phones.phone_b);
+    let hold_to_a = request(expect_message(&phones.phone_a, "INVITE "));
+    assert!(hold_to_a.body.contains("a=sendonly"));
+    assert_eq!(
+        hold_to_a.headers.get("From"),
+        Some(call.a_to.as_str()),
+        "re-INVITE to A is sent in the A-leg dialog"
+    );
+
+    let ok = reply(
+        &hold_to_a,
+        200,
+        "",
+        a_addr,
+        Some(&hold_sdp("alice", "recvonly")),
+    );
+    phones.worker.send(&ok, &phones.phone_a);
+    let ok_to_b = response(expect_message(&phones.phone_b, "SIP/2.0 200 OK"));
+    assert!(ok_to_b.body.contains("a=recvonly"));
+    assert_eq!(ok_to_b.cseq(), Some((1, "INVITE".to_string())));
+
+    let ack = in_dialog(
+        "ACK",
+        &call.b_from,
+        &call.b_to,
+        &call.b_call_id,
+        1,
+        b_addr,
+        "",
+    );
+    phones.worker.send(&ack, &phones.phone_b);
+    let ack_to_a = request(expect_message(&phones.phone_a, "ACK "));
+    assert_eq!(
+        ack_to_a.cseq(),
+        hold_to_a.cseq().map(|(n, _)| (n, "ACK".to_string()))
+    );
+
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let state = &guard.calls[0];
+        assert!(state.b_leg_media.on_hold);
+        assert!(!state.a_leg_media.on_hold);
+        assert!(state.relayed_requests.is_empty());
+    }
+
+    phones.stop();
+}
+
+#[test]
+fn crossing_reinvites_get_491() {
+    add_test_users();
+    let phones = match Phones::start("1004") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping glare test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call("1004", "", &[]);
+
+    // A's re-INVITE reaches B, which does not answer yet
+    let from_a = with_sdp(
+        &in_dialog(
+            "INVITE",
+            &call.a_from,
+            &call.a_to,
+            "a84b4c76e66710@pc33.atlanta.com",
+            314160,
+            addr(&phones.phone_a),
+            "",
+        ),
+        &hold_sdp("alice", "sendonly"),
+    );
+    phones.worker.send(&from_a, &phones.phone_a);
+    expect_message(&phones.phone_b, "INVITE ");
+
+    // B's own re-INVITE crosses it
+    let from_b = with_sdp(
+        &in_dialog(
+            "INVITE",
+            &call.b_from,
+            &call.b_to,
+            &call.b_call_id,
+            1,
+            addr(&phones.phone_b),
+            "",
+        ),
+        &hold_sdp("bob", "sendonly"),
+    );
+    phones.worker.send(&from_b, &phones.phone_b);
+    let glare = response(expect_message(&phones.phone_b, "SIP/2.0 491"));
+    assert_eq!(glare.cseq(), Some((1, "INVITE".to_string())));
+
+    phones.stop();
+}
+
+#[test]
+fn info_dtmf_is_relayed_and_recorded() {
+    add_test_users();
+    let phones = match Phones::start("1005") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping INFO test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call("1005", "", &[]);
+
+    let body = "Signal=5\r\nDuration=160\r\n";
+    let info = format!(
+        "INFO sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {};branch=z9hG4bKinfo1\r\n\
+From: {}\r\n\
+To: {}\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: 314160 INFO\r\n\
+Content-Type: application/dtmf-relay\r\n\
+Content-Length: {}\r\n\r\n{}",
+        addr(&phones.phone_a),
+        call.a_from,
+        call.a_to,
+        body.len(),
+        body
+    );
+    phones.worker.send(&info, &phones.phone_a);
+    let info_to_b = request(expect_message(&phones.phone_b, "INFO "));
+    assert_eq!(info_to_b.content_type(), Some("application/dtmf-relay"));
+    assert_eq!(info_to_b.body, body);
+
+    phones.worker.reply(&phones.phone_b, &info_to_b, 200, "");
+    let ok_to_a = response(expect_message(&phones.phone_a, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_a.cseq(), Some((314160, "INFO".to_string())));
+
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let state = &guard.calls[0];
+        assert_eq!(state.dtmf_digits.len(), 1);
+        assert_eq!(state.dtmf_digits[0].signal, '5');
+        assert_eq!(state.dtmf_digits[0].duration_ms, Some(160));
+        assert!(state.relayed_requests.is_empty());
+    }
+
+    phones.stop();
+}
+
+#[test]
+fn in_dialog_options_is_relayed_to_the_peer() {
+    add_test_users();
+    let phones = match Phones::start("1006") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping OPTIONS test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call("1006", "", &[]);
+
+    let options = in_dialog(
+        "OPTIONS",
+        &call.b_from,
+        &call.b_to,
+        &call.b_call_id,
+        2,
+        addr(&phones.phone_b),
+        "Accept: application/sdp\r\n",
+    );
+    phones.worker.send(&options, &phones.phone_b);
+    let options_to_a = request(expect_message(&phones.phone_a, "OPTIONS "));
+    assert_eq!(
+        options_to_a.call_id(),
+        Some("a84b4c76e66710@pc33.atlanta.com")
+    );
+    assert_eq!(options_to_a.headers.get("Accept"), Some("application/sdp"));
+
+    phones.worker.reply(&phones.phone_a, &options_to_a, 200, "");
+    let ok_to_b = response(expect_message(&phones.phone_b, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_b.cseq(), Some((2, "OPTIONS".to_string())));
+
+    phones.stop();
+}
+
+#[test]
+fn out_of_dialog_options_is_answered_by_the_server() {
+    let (server, monitor) = match (bind(), bind()) {
+        (Some(server), Some(monitor)) => (server, monitor),
+        _ => {
+            eprintln!("Skipping OPTIONS test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let worker = Worker::start(server);
+
+    let options = format!(
+        "OPTIONS sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {};branch=z9hG4bKping1\r\n\
+From: <sip:monitor@example.com>;tag=mon1\r\n\
+To: <sip:server>\r\n\
+Call-ID: keepalive-1\r\n\
+CSeq: 1 OPTIONS\r\n\
+Max-Forwards: 70\r\n\
+Content-Length: 0\r\n\r\n",
+        addr(&monitor)
+    );
+    worker.send(&options, &monitor);
+    let ok = response(expect_message(&monitor, "SIP/2.0 200 OK"));
+    assert_eq!(ok.cseq(), Some((1, "OPTIONS".to_string())));
+    assert!(ok.headers.get("To").unwrap().contains("tag="));
+    assert!(ok.headers.get("Allow").unwrap().contains("INVITE"));
+    assert!(ok.headers.get("Allow").unwrap().contains("OPTIONS"));
+    assert_eq!(ok.headers.get("Accept"), Some("application/sdp"));
+    assert!(ok.headers.get("Supported").unwrap().contains("timer"));
+    assert_eq!(worker.call_map.lock().unwrap().size, 0);
+
+    worker.stop();
+}
+
+#[test]
+fn requests_without_hops_left_are_refused() {
+    add_test_users();
+    let phones = match Phones::start("1002") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping Max-Forwards test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call("1002", "", &[]);
+
+    // A new call is not set up
+    let invite = authorize(
+        &sample_invite()
+            .replace("Max-Forwards: 70", "Max-Forwards: 0")
+            .replace(
+                "a84b4c76e66710@pc33.atlanta.com",
+                "looping@pc33.atlanta.com",
+            )
+            .replace("z9hG4bK776asdhds", "z9hG4bKlooping"),
+    );
+    phones.worker.send(&invite, &phones.phone_a);
+    let too_many_hops = response(expect_message(&phones.phone_a, "SIP/2.0 483"));
+    assert_eq!(too_many_hops.call_id(), Some("looping@pc33.atlanta.com"));
+
+    // Nor is a request within the call passed on
+    let options = in_dialog(
+        "OPTIONS",
+        &call.b_from,
+        &call.b_to,
+        &call.b_call_id,
+        2,
+        addr(&phones.phone_b),
+        "Max-Forwards: 0\r\n",
+    );
+    phones.worker.send(&options, &phones.phone_b);
+    let too_many_hops = response(expect_message(&phones.phone_b, "SIP/2.0 483"));
+    assert_eq!(too_many_hops.cseq(), Some((2, "OPTIONS".to_string())));
+    let map = phones.worker.call_map.lock().unwrap();
+    assert_eq!(map.calls.iter().filter(|c| c.is_active).count(), 1);
+    drop(map);
+
+    phones.stop();
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use common::{
+    add_test_users, addr, authorize, bind, expect_message, register, request, response, Worker,
+};
+use sip_server_rust::builder::ResponseBuilder;
+use sip_server_rust::location::location_service;
+use sip_server_rust::message::*;
+use sip_server_rust::message_store::stored_messages;
+use sip_server_rust::sip_defs::{LOCATION_ENTRIES, MESSAGE_STORE_DIR};
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Starts a worker that keeps messages for offline users in a directory of
+// its own.
+fn start_worker(server: Arc<UdpSocket>) -> Worker {
+    let dir = std::env::temp_dir().join(format!("sip_instant_message_flow_{}", std::process::id()));
+    *MESSAGE_STORE_DIR.lock().unwrap() = dir;
+    Worker::start(server)
+}
+
+fn chat(to: &str, text: &str, phone: &UdpSocket) -> String {
+    format!(
+        "MESSAGE sip:{to}@server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {addr};branch=z9hG4bKmsg{to}{text}\r\n\
+Max-Forwards: 70\r\n\
+From: \"Alice\" <sip:1001@server>;tag=alicechat\r\n\
+To: <sip:{to}@server>\r\n\
+Call-ID: chat-{to}-{text}\r\n\
+CSeq: 1 MESSAGE\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: {len}\r\n\r\n{text}",
+        to = to,
+        addr = addr(phone),
+        text = text,
+        len = text.len()
+    )
+}
+
+#[test]
+fn message_is_relayed_to_registered_user_and_answer_comes_back() {
+    add_test_users();
+    let (server, alice, bob) = match (bind(), bind(), bind()) {
+        (Some(server), Some(alice), Some(bob)) => (server, alice, bob),
+        _ => {
+            eprintln!("Skipping MESSAGE test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    register("1002", &bob);
+    let worker = start_worker(server);
+
+    worker.send(&chat("1002", "hello", &alice), &alice);
+    let to_bob = request(expect_message(&bob, "MESSAGE "));
+    assert_eq!(to_bob.uri, format!("sip:1002@{}", addr(&bob)));
+    assert_eq!(to_bob.body, "hello");
+    assert_eq!(to_bob.content_type(), Some("text/plain"));
+    assert_ne!(to_bob.call_id(), Some("chat-1002-hello"));
+    let from = to_bob.headers.get("From").unwrap();
+    assert!(from.starts_with("\"Alice\" <sip:1001@server>;tag="));
+    assert!(!from.contains("alicechat"));
+
+    let ok = ResponseBuilder::from_request(&to_bob, 200)
+        .to_tag("bobchat")
+        .build()
+        .to_string();
+    worker.send(&ok, &bob);
+    let ok_to_alice = response(expect_message(&alice, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_alice.call_id(), Some("chat-1002-hello"));
+    assert_eq!(ok_to_alice.cseq(), Some((1, "MESSAGE".to_string())));
+    worker.stop();
+}
+
+#[test]
+fn message_for_offline_user_is_stored_and_delivered_on_register() {
+    add_test_users();
+    let (server, alice, carol) = match (bind(), bind(), bind()) {
+        (Some(server), Some(alice), Some(carol)) => (server, alice, carol),
+        _ => {
+            eprintln!("Skipping MESSAGE test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    location_service().remove("1003", None).unwrap();
+    let worker = start_worker(server);
+
+    for text in ["first", "second"] {
+        worker.send(&chat("1003", text, &alice), &alice);
+        let accepted = response(expect_message(&alice, "SIP/2.0 202"));
+        assert!(accepted.headers.get("To").unwrap().contains("tag="));
+    }
+    assert_eq!(stored_messages("1003").len(), 2);
+
+    let register = format!(
+        "REGISTER sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {addr};branch=z9hG4bKreg1\r\n\
+From: <sip:1003@server>;tag=reg1\r\n\
+To: <sip:1003@server>\r\n\
+Call-ID: reg-{addr}\r\n\
+CSeq: 1 REGISTER\r\n\
+Contact: <sip:1003@{addr}>\r\n\
+Content-Length: 0\r\n\r\n",
+        addr = addr(&carol)
+    );
+    worker.send(&authorize(&register), &carol);
+    expect_message(&carol, "SIP/2.0 200 OK");
+    let first = request(expect_message(&carol, "MESSAGE "));
+    let second = request(expect_message(&carol, "MESSAGE "));
+    assert_eq!(first.body, "first");
+    assert_eq!(second.body, "second");
+
+    // Only accepted messages leave the store
+    let ok = ResponseBuilder::from_request(&first, 200)
+        .to_tag("carolchat")
+        .build()
+        .to_string();
+    worker.send(&ok, &carol);
+    let busy = ResponseBuilder::from_request(&second, 486)
+        .to_tag("carolchat")
+        .build()
+        .to_string();
+    worker.send(&busy, &carol);
+    thread::sleep(Duration::from_millis(50));
+    let stored = stored_messages("1003");
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].1.body, "second");
+
+    // Registering again retries what is left
+    worker.send(
+        &authorize(
+            &register
+                .replace("reg1", "reg2")
+                .replace("CSeq: 1", "CSeq: 2"),
+        ),
+        &carol,
+    );
+    expect_message(&carol, "SIP/2.0 200 OK");
+    let retry = request(expect_message(&carol, "MESSAGE "));
+    assert_eq!(retry.body, "second");
+    worker.stop();
+}
+
+#[test]
+fn message_for_unknown_user_is_rejected() {
+    let (server, alice) = match (bind(), bind()) {
+        (Some(server), Some(alice)) => (server, alice),
+        _ => {
+            eprintln!("Skipping MESSAGE test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let worker = start_worker(server);
+    worker.send(&chat("9999", "anyone", &alice), &alice);
+    expect_message(&alice, "SIP/2.0 404");
+    worker.stop();
+}
+
+#[test]
+fn message_for_user_without_messaging_is_refused() {
+    add_test_users();
+    let (server, alice) = match (bind(), bind()) {
+        (Some(server), Some(alice)) => (server, alice),
+        _ => {
+            eprintln!("Skipping MESSAGE test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    {
+        let mut entries = LOCATION_ENTRIES.lock().unwrap();
+        let entry = entries.iter_mut().find(|e| e.username == "1004").unwrap();
+        entry.features.messaging = false;
+    }
+    let worker = start_worker(server);
+    worker.send(&chat("1004", "hi", &alice), &alice);
+    expect_message(&alice, "SIP/2.0 403");
+    assert!(stored_messages("1004").is_empty());
+    worker.stop();
+}
+
+#[test]
+fn message_without_hops_left_is_refused() {
+    add_test_users();
+    let (server, alice) = match (bind(), bind()) {
+        (Some(server), Some(alice)) => (server, alice),
+        _ => {
+            eprintln!("Skipping MESSAGE test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let worker = start_worker(server);
+    let message = chat("1005", "looping", &alice).replace("Max-Forwards: 70", "Max-Forwards: 0");
+    worker.send(&message, &alice);
+    expect_message(&alice, "SIP/2.0 483");
+    assert!(stored_messages("1005").is_empty());
+    worker.stop();
+}
@@ -0,0 +1,250 @@
+mod common;
+
+use common::add_test_users;
+use sip_server_rust::location::{
+    location_service, set_location_service, InMemoryLocationService, LocationService,
+    SqliteLocationService,
+};
+use sip_server_rust::message::*;
+use sip_server_rust::registrar::register;
+use sip_server_rust::sip_defs::{get_registered_bindings, Binding};
+use std::time::{Duration, Instant};
+
+fn binding(contact: &str, expires_in: u64, now: Instant) -> Binding {
+    let mut binding = Binding::new(contact, "192.168.1.10:5060".parse().unwrap());
+    binding.expires = now + Duration::from_secs(expires_in);
+    binding
+}
+
+fn contacts(bindings: &[Binding]) -> Vec<&str> {
+    bindings
+        .iter()
+        .map(|binding| binding.contact.as_str())
+        .collect()
+}
+
+// What every location service must do.
+fn check_service(service: &mut dyn LocationService) {
+    let now = Instant::now();
+    service
+        .register_binding("1001", binding("sip:1001@10.0.0.1", 600, now))
+        .unwrap();
+    service
+        .register_binding("1001", binding("sip:1001@10.0.0.2", 60, now))
+        .unwrap();
+    service
+        .register_binding("1002", binding("sip:1002@10.0.0.3", 600, now))
+        .unwrap();
+
+    // A refresh keeps its place
+    let mut refreshed = binding("sip:1001@10.0.0.1", 3600, now);
+    refreshed.cseq = 2;
+    refreshed.q = 500;
+    service.register_binding("1001", refreshed).unwrap();
+    let bindings = service.lookup("1001", now).unwrap();
+    assert_eq!(
+        contacts(&bindings),
+        vec!["sip:1001@10.0.0.1", "sip:1001@10.0.0.2"]
+    );
+    assert_eq!(bindings[0].cseq, 2);
+    assert_eq!(bindings[0].q, 500);
+
+    // A binding with an instance-id replaces the one with the same id
+    let mut phone = binding("sip:1002@10.0.0.4", 600, now);
+    phone.instance_id = Some("\"<urn:uuid:00000000-0000-1000-8000-000a95a0e128>\"".to_string());
+    service.register_binding("1002", phone.clone()).unwrap();
+    phone.contact = "sip:1002@10.0.0.5".to_string();
+    service.register_binding("1002", phone.clone()).unwrap();
+    assert_eq!(
+        contacts(&service.lookup("1002", now).unwrap()),
+        vec!["sip:1002@10.0.0.3", "sip:1002@10.0.0.5"]
+    );
+
+    // Expired bindings are not looked up, and go away when swept
+    let later = now + Duration::from_secs(120);
+    assert_eq!(
+        contacts(&service.lookup("1001", later).unwrap()),
+        vec!["sip:1001@10.0.0.1"]
+    );
+    assert_eq!(
+        service.expire(later).unwrap(),
+        vec![("1001".to_string(), 1)]
+    );
+    let listed = service.list().unwrap();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].0, "1001");
+    assert_eq!(listed[0].1.len(), 1);
+
+    service.remove("1002", Some("sip:1002@10.0.0.3")).unwrap();
+    assert_eq!(
+        contacts(&service.lookup("1002", now).unwrap()),
+        vec!["sip:1002@10.0.0.5"]
+    );
+    service.remove("1001", None).unwrap();
+    assert!(service.lookup("1001", now).unwrap().is_empty());
+    assert!(service.lookup("9999", now).unwrap().is_empty());
+}
+
+#[test]
+fn in_memory_service_keeps_bindings() {
+    check_service(&mut InMemoryLocationService::new());
+}
+
+#[test]
+fn sqlite_service_keeps_bindings() {
+    check_service(&mut SqliteLocationService::open_in_memory().unwrap());
+}
+
+#[test]
+fn sqlite_bindings_survive_reopening() {
+    let path = std::env::temp_dir().join(format!("sip_locations_{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let now = Instant::now();
+    let mut stored = binding("sip:1003@10.0.0.9:5062", 600, now);
+    stored.call_id = "reg-1003".to_string();
+    stored.cseq = 7;
+    stored.q = 250;
+    {
+        let mut service = SqliteLocationService::open(&path).unwrap();
+        service.register_binding("1003", stored.clone()).unwrap();
+    }
+
+    let service = SqliteLocationService::open(&path).unwrap();
+    let bindings = service.lookup("1003", Instant::now()).unwrap();
+    assert_eq!(bindings.len(), 1);
+    let reopened = &bindings[0];
+    assert_eq!(reopened.contact, stored.contact);
+    assert_eq!(reopened.addr, stored.addr);
+    assert_eq!(reopened.call_id, "reg-1003");
+    assert_eq!((reopened.cseq, reopened.q), (7, 250));
+    // Expiry is kept to the millisecond
+    let drift = if reopened.expires > stored.expires {
+        reopened.expires - stored.expires
+    } else {
+        stored.expires - reopened.expires
+    };
+    assert!(drift < Duration::from_millis(50));
+    drop(service);
+    std::fs::remove_file(&path).unwrap();
+}
+
+// The only test here that swaps LOCATION_SERVICE.
+#[test]
+fn registrar_uses_the_injected_service() {
+    add_test_users();
+    set_location_service(Box::new(SqliteLocationService::open_in_memory().unwrap()));
+    let text = "REGISTER sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.10:5060;branch=z9hG4bKloc\r\n\
+From: <sip:1004@server>;tag=loc\r\n\
+To: <sip:1004@server>\r\n\
+Call-ID: loc-1004\r\n\
+CSeq: 1 REGISTER\r\n\
+Contact: <sip:1004@192.168.1.10:5060>\r\n\
+Content-Length: 0\r\n\r\n";
+    let request = match parse_message(text.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => unreachable!(),
+    };
+    let now = Instant::now();
+    register("1004", &request, "192.168.1.10:5060".parse().unwrap(), now).unwrap();
+    assert_eq!(
+        contacts(&location_service().lookup("1004", now).unwrap()),
+        vec!["sip:1004@192.168.1.10:5060"]
+    );
+    assert_eq!(get_registered_bindings("1004").len(), 1);
+
+    set_location_service(Box::new(InMemoryLocationService::new()));
+    assert!(get_registered_bindings("1004").is_empty());
+}
+
+fn snapshot_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "sip_bindings_{}_{}.snapshot",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn snapshot_service_keeps_bindings() {
+    let path = snapshot_path("check");
+    let mut service = InMemoryLocationService::with_snapshot(path.clone()).unwrap();
+    check_service(&mut service);
+    service.flush().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn snapshot_restores_bindings_that_are_still_valid() {
+    let path = snapshot_path("restore");
+    let now = Instant::now();
+    let mut phone = binding("sip:1005@10.0.0.7:5062", 600, now);
+    phone.call_id = "reg-1005".to_string();
+    phone.cseq = 3;
+    phone.q = 800;
+    phone.instance_id = Some("\"<urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6>\"".to_string());
+    {
+        let mut service = InMemoryLocationService::with_snapshot(path.clone()).unwrap();
+        service.register_binding("1005", phone.clone()).unwrap();
+        service
+            .register_binding("1005", binding("sip:1005@10.0.0.8", 1, now))
+            .unwrap();
+        service
+            .register_binding("1006", binding("sip:1006@10.0.0.9", 600, now))
+            .unwrap();
+        service.remove("1006", None).unwrap();
+        // Changes are only written out together, on flush
+        assert!(!path.exists());
+        service.flush().unwrap();
+    }
+    // Only the complete file is left behind
+    let mut temporary = path.clone().into_os_string();
+    temporary.push(".tmp");
+    assert!(!std::path::Path::new(&temporary).exists());
+
+    // Bindings that expire while the server is down are not restored
+    std::thread::sleep(Duration::from_millis(1100));
+    let service = InMemoryLocationService::with_snapshot(path.clone()).unwrap();
+    let listed = service.list().unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].0, "1005");
+    let restored = &listed[0].1;
+    assert_eq!(contacts(restored), vec!["sip:1005@10.0.0.7:5062"]);
+    assert_eq!(restored[0].addr, phone.addr);
+    assert_eq!(restored[0].call_id, "reg-1005");
+    assert_eq!((restored[0].cseq, restored[0].q), (3, 800));
+    assert_eq!(restored[0].instance_id, phone.instance_id);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn snapshot_skips_damaged_lines_and_refuses_other_files() {
+    let path = snapshot_path("damaged");
+    let later = std::time::SystemTime::now() + Duration::from_secs(600);
+    let millis = later
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    std::fs::write(
+        &path,
+        format!(
+            "# sip_server_rust bindings v1\n\
+1001\tsip:1001@10.0.0.1\t10.0.0.1:5060\t{millis}\treg-1001\t1\t1000\t-\n\
+1002\tsip:1002@10.0.0.2\tnot-an-address\t{millis}\treg-1002\t1\t1000\t-\n\
+1003\ttruncated\n",
+            millis = millis
+        ),
+    )
+    .unwrap();
+    let service = InMemoryLocationService::with_snapshot(path.clone()).unwrap();
+    let listed = service.list().unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(contacts(&listed[0].1), vec!["sip:1001@10.0.0.1"]);
+
+    std::fs::write(&path, "username,secret\n").unwrap();
+    assert!(InMemoryLocationService::with_snapshot(path.clone()).is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "username,secret\n");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,93 @@
+use sip_server_rust::message::*;
+use sip_server_rust::message_store::{
+    remove_stored_message, store_message, stored_messages, StoreError,
+};
+use sip_server_rust::sip_defs::{MAX_STORED_MESSAGES, MAX_STORED_MESSAGE_BYTES, MESSAGE_STORE_DIR};
+
+fn use_temp_store() {
+    let dir = std::env::temp_dir().join(format!("sip_message_store_tests_{}", std::process::id()));
+    *MESSAGE_STORE_DIR.lock().unwrap() = dir;
+}
+
+fn chat(text: &str) -> SipRequest {
+    let message = format!(
+        "MESSAGE sip:1002@server SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.10:5060;branch=z9hG4bK{}\r\n\
+From: <sip:1001@server>;tag=chat\r\n\
+To: <sip:1002@server>\r\n\
+Call-ID: chat-{}\r\n\
+CSeq: 1 MESSAGE\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: {}\r\n\r\n{}",
+        text,
+        text,
+        text.len(),
+        text
+    );
+    match parse_message(message.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn stored_messages_come_back_oldest_first_until_removed() {
+    use_temp_store();
+    for text in ["first", "second", "third"] {
+        store_message("1004", &chat(text)).unwrap();
+    }
+    let stored = stored_messages("1004");
+    let bodies = stored
+        .iter()
+        .map(|(_, req)| req.body.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(bodies, ["first", "second", "third"]);
+    assert_eq!(stored[0].1.content_type(), Some("text/plain"));
+
+    remove_stored_message(&stored[0].0);
+    let stored = stored_messages("1004");
+    assert_eq!(stored.len(), 2);
+    assert_eq!(stored[0].1.body, "second");
+    for (path, _) in stored {
+        remove_stored_message(&path);
+    }
+    assert!(stored_messages("1004").is_empty());
+}
+
+#[test]
+fn user_with_nothing_stored_has_no_messages() {
+    use_temp_store();
+    assert!(stored_messages("1005").is_empty());
+}
+
+#[test]
+fn queue_stops_taking_messages_at_its_limits() {
+    use_temp_store();
+    for n in 0..MAX_STORED_MESSAGES {
+        store_message("1006", &chat(&format!("m{}", n))).unwrap();
+    }
+    assert!(matches!(
+        store_message("1006", &chat("one-too-many")),
+        Err(StoreError::Full)
+    ));
+    // Delivering one makes room again
+    let stored = stored_messages("1006");
+    remove_stored_message(&stored[0].0);
+    store_message("1006", &chat("fits")).unwrap();
+    for (path, _) in stored_messages("1006") {
+        remove_stored_message(&path);
+    }
+
+    // Large messages fill it by size first
+    let large = "x".repeat(4096);
+    let mut stored = 0;
+    while store_message("1006", &chat(&large)).is_ok() {
+        stored += 1;
+    }
+    assert!(stored < MAX_STORED_MESSAGES);
+    let size = chat(&large).to_string().len() as u64;
+    assert_eq!(stored as u64, MAX_STORED_MESSAGE_BYTES / size);
+    for (path, _) in stored_messages("1006") {
+        remove_stored_message(&path);
+    }
+}
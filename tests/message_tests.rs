@@ -0,0 +1,211 @@
+mod common;
+
+use common::{sample_invite, sample_response};
+use sip_server_rust::message::*;
+
+fn parse_request(text: &str) -> SipRequest {
+    match parse_message(text.as_bytes()).expect("message should parse") {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    }
+}
+
+#[test]
+fn parses_request_line_and_headers() {
+    let req = parse_request(&sample_invite());
+    assert_eq!(req.method, "INVITE");
+    assert_eq!(req.uri, "sip:1002@server");
+    assert_eq!(req.version, "SIP/2.0");
+    assert_eq!(req.call_id(), Some("a84b4c76e66710@pc33.atlanta.com"));
+    assert_eq!(req.cseq(), Some((314159, "INVITE".to_string())));
+    assert_eq!(req.max_forwards(), Some(70));
+    assert!(req.body.is_empty());
+}
+
+#[test]
+fn parses_status_line() {
+    let msg = parse_message(sample_response(486, "Busy Here").as_bytes()).unwrap();
+    match msg {
+        ParsedMessage::Response(resp) => {
+            assert_eq!(resp.status_code, 486);
+            assert_eq!(resp.reason, "Busy Here");
+            assert!(resp.headers.get("To").unwrap().contains("tag=qwer"));
+        }
+        ParsedMessage::Request(_) => panic!("expected a response"),
+    }
+}
+
+#[test]
+fn header_lookup_is_case_insensitive_and_not_substring() {
+    let text = sample_invite().replace(
+        "To: \"Bob\" <sip:1002@server>\r\n",
+        "Reply-To: <sip:someone@else>\r\nto: \"Bob\" <sip:1002@server>\r\n",
+    );
+    let req = parse_request(&text);
+    assert_eq!(req.headers.get("To"), Some("\"Bob\" <sip:1002@server>"));
+    assert_eq!(req.headers.get("TO"), Some("\"Bob\" <sip:1002@server>"));
+    assert_eq!(req.headers.get("Reply-To"), Some("<sip:someone@else>"));
+    // The received spelling is preserved for echoing.
+    assert_eq!(req.headers.get_header("To").unwrap().name, "to");
+}
+
+#[test]
+fn multiple_via_headers_are_kept_in_order() {
+    let text = sample_invite().replace(
+        "Max-Forwards: 70\r\n",
+        "Via: SIP/2.0/UDP 10.0.0.2;branch=z9hG4bKb, SIP/2.0/UDP 10.0.0.3;branch=z9hG4bKc\r\nMax-Forwards: 70\r\n",
+    );
+    let req = parse_request(&text);
+    let vias = req.headers.get_list("Via");
+    assert_eq!(vias.len(), 3);
+    assert!(vias[0].contains("192.168.1.10"));
+    assert!(vias[1].contains("10.0.0.2"));
+    assert!(vias[2].contains("10.0.0.3"));
+    assert_eq!(req.top_via(), Some(vias[0]));
+}
+
+#[test]
+fn folded_header_lines_are_joined() {
+    let text = sample_invite().replace(
+        "Contact: <sip:1001@192.168.1.10:5060>\r\n",
+        "Contact: <sip:1001@192.168.1.10:5060>\r\n  ;expires=60\r\n",
+    );
+    let req = parse_request(&text);
+    assert_eq!(
+        req.headers.get("Contact"),
+        Some("<sip:1001@192.168.1.10:5060> ;expires=60")
+    );
+}
+
+#[test]
+fn body_is_framed_by_content_length() {
+    let text = sample_invite().replace(
+        "Content-Length: 0\r\n\r\n",
+        "Content-Type: application/sdp\r\nContent-Length: 5\r\n\r\nv=0\r\ntrailing-garbage",
+    );
+    let req = parse_request(&text);
+    assert_eq!(req.body, "v=0\r\n");
+    assert_eq!(req.sdp_body(), Some("v=0\r\n"));
+}
+
+#[test]
+fn short_body_is_rejected() {
+    let text = sample_invite().replace(
+        "Content-Length: 0\r\n\r\n",
+        "Content-Length: 50\r\n\r\nv=0\r\n",
+    );
+    assert_eq!(
+        parse_message(text.as_bytes()),
+        Err(ParseError::BodyTruncated {
+            expected: 50,
+            actual: 5
+        })
+    );
+}
+
+#[test]
+fn malformed_input_is_an_error() {
+    assert_eq!(parse_message(b"\r\n\r\n"), Err(ParseError::Empty));
+    assert!(matches!(
+        parse_message(b"INVITE\r\n\r\n"),
+        Err(ParseError::InvalidStartLine(_))
+    ));
+    assert!(matches!(
+        parse_message(b"SIP/2.0 abc OK\r\n\r\n"),
+        Err(ParseError::InvalidStartLine(_))
+    ));
+    assert!(matches!(
+        parse_message(b"INVITE sip:a@b SIP/2.0\r\nno colon here\r\n\r\n"),
+        Err(ParseError::InvalidHeader(_))
+    ));
+}
+
+#[test]
+fn display_round_trips() {
+    let req = parse_request(&sample_invite());
+    let text = req.to_string();
+    assert_eq!(text, sample_invite());
+    assert_eq!(parse_request(&text), req);
+}
+
+#[test]
+fn header_editing_helpers() {
+    let mut headers = Headers::new();
+    headers.push("Via", "SIP/2.0/UDP a");
+    headers.push("From", "<sip:a@b>");
+    headers.push_front("Via", "SIP/2.0/UDP top");
+    headers.push("via", "SIP/2.0/UDP bottom");
+    assert_eq!(
+        headers.get_list("Via"),
+        vec!["SIP/2.0/UDP top", "SIP/2.0/UDP a", "SIP/2.0/UDP bottom"]
+    );
+
+    headers.set("VIA", "SIP/2.0/UDP only");
+    assert_eq!(headers.get_list("Via"), vec!["SIP/2.0/UDP only"]);
+    assert_eq!(headers.remove("from"), 1);
+    assert!(!headers.contains("From"));
+}
+
+#[test]
+fn split_header_list_respects_quotes_and_brackets() {
+    let parts = split_header_list("\"Doe, John\" <sip:a@b;x=1,2>, <sip:c@d>");
+    assert_eq!(parts, vec!["\"Doe, John\" <sip:a@b;x=1,2>", "<sip:c@d>"]);
+}
+
+fn compact_invite() -> String {
+    "INVITE sip:1002@server SIP/2.0\r\n\
+v: SIP/2.0/UDP 192.168.1.10:5060;branch=z9hG4bKcompact\r\n\
+f: \"Alice\" <sip:1001@server>;tag=1928301774\r\n\
+t: <sip:1002@server>\r\n\
+m: <sip:1001@192.168.1.10:5060>\r\n\
+i: compact-call@pc33\r\n\
+CSeq: 1 INVITE\r\n\
+k: timer, 100rel\r\n\
+c: application/sdp\r\n\
+l: 4\r\n\r\nv=0\n"
+        .to_string()
+}
+
+#[test]
+fn compact_header_forms_are_recognised() {
+    let req = parse_request(&compact_invite());
+    assert_eq!(req.call_id(), Some("compact-call@pc33"));
+    assert!(req.top_via().unwrap().contains("branch=z9hG4bKcompact"));
+    assert!(req.headers.get("From").unwrap().contains("tag=1928301774"));
+    assert_eq!(req.headers.get("To"), Some("<sip:1002@server>"));
+    assert_eq!(
+        req.headers.get("Contact"),
+        Some("<sip:1001@192.168.1.10:5060>")
+    );
+    assert_eq!(req.headers.get_list("Supported"), vec!["timer", "100rel"]);
+    assert_eq!(req.sdp_body(), Some("v=0\n"));
+
+    // Long names also resolve when looking up by the compact letter.
+    let long = parse_request(&sample_invite());
+    assert_eq!(long.headers.get("i"), long.call_id());
+    assert!(long.headers.get("V").is_some());
+}
+
+#[test]
+fn compact_names_are_kept_for_echoing() {
+    let req = parse_request(&compact_invite());
+    assert_eq!(
+        req.headers.get_header("Call-ID").unwrap().to_string(),
+        "i: compact-call@pc33"
+    );
+    assert_eq!(
+        req.headers.get_header("Via").unwrap().name,
+        "v",
+        "received header name should be preserved"
+    );
+    assert!(req.to_string().contains("\r\nf: \"Alice\""));
+}
+
+#[test]
+fn canonical_header_name_maps_compact_forms() {
+    assert_eq!(canonical_header_name("i"), "Call-ID");
+    assert_eq!(canonical_header_name("M"), "Contact");
+    assert_eq!(canonical_header_name("x"), "Session-Expires");
+    assert_eq!(canonical_header_name("Via"), "Via");
+    assert_eq!(canonical_header_name("q"), "q");
+}
@@ -48,3 +48,24 @@ fn content_type_detection_respects_case() {
     no_sdp.push_str("Content-Type: text/plain\r\n\r\nhello");
     assert!(get_sdp_body(&no_sdp).is_none());
 }
+
+#[test]
+fn legacy_helpers_accept_compact_forms() {
+    let msg = "INVITE sip:1002@server SIP/2.0\r\n\
+v: SIP/2.0/UDP 192.168.1.10:5060;branch=z9hG4bKc\r\n\
+f: <sip:1001@server>;tag=1\r\n\
+t: <sip:1002@server>\r\n\
+m: <sip:1001@192.168.1.10:5060>\r\n\
+i: compact@host\r\n\
+CSeq: 1 INVITE\r\n\
+c: application/sdp\r\n\r\nv=0\r\n";
+
+    assert_eq!(get_call_id(msg).unwrap(), "compact@host");
+    assert!(get_via_header(msg).unwrap().contains("branch=z9hG4bKc"));
+    assert!(get_from_header(msg).unwrap().contains("tag=1"));
+    assert!(get_to_header(msg).unwrap().contains("1002"));
+    assert!(get_contact_header(msg)
+        .unwrap()
+        .contains("1001@192.168.1.10"));
+    assert_eq!(get_sdp_body(msg), Some("v=0\r\n"));
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{
+    add_test_users, addr, authorize, bind, expect_message, request, response, sample_invite,
+    Phones, Worker,
+};
+use sip_server_rust::builder::ResponseBuilder;
+use sip_server_rust::message::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// Starts a worker and sends A's INVITE (with `extra` headers) to `callee`.
+// Returns None if the sandbox does not allow UDP sockets.
+fn start_call(callee: &str, extra: &str) -> Option<(Phones, SipRequest)> {
+    let phones = Phones::start(callee)?;
+    let invite_to_b = phones.call(callee, extra);
+    Some((phones, invite_to_b))
+}
+
+fn reliable_183(invite_to_b: &SipRequest, b_addr: SocketAddr) -> String {
+    ResponseBuilder::from_request(invite_to_b, 183)
+        .to_tag("bobtag")
+        .contact(&format!("<sip:phone@{}>", b_addr))
+        .header("Require", "100rel")
+        .header("RSeq", "42")
+        .build()
+        .to_string()
+}
+
+#[test]
+fn prack_is_relayed_end_to_end() {
+    add_test_users();
+    let (phones, invite_to_b) = match start_call("1003", "Supported: 100rel\r\n") {
+        Some(started) => started,
+        None => {
+            eprintln!("Skipping PRACK test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let a_addr = addr(&phones.phone_a);
+    let b_addr = addr(&phones.phone_b);
+    assert!(invite_to_b.has_option_tag("Supported", "100rel"));
+
+    let progress = reliable_183(&invite_to_b, b_addr);
+    phones.worker.send(&progress, &phones.phone_b);
+    let progress_to_a = response(expect_message(&phones.phone_a, "SIP/2.0 183"));
+    assert!(progress_to_a.has_option_tag("Require", "100rel"));
+    let a_rseq = progress_to_a.rseq().expect("RSeq towards A");
+
+    // B retransmits its 183 (same RSeq): not relayed again
+    phones.worker.send(&progress, &phones.phone_b);
+
+    let prack = format!(
+        "PRACK sip:TinySIP@server SIP/2.0\r\n\
+Via: SIP/2.0/UDP {};branch=z9hG4bKprack1\r\n\
+From: {}\r\n\
+To: {}\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: 314160 PRACK\r\n\
+RAck: {} 314159 INVITE\r\n\
+Content-Length: 0\r\n\r\n",
+        a_addr,
+        progress_to_a.headers.get("From").unwrap(),
+        progress_to_a.headers.get("To").unwrap(),
+        a_rseq
+    );
+    phones.worker.send(&prack, &phones.phone_a);
+    let prack_to_b = request(expect_message(&phones.phone_b, "PRACK "));
+    let b_cseq = invite_to_b.cseq().unwrap().0;
+    assert_eq!(prack_to_b.rack(), Some((42, b_cseq, "INVITE".to_string())));
+
+    let ok = ResponseBuilder::from_request(&prack_to_b, 200)
+        .build()
+        .to_string();
+    phones.worker.send(&ok, &phones.phone_b);
+    let ok_to_a = response(expect_message(&phones.phone_a, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_a.cseq(), Some((314160, "PRACK".to_string())));
+
+    phones.stop();
+}
+
+#[test]
+fn server_pracks_for_caller_without_100rel() {
+    add_test_users();
+    let (phones, invite_to_b) = match start_call("1004", "") {
+        Some(started) => started,
+        None => {
+            eprintln!("Skipping PRACK test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let b_addr = addr(&phones.phone_b);
+
+    let progress = reliable_183(&invite_to_b, b_addr);
+    phones.worker.send(&progress, &phones.phone_b);
+    let prack_to_b = request(expect_message(&phones.phone_b, "PRACK "));
+    let b_cseq = invite_to_b.cseq().unwrap().0;
+    assert_eq!(prack_to_b.rack(), Some((42, b_cseq, "INVITE".to_string())));
+
+    // A gets the 183 as an ordinary provisional
+    let progress_to_a = response(expect_message(&phones.phone_a, "SIP/2.0 183"));
+    assert!(progress_to_a.rseq().is_none());
+    assert!(!progress_to_a.has_option_tag("Require", "100rel"));
+
+    phones.stop();
+}
+
+#[test]
+fn unsupported_required_extension_is_rejected() {
+    add_test_users();
+    let (server, phone_a) = match (bind(), bind()) {
+        (Some(server), Some(a)) => (server, a),
+        _ => {
+            eprintln!("Skipping 420 test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let worker = Worker::start(server);
+
+    let invite = sample_invite().replace(
+        "Content-Length: 0\r\n",
+        "Require: 100rel, foo\r\nContent-Length: 0\r\n",
+    );
+    worker.send(&authorize(&invite), &phone_a);
+    let rejected = response(expect_message(&phone_a, "SIP/2.0 420"));
+    assert_eq!(rejected.headers.get("Unsupported"), Some("foo"));
+
+    let call_map = Arc::clone(&worker.call_map);
+    worker.stop();
+    assert_eq!(call_map.lock().unwrap().size, 0);
+}
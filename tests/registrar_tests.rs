@@ -0,0 +1,245 @@
+mod common;
+
+use common::add_test_users;
+use sip_server_rust::location::location_service;
+use sip_server_rust::message::*;
+use sip_server_rust::registrar::{
+    binding_contact, register, sweep_expired_bindings, RegisterError,
+};
+use sip_server_rust::sip_defs::get_registered_bindings;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+fn register_request(
+    user: &str,
+    call_id: &str,
+    cseq: u32,
+    contacts: &[&str],
+    expires: Option<u32>,
+) -> SipRequest {
+    let mut text = format!(
+        "REGISTER sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.10:5060;branch=z9hG4bKreg{cseq}\r\n\
+From: <sip:{user}@server>;tag=reg\r\n\
+To: <sip:{user}@server>\r\n\
+Call-ID: {call_id}\r\n\
+CSeq: {cseq} REGISTER\r\n",
+        user = user,
+        call_id = call_id,
+        cseq = cseq
+    );
+    for contact in contacts {
+        text.push_str(&format!("Contact: {}\r\n", contact));
+    }
+    if let Some(expires) = expires {
+        text.push_str(&format!("Expires: {}\r\n", expires));
+    }
+    text.push_str("Content-Length: 0\r\n\r\n");
+    match parse_message(text.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => unreachable!(),
+    }
+}
+
+fn source() -> SocketAddr {
+    "192.168.1.10:5060".parse().unwrap()
+}
+
+#[test]
+fn bindings_take_their_expiry_and_q_and_are_listed_by_preference() {
+    add_test_users();
+    let now = Instant::now();
+    let request = register_request(
+        "1001",
+        "reg-a",
+        1,
+        &[
+            "<sip:1001@192.168.1.10:5060>;q=0.5",
+            "<sip:1001@192.168.1.11:5060>;expires=3600",
+        ],
+        Some(7000),
+    );
+    let bindings = register("1001", &request, source(), now).unwrap();
+    assert_eq!(bindings.len(), 2);
+    assert_eq!(bindings[0].q, 500);
+    assert_eq!(bindings[0].expires, now + Duration::from_secs(7000));
+    assert_eq!(bindings[1].expires, now + Duration::from_secs(3600));
+    assert_eq!(
+        binding_contact(&bindings[0], now),
+        "<sip:1001@192.168.1.10:5060>;expires=7000;q=0.5"
+    );
+    assert_eq!(
+        binding_contact(&bindings[1], now + Duration::from_millis(1500)),
+        "<sip:1001@192.168.1.11:5060>;expires=3599"
+    );
+
+    let preferred = get_registered_bindings("1001");
+    assert_eq!(preferred[0].contact, "sip:1001@192.168.1.11:5060");
+    assert_eq!(preferred[1].contact, "sip:1001@192.168.1.10:5060");
+}
+
+#[test]
+fn register_must_be_newer_within_its_call_id() {
+    add_test_users();
+    let now = Instant::now();
+    let contact = ["<sip:1002@192.168.1.10:5060>"];
+    register(
+        "1002",
+        &register_request("1002", "reg-b", 5, &contact, None),
+        source(),
+        now,
+    )
+    .unwrap();
+
+    for cseq in [4, 5] {
+        let request = register_request("1002", "reg-b", cseq, &contact, Some(3600));
+        assert_eq!(
+            register("1002", &request, source(), now),
+            Err(RegisterError::OutOfOrder)
+        );
+    }
+    let bindings = get_registered_bindings("1002");
+    assert_eq!(bindings[0].cseq, 5);
+
+    // Another Call-ID (e.g. after a reboot) starts over
+    let request = register_request("1002", "reg-b2", 1, &contact, Some(3600));
+    let bindings = register("1002", &request, source(), now).unwrap();
+    assert_eq!(bindings.len(), 1);
+    assert_eq!(bindings[0].call_id, "reg-b2");
+}
+
+#[test]
+fn wildcard_with_zero_expires_removes_every_binding() {
+    add_test_users();
+    let now = Instant::now();
+    let contacts = [
+        "<sip:1003@192.168.1.10:5060>",
+        "<sip:1003@192.168.1.11:5060>",
+    ];
+    register(
+        "1003",
+        &register_request("1003", "reg-c", 1, &contacts, None),
+        source(),
+        now,
+    )
+    .unwrap();
+
+    let request = register_request("1003", "reg-c", 2, &["*"], None);
+    assert_eq!(
+        register("1003", &request, source(), now),
+        Err(RegisterError::BadContact)
+    );
+    let request = register_request("1003", "reg-c", 2, &["*"], Some(0));
+    assert_eq!(register("1003", &request, source(), now), Ok(Vec::new()));
+    assert!(get_registered_bindings("1003").is_empty());
+    let listed = location_service().list().unwrap();
+    assert!(listed.iter().all(|(aor, _)| aor != "1003"));
+}
+
+#[test]
+fn brief_intervals_and_bad_contacts_change_nothing() {
+    add_test_users();
+    let now = Instant::now();
+    let request = register_request(
+        "1004",
+        "reg-d",
+        1,
+        &[
+            "<sip:1004@192.168.1.10:5060>",
+            "<sip:1004@192.168.1.11:5060>;expires=30",
+        ],
+        None,
+    );
+    assert_eq!(
+        register("1004", &request, source(), now),
+        Err(RegisterError::IntervalTooBrief)
+    );
+    let request = register_request(
+        "1004",
+        "reg-d",
+        2,
+        &["<sip:1004@192.168.1.10:5060>;q=2"],
+        None,
+    );
+    assert_eq!(
+        register("1004", &request, source(), now),
+        Err(RegisterError::BadContact)
+    );
+    assert!(get_registered_bindings("1004").is_empty());
+
+    let request = register_request("9999", "reg-d", 3, &["<sip:9999@192.168.1.10>"], None);
+    assert_eq!(
+        register("9999", &request, source(), now),
+        Err(RegisterError::UnknownUser)
+    );
+}
+
+#[test]
+fn instance_id_identifies_a_binding_across_contact_changes() {
+    add_test_users();
+    let now = Instant::now();
+    let instance = "\"<urn:uuid:00000000-0000-1000-8000-000A95A0E128>\"";
+    let first = format!("<sip:1005@192.168.1.10:5060>;+sip.instance={}", instance);
+    register(
+        "1005",
+        &register_request("1005", "reg-e", 1, &[&first], None),
+        source(),
+        now,
+    )
+    .unwrap();
+
+    // The phone moved to another address
+    let moved = format!("<sip:1005@10.0.0.7:5062>;+sip.instance={}", instance);
+    let new_source = "10.0.0.7:5062".parse().unwrap();
+    let bindings = register(
+        "1005",
+        &register_request("1005", "reg-e", 2, &[&moved], None),
+        new_source,
+        now,
+    )
+    .unwrap();
+    assert_eq!(bindings.len(), 1);
+    assert_eq!(bindings[0].contact, "sip:1005@10.0.0.7:5062");
+    assert_eq!(bindings[0].addr, new_source);
+    assert_eq!(bindings[0].instance_id.as_deref(), Some(instance));
+    assert!(binding_contact(&bindings[0], now).ends_with(&format!(";+sip.instance={}", instance)));
+
+    // Expires 0 removes it, whatever its contact
+    let gone = format!(
+        "<sip:1005@192.168.1.10:5060>;expires=0;+sip.instance={}",
+        instance
+    );
+    let bindings = register(
+        "1005",
+        &register_request("1005", "reg-e", 3, &[&gone], None),
+        source(),
+        now,
+    )
+    .unwrap();
+    assert!(bindings.is_empty());
+}
+
+#[test]
+fn expired_bindings_are_swept() {
+    add_test_users();
+    let now = Instant::now();
+    let contacts = [
+        "<sip:1006@192.168.1.10:5060>;expires=120",
+        "<sip:1006@192.168.1.11:5060>;expires=90000",
+    ];
+    let bindings = register(
+        "1006",
+        &register_request("1006", "reg-f", 1, &contacts, None),
+        source(),
+        now,
+    )
+    .unwrap();
+    // Registrations are capped at a day
+    assert_eq!(bindings[1].expires, now + Duration::from_secs(86400));
+
+    sweep_expired_bindings(now + Duration::from_secs(121));
+    let listed = location_service().list().unwrap();
+    let (_, bindings) = listed.iter().find(|(aor, _)| aor == "1006").unwrap();
+    assert_eq!(bindings.len(), 1);
+    assert_eq!(bindings[0].contact, "sip:1006@192.168.1.11:5060");
+}
@@ -0,0 +1,261 @@
+mod common;
+
+use common::{
+    add_test_users, addr, authorize, bind, expect_message, in_dialog, reply, request, response,
+    sdp, with_sdp, Phones,
+};
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::CallState;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// The INVITE a third phone sends to take the place of a dialog, with the
+// credentials of its user and the user who referred it, if any.
+fn replacing_invite(phone: SocketAddr, replaces: &str, referrer: Option<&str>) -> String {
+    authorize(&unauthorized_replacing_invite(phone, replaces, referrer))
+}
+
+fn unauthorized_replacing_invite(
+    phone: SocketAddr,
+    replaces: &str,
+    referrer: Option<&str>,
+) -> String {
+    let referred_by = referrer
+        .map(|user| format!("Referred-By: <sip:{}@server>\r\n", user))
+        .unwrap_or_default();
+    let invite = in_dialog(
+        "INVITE",
+        "<sip:1005@server>;tag=xtag",
+        "<sip:1001@server>",
+        &format!("replace-{}", phone.port()),
+        1,
+        phone,
+        &format!(
+            "Replaces: {}\r\n{}Require: replaces\r\n",
+            replaces, referred_by
+        ),
+    );
+    with_sdp(&invite, &sdp("xavier"))
+}
+
+// A worker with caller A, `callee`'s phone B and a third phone X; or None if
+// the sandbox does not allow UDP sockets.
+fn start(callee: &str) -> Option<(Phones, Arc<UdpSocket>)> {
+    Some((Phones::start(callee)?, bind()?))
+}
+
+// Replaces value naming the dialog of the INVITE to B, as the server sees it.
+fn b_leg_replaces(invite_to_b: &SipRequest, b_tag: &str) -> String {
+    let from = invite_to_b.headers.get("From").unwrap();
+    let our_tag = from.split("tag=").nth(1).unwrap();
+    format!(
+        "{};to-tag={};from-tag={}",
+        invite_to_b.call_id().unwrap(),
+        our_tag,
+        b_tag
+    )
+}
+
+#[test]
+fn invite_with_replaces_takes_over_a_connected_leg() {
+    add_test_users();
+    let (phones, phone_x) = match start("1002") {
+        Some(started) => started,
+        None => {
+            eprintln!("Skipping Replaces test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let a_addr = addr(&phones.phone_a);
+    let x_addr = addr(&phone_x);
+    let call = phones.connect_call("1002", "", &[]);
+    let (invite_to_b, ok_to_a) = (call.invite_to_b, call.ok_to_a);
+
+    // X replaces B (the consultation call B made is being completed)
+    let replaces = b_leg_replaces(&invite_to_b, "bobtag");
+    phones
+        .worker
+        .send(&replacing_invite(x_addr, &replaces, Some("1002")), &phone_x);
+    request(expect_message(&phones.phone_b, "BYE "));
+    let reinvite = request(expect_message(&phones.phone_a, "INVITE "));
+    assert_eq!(reinvite.body, sdp("xavier"));
+
+    phones.worker.send(
+        &reply(&reinvite, 200, "", a_addr, Some(&sdp("alice2"))),
+        &phones.phone_a,
+    );
+    let ok_to_x = response(expect_message(&phone_x, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_x.body, sdp("alice2"));
+    assert!(ok_to_x.headers.get("To").unwrap().contains("tag="));
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let call = &guard.calls[0];
+        assert_eq!(call.b_leg_addr, Some(x_addr));
+        assert_eq!(call.b_leg_uuid, format!("replace-{}", x_addr.port()));
+    }
+
+    // X's ACK and BYE reach A in the A-leg dialog
+    let x_to = ok_to_x.headers.get("To").unwrap();
+    let x_call_id = format!("replace-{}", x_addr.port());
+    let x_from = "<sip:1005@server>;tag=xtag";
+    let ack = in_dialog("ACK", x_from, x_to, &x_call_id, 1, x_addr, "");
+    phones.worker.send(&ack, &phone_x);
+    let ack_to_a = request(expect_message(&phones.phone_a, "ACK "));
+    assert_eq!(ack_to_a.cseq().unwrap().0, reinvite.cseq().unwrap().0);
+    let bye = in_dialog("BYE", x_from, x_to, &x_call_id, 2, x_addr, "");
+    phones.worker.send(&bye, &phone_x);
+    let bye_to_a = request(expect_message(&phones.phone_a, "BYE "));
+    assert_eq!(
+        bye_to_a.headers.get("From"),
+        Some(ok_to_a.headers.get("To").unwrap())
+    );
+    phones.stop();
+}
+
+#[test]
+fn ringing_call_is_picked_up() {
+    add_test_users();
+    let (phones, phone_x) = match start("1003") {
+        Some(started) => started,
+        None => {
+            eprintln!("Skipping pickup test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let b_addr = addr(&phones.phone_b);
+    let x_addr = addr(&phone_x);
+    let invite_to_b = phones.call("1003", "");
+    phones.worker.send(
+        &reply(&invite_to_b, 180, "bobtag", b_addr, None),
+        &phones.phone_b,
+    );
+    expect_message(&phones.phone_a, "SIP/2.0 180");
+
+    // X's user may pick up calls
+    let replaces = format!("{};early-only", b_leg_replaces(&invite_to_b, "bobtag"));
+    phones
+        .worker
+        .send(&replacing_invite(x_addr, &replaces, None), &phone_x);
+    let cancel = request(expect_message(&phones.phone_b, "CANCEL "));
+    assert_eq!(cancel.top_via(), invite_to_b.top_via());
+    let ok_to_x = response(expect_message(&phone_x, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_x.body, sdp("alice"));
+    let ok_to_a = response(expect_message(&phones.phone_a, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_a.body, sdp("xavier"));
+    assert_eq!(ok_to_a.cseq(), Some((314159, "INVITE".to_string())));
+
+    let ack = in_dialog(
+        "ACK",
+        ok_to_a.headers.get("From").unwrap(),
+        ok_to_a.headers.get("To").unwrap(),
+        "a84b4c76e66710@pc33.atlanta.com",
+        314159,
+        addr(&phones.phone_a),
+        "",
+    );
+    phones.worker.send(&ack, &phones.phone_a);
+    thread::sleep(Duration::from_millis(50));
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let call = &guard.calls[0];
+        assert_eq!(call.call_state, CallState::Connected);
+        assert_eq!(call.b_leg_addr, Some(x_addr));
+    }
+
+    // A hangs up; the BYE goes to the phone that picked up
+    let bye = in_dialog(
+        "BYE",
+        ok_to_a.headers.get("From").unwrap(),
+        ok_to_a.headers.get("To").unwrap(),
+        "a84b4c76e66710@pc33.atlanta.com",
+        314160,
+        addr(&phones.phone_a),
+        "",
+    );
+    phones.worker.send(&bye, &phones.phone_a);
+    let bye_to_x = request(expect_message(&phone_x, "BYE "));
+    assert_eq!(bye_to_x.call_id(), ok_to_x.call_id());
+    assert!(bye_to_x.headers.get("To").unwrap().contains("tag=xtag"));
+    phones.stop();
+}
+
+#[test]
+fn replaces_for_unknown_or_answered_dialog_is_refused() {
+    add_test_users();
+    let (phones, phone_x) = match start("1004") {
+        Some(started) => started,
+        None => {
+            eprintln!("Skipping Replaces test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let b_addr = addr(&phones.phone_b);
+    let x_addr = addr(&phone_x);
+
+    phones.worker.send(
+        &replacing_invite(x_addr, "nosuchcall;to-tag=1;from-tag=2", None),
+        &phone_x,
+    );
+    response(expect_message(&phone_x, "SIP/2.0 481"));
+
+    let invite_to_b = phones.connect_call("1004", "", &[]).invite_to_b;
+
+    // Too late for a pickup
+    let replaces = format!("{};early-only", b_leg_replaces(&invite_to_b, "bobtag"));
+    let invite = replacing_invite(x_addr, &replaces, Some("1004"))
+        .replace("replace-", "late-")
+        .replace("z9hG4bKinvite", "z9hG4bKlate");
+    phones.worker.send(&invite, &phone_x);
+    response(expect_message(&phone_x, "SIP/2.0 486"));
+    assert_eq!(
+        phones.worker.call_map.lock().unwrap().calls[0].b_leg_addr,
+        Some(b_addr)
+    );
+    phones.stop();
+}
+
+#[test]
+fn replaces_needs_credentials_of_someone_the_call_concerns() {
+    add_test_users();
+    let (phones, phone_x) = match start("1006") {
+        Some(started) => started,
+        None => {
+            eprintln!("Skipping Replaces test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let b_addr = addr(&phones.phone_b);
+    let x_addr = addr(&phone_x);
+    let invite_to_b = phones.connect_call("1006", "", &[]).invite_to_b;
+
+    // Knowing the dialog is not enough to take it over
+    let replaces = b_leg_replaces(&invite_to_b, "bobtag");
+    phones.worker.send(
+        &unauthorized_replacing_invite(x_addr, &replaces, Some("1006")),
+        &phone_x,
+    );
+    let challenge = response(expect_message(&phone_x, "SIP/2.0 407"));
+    assert!(challenge.headers.get("Proxy-Authenticate").is_some());
+
+    // Nor is authenticating as a user who is not in the call
+    let invite = replacing_invite(x_addr, &replaces, None)
+        .replace("replace-", "stranger-")
+        .replace("z9hG4bKinvite", "z9hG4bKstranger");
+    phones.worker.send(&invite, &phone_x);
+    response(expect_message(&phone_x, "SIP/2.0 403"));
+    let invite = replacing_invite(x_addr, &replaces, Some("1003"))
+        .replace("replace-", "forged-")
+        .replace("z9hG4bKinvite", "z9hG4bKforged");
+    phones.worker.send(&invite, &phone_x);
+    response(expect_message(&phone_x, "SIP/2.0 403"));
+    thread::sleep(Duration::from_millis(50));
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let call = &guard.calls[0];
+        assert_eq!(call.call_state, CallState::Connected);
+        assert_eq!(call.b_leg_addr, Some(b_addr));
+    }
+    phones.stop();
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::{
+    add_test_users, authorize, bind, expect_message, request, sample_invite, Phones, Worker,
+};
+use sip_server_rust::message::parse_message;
+use sip_server_rust::transaction::{TransactionKey, TransactionState};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn retransmitted_invite_gets_latest_provisional_response() {
+    add_test_users();
+    let phones = match Phones::start("1002") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping retransmission test; unable to bind UDP sockets");
+            return;
+        }
+    };
+
+    let invite = authorize(&sample_invite());
+    phones.worker.send(&invite, &phones.phone_a);
+    expect_message(&phones.phone_a, "SIP/2.0 100 Trying");
+    let invite_to_b = request(expect_message(&phones.phone_b, "INVITE "));
+
+    // A retransmits before B rings: it gets the 100 Trying again
+    phones.worker.send(&invite, &phones.phone_a);
+    expect_message(&phones.phone_a, "SIP/2.0 100 Trying");
+
+    // B rings; the retransmitted INVITE now gets the 180
+    phones
+        .worker
+        .reply(&phones.phone_b, &invite_to_b, 180, "bobtag");
+    expect_message(&phones.phone_a, "SIP/2.0 180 Ringing");
+
+    phones.worker.send(&invite, &phones.phone_a);
+    expect_message(&phones.phone_a, "SIP/2.0 180 Ringing");
+
+    let call_map = Arc::clone(&phones.worker.call_map);
+    phones.stop();
+    assert_eq!(call_map.lock().unwrap().size, 1);
+}
+
+#[test]
+fn request_for_an_unknown_call_is_answered() {
+    let (server, phone) = match (bind(), bind()) {
+        (Some(server), Some(phone)) => (server, phone),
+        _ => {
+            eprintln!("Skipping unknown call test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let worker = Worker::start(server);
+
+    // Its server transaction ends with the 481 instead of staying open
+    let bye = sample_invite()
+        .replace("INVITE sip:", "BYE sip:")
+        .replace("314159 INVITE", "314160 BYE")
+        .replace("Call-ID: a84b4c76e66710", "Call-ID: unknown-call");
+    worker.send(&bye, &phone);
+    expect_message(&phone, "SIP/2.0 481");
+    thread::sleep(Duration::from_millis(50));
+    let bye = request(parse_message(bye.as_bytes()).unwrap());
+    let key = TransactionKey::for_request(&bye, true).unwrap();
+    assert_eq!(
+        worker
+            .call_map
+            .lock()
+            .unwrap()
+            .transactions
+            .get(&key)
+            .unwrap()
+            .state,
+        TransactionState::Completed
+    );
+
+    worker.stop();
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::{add_test_users, bind, expect_message, register, request, response, Worker};
+use sip_server_rust::location::location_service;
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::{HuntStrategy, RingGroup, RING_GROUPS};
+use std::thread;
+use std::time::Duration;
+
+// Removes the phones of `user`.
+fn unregister(user: &str) {
+    location_service().remove(user, None).unwrap();
+}
+
+#[test]
+fn sequential_group_hunts_on_no_answer_and_busy_then_overflows() {
+    add_test_users();
+    let (server, phone_a, first, second, overflow) = match (bind(), bind(), bind(), bind(), bind())
+    {
+        (Some(server), Some(a), Some(first), Some(second), Some(overflow)) => {
+            (server, a, first, second, overflow)
+        }
+        _ => {
+            eprintln!("Skipping ring group test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    RING_GROUPS.lock().unwrap().push(RingGroup {
+        number: "2801".to_string(),
+        strategy: HuntStrategy::Sequential,
+        members: vec![
+            "1001".to_string(), // Not logged in: skipped
+            "1002".to_string(),
+            "1003".to_string(),
+        ],
+        member_timeout: Duration::from_millis(300),
+        overflow: Some("1004".to_string()),
+        next_start: 0,
+    });
+    unregister("1001");
+    register("1002", &first);
+    register("1003", &second);
+    register("1004", &overflow);
+    let worker = Worker::start(server);
+
+    worker.call(&phone_a, "2801", "");
+
+    // 1002 rings but does not answer within the member timeout
+    let to_first = request(expect_message(&first, "INVITE "));
+    worker.reply(&first, &to_first, 180, "phonetag");
+    expect_message(&phone_a, "SIP/2.0 180");
+    let cancel = request(expect_message(&first, "CANCEL "));
+    assert_eq!(cancel.top_via(), to_first.top_via());
+
+    // 1003 is busy
+    let to_second = request(expect_message(&second, "INVITE "));
+    assert!(to_second.cseq().unwrap().0 > to_first.cseq().unwrap().0);
+    worker.reply(&first, &to_first, 487, "phonetag");
+    worker.reply(&second, &to_second, 486, "phonetag");
+
+    // The overflow destination answers
+    let to_overflow = request(expect_message(&overflow, "INVITE "));
+    worker.reply(&overflow, &to_overflow, 200, "phonetag");
+    let ok = response(expect_message(&phone_a, "SIP/2.0 200 OK"));
+    assert_eq!(ok.cseq(), Some((314159, "INVITE".to_string())));
+    {
+        let guard = worker.call_map.lock().unwrap();
+        let call = &guard.calls[0];
+        assert_eq!(call.b_leg_addr, Some(overflow.local_addr().unwrap()));
+        assert_eq!(call.callee, "2801");
+    }
+
+    worker.stop();
+}
+
+#[test]
+fn parallel_group_that_rings_out_answers_480() {
+    add_test_users();
+    let (server, phone_a, member) = match (bind(), bind(), bind()) {
+        (Some(server), Some(a), Some(member)) => (server, a, member),
+        _ => {
+            eprintln!("Skipping ring group test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    RING_GROUPS.lock().unwrap().push(RingGroup {
+        number: "2802".to_string(),
+        strategy: HuntStrategy::Parallel,
+        members: vec!["1005".to_string(), "1006".to_string()],
+        member_timeout: Duration::from_millis(200),
+        overflow: None,
+        next_start: 0,
+    });
+    register("1005", &member);
+    unregister("1006");
+    let worker = Worker::start(server);
+
+    worker.call(&phone_a, "2802", "");
+    let to_member = request(expect_message(&member, "INVITE "));
+    worker.reply(&member, &to_member, 180, "phonetag");
+
+    expect_message(&member, "CANCEL ");
+    expect_message(&phone_a, "SIP/2.0 480");
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(worker.call_map.lock().unwrap().size, 0);
+
+    worker.stop();
+}
@@ -0,0 +1,100 @@
+use sip_server_rust::directory::DirectoryError;
+use sip_server_rust::ring_group::{parse_ring_groups, start_hunt};
+use sip_server_rust::sip_defs::{HuntStrategy, RingGroup, RING_GROUPS};
+use std::time::Duration;
+
+fn add_group(number: &str, strategy: HuntStrategy, overflow: Option<&str>) {
+    RING_GROUPS.lock().unwrap().push(RingGroup {
+        number: number.to_string(),
+        strategy,
+        members: vec!["1003".to_string(), "1004".to_string(), "1005".to_string()],
+        member_timeout: Duration::from_secs(10),
+        overflow: overflow.map(str::to_string),
+        next_start: 0,
+    });
+}
+
+fn users(list: &[&str]) -> Vec<String> {
+    list.iter().map(|user| user.to_string()).collect()
+}
+
+#[test]
+fn user_is_rung_alone_until_it_answers() {
+    let mut hunt = start_hunt("1002");
+    assert!(hunt.group.is_none());
+    assert_eq!(hunt.next_step(), Some((users(&["1002"]), None)));
+    assert_eq!(hunt.next_step(), None);
+}
+
+#[test]
+fn sequential_group_rings_members_in_order_then_overflow() {
+    add_group("2901", HuntStrategy::Sequential, Some("1001"));
+    let mut hunt = start_hunt("2901");
+    assert_eq!(hunt.group.as_deref(), Some("2901"));
+    let ring_time = Some(Duration::from_secs(10));
+    for member in ["1003", "1004", "1005"] {
+        assert_eq!(hunt.next_step(), Some((users(&[member]), ring_time)));
+    }
+    assert_eq!(hunt.next_step(), Some((users(&["1001"]), None)));
+    assert_eq!(hunt.next_step(), None);
+}
+
+#[test]
+fn parallel_group_rings_all_members_at_once() {
+    add_group("2902", HuntStrategy::Parallel, None);
+    let mut hunt = start_hunt("2902");
+    assert_eq!(
+        hunt.next_step(),
+        Some((
+            users(&["1003", "1004", "1005"]),
+            Some(Duration::from_secs(10))
+        ))
+    );
+    assert_eq!(hunt.next_step(), None);
+}
+
+#[test]
+fn round_robin_group_starts_each_call_one_member_further() {
+    add_group("2903", HuntStrategy::RoundRobin, None);
+    let first_members = (0..4)
+        .map(|_| start_hunt("2903").members[0].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(first_members, users(&["1003", "1004", "1005", "1003"]));
+
+    let mut hunt = start_hunt("2903");
+    assert_eq!(hunt.members, users(&["1004", "1005", "1003"]));
+    assert_eq!(hunt.next_step().unwrap().0, users(&["1004"]));
+}
+
+#[test]
+fn ring_groups_are_read_from_their_file() {
+    let groups = parse_ring_groups(
+        "# number,strategy,members,member timeout,overflow\n\
+2000, sequential ,1003 1004 1005,15,1001\n\
+\n\
+2001,round-robin,1002,20,   # no overflow\n",
+    )
+    .unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].number, "2000");
+    assert_eq!(groups[0].strategy, HuntStrategy::Sequential);
+    assert_eq!(groups[0].members, users(&["1003", "1004", "1005"]));
+    assert_eq!(groups[0].member_timeout, Duration::from_secs(15));
+    assert_eq!(groups[0].overflow.as_deref(), Some("1001"));
+    assert_eq!(groups[1].strategy, HuntStrategy::RoundRobin);
+    assert_eq!(groups[1].overflow, None);
+
+    for (text, line) in [
+        ("2000,sequential,1003,15\n", 1),
+        ("2000,sequential,1003,15,\n2000,parallel,1004,15,\n", 2),
+        ("2000,hunt,1003,15,\n", 1),
+        ("# empty\n2000,parallel,,15,\n", 2),
+        ("2000,parallel,1003,0,\n", 1),
+        ("2000,parallel,1003,soon,\n", 1),
+    ] {
+        match parse_ring_groups(text) {
+            Err(DirectoryError::Line(number, _)) => assert_eq!(number, line, "{:?}", text),
+            other => panic!("expected a line error for {:?}, got {:?}", text, other),
+        }
+    }
+}
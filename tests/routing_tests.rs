@@ -0,0 +1,72 @@
+use sip_server_rust::message::*;
+use sip_server_rust::routing::*;
+use sip_server_rust::sip_defs::{SIP_PORT, SIP_SERVER_IP_ADDRESS};
+use sip_server_rust::uri::SipUri;
+
+fn request_with_routes(uri: &str, routes: &[&str]) -> SipRequest {
+    let mut text = format!(
+        "BYE {} SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.20:5060;branch=z9hG4bKroute1\r\n\
+From: <sip:1002@server>;tag=b\r\n\
+To: <sip:1001@server>;tag=a\r\n\
+Call-ID: route-test@server\r\n\
+CSeq: 2 BYE\r\n",
+        uri
+    );
+    for route in routes {
+        text.push_str(&format!("Route: {}\r\n", route));
+    }
+    text.push_str("Content-Length: 0\r\n\r\n");
+    match parse_message(text.as_bytes()).expect("message should parse") {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    }
+}
+
+#[test]
+fn server_route_is_loose_and_ours() {
+    let route = server_route();
+    assert!(is_loose_route(&route));
+    let uri = route_uri(&route).expect("route URI");
+    assert!(is_server_route_uri(&uri));
+    // a user on our host is a call target, not a route entry
+    let target = SipUri::new(Some("1002"), SIP_SERVER_IP_ADDRESS, Some(SIP_PORT));
+    assert!(!is_server_route_uri(&target));
+}
+
+#[test]
+fn pops_our_route_entry_from_the_top() {
+    let ours = server_route();
+    let mut req = request_with_routes(
+        "sip:1001@192.168.1.10:5060",
+        &[&ours, "<sip:edge.example.com;lr>"],
+    );
+    assert!(preprocess_routes(&mut req));
+    assert_eq!(req.uri, "sip:1001@192.168.1.10:5060");
+    assert_eq!(
+        req.headers.get_list("Route"),
+        vec!["<sip:edge.example.com;lr>"]
+    );
+}
+
+#[test]
+fn foreign_top_route_is_left_alone() {
+    let mut req = request_with_routes("sip:1001@192.168.1.10:5060", &["<sip:edge.example.com;lr>"]);
+    assert!(!preprocess_routes(&mut req));
+    assert_eq!(req.headers.get_list("Route").len(), 1);
+}
+
+#[test]
+fn strict_router_request_uri_is_restored_from_last_route() {
+    let our_uri = format!("sip:{}:{};lr", SIP_SERVER_IP_ADDRESS, SIP_PORT);
+    let mut req = request_with_routes(
+        &our_uri,
+        &["<sip:edge.example.com;lr>", "<sip:1001@192.168.1.10:5060>"],
+    );
+    assert!(preprocess_routes(&mut req));
+    assert_eq!(req.uri, "sip:1001@192.168.1.10:5060");
+    assert_eq!(
+        req.headers.get_list("Route"),
+        vec!["<sip:edge.example.com;lr>"]
+    );
+}
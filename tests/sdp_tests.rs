@@ -0,0 +1,152 @@
+use sip_server_rust::sdp::*;
+
+fn sample_offer() -> String {
+    "v=0\r\n\
+o=alice 2890844526 2890844526 IN IP4 192.168.1.10\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.10\r\n\
+t=0 0\r\n\
+m=audio 49170 RTP/AVP 0 8 101\r\n\
+a=rtpmap:0 PCMU/8000\r\n\
+a=rtpmap:8 PCMA/8000\r\n\
+a=rtpmap:101 telephone-event/8000\r\n\
+a=fmtp:101 0-16\r\n\
+a=ptime:20\r\n\
+a=sendrecv\r\n"
+        .to_string()
+}
+
+fn with_version(text: &str, version: u64) -> SessionDescription {
+    let sdp = text.replace("2890844526 2890844526", &format!("2890844526 {}", version));
+    SessionDescription::parse(&sdp).unwrap()
+}
+
+#[test]
+fn parses_session_and_media_lines() {
+    let sdp = SessionDescription::parse(&sample_offer()).unwrap();
+    assert_eq!(sdp.version, 0);
+    assert_eq!(sdp.origin.username, "alice");
+    assert_eq!(sdp.origin.session_version, 2890844526);
+    assert_eq!(
+        sdp.connection.as_ref().map(|c| c.address.as_str()),
+        Some("192.168.1.10")
+    );
+    assert_eq!(sdp.media.len(), 1);
+
+    let audio = &sdp.media[0];
+    assert_eq!(audio.media, "audio");
+    assert_eq!(audio.port, 49170);
+    assert_eq!(audio.protocol, "RTP/AVP");
+    assert_eq!(audio.formats, vec!["0", "8", "101"]);
+    assert_eq!(audio.rtpmap("8").unwrap().encoding, "PCMA");
+    assert_eq!(audio.rtpmap("101").unwrap().clock_rate, 8000);
+    assert_eq!(audio.fmtp("101"), Some("0-16"));
+    assert_eq!(audio.attribute("ptime"), Some("20"));
+    assert_eq!(audio.direction(), Some(Direction::SendRecv));
+}
+
+#[test]
+fn serializes_back_to_the_same_text() {
+    let text = sample_offer();
+    let sdp: SessionDescription = text.parse().unwrap();
+    assert_eq!(sdp.to_string(), text);
+}
+
+#[test]
+fn accepts_bare_lf_and_media_level_connection() {
+    let text = "v=0\no=- 1 1 IN IP4 10.0.0.1\ns=call\nt=0 0\n\
+m=audio 4000 RTP/AVP 0\nc=IN IP4 10.0.0.2\n\
+m=video 0 RTP/AVP 96\nb=AS:256\na=rtpmap:96 H264/90000\n";
+    let sdp = SessionDescription::parse(text).unwrap();
+    assert!(sdp.connection.is_none());
+    assert_eq!(sdp.media.len(), 2);
+    assert_eq!(
+        sdp.media_connection(&sdp.media[0]).unwrap().address,
+        "10.0.0.2"
+    );
+    assert!(sdp.media[1].is_disabled());
+    assert_eq!(sdp.media[1].bandwidth, vec!["AS:256"]);
+    assert!(sdp
+        .to_string()
+        .contains("m=video 0 RTP/AVP 96\r\nb=AS:256\r\n"));
+}
+
+#[test]
+fn malformed_descriptions_are_errors() {
+    assert_eq!(SessionDescription::parse(""), Err(SdpError::Empty));
+    assert_eq!(
+        SessionDescription::parse("o=- 1 1 IN IP4 a\r\n"),
+        Err(SdpError::MissingVersion)
+    );
+    assert!(matches!(
+        SessionDescription::parse("v=0\r\no=- 1 IN IP4 a\r\n"),
+        Err(SdpError::InvalidOrigin(_))
+    ));
+    assert!(matches!(
+        SessionDescription::parse("v=0\r\no=- 1 1 IN IP4 a\r\nm=audio x RTP/AVP 0\r\n"),
+        Err(SdpError::InvalidMedia(_))
+    ));
+    assert_eq!(
+        SessionDescription::parse("v=0\r\ns=-\r\n"),
+        Err(SdpError::MissingOrigin)
+    );
+}
+
+#[test]
+fn hold_detection() {
+    let mut sdp = SessionDescription::parse(&sample_offer()).unwrap();
+    assert!(!sdp.is_hold());
+    sdp.media[0].set_direction(Direction::SendOnly);
+    assert!(sdp.is_hold());
+    assert_eq!(
+        sdp.media[0].direction().unwrap().reversed(),
+        Direction::RecvOnly
+    );
+
+    // RFC 2543 style hold
+    let old_style = sample_offer()
+        .replace("c=IN IP4 192.168.1.10", "c=IN IP4 0.0.0.0")
+        .replace("a=sendrecv\r\n", "");
+    assert!(SessionDescription::parse(&old_style).unwrap().is_hold());
+}
+
+#[test]
+fn offer_answer_exchange() {
+    let offer = with_version(&sample_offer(), 1);
+    let answer = with_version(&sample_offer().replace("alice", "bob"), 1);
+
+    let mut leg = OfferAnswer::default();
+    assert_eq!(leg.remote_sdp(offer.clone()), Ok(SdpRole::Offer));
+    assert_eq!(leg.state, NegotiationState::RemoteOffer);
+    assert!(leg.offer_pending());
+    assert_eq!(leg.local_sdp(answer.clone()), Ok(SdpRole::Answer));
+    assert_eq!(leg.state, NegotiationState::Complete);
+    assert_eq!(leg.remote.as_ref(), Some(&offer));
+    assert_eq!(leg.local.as_ref(), Some(&answer));
+
+    // The same answer repeated (183 then 200 OK) changes nothing.
+    assert_eq!(leg.local_sdp(answer), Ok(SdpRole::Repeat));
+    assert_eq!(leg.state, NegotiationState::Complete);
+
+    // A new version from the peer is a re-offer.
+    assert_eq!(
+        leg.remote_sdp(with_version(&sample_offer(), 2)),
+        Ok(SdpRole::Offer)
+    );
+}
+
+#[test]
+fn second_offer_while_pending_is_rejected() {
+    let mut leg = OfferAnswer::default();
+    assert_eq!(
+        leg.local_sdp(with_version(&sample_offer(), 1)),
+        Ok(SdpRole::Offer)
+    );
+    assert_eq!(
+        leg.local_sdp(with_version(&sample_offer(), 2)),
+        Err(OfferAnswerError::OfferPending)
+    );
+    leg.rollback();
+    assert_eq!(leg.state, NegotiationState::Idle);
+    assert!(!leg.offer_pending());
+}
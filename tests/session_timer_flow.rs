@@ -0,0 +1,169 @@
+mod common;
+
+use common::{
+    add_test_users, addr, authorize, expect_message, in_dialog, request, response, sample_invite,
+    Phones,
+};
+use sip_server_rust::builder::ResponseBuilder;
+use sip_server_rust::message::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn invite_from_a(callee: &str, extra: &str) -> String {
+    let invite = sample_invite()
+        .replace("sip:1002@server", &format!("sip:{}@server", callee))
+        .replace(
+            "Content-Length: 0\r\n",
+            &format!("{}Content-Length: 0\r\n", extra),
+        );
+    authorize(&invite)
+}
+
+#[test]
+fn session_expires_below_min_se_is_rejected() {
+    add_test_users();
+    let phones = match Phones::start("1004") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping session timer test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let invite = invite_from_a("1004", "Supported: timer\r\nSession-Expires: 30\r\n");
+    phones.worker.send(&invite, &phones.phone_a);
+
+    let rejected = response(expect_message(&phones.phone_a, "SIP/2.0 422"));
+    assert_eq!(rejected.headers.get("Min-SE"), Some("90"));
+    assert_eq!(phones.worker.call_map.lock().unwrap().size, 0);
+
+    phones.stop();
+}
+
+#[test]
+fn expired_session_is_torn_down_on_both_legs() {
+    add_test_users();
+    let phones = match Phones::start("1005") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping session timer test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call(
+        "1005",
+        "Supported: timer\r\nSession-Expires: 600\r\nMin-SE: 120\r\n",
+        &[("Session-Expires", "600;refresher=uac")],
+    );
+    assert_eq!(call.invite_to_b.headers.get("Session-Expires"), Some("600"));
+    assert_eq!(call.invite_to_b.headers.get("Min-SE"), Some("120"));
+    assert!(call.invite_to_b.has_option_tag("Supported", "timer"));
+    // A asked for the interval, so A refreshes
+    assert_eq!(
+        call.ok_to_a.headers.get("Session-Expires"),
+        Some("600;refresher=uac")
+    );
+    assert!(call.ok_to_a.has_option_tag("Require", "timer"));
+
+    {
+        let mut guard = phones.worker.call_map.lock().unwrap();
+        let call = &mut guard.calls[0];
+        assert_eq!(call.session_timer.interval, Some(Duration::from_secs(600)));
+        assert!(call.session_timer.refresh_at.is_none());
+        // No refresh arrived in time
+        call.session_timer.expires_at = Some(Instant::now());
+    }
+    let bye_to_a = request(expect_message(&phones.phone_a, "BYE "));
+    let bye_to_b = request(expect_message(&phones.phone_b, "BYE "));
+    for (sock, bye) in [(&phones.phone_a, bye_to_a), (&phones.phone_b, bye_to_b)] {
+        let ok = ResponseBuilder::from_request(&bye, 200).build().to_string();
+        phones.worker.send(&ok, sock);
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(phones.worker.call_map.lock().unwrap().size, 0);
+
+    phones.stop();
+}
+
+#[test]
+fn server_refreshes_when_neither_phone_does() {
+    add_test_users();
+    let phones = match Phones::start("1006") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping session timer test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call("1006", "", &[]);
+    // A does not support session timers: nothing is required of it
+    assert!(call.ok_to_a.headers.get("Session-Expires").is_none());
+
+    {
+        let mut guard = phones.worker.call_map.lock().unwrap();
+        let call = &mut guard.calls[0];
+        assert_eq!(call.session_timer.interval, Some(Duration::from_secs(1800)));
+        assert!(call.session_timer.refresh_at.is_some());
+        call.session_timer.refresh_at = Some(Instant::now());
+    }
+    let update_to_a = request(expect_message(&phones.phone_a, "UPDATE "));
+    let update_to_b = request(expect_message(&phones.phone_b, "UPDATE "));
+    assert_eq!(
+        update_to_a.headers.get("Session-Expires"),
+        Some("1800;refresher=uac")
+    );
+
+    // A answers; B has lost the dialog, so the call is torn down
+    let a_ok = ResponseBuilder::from_request(&update_to_a, 200)
+        .build()
+        .to_string();
+    let b_481 = ResponseBuilder::from_request(&update_to_b, 481)
+        .build()
+        .to_string();
+    phones.worker.send(&a_ok, &phones.phone_a);
+    phones.worker.send(&b_481, &phones.phone_b);
+    expect_message(&phones.phone_a, "BYE ");
+    expect_message(&phones.phone_b, "BYE ");
+
+    phones.stop();
+}
+
+#[test]
+fn refresh_answer_is_relayed_without_timer_to_a_phone_without_support() {
+    add_test_users();
+    let phones = match Phones::start("1003") {
+        Some(phones) => phones,
+        None => {
+            eprintln!("Skipping session timer test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let call = phones.connect_call("1003", "", &[]);
+
+    // A, which does not support session timers, sends a re-INVITE
+    let reinvite = in_dialog(
+        "INVITE",
+        &call.a_from,
+        &call.a_to,
+        "a84b4c76e66710@pc33.atlanta.com",
+        314160,
+        addr(&phones.phone_a),
+        "",
+    );
+    phones.worker.send(&reinvite, &phones.phone_a);
+    let reinvite_to_b = request(expect_message(&phones.phone_b, "INVITE "));
+
+    // B's answer carries a session timer, which A is not made to require
+    let ok = ResponseBuilder::from_request(&reinvite_to_b, 200)
+        .contact(&format!("<sip:phone@{}>", addr(&phones.phone_b)))
+        .header("Require", "timer")
+        .header("Session-Expires", "1800;refresher=uas")
+        .build()
+        .to_string();
+    phones.worker.send(&ok, &phones.phone_b);
+    let ok_to_a = response(expect_message(&phones.phone_a, "SIP/2.0 200 OK"));
+    assert_eq!(ok_to_a.cseq(), Some((314160, "INVITE".to_string())));
+    assert!(ok_to_a.headers.get("Session-Expires").is_none());
+    assert!(!ok_to_a.has_option_tag("Require", "timer"));
+
+    phones.stop();
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::{sample_invite, sample_response};
+use sip_server_rust::message::*;
+use sip_server_rust::session_timer::*;
+use std::time::Duration;
+
+#[test]
+fn parses_session_expires_with_refresher() {
+    let se = SessionExpires::parse("1800;refresher=uas").unwrap();
+    assert_eq!(se.delta, 1800);
+    assert_eq!(se.refresher, Some(Refresher::Uas));
+    assert_eq!(se.interval(), Duration::from_secs(1800));
+    assert_eq!(se.to_string(), "1800;refresher=uas");
+
+    let bare = SessionExpires::parse(" 90 ").unwrap();
+    assert_eq!(bare.refresher, None);
+    assert_eq!(bare.to_string(), "90");
+
+    assert!(SessionExpires::parse("soon").is_none());
+    assert_eq!(
+        SessionExpires::parse("600;refresher=both")
+            .unwrap()
+            .refresher,
+        None
+    );
+}
+
+#[test]
+fn reads_session_timer_headers_from_messages() {
+    let invite = sample_invite().replace(
+        "Content-Length: 0\r\n",
+        "Supported: timer\r\nx: 600;refresher=uac\r\nMin-SE: 120\r\nContent-Length: 0\r\n",
+    );
+    let invite = match parse_message(invite.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    };
+    assert_eq!(
+        session_expires(&invite),
+        Some(SessionExpires {
+            delta: 600,
+            refresher: Some(Refresher::Uac),
+        })
+    );
+    assert_eq!(min_se(&invite), Some(120));
+    assert!(supports_timer(&invite));
+
+    let ok = match parse_message(sample_response(200, "OK").as_bytes()).unwrap() {
+        ParsedMessage::Response(resp) => resp,
+        ParsedMessage::Request(_) => panic!("expected a response"),
+    };
+    assert_eq!(session_expires(&ok), None);
+    assert_eq!(min_se(&ok), None);
+    assert!(!supports_timer(&ok));
+}
+
+#[test]
+fn expiry_margin_is_a_third_capped_at_32_seconds() {
+    assert_eq!(
+        expiry_margin(Duration::from_secs(90)),
+        Duration::from_secs(30)
+    );
+    assert_eq!(
+        expiry_margin(Duration::from_secs(1800)),
+        Duration::from_secs(32)
+    );
+}
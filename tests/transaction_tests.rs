@@ -0,0 +1,435 @@
+mod common;
+
+use common::{dummy_socket_addr, sample_invite};
+use sip_server_rust::builder::*;
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::{TIMER_T1, TIMER_T2, TIMER_T4};
+use sip_server_rust::transaction::*;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+fn parse_request(text: &str) -> SipRequest {
+    match parse_message(text.as_bytes()).expect("message should parse") {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    }
+}
+
+fn peer() -> SocketAddr {
+    "127.0.0.1:7000".parse().unwrap()
+}
+
+fn request(method: &str, cseq: u32) -> SipRequest {
+    RequestBuilder::new(method, "sip:1002@127.0.0.1:7000")
+        .via(&generate_branch())
+        .from("<sip:1001@server>;tag=a")
+        .to("<sip:1002@server>")
+        .call_id("txn-call")
+        .cseq(cseq)
+        .build()
+}
+
+// Response from the peer to a request we sent.
+fn answer(req: &SipRequest, code: u16) -> SipResponse {
+    ResponseBuilder::from_request(req, code).to_tag("b").build()
+}
+
+fn retransmits(events: &[TimerEvent]) -> usize {
+    events
+        .iter()
+        .filter(|e| matches!(e, TimerEvent::Retransmit(_)))
+        .count()
+}
+
+#[test]
+fn via_helpers() {
+    let via = "SIP/2.0/UDP PC33.example.com:5060;rport;Branch=z9hG4bKabc";
+    assert_eq!(via_branch(via), Some("z9hG4bKabc"));
+    assert_eq!(via_sent_by(via), "pc33.example.com:5060");
+    assert_eq!(via_branch("SIP/2.0/UDP host"), None);
+}
+
+#[test]
+fn server_request_retransmission_is_absorbed() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+
+    assert!(
+        table
+            .receive_request(&invite, dummy_socket_addr(), now)
+            .deliver
+    );
+    let again = table.receive_request(&invite, dummy_socket_addr(), now);
+    assert!(!again.deliver);
+    assert_eq!(table.len(), 1);
+
+    // CANCEL shares the branch but is a transaction of its own
+    let cancel = parse_request(&sample_invite().replace("INVITE", "CANCEL"));
+    assert!(
+        table
+            .receive_request(&cancel, dummy_socket_addr(), now)
+            .deliver
+    );
+    assert_eq!(table.len(), 2);
+}
+
+#[test]
+fn server_invite_failure_is_retransmitted_until_ack() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+    table.receive_request(&invite, dummy_socket_addr(), now);
+
+    let busy = ResponseBuilder::from_request(&invite, 486).build();
+    table.send_response(&busy, dummy_socket_addr(), now);
+    let key = TransactionKey::for_request(&invite, true).unwrap();
+    assert_eq!(table.get(&key).unwrap().state, TransactionState::Completed);
+
+    // Timer G
+    let events = table.poll_timers(now + TIMER_T1);
+    assert_eq!(
+        events,
+        vec![TimerEvent::Retransmit(Outgoing {
+            payload: busy.to_string(),
+            destination: dummy_socket_addr(),
+        })]
+    );
+
+    // ACK for a non-2xx reuses the INVITE branch and is absorbed
+    let ack = RequestBuilder::ack_for(&invite, &busy);
+    let received = table.receive_request(&ack, dummy_socket_addr(), now + TIMER_T1);
+    assert!(!received.deliver);
+    assert_eq!(table.get(&key).unwrap().state, TransactionState::Confirmed);
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1 * 4)), 0);
+
+    // Timer I
+    table.poll_timers(now + TIMER_T1 + TIMER_T4);
+    assert!(table.is_empty());
+}
+
+#[test]
+fn server_invite_2xx_is_retransmitted_until_ack() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+    table.receive_request(&invite, dummy_socket_addr(), now);
+    let ok = ResponseBuilder::from_request(&invite, 200)
+        .to_tag("srv")
+        .build();
+    table.send_response(&ok, dummy_socket_addr(), now);
+
+    // T1, then 2*T1 later
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1)), 1);
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1 * 2)), 0);
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1 * 3)), 1);
+
+    // The ACK for a 2xx has its own branch; it goes to the call once
+    let ack = RequestBuilder::new("ACK", "sip:TinySIP@server")
+        .via(&generate_branch())
+        .call_id(invite.call_id().unwrap())
+        .cseq(314159)
+        .build();
+    assert!(
+        table
+            .receive_request(&ack, dummy_socket_addr(), now)
+            .deliver
+    );
+    assert!(
+        !table
+            .receive_request(&ack, dummy_socket_addr(), now)
+            .deliver
+    );
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1 * 7)), 0);
+}
+
+#[test]
+fn unacknowledged_2xx_is_reported() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+    table.receive_request(&invite, dummy_socket_addr(), now);
+    let ok = ResponseBuilder::from_request(&invite, 200).build();
+    table.send_response(&ok, dummy_socket_addr(), now);
+
+    let events = table.poll_timers(now + TIMER_T1 * 64);
+    assert!(events.contains(&TimerEvent::NoAck(invite)));
+    assert!(table.is_empty());
+}
+
+#[test]
+fn client_invite_retransmits_and_times_out() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = request("INVITE", 1);
+    table.send_request(&invite, peer(), now);
+
+    // Timer A: T1, 2*T1, 4*T1 ...
+    let mut at = now;
+    let mut interval = TIMER_T1;
+    for _ in 0..3 {
+        at += interval;
+        let events = table.poll_timers(at);
+        assert_eq!(retransmits(&events), 1);
+        interval *= 2;
+    }
+    assert_eq!(retransmits(&table.poll_timers(at + interval / 2)), 0);
+
+    // Timer B
+    let events = table.poll_timers(now + TIMER_T1 * 64);
+    assert_eq!(events, vec![TimerEvent::NoResponse(invite)]);
+    assert!(table.is_empty());
+}
+
+#[test]
+fn client_invite_provisional_stops_retransmission() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = request("INVITE", 1);
+    table.send_request(&invite, peer(), now);
+
+    assert!(table.receive_response(&answer(&invite, 180), now).deliver);
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1)), 0);
+    // No Timer B once proceeding
+    assert!(table.poll_timers(now + TIMER_T1 * 64).is_empty());
+    assert_eq!(table.len(), 1);
+}
+
+#[test]
+fn client_invite_acknowledges_failures_itself() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = request("INVITE", 1);
+    table.send_request(&invite, peer(), now);
+
+    let busy = answer(&invite, 486);
+    let received = table.receive_response(&busy, now);
+    assert!(received.deliver);
+    assert_eq!(received.transmit.len(), 1);
+    let ack = parse_request(&received.transmit[0].payload);
+    assert_eq!(ack.method, "ACK");
+    assert_eq!(ack.top_via(), invite.top_via());
+    assert!(ack.headers.get("To").unwrap().contains("tag=b"));
+    assert_eq!(received.transmit[0].destination, peer());
+
+    // A retransmitted final response is answered with the same ACK
+    let again = table.receive_response(&busy, now + Duration::from_millis(10));
+    assert!(!again.deliver);
+    assert_eq!(again.transmit, received.transmit);
+}
+
+#[test]
+fn client_invite_2xx_retransmission_repeats_ack() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = request("INVITE", 5);
+    table.send_request(&invite, peer(), now);
+
+    let ok = answer(&invite, 200);
+    assert!(table.receive_response(&ok, now).deliver);
+
+    let ack = RequestBuilder::new("ACK", "sip:1002@127.0.0.1:7000")
+        .via(&generate_branch())
+        .call_id("txn-call")
+        .cseq(5)
+        .build();
+    table.send_request(&ack, peer(), now);
+
+    let again = table.receive_response(&ok, now);
+    assert!(!again.deliver);
+    assert_eq!(again.transmit[0].payload, ack.to_string());
+}
+
+#[test]
+fn client_non_invite_retransmission_is_capped_at_t2() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let bye = request("BYE", 2);
+    table.send_request(&bye, peer(), now);
+
+    // T1, 2*T1, 4*T1, then T2 (= 8*T1) steps
+    let mut at = now;
+    for interval in [TIMER_T1, TIMER_T1 * 2, TIMER_T1 * 4, TIMER_T2, TIMER_T2] {
+        at += interval;
+        assert_eq!(retransmits(&table.poll_timers(at)), 1);
+    }
+
+    assert!(table.receive_response(&answer(&bye, 200), at).deliver);
+    assert!(!table.receive_response(&answer(&bye, 200), at).deliver);
+    assert_eq!(retransmits(&table.poll_timers(at + TIMER_T2)), 0);
+}
+
+#[test]
+fn client_non_invite_times_out() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let bye = request("BYE", 2);
+    table.send_request(&bye, peer(), now);
+    let events = table.poll_timers(now + TIMER_T1 * 64);
+    assert!(events.contains(&TimerEvent::NoResponse(bye)));
+}
+
+#[test]
+fn unmatched_responses_are_delivered() {
+    let mut table = TransactionTable::new();
+    let stray = answer(&request("INVITE", 9), 200);
+    let received = table.receive_response(&stray, Instant::now());
+    assert!(received.deliver);
+    assert!(received.transmit.is_empty());
+}
+
+#[test]
+fn retransmitted_invite_gets_last_response_again() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+    let resent = |table: &mut TransactionTable| {
+        let received = table.receive_request(&invite, dummy_socket_addr(), now);
+        assert!(!received.deliver);
+        received
+            .transmit
+            .iter()
+            .map(|o| parse_message(o.payload.as_bytes()).unwrap())
+            .map(|m| match m {
+                ParsedMessage::Response(r) => r.status_code,
+                ParsedMessage::Request(_) => panic!("expected a response"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    table.receive_request(&invite, dummy_socket_addr(), now);
+    assert!(resent(&mut table).is_empty());
+
+    for code in [100, 180, 486] {
+        let response = ResponseBuilder::from_request(&invite, code).build();
+        table.send_response(&response, dummy_socket_addr(), now);
+        assert_eq!(resent(&mut table), vec![code]);
+    }
+
+    // Once the failure is acknowledged there is nothing left to repeat
+    let busy = ResponseBuilder::from_request(&invite, 486).build();
+    let ack = RequestBuilder::ack_for(&invite, &busy);
+    table.receive_request(&ack, dummy_socket_addr(), now);
+    assert!(resent(&mut table).is_empty());
+}
+
+#[test]
+fn retransmitted_bye_gets_its_200_again() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let bye = request("BYE", 3);
+    table.receive_request(&bye, peer(), now);
+    let ok = ResponseBuilder::from_request(&bye, 200).build();
+    table.send_response(&ok, peer(), now);
+
+    let received = table.receive_request(&bye, peer(), now + TIMER_T1);
+    assert!(!received.deliver);
+    assert_eq!(
+        received.transmit,
+        vec![Outgoing {
+            payload: ok.to_string(),
+            destination: peer(),
+        }]
+    );
+}
+
+fn prack_for(invite: &SipRequest, rseq: u32) -> SipRequest {
+    RequestBuilder::new("PRACK", "sip:TinySIP@server")
+        .via(&generate_branch())
+        .call_id(invite.call_id().unwrap())
+        .cseq(314160)
+        .header("RAck", &format!("{} 314159 INVITE", rseq))
+        .build()
+}
+
+#[test]
+fn reliable_provisional_is_retransmitted_until_prack() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+    table.receive_request(&invite, dummy_socket_addr(), now);
+    let progress = ResponseBuilder::from_request(&invite, 183)
+        .to_tag("srv")
+        .header("Require", "100rel")
+        .header("RSeq", "7")
+        .build();
+    table.send_response(&progress, dummy_socket_addr(), now);
+
+    // T1, then 2*T1 later
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1)), 1);
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1 * 2)), 0);
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1 * 3)), 1);
+
+    // Wrong RSeq does not acknowledge it
+    assert!(!table.receive_prack(&prack_for(&invite, 6)));
+    assert!(table.receive_prack(&prack_for(&invite, 7)));
+    assert!(!table.receive_prack(&prack_for(&invite, 7)));
+    assert_eq!(retransmits(&table.poll_timers(now + TIMER_T1 * 10)), 0);
+
+    let key = TransactionKey::for_request(&invite, true).unwrap();
+    assert_eq!(table.get(&key).unwrap().state, TransactionState::Proceeding);
+}
+
+#[test]
+fn unacknowledged_reliable_provisional_times_out() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+    table.receive_request(&invite, dummy_socket_addr(), now);
+    let ringing = ResponseBuilder::from_request(&invite, 180)
+        .to_tag("srv")
+        .header("Require", "100rel")
+        .header("RSeq", "1")
+        .build();
+    table.send_response(&ringing, dummy_socket_addr(), now);
+
+    let events = table.poll_timers(now + TIMER_T1 * 64);
+    assert!(events.contains(&TimerEvent::NoPrack(invite.clone())));
+    // The INVITE transaction is still there for the final response
+    assert_eq!(table.len(), 1);
+    assert!(table.poll_timers(now + TIMER_T1 * 200).is_empty());
+}
+
+#[test]
+fn unreliable_provisional_is_not_retransmitted() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let invite = parse_request(&sample_invite());
+    table.receive_request(&invite, dummy_socket_addr(), now);
+    let ringing = ResponseBuilder::from_request(&invite, 180)
+        .to_tag("srv")
+        .build();
+    table.send_response(&ringing, dummy_socket_addr(), now);
+    assert!(table.poll_timers(now + TIMER_T1 * 4).is_empty());
+}
+
+#[test]
+fn unanswered_server_transactions_are_reclaimed() {
+    let mut table = TransactionTable::new();
+    let now = Instant::now();
+    let info = request("INFO", 2);
+    table.receive_request(&info, peer(), now);
+    let invite = parse_request(&sample_invite());
+    table.receive_request(&invite, dummy_socket_addr(), now);
+    assert_eq!(table.len(), 2);
+
+    // The non-INVITE one goes when its client has given up
+    assert!(table.poll_timers(now + TIMER_T1 * 63).is_empty());
+    assert!(table.poll_timers(now + TIMER_T1 * 64).is_empty());
+    assert_eq!(table.len(), 1);
+
+    // The INVITE one waits for its final response however long it rings
+    let ringing = ResponseBuilder::from_request(&invite, 180)
+        .to_tag("srv")
+        .build();
+    table.send_response(&ringing, dummy_socket_addr(), now);
+    let later = now + Duration::from_secs(600);
+    assert!(table.poll_timers(later).is_empty());
+    assert_eq!(table.len(), 1);
+    let busy = ResponseBuilder::from_request(&invite, 486)
+        .to_tag("srv")
+        .build();
+    table.send_response(&busy, dummy_socket_addr(), later);
+    table.poll_timers(later + TIMER_T1 * 64);
+    assert!(table.is_empty());
+}
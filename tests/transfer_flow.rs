@@ -0,0 +1,261 @@
+mod common;
+
+use common::{
+    add_test_users, addr, bind, expect_message, in_dialog, register, reply, request, response, sdp,
+    Connected, Phones,
+};
+use sip_server_rust::message::*;
+use sip_server_rust::sip_defs::CallState;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Sets up a connected call between A and `callee` (B), with `target` (C)
+// registered as well, or None if the sandbox does not allow UDP sockets.
+fn connect_call(callee: &str, target: &str) -> Option<(Phones, Connected, Arc<UdpSocket>)> {
+    let phones = Phones::start(callee)?;
+    let phone_c = bind()?;
+    register(target, &phone_c);
+    let call = phones.connect_call(callee, "", &[]);
+    Some((phones, call, phone_c))
+}
+
+// Checks a NOTIFY of the transfer subscription and returns its sipfrag.
+fn expect_notify(phone: &UdpSocket) -> (String, String) {
+    let notify = request(expect_message(phone, "NOTIFY "));
+    assert!(notify.headers.get("Event").unwrap().starts_with("refer"));
+    assert_eq!(
+        notify.headers.get("Content-Type"),
+        Some("message/sipfrag;version=2.0")
+    );
+    (
+        notify.body.trim_end().to_string(),
+        notify
+            .headers
+            .get("Subscription-State")
+            .unwrap()
+            .to_string(),
+    )
+}
+
+#[test]
+fn callee_transfers_caller_to_another_phone() {
+    add_test_users();
+    let (phones, call, phone_c) = match connect_call("1002", "1003") {
+        Some(connected) => connected,
+        None => {
+            eprintln!("Skipping transfer test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let a_addr = addr(&phones.phone_a);
+    let b_addr = addr(&phones.phone_b);
+    let c_addr = addr(&phone_c);
+
+    let refer = in_dialog(
+        "REFER",
+        &call.b_from,
+        &call.b_to,
+        &call.b_call_id,
+        1,
+        b_addr,
+        "Refer-To: <sip:1003@server>\r\nReferred-By: <sip:1002@server>\r\n",
+    );
+    phones.worker.send(&refer, &phones.phone_b);
+    response(expect_message(&phones.phone_b, "SIP/2.0 202 Accepted"));
+    let (frag, state) = expect_notify(&phones.phone_b);
+    assert_eq!(frag, "SIP/2.0 100 Trying");
+    assert!(state.starts_with("active"));
+
+    // The target is called on behalf of the transferee, without an offer
+    let to_c = request(expect_message(&phone_c, "INVITE "));
+    assert!(to_c
+        .headers
+        .get("From")
+        .unwrap()
+        .contains("sip:1001@server"));
+    assert_eq!(to_c.headers.get("Referred-By"), Some("<sip:1002@server>"));
+    assert!(to_c.body.is_empty());
+    phones
+        .worker
+        .send(&reply(&to_c, 180, "caroltag", c_addr, None), &phone_c);
+    assert_eq!(expect_notify(&phones.phone_b).0, "SIP/2.0 180 Ringing");
+
+    // The target answers with an offer, which the transferee gets
+    let carol_sdp = sdp("carol");
+    phones.worker.send(
+        &reply(&to_c, 200, "caroltag", c_addr, Some(&carol_sdp)),
+        &phone_c,
+    );
+    let (frag, state) = expect_notify(&phones.phone_b);
+    assert_eq!(frag, "SIP/2.0 200 OK");
+    assert!(state.starts_with("terminated"));
+    let reinvite = request(expect_message(&phones.phone_a, "INVITE "));
+    assert_eq!(reinvite.body, carol_sdp);
+    assert_eq!(reinvite.headers.get("From"), Some(call.a_to.as_str()));
+
+    let alice_sdp = sdp("alice2");
+    phones.worker.send(
+        &reply(&reinvite, 200, "", a_addr, Some(&alice_sdp)),
+        &phones.phone_a,
+    );
+    let ack_to_a = request(expect_message(&phones.phone_a, "ACK "));
+    assert_eq!(ack_to_a.cseq().unwrap().0, reinvite.cseq().unwrap().0);
+    let ack_to_c = request(expect_message(&phone_c, "ACK "));
+    assert_eq!(ack_to_c.body, alice_sdp);
+    request(expect_message(&phones.phone_b, "BYE "));
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let state = &guard.calls[0];
+        assert!(state.transfer.is_none());
+        assert_eq!(state.b_leg_addr, Some(c_addr));
+        assert_eq!(state.b_leg_uuid, to_c.call_id().unwrap());
+        assert_eq!(state.call_state, CallState::Connected);
+    }
+
+    // A's BYE now reaches the target
+    let bye = in_dialog(
+        "BYE",
+        &call.a_from,
+        &call.a_to,
+        "a84b4c76e66710@pc33.atlanta.com",
+        314160,
+        a_addr,
+        "",
+    );
+    phones.worker.send(&bye, &phones.phone_a);
+    let bye_to_c = request(expect_message(&phone_c, "BYE "));
+    assert!(bye_to_c.headers.get("To").unwrap().contains("tag=caroltag"));
+    phones.stop();
+}
+
+#[test]
+fn failed_transfer_leaves_the_call_up() {
+    add_test_users();
+    let (phones, call, phone_c) = match connect_call("1004", "1005") {
+        Some(connected) => connected,
+        None => {
+            eprintln!("Skipping transfer test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let a_addr = addr(&phones.phone_a);
+    let b_addr = addr(&phones.phone_b);
+    let c_addr = addr(&phone_c);
+
+    // A transfers B to a target that is busy
+    let refer = in_dialog(
+        "REFER",
+        &call.a_from,
+        &call.a_to,
+        "a84b4c76e66710@pc33.atlanta.com",
+        314160,
+        a_addr,
+        "Refer-To: <sip:1005@server>\r\n",
+    );
+    phones.worker.send(&refer, &phones.phone_a);
+    response(expect_message(&phones.phone_a, "SIP/2.0 202"));
+    expect_notify(&phones.phone_a);
+    let to_c = request(expect_message(&phone_c, "INVITE "));
+    assert!(to_c
+        .headers
+        .get("From")
+        .unwrap()
+        .contains("sip:1004@server"));
+    phones
+        .worker
+        .send(&reply(&to_c, 486, "caroltag", c_addr, None), &phone_c);
+    let (frag, state) = expect_notify(&phones.phone_a);
+    assert_eq!(frag, "SIP/2.0 486 Busy Here");
+    assert!(state.starts_with("terminated"));
+    assert!(phones.worker.call_map.lock().unwrap().calls[0]
+        .transfer
+        .is_none());
+
+    // A second REFER to nobody is refused outright
+    let refer = in_dialog(
+        "REFER",
+        &call.a_from,
+        &call.a_to,
+        "a84b4c76e66710@pc33.atlanta.com",
+        314161,
+        a_addr,
+        "Refer-To: <sip:1099@server>\r\n",
+    );
+    phones.worker.send(&refer, &phones.phone_a);
+    response(expect_message(&phones.phone_a, "SIP/2.0 404"));
+
+    // The original call is still up
+    let bye = in_dialog(
+        "BYE",
+        &call.b_from,
+        &call.b_to,
+        &call.b_call_id,
+        1,
+        b_addr,
+        "",
+    );
+    phones.worker.send(&bye, &phones.phone_b);
+    request(expect_message(&phones.phone_a, "BYE "));
+    phones.stop();
+}
+
+#[test]
+fn transfer_completes_after_the_transferor_hung_up() {
+    add_test_users();
+    let (phones, call, phone_c) = match connect_call("1006", "1001") {
+        Some(connected) => connected,
+        None => {
+            eprintln!("Skipping transfer test; unable to bind UDP sockets");
+            return;
+        }
+    };
+    let a_addr = addr(&phones.phone_a);
+    let b_addr = addr(&phones.phone_b);
+    let c_addr = addr(&phone_c);
+
+    let refer = in_dialog(
+        "REFER",
+        &call.b_from,
+        &call.b_to,
+        &call.b_call_id,
+        1,
+        b_addr,
+        "Refer-To: sip:1001@server\r\n",
+    );
+    phones.worker.send(&refer, &phones.phone_b);
+    response(expect_message(&phones.phone_b, "SIP/2.0 202"));
+    let bye = in_dialog(
+        "BYE",
+        &call.b_from,
+        &call.b_to,
+        &call.b_call_id,
+        2,
+        b_addr,
+        "",
+    );
+    phones.worker.send(&bye, &phones.phone_b);
+    response(expect_message(&phones.phone_b, "SIP/2.0 200 OK"));
+
+    // The transferee is not hung up; the target's answer reaches it
+    let to_c = request(expect_message(&phone_c, "INVITE "));
+    phones.worker.send(
+        &reply(&to_c, 200, "caroltag", c_addr, Some(&sdp("carol"))),
+        &phone_c,
+    );
+    let reinvite = request(expect_message(&phones.phone_a, "INVITE "));
+    phones.worker.send(
+        &reply(&reinvite, 200, "", a_addr, Some(&sdp("alice2"))),
+        &phones.phone_a,
+    );
+    expect_message(&phone_c, "ACK ");
+    thread::sleep(Duration::from_millis(50));
+    {
+        let guard = phones.worker.call_map.lock().unwrap();
+        let state = &guard.calls[0];
+        assert_eq!(state.b_leg_addr, Some(c_addr));
+        assert_eq!(state.call_state, CallState::Connected);
+    }
+    phones.stop();
+}
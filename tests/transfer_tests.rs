@@ -0,0 +1,84 @@
+use sip_server_rust::dialog::Dialog;
+use sip_server_rust::message::*;
+use sip_server_rust::transfer::{refer_target, sipfrag, Replaces};
+
+fn refer(refer_to: &str) -> SipRequest {
+    let text = format!(
+        "REFER sip:server SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.20:5060;branch=z9hG4bKrefer1\r\n\
+From: <sip:1002@server>;tag=bobtag\r\n\
+To: <sip:1001@server>;tag=servertag\r\n\
+Call-ID: b-leg-call\r\n\
+CSeq: 2 REFER\r\n\
+{}\r\n\
+Content-Length: 0\r\n\r\n",
+        refer_to
+    );
+    match parse_message(text.as_bytes()).unwrap() {
+        ParsedMessage::Request(req) => req,
+        ParsedMessage::Response(_) => panic!("expected a request"),
+    }
+}
+
+#[test]
+fn refer_target_reads_name_addr_and_compact_forms() {
+    let target = refer_target(&refer("Refer-To: \"Carol\" <sip:1003@server>")).unwrap();
+    assert_eq!(target.user.as_deref(), Some("1003"));
+
+    let target = refer_target(&refer("r: sip:1004@server")).unwrap();
+    assert_eq!(target.user.as_deref(), Some("1004"));
+
+    let target = refer_target(&refer(
+        "Refer-To: <sip:1005@server?Replaces=abc%40host%3Bto-tag%3D1%3Bfrom-tag%3D2>",
+    ))
+    .unwrap();
+    assert_eq!(target.user.as_deref(), Some("1005"));
+    assert_eq!(target.headers.len(), 1, "embedded headers are kept");
+
+    assert!(refer_target(&refer("Referred-By: <sip:1002@server>")).is_none());
+}
+
+#[test]
+fn sipfrag_is_a_status_line() {
+    assert_eq!(sipfrag(180, "Ringing"), "SIP/2.0 180 Ringing\r\n");
+}
+
+#[test]
+fn replaces_header_is_parsed() {
+    let replaces =
+        Replaces::parse("98732@sip.example.com;from-tag=r33th4x0r;to-tag=ff87ff").unwrap();
+    assert_eq!(replaces.call_id, "98732@sip.example.com");
+    assert_eq!(replaces.to_tag, "ff87ff");
+    assert_eq!(replaces.from_tag, "r33th4x0r");
+    assert!(!replaces.early_only);
+
+    assert!(
+        Replaces::parse("abc;to-tag=1;from-tag=2;early-only")
+            .unwrap()
+            .early_only
+    );
+    assert!(
+        Replaces::parse("abc;to-tag=1").is_none(),
+        "from-tag is mandatory"
+    );
+    assert!(Replaces::parse(";to-tag=1;from-tag=2").is_none());
+}
+
+#[test]
+fn replaces_matches_dialog_by_call_id_and_tags() {
+    let mut dialog = Dialog::uac("b-leg-abc", "<sip:1001@server>", "<sip:1002@server>", "");
+    let replaces = |from_tag: &str| Replaces {
+        call_id: "b-leg-abc".to_string(),
+        to_tag: dialog.local_tag.clone(),
+        from_tag: from_tag.to_string(),
+        early_only: false,
+    };
+    // B has not sent its tag yet
+    assert!(replaces("").matches(&dialog));
+    let named = replaces("bobtag");
+    dialog.remote_tag = Some("bobtag".to_string());
+    assert!(named.matches(&dialog));
+    assert!(!replaces("othertag").matches(&dialog));
+    dialog.call_id = "other".to_string();
+    assert!(!named.matches(&dialog));
+}
@@ -0,0 +1,150 @@
+use sip_server_rust::uri::*;
+use std::net::SocketAddr;
+
+#[test]
+fn parses_full_sip_uri() {
+    let uri =
+        SipUri::parse("sip:alice:secret@example.com:5070;transport=udp;lr?subject=hi%20there")
+            .expect("valid URI");
+    assert_eq!(uri.scheme, UriScheme::Sip);
+    assert_eq!(uri.user.as_deref(), Some("alice"));
+    assert_eq!(uri.password.as_deref(), Some("secret"));
+    assert_eq!(uri.host, "example.com");
+    assert_eq!(uri.port, Some(5070));
+    assert_eq!(uri.param("transport"), Some("udp"));
+    assert_eq!(uri.param("lr"), Some(""));
+    assert!(uri.has_param("LR"));
+    assert_eq!(uri.header("Subject"), Some("hi there"));
+}
+
+#[test]
+fn parses_sips_and_tel_uris() {
+    let sips = SipUri::parse("sips:1001@10.0.0.1").unwrap();
+    assert_eq!(sips.scheme, UriScheme::Sips);
+    assert_eq!(
+        sips.socket_addr(),
+        Some("10.0.0.1:5061".parse::<SocketAddr>().unwrap())
+    );
+
+    let tel = SipUri::parse("tel:+1-212-555-0101;phone-context=example.com").unwrap();
+    assert_eq!(tel.scheme, UriScheme::Tel);
+    assert_eq!(tel.user.as_deref(), Some("+1-212-555-0101"));
+    assert_eq!(tel.param("phone-context"), Some("example.com"));
+    assert_eq!(
+        tel.to_string(),
+        "tel:+1-212-555-0101;phone-context=example.com"
+    );
+}
+
+#[test]
+fn escaped_and_long_usernames() {
+    let uri = SipUri::parse("sip:john%20doe@host").unwrap();
+    assert_eq!(uri.user.as_deref(), Some("john doe"));
+    assert_eq!(uri.to_string(), "sip:john%20doe@host");
+
+    let long_user = "a-very-long-user-name-beyond-sixteen";
+    let uri = SipUri::parse(&format!("sip:{}@host", long_user)).unwrap();
+    assert_eq!(uri.user.as_deref(), Some(long_user));
+}
+
+#[test]
+fn question_mark_in_user_part_and_at_sign_in_headers() {
+    let uri = SipUri::parse("sip:what?now@host;lr?subject=hi").unwrap();
+    assert_eq!(uri.user.as_deref(), Some("what?now"));
+    assert_eq!(uri.host, "host");
+    assert!(uri.has_param("lr"));
+    assert_eq!(uri.header("Subject"), Some("hi"));
+
+    let uri = SipUri::parse("sip:1003@server?Replaces=abc@pc33%3Bto-tag%3D1").unwrap();
+    assert_eq!(uri.user.as_deref(), Some("1003"));
+    assert_eq!(uri.host, "server");
+    assert_eq!(uri.header("Replaces"), Some("abc@pc33;to-tag=1"));
+}
+
+#[test]
+fn only_two_hex_digits_make_an_escape() {
+    assert_eq!(percent_decode("%+1"), "%+1");
+    assert_eq!(percent_decode("%-1x%4"), "%-1x%4");
+    assert_eq!(percent_decode("100%25%41"), "100%A");
+}
+
+#[test]
+fn ipv6_host_and_socket_addr() {
+    let uri = SipUri::parse("sip:1001@[2001:db8::1]:5080").unwrap();
+    assert_eq!(uri.host, "2001:db8::1");
+    assert_eq!(uri.port, Some(5080));
+    assert_eq!(uri.host_port(), "[2001:db8::1]:5080");
+    assert_eq!(
+        uri.socket_addr(),
+        Some("[2001:db8::1]:5080".parse::<SocketAddr>().unwrap())
+    );
+    assert!(SipUri::parse("sip:1001@example.com")
+        .unwrap()
+        .socket_addr()
+        .is_none());
+}
+
+#[test]
+fn invalid_uris_are_rejected() {
+    assert!(matches!(
+        SipUri::parse("1001@host"),
+        Err(UriError::MissingScheme(_))
+    ));
+    assert!(matches!(
+        SipUri::parse("http://host"),
+        Err(UriError::UnsupportedScheme(_))
+    ));
+    assert!(matches!(
+        SipUri::parse("sip:1001@host:port"),
+        Err(UriError::InvalidPort(_))
+    ));
+    assert!(matches!(
+        SipUri::parse("sip:"),
+        Err(UriError::MissingHost(_))
+    ));
+}
+
+#[test]
+fn name_addr_with_display_name_and_tag() {
+    let addr =
+        NameAddr::parse("\"Alice \\\"A\\\" Smith\" <sip:1001@server;transport=udp>;tag=1928301774")
+            .unwrap();
+    assert_eq!(addr.display_name.as_deref(), Some("Alice \"A\" Smith"));
+    assert_eq!(addr.user(), Some("1001"));
+    assert_eq!(addr.uri.param("transport"), Some("udp"));
+    assert_eq!(addr.tag(), Some("1928301774"));
+}
+
+#[test]
+fn bare_addr_spec_params_belong_to_header() {
+    let addr = NameAddr::parse("sip:1002@192.168.1.20:5060;tag=abc;expires=60").unwrap();
+    assert!(addr.display_name.is_none());
+    assert_eq!(addr.uri.to_string(), "sip:1002@192.168.1.20:5060");
+    assert_eq!(addr.tag(), Some("abc"));
+    assert_eq!(addr.param("expires"), Some("60"));
+}
+
+#[test]
+fn name_addr_round_trip_and_tag_update() {
+    let mut addr = NameAddr::parse("Bob <sip:1002@server>").unwrap();
+    assert_eq!(addr.display_name.as_deref(), Some("Bob"));
+    assert!(addr.tag().is_none());
+    addr.set_tag("xyz");
+    assert_eq!(addr.to_string(), "\"Bob\" <sip:1002@server>;tag=xyz");
+    addr.remove_param("tag");
+    assert_eq!(addr.to_string(), "\"Bob\" <sip:1002@server>");
+}
+
+#[test]
+fn unterminated_name_addr_is_an_error() {
+    assert!(matches!(
+        NameAddr::parse("<sip:1001@host"),
+        Err(UriError::Unterminated(_))
+    ));
+}
+
+#[test]
+fn aor_normalises_host_case_and_drops_port() {
+    let uri = SipUri::parse("sip:1001@Example.COM:5060;transport=udp").unwrap();
+    assert_eq!(uri.aor(), "sip:1001@example.com");
+}